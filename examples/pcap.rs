@@ -1,11 +1,11 @@
 use std::collections::VecDeque;
 use std::fs::File;
 use std::io::{self, Write};
-use std::time::{SystemTime, UNIX_EPOCH};
 use std::{env, process};
 
 use byteorder::{WriteBytesExt, LE};
 use ixy::memory::Packet;
+use ixy::timestamp;
 use ixy::*;
 use simple_logger::SimpleLogger;
 
@@ -44,18 +44,21 @@ pub fn main() -> Result<(), io::Error> {
     pcap.write_u32::<LE>(65535)?; // snaplen
     pcap.write_u32::<LE>(1)?; // network: Ethernet
 
-    let mut dev = ixy_init(&pci_addr, 1, 1, 0).unwrap();
+    let config = DeviceConfig::new().timestamping(true);
+    let mut dev = ixy_init_with_config(&pci_addr, &config).unwrap();
 
     let mut buffer: VecDeque<Packet> = VecDeque::with_capacity(BATCH_SIZE);
     while n_packets != Some(0) {
         dev.rx_batch(0, &mut buffer, BATCH_SIZE);
-        let time = SystemTime::now();
-        let time = time.duration_since(UNIX_EPOCH).unwrap();
 
         for packet in buffer.drain(..) {
+            let nanos = packet
+                .timestamp()
+                .map_or_else(timestamp::now, |time| time.nanos());
+
             // pcap record header
-            pcap.write_u32::<LE>(time.as_secs() as u32)?; // ts_sec
-            pcap.write_u32::<LE>(time.subsec_millis())?; // ts_usec
+            pcap.write_u32::<LE>((nanos / 1_000_000_000) as u32)?; // ts_sec
+            pcap.write_u32::<LE>((nanos % 1_000_000_000 / 1000) as u32)?; // ts_usec
             pcap.write_u32::<LE>(packet.len() as u32)?; // incl_len
             pcap.write_u32::<LE>(packet.len() as u32)?; // orig_len
 
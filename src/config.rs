@@ -0,0 +1,402 @@
+use crate::error::IxyError;
+use crate::fdir::FdirMode;
+use crate::memory::CACHE_SIZE;
+use crate::rss::RssConfig;
+
+const DEFAULT_RING_SIZE: usize = 512;
+const DEFAULT_BUFFER_SIZE: usize = 2048;
+const DEFAULT_TX_CLEAN_BATCH: usize = 32;
+const MIN_MEMPOOL_SIZE: usize = 4096;
+const DEFAULT_MTU: usize = 1500;
+const MIN_MTU: usize = 68;
+
+// ethernet header and crc
+const ETHERNET_OVERHEAD: usize = 14 + 4;
+
+/// Configuration of a device, used with [`ixy_init_with_config`](crate::ixy_init_with_config).
+///
+/// Ring sizes must be powers of two within the limits of the driver, the buffer size must be a
+/// power of two between 1 KiB and 16 KiB. Invalid configurations are rejected during
+/// initialization with [`IxyError::InvalidConfig`].
+///
+/// Received frames larger than the buffer size are split over multiple buffers and delivered as
+/// packets with multiple segments, see [`Packet::segments`](crate::memory::Packet::segments).
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use ixy::*;
+///
+/// let config = DeviceConfig::new()
+///     .num_rx_queues(2)
+///     .num_tx_queues(2)
+///     .rx_ring_size(4096)
+///     .tx_ring_size(4096);
+///
+/// let mut dev = ixy_init_with_config("0000:01:00.0", &config).unwrap();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceConfig {
+    pub(crate) num_rx_queues: u16,
+    pub(crate) num_tx_queues: u16,
+    pub(crate) interrupt_timeout: i16,
+    pub(crate) rx_ring_size: usize,
+    pub(crate) tx_ring_size: usize,
+    pub(crate) buffer_size: usize,
+    pub(crate) mempool_size: Option<usize>,
+    pub(crate) tx_clean_batch: usize,
+    pub(crate) mtu: usize,
+    pub(crate) rss: Option<RssConfig>,
+    pub(crate) fdir: Option<FdirMode>,
+    pub(crate) rsc_queues: u64,
+    pub(crate) vlan_strip: bool,
+    pub(crate) promiscuous: bool,
+    pub(crate) timestamping: bool,
+}
+
+/// Limits of a driver a [`DeviceConfig`] is validated against.
+pub(crate) struct DeviceLimits {
+    pub max_queues: u16,
+    pub min_ring_size: usize,
+    pub max_ring_size: usize,
+    pub max_mtu: usize,
+    /// Number of rx queues packets can be distributed to with RSS, 0 if unsupported.
+    pub max_rss_queues: u16,
+    /// Whether the device supports flow director filters.
+    pub flow_director: bool,
+    /// Whether the device supports receive segment coalescing.
+    pub rsc: bool,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        DeviceConfig {
+            num_rx_queues: 1,
+            num_tx_queues: 1,
+            interrupt_timeout: 0,
+            rx_ring_size: DEFAULT_RING_SIZE,
+            tx_ring_size: DEFAULT_RING_SIZE,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            mempool_size: None,
+            tx_clean_batch: DEFAULT_TX_CLEAN_BATCH,
+            mtu: DEFAULT_MTU,
+            rss: None,
+            fdir: None,
+            rsc_queues: 0,
+            vlan_strip: false,
+            promiscuous: true,
+            timestamping: false,
+        }
+    }
+}
+
+impl DeviceConfig {
+    /// Returns the default configuration with one rx and one tx queue of 512 descriptors each.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the number of rx queues.
+    pub fn num_rx_queues(mut self, num: u16) -> Self {
+        self.num_rx_queues = num;
+        self
+    }
+
+    /// Sets the number of tx queues.
+    pub fn num_tx_queues(mut self, num: u16) -> Self {
+        self.num_tx_queues = num;
+        self
+    }
+
+    /// Enables interrupts if `timeout` is greater or less than zero, see
+    /// [`ixy_init`](crate::ixy_init).
+    pub fn interrupt_timeout(mut self, timeout: i16) -> Self {
+        self.interrupt_timeout = timeout;
+        self
+    }
+
+    /// Sets the number of descriptors of each rx queue.
+    pub fn rx_ring_size(mut self, size: usize) -> Self {
+        self.rx_ring_size = size;
+        self
+    }
+
+    /// Sets the number of descriptors of each tx queue.
+    pub fn tx_ring_size(mut self, size: usize) -> Self {
+        self.tx_ring_size = size;
+        self
+    }
+
+    /// Sets the size of the packet buffers in the rx memory pools.
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size;
+        self
+    }
+
+    /// Sets the number of packet buffers in each rx memory pool.
+    ///
+    /// Must be at least the rx ring size plus [`CACHE_SIZE`] for the buffers cached by the
+    /// thread polling the queue. Every other thread that allocates or frees the pool's packets,
+    /// e.g. one sending them out on another device, may cache another `CACHE_SIZE` buffers.
+    ///
+    /// Defaults to the sum of the rx and tx ring sizes and `CACHE_SIZE` but at least 4096
+    /// buffers.
+    pub fn mempool_size(mut self, size: usize) -> Self {
+        self.mempool_size = Some(size);
+        self
+    }
+
+    /// Sets the number of sent packets that are freed at once when cleaning a tx queue.
+    pub fn tx_clean_batch(mut self, batch: usize) -> Self {
+        self.tx_clean_batch = batch;
+        self
+    }
+
+    /// Sets the maximum transmission unit, i.e. the largest payload of an ethernet frame.
+    ///
+    /// MTUs above 1500 enable jumbo frames.
+    pub fn mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Enables receive side scaling to distribute received packets over the rx queues.
+    pub fn rss(mut self, rss: RssConfig) -> Self {
+        self.rss = Some(rss);
+        self
+    }
+
+    /// Enables the flow director in `mode`, see [`fdir`](crate::fdir).
+    pub fn flow_director(mut self, mode: FdirMode) -> Self {
+        self.fdir = Some(mode);
+        self
+    }
+
+    /// Enables receive segment coalescing on the rx queues set in the bit mask `queues`.
+    ///
+    /// The device merges consecutive TCP segments of a flow into one large packet that spans
+    /// multiple buffers, see [`Packet::coalesced_segments`](crate::memory::Packet::coalesced_segments).
+    pub fn rsc(mut self, queues: u64) -> Self {
+        self.rsc_queues = queues;
+        self
+    }
+
+    /// Enables stripping the 802.1Q tags of received packets, see
+    /// [`Packet::vlan_tci`](crate::memory::Packet::vlan_tci).
+    pub fn vlan_strip(mut self, enabled: bool) -> Self {
+        self.vlan_strip = enabled;
+        self
+    }
+
+    /// Sets whether the device starts in promiscuous mode, enabled by default to make testing
+    /// easier. See [`IxyDevice::set_promiscuous`](crate::IxyDevice::set_promiscuous).
+    pub fn promiscuous(mut self, enabled: bool) -> Self {
+        self.promiscuous = enabled;
+        self
+    }
+
+    /// Enables timestamping of received packets and of sent packets that request it, see
+    /// [`timestamp`](crate::timestamp).
+    pub fn timestamping(mut self, enabled: bool) -> Self {
+        self.timestamping = enabled;
+        self
+    }
+
+    /// Returns whether receive segment coalescing is enabled on rx queue `queue_id`.
+    pub(crate) fn rsc_enabled(&self, queue_id: u16) -> bool {
+        queue_id < 64 && self.rsc_queues & (1 << queue_id) != 0
+    }
+
+    /// Returns the number of rx queues RSS distributes packets to by default.
+    pub(crate) fn num_rss_queues(&self, limits: &DeviceLimits) -> u16 {
+        self.num_rx_queues.min(limits.max_rss_queues)
+    }
+
+    /// Returns the size of the largest frame including ethernet header and crc.
+    pub(crate) fn max_frame_size(&self) -> usize {
+        self.mtu + ETHERNET_OVERHEAD
+    }
+
+    /// Returns whether jumbo frames are enabled.
+    pub(crate) fn jumbo_frames(&self) -> bool {
+        self.mtu > DEFAULT_MTU
+    }
+
+    /// Returns the number of packet buffers in each rx memory pool.
+    pub(crate) fn rx_mempool_size(&self) -> usize {
+        self.mempool_size.unwrap_or_else(|| {
+            (self.rx_ring_size + self.tx_ring_size + CACHE_SIZE).max(MIN_MEMPOOL_SIZE)
+        })
+    }
+
+    /// Checks this configuration against the limits of a driver.
+    pub(crate) fn validate(&self, limits: &DeviceLimits) -> Result<(), IxyError> {
+        if self.num_rx_queues > limits.max_queues || self.num_tx_queues > limits.max_queues {
+            return Err(IxyError::InvalidConfig(format!(
+                "cannot configure {} rx and {} tx queues: limit is {}",
+                self.num_rx_queues, self.num_tx_queues, limits.max_queues
+            )));
+        }
+
+        for &(name, size) in &[("rx", self.rx_ring_size), ("tx", self.tx_ring_size)] {
+            if !size.is_power_of_two() || size < limits.min_ring_size || size > limits.max_ring_size
+            {
+                return Err(IxyError::InvalidConfig(format!(
+                    "{} ring size {} must be a power of 2 between {} and {}",
+                    name, size, limits.min_ring_size, limits.max_ring_size
+                )));
+            }
+        }
+
+        if !self.buffer_size.is_power_of_two() || !(1024..=16384).contains(&self.buffer_size) {
+            return Err(IxyError::InvalidConfig(format!(
+                "buffer size {} must be a power of 2 between 1024 and 16384",
+                self.buffer_size
+            )));
+        }
+
+        // the thread polling a queue caches up to CACHE_SIZE buffers on top of the filled ring
+        if self.rx_mempool_size() < self.rx_ring_size + CACHE_SIZE {
+            return Err(IxyError::InvalidConfig(format!(
+                "mempool size {} is smaller than the rx ring size {} plus {} cached buffers",
+                self.rx_mempool_size(),
+                self.rx_ring_size,
+                CACHE_SIZE
+            )));
+        }
+
+        if self.mtu < MIN_MTU || self.mtu > limits.max_mtu {
+            return Err(IxyError::InvalidConfig(format!(
+                "mtu {} must be between {} and {}",
+                self.mtu, MIN_MTU, limits.max_mtu
+            )));
+        }
+
+        if let Some(rss) = &self.rss {
+            if limits.max_rss_queues == 0 {
+                return Err(IxyError::InvalidConfig(
+                    "rss is not supported by this device".to_string(),
+                ));
+            }
+
+            let num_queues = self.num_rss_queues(limits);
+            if let Some(&queue) = rss.redirection_table(num_queues).iter().max() {
+                if u16::from(queue) >= num_queues {
+                    return Err(IxyError::InvalidConfig(format!(
+                        "rss redirects to queue {} but only {} rx queues can be used",
+                        queue, num_queues
+                    )));
+                }
+            }
+        }
+
+        if self.fdir.is_some() && !limits.flow_director {
+            return Err(IxyError::InvalidConfig(
+                "flow director is not supported by this device".to_string(),
+            ));
+        }
+
+        if self.rsc_queues != 0 {
+            if !limits.rsc {
+                return Err(IxyError::InvalidConfig(
+                    "rsc is not supported by this device".to_string(),
+                ));
+            }
+
+            if self
+                .rsc_queues
+                .checked_shr(u32::from(self.num_rx_queues))
+                .unwrap_or(0)
+                != 0
+            {
+                return Err(IxyError::InvalidConfig(format!(
+                    "rsc queues {:#x} exceed the {} rx queues",
+                    self.rsc_queues, self.num_rx_queues
+                )));
+            }
+        }
+
+        if self.tx_clean_batch == 0 || self.tx_clean_batch >= self.tx_ring_size {
+            return Err(IxyError::InvalidConfig(format!(
+                "tx clean batch {} must be between 1 and the tx ring size {}",
+                self.tx_clean_batch, self.tx_ring_size
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: DeviceLimits = DeviceLimits {
+        max_queues: 8,
+        min_ring_size: 64,
+        max_ring_size: 4096,
+        max_mtu: 9000,
+        max_rss_queues: 4,
+        flow_director: false,
+        rsc: true,
+    };
+
+    #[test]
+    fn test_validate() {
+        assert!(DeviceConfig::new().validate(&LIMITS).is_ok());
+        assert!(DeviceConfig::new()
+            .rx_ring_size(4096)
+            .tx_ring_size(64)
+            .buffer_size(1024)
+            .mtu(9000)
+            .num_rx_queues(8)
+            .rss(RssConfig::new().reta([3; 128]))
+            .rsc(0x81)
+            .validate(&LIMITS)
+            .is_ok());
+
+        let invalid = [
+            DeviceConfig::new().num_rx_queues(9),
+            DeviceConfig::new().rx_ring_size(1000),
+            DeviceConfig::new().tx_ring_size(8192),
+            DeviceConfig::new().rx_ring_size(32),
+            DeviceConfig::new().buffer_size(3000),
+            DeviceConfig::new().buffer_size(32768),
+            DeviceConfig::new().mempool_size(256),
+            DeviceConfig::new().mempool_size(DEFAULT_RING_SIZE),
+            DeviceConfig::new().tx_clean_batch(0),
+            DeviceConfig::new().tx_ring_size(64).tx_clean_batch(64),
+            DeviceConfig::new().mtu(9001),
+            DeviceConfig::new().mtu(0),
+            DeviceConfig::new()
+                .num_rx_queues(8)
+                .rss(RssConfig::new().reta([4; 128])),
+            DeviceConfig::new()
+                .num_rx_queues(2)
+                .rss(RssConfig::new().reta([2; 128])),
+            DeviceConfig::new().num_rx_queues(2).rsc(0b100),
+        ];
+        for config in &invalid {
+            match config.validate(&LIMITS) {
+                Err(IxyError::InvalidConfig(_)) => {}
+                _ => panic!("{:?} should be invalid", config),
+            }
+        }
+    }
+
+    #[test]
+    fn test_mempool_size() {
+        assert_eq!(DeviceConfig::new().rx_mempool_size(), 4096);
+        assert_eq!(
+            DeviceConfig::new()
+                .rx_ring_size(4096)
+                .tx_ring_size(4096)
+                .rx_mempool_size(),
+            8192 + CACHE_SIZE
+        );
+        assert_eq!(
+            DeviceConfig::new().mempool_size(1024).rx_mempool_size(),
+            1024
+        );
+    }
+}
@@ -0,0 +1,110 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Errors returned by ixy.
+///
+/// Variants are coarse enough to decide how to recover, e.g. whether a device should be reset
+/// (`MailboxTimeout`) or the host is misconfigured (`Dma`, `Vfio`).
+#[derive(Debug)]
+pub enum IxyError {
+    /// Accessing a resource of the device at `pci_addr` via sysfs failed.
+    Pci { pci_addr: String, source: io::Error },
+
+    /// The device at `pci_addr` is not a supported network card.
+    UnsupportedDevice { pci_addr: String, reason: String },
+
+    /// A VFIO ioctl or another syscall needed for VFIO failed with `errno`, `0` if the call
+    /// succeeded but returned an unusable result.
+    Vfio { operation: &'static str, errno: i32 },
+
+    /// Allocating, locking or mapping DMA memory (e.g. huge pages) failed.
+    Dma(String),
+
+    /// A memory pool has no free buffers left.
+    MempoolExhausted,
+
+    /// The device does not offer all features required by the driver.
+    FeatureNegotiation { required: u64, offered: u64 },
+
+    /// The PF did not answer a mailbox request of a VF in time.
+    MailboxTimeout(&'static str),
+
+    /// Communicating with the PF via the mailbox failed for reasons other than a timeout.
+    Mailbox(&'static str),
+
+    /// The device reported an error, e.g. a failed status or a rejected command.
+    Device(String),
+
+    /// The requested configuration is not supported by the device or the driver.
+    InvalidConfig(String),
+
+    /// Any other I/O error.
+    Io(io::Error),
+}
+
+impl IxyError {
+    /// Returns an `IxyError::Vfio` for `operation` with the last OS error as errno.
+    pub(crate) fn last_vfio_error(operation: &'static str) -> IxyError {
+        IxyError::Vfio {
+            operation,
+            errno: io::Error::last_os_error().raw_os_error().unwrap_or(0),
+        }
+    }
+
+    /// Returns an `IxyError::Dma` for `reason` with the last OS error appended.
+    pub(crate) fn last_dma_error(reason: &str) -> IxyError {
+        IxyError::Dma(format!("{}. Errno: {}", reason, io::Error::last_os_error()))
+    }
+}
+
+impl fmt::Display for IxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IxyError::Pci { pci_addr, source } => {
+                write!(f, "failed to access pci device {}: {}", pci_addr, source)
+            }
+            IxyError::UnsupportedDevice { pci_addr, reason } => {
+                write!(f, "unsupported device {}: {}", pci_addr, reason)
+            }
+            IxyError::Vfio {
+                operation,
+                errno: 0,
+            } => write!(f, "failed to {}", operation),
+            IxyError::Vfio { operation, errno } => write!(
+                f,
+                "failed to {}. Errno: {}",
+                operation,
+                io::Error::from_raw_os_error(*errno)
+            ),
+            IxyError::Dma(reason) => write!(f, "{}", reason),
+            IxyError::MempoolExhausted => write!(f, "memory pool exhausted"),
+            IxyError::FeatureNegotiation { required, offered } => write!(
+                f,
+                "device does not support all required features (required: {:#x}, offered: {:#x})",
+                required, offered
+            ),
+            IxyError::MailboxTimeout(reason) => write!(f, "timeout while {}", reason),
+            IxyError::Mailbox(reason) => write!(f, "mailbox error: {}", reason),
+            IxyError::Device(reason) => write!(f, "device error: {}", reason),
+            IxyError::InvalidConfig(reason) => write!(f, "invalid configuration: {}", reason),
+            IxyError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for IxyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            IxyError::Pci { source, .. } => Some(source),
+            IxyError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for IxyError {
+    fn from(e: io::Error) -> IxyError {
+        IxyError::Io(e)
+    }
+}
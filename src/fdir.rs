@@ -0,0 +1,319 @@
+//! Flow director filters steer received packets to rx queues or drop them based on their
+//! addresses, ports, protocol and vlan.
+//!
+//! Filters are added with [`IxyDevice::add_flow_filter`](crate::IxyDevice::add_flow_filter)
+//! after enabling the flow director with
+//! [`DeviceConfig::flow_director`](crate::DeviceConfig::flow_director). Packets that match no
+//! filter are distributed as usual, e.g. with RSS.
+
+use std::net::Ipv4Addr;
+
+/// Key of the bucket hash, the same as used by the Linux driver.
+pub(crate) const FDIR_BUCKET_HASH_KEY: u32 = 0x3dad_14e2;
+
+/// Key of the signature hash, the same as used by the Linux driver.
+pub(crate) const FDIR_SIGNATURE_HASH_KEY: u32 = 0x174d_3614;
+
+/// Matching mode of the flow director.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FdirMode {
+    /// Filters compare all matched fields of a packet.
+    Perfect,
+    /// Filters only compare a hash of the matched fields, packets of other flows with colliding
+    /// hashes match as well. Supports more filters than perfect match mode but no drop action.
+    Signature,
+}
+
+/// Protocol of a flow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowProtocol {
+    /// IPv4 packets that are neither TCP, UDP nor SCTP packets.
+    Ip,
+    Tcp,
+    Udp,
+    /// SCTP packets, filters can't match their ports.
+    Sctp,
+}
+
+impl FlowProtocol {
+    /// Returns the flow type of this protocol used by the flow director.
+    pub(crate) fn flow_type(self) -> u32 {
+        match self {
+            FlowProtocol::Ip => 0,
+            FlowProtocol::Udp => 1,
+            FlowProtocol::Tcp => 2,
+            FlowProtocol::Sctp => 3,
+        }
+    }
+
+    /// Returns the protocol of the flow director's flow type `flow_type`.
+    #[cfg(test)]
+    pub(crate) fn from_flow_type(flow_type: u32) -> FlowProtocol {
+        match flow_type & 0x3 {
+            1 => FlowProtocol::Udp,
+            2 => FlowProtocol::Tcp,
+            3 => FlowProtocol::Sctp,
+            _ => FlowProtocol::Ip,
+        }
+    }
+}
+
+/// Action applied to the packets matching a filter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FilterAction {
+    /// Receive the packets on the rx queue with this id.
+    Queue(u16),
+    /// Drop the packets.
+    Drop,
+}
+
+/// IPv4 flow matched by a flow director filter, fields that are not set match any value.
+///
+/// The device compares the same fields for all filters, i.e. all filters added to a device must
+/// set the same fields.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use ixy::*;
+/// use ixy::fdir::*;
+/// use std::net::Ipv4Addr;
+///
+/// let config = DeviceConfig::new()
+///     .num_rx_queues(4)
+///     .flow_director(FdirMode::Perfect);
+/// let mut dev = ixy_init_with_config("0000:01:00.0", &config).unwrap();
+///
+/// // receive bgp sessions to 10.0.0.1 on a dedicated queue
+/// let bgp = FlowFilter::new(FlowProtocol::Tcp)
+///     .dst_addr(Ipv4Addr::new(10, 0, 0, 1))
+///     .dst_port(179);
+/// let id = dev.add_flow_filter(&bgp, FilterAction::Queue(3)).unwrap();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowFilter {
+    pub(crate) protocol: FlowProtocol,
+    pub(crate) src_addr: Option<Ipv4Addr>,
+    pub(crate) dst_addr: Option<Ipv4Addr>,
+    pub(crate) src_port: Option<u16>,
+    pub(crate) dst_port: Option<u16>,
+    pub(crate) vlan: Option<u16>,
+}
+
+/// The fields compared by the flow director, `true` if a field is compared.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct FlowMask {
+    pub src_addr: bool,
+    pub dst_addr: bool,
+    pub src_port: bool,
+    pub dst_port: bool,
+    pub vlan: bool,
+}
+
+impl FlowFilter {
+    /// Returns a filter matching all packets of `protocol`.
+    pub fn new(protocol: FlowProtocol) -> Self {
+        FlowFilter {
+            protocol,
+            src_addr: None,
+            dst_addr: None,
+            src_port: None,
+            dst_port: None,
+            vlan: None,
+        }
+    }
+
+    /// Matches packets from `addr`.
+    pub fn src_addr(mut self, addr: Ipv4Addr) -> Self {
+        self.src_addr = Some(addr);
+        self
+    }
+
+    /// Matches packets to `addr`.
+    pub fn dst_addr(mut self, addr: Ipv4Addr) -> Self {
+        self.dst_addr = Some(addr);
+        self
+    }
+
+    /// Matches packets from `port`.
+    pub fn src_port(mut self, port: u16) -> Self {
+        self.src_port = Some(port);
+        self
+    }
+
+    /// Matches packets to `port`.
+    pub fn dst_port(mut self, port: u16) -> Self {
+        self.dst_port = Some(port);
+        self
+    }
+
+    /// Matches packets with the vlan id `vlan`, untagged packets have the vlan id 0.
+    pub fn vlan(mut self, vlan: u16) -> Self {
+        self.vlan = Some(vlan & 0xfff);
+        self
+    }
+
+    /// Returns the flow of the ethernet `frame` with all fields set, or [`None`] if it is not an
+    /// IPv4 packet.
+    #[cfg(test)]
+    pub(crate) fn from_frame(frame: &[u8]) -> Option<FlowFilter> {
+        let mut ether_type = u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]);
+        let mut offset = 14;
+        let mut vlan = 0;
+
+        if ether_type == 0x8100 {
+            vlan = u16::from_be_bytes([*frame.get(14)?, *frame.get(15)?]) & 0xfff;
+            ether_type = u16::from_be_bytes([*frame.get(16)?, *frame.get(17)?]);
+            offset = 18;
+        }
+
+        if ether_type != 0x0800 {
+            return None;
+        }
+
+        let ip = frame.get(offset..offset + 20)?;
+        let protocol = match ip[9] {
+            6 => FlowProtocol::Tcp,
+            17 => FlowProtocol::Udp,
+            132 => FlowProtocol::Sctp,
+            _ => FlowProtocol::Ip,
+        };
+
+        let mut filter = FlowFilter::new(protocol)
+            .src_addr(Ipv4Addr::new(ip[12], ip[13], ip[14], ip[15]))
+            .dst_addr(Ipv4Addr::new(ip[16], ip[17], ip[18], ip[19]))
+            .vlan(vlan);
+
+        let l4 = offset + usize::from(ip[0] & 0xf) * 4;
+        if protocol == FlowProtocol::Tcp || protocol == FlowProtocol::Udp {
+            let ports = frame.get(l4..l4 + 4)?;
+            filter = filter
+                .src_port(u16::from_be_bytes([ports[0], ports[1]]))
+                .dst_port(u16::from_be_bytes([ports[2], ports[3]]));
+        }
+
+        Some(filter)
+    }
+
+    /// Returns the fields matched by this filter.
+    pub(crate) fn mask(&self) -> FlowMask {
+        FlowMask {
+            src_addr: self.src_addr.is_some(),
+            dst_addr: self.dst_addr.is_some(),
+            src_port: self.src_port.is_some(),
+            dst_port: self.dst_port.is_some(),
+            vlan: self.vlan.is_some(),
+        }
+    }
+
+    /// Returns this filter with all fields not in `mask` cleared.
+    #[cfg(test)]
+    pub(crate) fn masked(&self, mask: FlowMask) -> FlowFilter {
+        FlowFilter {
+            protocol: self.protocol,
+            src_addr: self.src_addr.filter(|_| mask.src_addr),
+            dst_addr: self.dst_addr.filter(|_| mask.dst_addr),
+            src_port: self.src_port.filter(|_| mask.src_port),
+            dst_port: self.dst_port.filter(|_| mask.dst_port),
+            vlan: self.vlan.filter(|_| mask.vlan),
+        }
+    }
+
+    /// Returns the hash input of this filter as big endian dwords, i.e. the flow type and vlan,
+    /// the destination and source addresses, the ports and the flexible bytes.
+    pub(crate) fn hash_input(&self) -> [u32; 11] {
+        let mut input = [0; 11];
+        input[0] = self.protocol.flow_type() << 16 | u32::from(self.vlan.unwrap_or(0));
+        input[1] = self.dst_addr.map_or(0, u32::from);
+        input[5] = self.src_addr.map_or(0, u32::from);
+        input[9] =
+            u32::from(self.src_port.unwrap_or(0)) << 16 | u32::from(self.dst_port.unwrap_or(0));
+        input
+    }
+}
+
+/// A filter installed on a device, see [`IxyDevice::flow_filters`](crate::IxyDevice::flow_filters).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowFilterEntry {
+    pub id: u16,
+    pub filter: FlowFilter,
+    pub action: FilterAction,
+    /// Number of packets received with [`IxyDevice::rx_batch`](crate::IxyDevice::rx_batch)
+    /// that matched this filter. [`None`] if they are not counted, which is the case for
+    /// filters dropping packets, in signature mode and once the queues are split.
+    pub hits: Option<u64>,
+}
+
+/// Holds flow director stats about received packets.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FdirStats {
+    /// Packets that matched a filter, including dropped packets.
+    pub matched: u64,
+    /// Packets that matched no filter.
+    pub missed: u64,
+}
+
+/// Returns the flow director hash of `input` with `key`.
+///
+/// This follows `ixgbe_atr_compute_perfect_hash_82599` of the Linux driver.
+pub(crate) fn fdir_hash(input: &[u32; 11], key: u32) -> u32 {
+    let flow_vm_vlan = input[0];
+    let common = input[1..].iter().fold(0, |hash, dword| hash ^ dword);
+
+    let hi = common ^ flow_vm_vlan ^ (flow_vm_vlan >> 16);
+    let mut lo = common.rotate_left(16);
+
+    let mut hash = 0;
+    for n in 0..16 {
+        // the vlan is added to the low dword after its first bit was processed
+        if n == 1 {
+            lo ^= flow_vm_vlan ^ (flow_vm_vlan << 16);
+        }
+        if key & (1 << n) != 0 {
+            hash ^= lo >> n;
+        }
+        if key & (1 << (n + 16)) != 0 {
+            hash ^= hi >> n;
+        }
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_frame() {
+        let mut frame = vec![0; 64];
+        frame[12..14].copy_from_slice(&[0x81, 0x00]);
+        frame[14..16].copy_from_slice(&[0x20, 0x2a]);
+        frame[16..18].copy_from_slice(&[0x08, 0x00]);
+        frame[18] = 0x45;
+        frame[27] = 17;
+        frame[30..38].copy_from_slice(&[10, 0, 0, 2, 10, 0, 0, 1]);
+        frame[38..42].copy_from_slice(&[0x30, 0x39, 0x00, 0x35]);
+
+        let filter = FlowFilter::new(FlowProtocol::Udp)
+            .src_addr(Ipv4Addr::new(10, 0, 0, 2))
+            .dst_addr(Ipv4Addr::new(10, 0, 0, 1))
+            .src_port(12345)
+            .dst_port(53)
+            .vlan(42);
+        assert_eq!(FlowFilter::from_frame(&frame), Some(filter));
+
+        let dns = FlowFilter::new(FlowProtocol::Udp).dst_port(53);
+        assert_eq!(filter.masked(dns.mask()), dns);
+        assert_eq!(
+            fdir_hash(
+                &filter.masked(dns.mask()).hash_input(),
+                FDIR_BUCKET_HASH_KEY
+            ),
+            fdir_hash(&dns.hash_input(), FDIR_BUCKET_HASH_KEY)
+        );
+
+        frame[16..18].copy_from_slice(&[0x86, 0xdd]);
+        assert_eq!(FlowFilter::from_frame(&frame), None);
+    }
+}
@@ -1,10 +1,11 @@
+use crate::error::IxyError;
 use crate::vfio::{
     vfio_irq_info, vfio_irq_set, Event, VFIO_DEVICE_GET_IRQ_INFO, VFIO_DEVICE_SET_IRQS,
     VFIO_IRQ_INFO_EVENTFD, VFIO_IRQ_SET_ACTION_TRIGGER, VFIO_IRQ_SET_DATA_EVENTFD,
     VFIO_IRQ_SET_DATA_NONE, VFIO_PCI_MSIX_IRQ_INDEX, VFIO_PCI_MSI_IRQ_INDEX,
 };
 use std::collections::VecDeque;
-use std::error::Error;
+use std::io;
 use std::mem;
 use std::os::unix::io::RawFd;
 use std::time::Instant;
@@ -42,7 +43,7 @@ pub struct InterruptMovingAvg {
 
 impl Interrupts {
     /// Setup VFIO interrupts by checking the `device_fd` for which interrupts this device supports.
-    pub fn vfio_setup_interrupt(&mut self, device_fd: RawFd) -> Result<(), Box<dyn Error>> {
+    pub fn vfio_setup_interrupt(&mut self, device_fd: RawFd) -> Result<(), IxyError> {
         info!("setting up VFIO interrupts");
         for index in (0..=VFIO_PCI_MSIX_IRQ_INDEX).rev() {
             let mut irq_info: vfio_irq_info = vfio_irq_info {
@@ -53,12 +54,7 @@ impl Interrupts {
             };
 
             if unsafe { libc::ioctl(device_fd, VFIO_DEVICE_GET_IRQ_INFO, &mut irq_info) } == -1 {
-                return Err(format!(
-                    "failed to VFIO_DEVICE_GET_IRQ_INFO for index {}. Errno: {}",
-                    index,
-                    std::io::Error::last_os_error()
-                )
-                .into());
+                return Err(IxyError::last_vfio_error("VFIO_DEVICE_GET_IRQ_INFO"));
             }
 
             if (irq_info.flags & VFIO_IRQ_INFO_EVENTFD) == 0 {
@@ -72,11 +68,66 @@ impl Interrupts {
         self.interrupt_type = 0;
         Ok(())
     }
+
+    /// Enables VFIO MSI-X interrupts with one vector per queue for the given `device_fd`.
+    ///
+    /// Vector `i` signals the event fd of the `i`th queue, all vectors are set up at once as
+    /// VFIO may not allow adding vectors later on.
+    pub fn vfio_enable_msix_queues(
+        &mut self,
+        device_fd: RawFd,
+        num_queues: u16,
+    ) -> Result<(), IxyError> {
+        info!("enabling MSIX interrupts for {} queues", num_queues);
+        if u32::from(num_queues) > MAX_INTERRUPT_VECTORS {
+            return Err(IxyError::Vfio {
+                operation: "enable MSI-X interrupts (too many vectors)",
+                errno: libc::EINVAL,
+            });
+        }
+
+        self.queues = Vec::with_capacity(num_queues as usize);
+        let mut event_fds = [-1; MAX_INTERRUPT_VECTORS as usize];
+        for event_fd in event_fds.iter_mut().take(num_queues as usize) {
+            *event_fd = unsafe { libc::eventfd(0, 0) };
+            if *event_fd == -1 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            let mut queue = InterruptsQueue {
+                vfio_event_fd: *event_fd,
+                vfio_epoll_fd: 0,
+                last_time_checked: Instant::now(),
+                rx_pkts: 0,
+                moving_avg: Default::default(),
+                interrupt_enabled: true,
+                interval: INTERRUPT_INITIAL_INTERVAL,
+                instr_counter: 0,
+            };
+            queue.vfio_epoll_ctl(*event_fd)?;
+            self.queues.push(queue);
+        }
+
+        let irq_set: vfio_irq_set<[RawFd; MAX_INTERRUPT_VECTORS as usize]> = vfio_irq_set {
+            argsz: mem::size_of::<vfio_irq_set<[RawFd; MAX_INTERRUPT_VECTORS as usize]>>() as u32,
+            count: u32::from(num_queues),
+            flags: VFIO_IRQ_SET_DATA_EVENTFD | VFIO_IRQ_SET_ACTION_TRIGGER,
+            index: VFIO_PCI_MSIX_IRQ_INDEX as u32,
+            start: 0,
+            data: event_fds,
+        };
+
+        if unsafe { libc::ioctl(device_fd, VFIO_DEVICE_SET_IRQS, &irq_set) } == -1 {
+            return Err(IxyError::last_vfio_error("VFIO_DEVICE_SET_IRQS"));
+        }
+
+        Ok(())
+    }
 }
 
 impl InterruptsQueue {
     /// Add the `event_fd` file descriptor to epoll.
-    pub fn vfio_epoll_ctl(&mut self, event_fd: RawFd) -> Result<(), Box<dyn Error>> {
+    pub fn vfio_epoll_ctl(&mut self, event_fd: RawFd) -> Result<(), IxyError> {
         let mut event: Event = Event {
             events: libc::EPOLLIN as u32,
             data: event_fd as u64,
@@ -84,11 +135,7 @@ impl InterruptsQueue {
 
         let epoll_fd: RawFd = unsafe { libc::epoll_create1(0) };
         if epoll_fd == -1 {
-            return Err(format!(
-                "failed to epoll_create1. Errno: {}",
-                std::io::Error::last_os_error()
-            )
-            .into());
+            return Err(io::Error::last_os_error().into());
         }
 
         if unsafe {
@@ -100,11 +147,9 @@ impl InterruptsQueue {
             )
         } == -1
         {
-            return Err(format!(
-                "failed to epoll_ctl. Errno: {}",
-                std::io::Error::last_os_error()
-            )
-            .into());
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(epoll_fd) };
+            return Err(err.into());
         }
 
         self.vfio_epoll_fd = epoll_fd;
@@ -118,9 +163,8 @@ impl InterruptsQueue {
     /// Specifying a `timeout` of -1 causes epoll_wait to block indefinitely, while specifying a
     /// `timeout` equal to zero cause epoll_wait to return immediately, even if no events are available.
     /// Returns the number of ready file descriptors.
-    pub fn vfio_epoll_wait(&self, timeout: i32) -> Result<usize, Box<dyn Error>> {
+    pub fn vfio_epoll_wait(&self, timeout: i32) -> Result<usize, IxyError> {
         let mut events = [Event::default(); 10];
-        let rc: usize;
 
         let status = unsafe {
             libc::epoll_wait(
@@ -131,13 +175,9 @@ impl InterruptsQueue {
             )
         };
         if status == -1 {
-            return Err(format!(
-                "failed to epoll_wait. Errno: {}",
-                std::io::Error::last_os_error()
-            )
-            .into());
+            return Err(io::Error::last_os_error().into());
         }
-        rc = status as usize;
+        let rc = status as usize;
         if rc > 0 {
             /* epoll_wait has at least one fd ready to read */
             for event in events.iter().take(rc) {
@@ -151,11 +191,7 @@ impl InterruptsQueue {
                     )
                 } == -1
                 {
-                    return Err(format!(
-                        "failed to read event. Errno: {}",
-                        std::io::Error::last_os_error()
-                    )
-                    .into());
+                    return Err(io::Error::last_os_error().into());
                 }
             }
         }
@@ -164,17 +200,13 @@ impl InterruptsQueue {
     }
 
     /// Enable VFIO MSI interrupts for the given `device_fd`.
-    pub fn vfio_enable_msi(&mut self, device_fd: RawFd) -> Result<(), Box<dyn Error>> {
+    pub fn vfio_enable_msi(&mut self, device_fd: RawFd) -> Result<(), IxyError> {
         info!("enabling MSI interrupts");
         // setup event fd
         let event_fd: RawFd = unsafe { libc::eventfd(0, 0) };
 
         if event_fd == -1 {
-            return Err(format!(
-                "failed to create eventfd. Errno: {}",
-                std::io::Error::last_os_error()
-            )
-            .into());
+            return Err(io::Error::last_os_error().into());
         }
 
         let irq_set: vfio_irq_set<[u8; 1]> = vfio_irq_set {
@@ -187,11 +219,7 @@ impl InterruptsQueue {
         };
 
         if unsafe { libc::ioctl(device_fd, VFIO_DEVICE_SET_IRQS, &irq_set) } == -1 {
-            return Err(format!(
-                "failed to VFIO_DEVICE_SET_IRQS. Errno: {}",
-                std::io::Error::last_os_error()
-            )
-            .into());
+            return Err(IxyError::last_vfio_error("VFIO_DEVICE_SET_IRQS"));
         }
 
         self.vfio_event_fd = event_fd;
@@ -200,7 +228,7 @@ impl InterruptsQueue {
 
     /// Disable VFIO MSI interrupts for the given `device_fd`.
     #[allow(dead_code)]
-    pub fn vfio_disable_msi(&mut self, device_fd: RawFd) -> Result<(), Box<dyn Error>> {
+    pub fn vfio_disable_msi(&mut self, device_fd: RawFd) -> Result<(), IxyError> {
         info!("disabling MSI interrupts");
         let irq_set: vfio_irq_set<[u8; 0]> = vfio_irq_set {
             argsz: mem::size_of::<vfio_irq_set<[u8; 0]>>() as u32,
@@ -212,11 +240,7 @@ impl InterruptsQueue {
         };
 
         if unsafe { libc::ioctl(device_fd, VFIO_DEVICE_SET_IRQS, &irq_set) } == -1 {
-            return Err(format!(
-                "failed to VFIO_DEVICE_SET_IRQS. Errno: {}",
-                std::io::Error::last_os_error()
-            )
-            .into());
+            return Err(IxyError::last_vfio_error("VFIO_DEVICE_SET_IRQS"));
         }
 
         self.vfio_event_fd = 0;
@@ -230,19 +254,18 @@ impl InterruptsQueue {
         &mut self,
         device_fd: RawFd,
         mut interrupt_vector: u32,
-    ) -> Result<(), Box<dyn Error>> {
+    ) -> Result<(), IxyError> {
         info!("enabling MSIX interrupts");
         if device_fd < 0 {
-            return Err("device file descriptor invalid!".to_string().into());
+            return Err(IxyError::Vfio {
+                operation: "enable MSI-X interrupts (device file descriptor invalid)",
+                errno: libc::EBADF,
+            });
         }
         // setup event fd
         let event_fd: RawFd = unsafe { libc::eventfd(0, 0) };
         if event_fd == -1 {
-            return Err(format!(
-                "failed to create eventfd. Errno: {}",
-                std::io::Error::last_os_error()
-            )
-            .into());
+            return Err(io::Error::last_os_error().into());
         }
 
         if interrupt_vector == 0 {
@@ -261,11 +284,7 @@ impl InterruptsQueue {
         };
 
         if unsafe { libc::ioctl(device_fd, VFIO_DEVICE_SET_IRQS, &irq_set) } == -1 {
-            return Err(format!(
-                "failed to VFIO_DEVICE_SET_IRQS. Errno: {}",
-                std::io::Error::last_os_error()
-            )
-            .into());
+            return Err(IxyError::last_vfio_error("VFIO_DEVICE_SET_IRQS"));
         }
 
         self.vfio_event_fd = event_fd;
@@ -274,7 +293,7 @@ impl InterruptsQueue {
 
     /// Disable VFIO MSI-X interrupts for the given `device_fd`.
     #[allow(dead_code)]
-    pub fn vfio_disable_msix(&mut self, device_fd: RawFd) -> Result<(), Box<dyn Error>> {
+    pub fn vfio_disable_msix(&mut self, device_fd: RawFd) -> Result<(), IxyError> {
         info!("disabling MSIX interrupts");
         let irq_set: vfio_irq_set<[u8; 0]> = vfio_irq_set {
             argsz: mem::size_of::<vfio_irq_set<[u8; 0]>>() as u32,
@@ -286,11 +305,7 @@ impl InterruptsQueue {
         };
 
         if unsafe { libc::ioctl(device_fd, VFIO_DEVICE_SET_IRQS, &irq_set) } == -1 {
-            return Err(format!(
-                "failed to VFIO_DEVICE_SET_IRQS. Errno: {}",
-                std::io::Error::last_os_error()
-            )
-            .into());
+            return Err(IxyError::last_vfio_error("VFIO_DEVICE_SET_IRQS"));
         }
 
         self.vfio_event_fd = 0;
@@ -316,11 +331,38 @@ impl InterruptsQueue {
         }
         self.rx_pkts = 0;
         let average = self.moving_avg.sum / self.moving_avg.measured_rates.len() as u64;
-        if average > INTERRUPT_THRESHOLD || buf_index == buf_size {
-            self.interrupt_enabled = false;
-        } else {
-            self.interrupt_enabled = true;
-        }
+        self.interrupt_enabled = !(average > INTERRUPT_THRESHOLD || buf_index == buf_size);
         self.last_time_checked = Instant::now();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_epoll_errors() {
+        let mut queue = InterruptsQueue {
+            vfio_event_fd: -1,
+            vfio_epoll_fd: -1,
+            interrupt_enabled: true,
+            instr_counter: 0,
+            last_time_checked: Instant::now(),
+            rx_pkts: 0,
+            interval: INTERRUPT_INITIAL_INTERVAL,
+            moving_avg: InterruptMovingAvg::default(),
+        };
+
+        // syscall failures are reported with their errno instead of as vfio errors
+        match queue.vfio_epoll_ctl(-1) {
+            Err(IxyError::Io(e)) => assert_eq!(e.raw_os_error(), Some(libc::EBADF)),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(queue.vfio_epoll_fd, -1);
+
+        match queue.vfio_epoll_wait(0) {
+            Err(IxyError::Io(e)) => assert_eq!(e.raw_os_error(), Some(libc::EBADF)),
+            r => panic!("unexpected result {:?}", r),
+        }
+    }
+}
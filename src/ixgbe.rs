@@ -1,10 +1,9 @@
-use std::collections::VecDeque;
-use std::error::Error;
+use std::collections::{BTreeMap, VecDeque};
 use std::mem;
 use std::os::unix::io::RawFd;
 use std::path::Path;
 use std::ptr;
-use std::rc::Rc;
+use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 use std::time::{Duration, Instant};
 
@@ -13,58 +12,432 @@ use crate::interrupts::*;
 use crate::memory::*;
 use crate::vfio::*;
 
+use crate::config::DeviceLimits;
+use crate::error::IxyError;
+use crate::fdir::*;
+use crate::offload::{self, ChecksumStatus, L3Type, L4Type, TxOffload};
 use crate::pci::pci_map_resource;
+use crate::rss::{RssConfig, RssHashFields};
+use crate::stats::{ExtendedStats, QueueCounters, RxQueueStats, TxQueueStats};
+use crate::timestamp::{self, Timestamp};
 use crate::vfio::VFIO_PCI_BAR0_REGION_INDEX;
+use crate::DeviceConfig;
 use crate::DeviceStats;
 use crate::Interrupts;
 use crate::IxyDevice;
+use crate::{DeviceControl, RxQueue, SplitQueues, TxQueue};
+
+#[cfg(test)]
+mod sim;
 
 const DRIVER_NAME: &str = "ixy-ixgbe";
 
 const MAX_QUEUES: u16 = 64;
 
-const PKT_BUF_ENTRY_SIZE: usize = 2048;
-const MIN_MEMPOOL_SIZE: usize = 4096;
+// section 7.2.3.3 - a frame must not span more than 40 data descriptors
+pub(crate) const MAX_TX_SEGMENTS: usize = 40;
+
+const LIMITS: DeviceLimits = DeviceLimits {
+    max_queues: MAX_QUEUES,
+    min_ring_size: 64,
+    max_ring_size: 8192,
+    // largest frame is 9728 bytes including ethernet header and crc
+    max_mtu: 9710,
+    max_rss_queues: 16,
+    flow_director: true,
+    rsc: true,
+};
+
+// the vlan filter table has one bit for each of the 4096 vlan ids
+const VFTA_SIZE: u32 = 128;
+
+// receive address register 0 holds the device's own mac address, the others secondary addresses
+const RAR_ENTRIES: u32 = 128;
+
+// the multicast table array has one bit for each of the 4096 multicast hash values
+const MTA_SIZE: usize = 128;
+
+// the 32 bit packet counters wrap after about 5 minutes and the 36 bit byte counters after about a
+// minute at 10 Gbit/s, they are added to the totals well before while the queues are polled
+pub(crate) const STATS_UPDATE_INTERVAL: Duration = Duration::from_secs(10);
+
+// there are 16 sets of queue statistics registers, queues beyond share them
+const QUEUE_STATS: u32 = 16;
+
+// size histogram counters of received and sent packets
+const RX_SIZE_COUNTERS: [u32; 6] = [
+    IXGBE_PRC64,
+    IXGBE_PRC127,
+    IXGBE_PRC255,
+    IXGBE_PRC511,
+    IXGBE_PRC1023,
+    IXGBE_PRC1522,
+];
+const TX_SIZE_COUNTERS: [u32; 6] = [
+    IXGBE_PTC64,
+    IXGBE_PTC127,
+    IXGBE_PTC255,
+    IXGBE_PTC511,
+    IXGBE_PTC1023,
+    IXGBE_PTC1522,
+];
+
+// ethertype of PTP messages sent directly over ethernet
+const ETH_P_1588: u32 = 0x88f7;
+
+// the sign of the time adjustment offset is in the highest bit of TIMADJH
+const TIMADJH_SIGN: u32 = 1 << 31;
+
+// section 7.1.2.7.4 - 64 KB of the packet buffer hold 2K - 2 perfect or 8K - 2 signature filters
+const FDIR_MAX_PERFECT_FILTERS: usize = 2046;
+const FDIR_MAX_SIGNATURE_FILTERS: usize = 8190;
+
+// section 8.2.3.7.12 - fields of each packet type included in the rss hash
+const RSS_FIELDS: [(RssHashFields, u32); 6] = [
+    (RssHashFields::IPV4, IXGBE_MRQC_RSS_FIELD_IPV4),
+    (RssHashFields::IPV4_TCP, IXGBE_MRQC_RSS_FIELD_IPV4_TCP),
+    (RssHashFields::IPV4_UDP, IXGBE_MRQC_RSS_FIELD_IPV4_UDP),
+    (RssHashFields::IPV6, IXGBE_MRQC_RSS_FIELD_IPV6),
+    (RssHashFields::IPV6_TCP, IXGBE_MRQC_RSS_FIELD_IPV6_TCP),
+    (RssHashFields::IPV6_UDP, IXGBE_MRQC_RSS_FIELD_IPV6_UDP),
+];
+
+/// Returns the values of the `RAL` and `RAH` registers for `mac`, without the address valid bit.
+fn receive_address(mac: [u8; 6]) -> (u32, u32) {
+    let low: u32 = u32::from(mac[0])
+        + (u32::from(mac[1]) << 8)
+        + (u32::from(mac[2]) << 16)
+        + (u32::from(mac[3]) << 24);
+    let high: u32 = u32::from(mac[4]) + (u32::from(mac[5]) << 8);
+
+    (low, high)
+}
+
+/// Returns the 12 bit multicast table array index of `mac`, hashing bits 36 to 47 of the address
+/// (`MCSTCTRL.MO` = 0).
+pub(crate) fn mta_vector(mac: [u8; 6]) -> u32 {
+    (u32::from(mac[4]) >> 4 | u32::from(mac[5]) << 4) & 0xfff
+}
+
+/// Returns the fields `vlan_macip_lens`, `type_tucmd_mlhl` and `mss_l4len_idx` of the context
+/// descriptor for `offload` and the vlan tag `vlan_tci` and the `POPTS` of its data descriptors,
+/// [`None`] if the packet needs no context.
+pub(crate) fn tx_context(
+    offload: Option<&TxOffload>,
+    vlan_tci: Option<u16>,
+) -> Option<([u32; 3], u32)> {
+    if offload.is_none() && vlan_tci.is_none() {
+        return None;
+    }
+
+    let mut macip_lens = u32::from(vlan_tci.unwrap_or(0)) << IXGBE_ADVTXD_VLAN_SHIFT;
+    let mut type_tucmd = IXGBE_ADVTXD_DCMD_DEXT | IXGBE_ADVTXD_DTYP_CTXT;
+    let mut mss_l4len = 0;
+    let mut popts = 0;
+
+    let offload = match offload {
+        Some(offload) => offload,
+        None => {
+            type_tucmd |= IXGBE_ADVTXD_TUCMD_L4T_RSV;
+            return Some(([macip_lens, type_tucmd, mss_l4len], popts));
+        }
+    };
+
+    if offload.l3_type == L3Type::Ipv4 {
+        type_tucmd |= IXGBE_ADVTXD_TUCMD_IPV4;
+    }
+    if offload.ip_checksum {
+        popts |= IXGBE_ADVTXD_POPTS_IXSM;
+    }
+
+    match offload.l4_checksum {
+        Some(L4Type::Tcp) => {
+            type_tucmd |= IXGBE_ADVTXD_TUCMD_L4T_TCP;
+            popts |= IXGBE_ADVTXD_POPTS_TXSM;
+        }
+        Some(L4Type::Udp) => {
+            type_tucmd |= IXGBE_ADVTXD_TUCMD_L4T_UDP;
+            popts |= IXGBE_ADVTXD_POPTS_TXSM;
+        }
+        None => type_tucmd |= IXGBE_ADVTXD_TUCMD_L4T_RSV,
+    }
+
+    macip_lens |= offload.l3_len as u32 | (offload.l2_len as u32) << IXGBE_ADVTXD_MACLEN_SHIFT;
+    if let Some(mss) = offload.mss {
+        mss_l4len = u32::from(mss) << IXGBE_ADVTXD_MSS_SHIFT
+            | (offload.l4_len as u32) << IXGBE_ADVTXD_L4LEN_SHIFT;
+    }
+
+    Some(([macip_lens, type_tucmd, mss_l4len], popts))
+}
+
+/// Returns the status of a checksum whose validation is reported by `checksum_bit` and
+/// `error_bit` in the `status_error` field of an rx descriptor.
+pub(crate) fn checksum_status(
+    status_error: u32,
+    checksum_bit: u32,
+    error_bit: u32,
+) -> ChecksumStatus {
+    if status_error & checksum_bit == 0 {
+        ChecksumStatus::Unknown
+    } else if status_error & error_bit != 0 {
+        ChecksumStatus::Bad
+    } else {
+        ChecksumStatus::Good
+    }
+}
+
+/// Returns the value of the FDIRHASH register of the filter `filter` with id `id`, i.e. the
+/// bucket hash and the software index or signature.
+fn fdir_filter_hash(mode: FdirMode, filter: &FlowFilter, id: u16) -> u32 {
+    let input = filter.hash_input();
+    let bucket = fdir_hash(&input, FDIR_BUCKET_HASH_KEY);
+
+    match mode {
+        FdirMode::Perfect => bucket & 0x1fff | u32::from(id) << IXGBE_FDIRHASH_SIG_SW_INDEX_SHIFT,
+        FdirMode::Signature => {
+            let signature = fdir_hash(&input, FDIR_SIGNATURE_HASH_KEY);
+            bucket & 0x7fff | (signature & 0x7fff) << IXGBE_FDIRHASH_SIG_SW_INDEX_SHIFT
+        }
+    }
+}
 
-const NUM_RX_QUEUE_ENTRIES: usize = 512;
-const NUM_TX_QUEUE_ENTRIES: usize = 512;
-const TX_CLEAN_BATCH: usize = 32;
+/// Returns the `MAXDESC` field of `RSCCTL` that limits coalesced frames to 64 KB.
+fn rsc_max_desc(buffer_size: usize) -> u32 {
+    match buffer_size {
+        0..=2048 => IXGBE_RSCCTL_MAXDESC_16,
+        2049..=4096 => IXGBE_RSCCTL_MAXDESC_8,
+        4097..=8192 => IXGBE_RSCCTL_MAXDESC_4,
+        _ => IXGBE_RSCCTL_MAXDESC_1,
+    }
+}
 
 fn wrap_ring(index: usize, ring_size: usize) -> usize {
     (index + 1) & (ring_size - 1)
 }
 
+/// Returns how many consecutive descriptors from `rx_index` on, but at most `limit`, the device
+/// is done with.
+pub(crate) fn ready_rx_descriptors(
+    descriptors: *mut ixgbe_adv_rx_desc,
+    num_descriptors: usize,
+    rx_index: usize,
+    limit: usize,
+) -> usize {
+    let mut index = rx_index;
+    let mut ready = 0;
+
+    while ready < limit.min(num_descriptors) {
+        let status = unsafe {
+            ptr::read_volatile(&mut (*descriptors.add(index)).wb.upper.status_error as *mut u32)
+        };
+
+        if (status & IXGBE_RXDADV_STAT_DD) == 0 {
+            break;
+        }
+
+        ready += 1;
+        index = wrap_ring(index, num_descriptors);
+    }
+
+    ready
+}
+
 pub struct IxgbeDevice {
     pci_addr: String,
-    addr: *mut u8,
-    len: usize,
+    regs: Registers,
     num_rx_queues: u16,
     num_tx_queues: u16,
+    config: DeviceConfig,
     rx_queues: Vec<IxgbeRxQueue>,
     tx_queues: Vec<IxgbeTxQueue>,
     vfio: bool,
     vfio_fd: RawFd,
     vfio_device_fd: RawFd,
     interrupts: Interrupts,
+    fdir_filters: BTreeMap<u16, FlowFilterEntry>,
+    fdir_mask: Option<FlowMask>,
+    promisc: bool,
+    all_multicast: bool,
+    multicast_addrs: Vec<[u8; 6]>,
+    stats: StatsPoller,
+}
+
+/// Totals of the clear-on-read stats registers since the last reset, shared by a device and its
+/// queue handles.
+struct StatsTotals {
+    stats: DeviceStats,
+    xstats: ExtendedStats,
+    fdir: FdirStats,
+    updated: Instant,
+}
+
+impl StatsTotals {
+    /// Adds the clear-on-read stats registers to these totals, the queue stats only for the
+    /// first `num_queues` queues.
+    fn update(&mut self, regs: &Registers, num_queues: u16) {
+        let reg = |reg| u64::from(regs.get_reg32(reg));
+
+        let stats = &mut self.stats;
+        stats.rx_pkts += reg(IXGBE_GPRC);
+        stats.tx_pkts += reg(IXGBE_GPTC);
+        stats.rx_bytes += reg(IXGBE_GORCL) + (reg(IXGBE_GORCH) << 32);
+        stats.tx_bytes += reg(IXGBE_GOTCL) + (reg(IXGBE_GOTCH) << 32);
+
+        let xstats = &mut self.xstats;
+
+        // the 82599 has no RNBC but counts no descriptor drops per queue
+        for i in 0..QUEUE_STATS {
+            let counters = QueueCounters {
+                rx_pkts: reg(IXGBE_QPRC(i)),
+                rx_bytes: reg(IXGBE_QBRC_L(i)) + (reg(IXGBE_QBRC_H(i)) << 32),
+                rx_no_buffer: reg(IXGBE_QPRDC(i)),
+                tx_pkts: reg(IXGBE_QPTC(i)),
+                tx_bytes: reg(IXGBE_QBTC_L(i)) + (reg(IXGBE_QBTC_H(i)) << 32),
+            };
+            xstats.rx_no_buffer += counters.rx_no_buffer;
+
+            if i < u32::from(num_queues) {
+                let queue = xstats.queue_mut(i as usize);
+                queue.rx_pkts += counters.rx_pkts;
+                queue.rx_bytes += counters.rx_bytes;
+                queue.rx_no_buffer += counters.rx_no_buffer;
+                queue.tx_pkts += counters.tx_pkts;
+                queue.tx_bytes += counters.tx_bytes;
+            }
+        }
+
+        xstats.rx_missed += (0..8).map(|i| reg(IXGBE_MPC(i))).sum::<u64>();
+        xstats.rx_crc_errors += reg(IXGBE_CRCERRS);
+        xstats.rx_illegal_bytes += reg(IXGBE_ILLERRC);
+        xstats.rx_error_bytes += reg(IXGBE_ERRBC);
+        xstats.rx_length_errors += reg(IXGBE_RLEC);
+        xstats.rx_undersize += reg(IXGBE_RUC);
+        xstats.rx_fragments += reg(IXGBE_RFC);
+        xstats.rx_oversize += reg(IXGBE_ROC);
+        xstats.rx_jabbers += reg(IXGBE_RJC);
+        xstats.rx_dma_pkts += reg(IXGBE_RXDGPC);
+        xstats.rx_broadcast += reg(IXGBE_BPRC);
+        xstats.rx_multicast += reg(IXGBE_MPRC);
+        xstats.tx_broadcast += reg(IXGBE_BPTC);
+        xstats.tx_multicast += reg(IXGBE_MPTC);
+        xstats.rx_xon += reg(IXGBE_LXONRXCNT);
+        xstats.rx_xoff += reg(IXGBE_LXOFFRXCNT);
+        xstats.tx_xon += reg(IXGBE_LXONTXC);
+        xstats.tx_xoff += reg(IXGBE_LXOFFTXC);
+
+        for (count, &counter) in xstats.rx_sizes.iter_mut().zip(&RX_SIZE_COUNTERS) {
+            *count += reg(counter);
+        }
+        for (count, &counter) in xstats.tx_sizes.iter_mut().zip(&TX_SIZE_COUNTERS) {
+            *count += reg(counter);
+        }
+
+        self.fdir.matched += reg(IXGBE_FDIRMATCH);
+        self.fdir.missed += reg(IXGBE_FDIRMISS);
+
+        self.updated = Instant::now();
+    }
+}
+
+/// Updates the `StatsTotals` of a device while its queues are polled, so the 36 bit byte
+/// counters don't wrap around if nobody reads the stats. The device and each of its queue
+/// handles poll with their own clone.
+#[derive(Clone)]
+struct StatsPoller {
+    totals: Arc<Mutex<StatsTotals>>,
+    num_queues: u16,
+    polls: u32,
+}
+
+impl StatsPoller {
+    /// Updates the totals and returns them.
+    fn update(&self, regs: &Registers) -> MutexGuard<'_, StatsTotals> {
+        let mut totals = self.totals.lock().unwrap();
+        totals.update(regs, self.num_queues);
+        totals
+    }
+
+    /// Updates the totals if the last update is more than `STATS_UPDATE_INTERVAL` ago.
+    fn poll(&mut self, regs: &Registers) {
+        self.polls = self.polls.wrapping_add(1);
+
+        // don't poll the time unnecessarily
+        if self.polls & 0xfff == 0 {
+            let mut totals = self.totals.lock().unwrap();
+            if totals.updated.elapsed() > STATS_UPDATE_INTERVAL {
+                totals.update(regs, self.num_queues);
+            }
+        }
+    }
+}
+
+/// The memory mapped registers of a device, shared by the device and its queue handles.
+#[derive(Clone)]
+struct Registers {
+    addr: *mut u8,
+    len: usize,
+    #[cfg(test)]
+    sim: Option<Arc<sim::Simulator>>,
 }
 
+// the mapping is never removed and each queue handle only writes the registers of its queue
+unsafe impl Send for Registers {}
+
 struct IxgbeRxQueue {
     descriptors: *mut ixgbe_adv_rx_desc,
     num_descriptors: usize,
-    pool: Rc<Mempool>,
+    pool: Arc<Mempool>,
     bufs_in_use: Vec<usize>,
+    // buffers allocated for the ready descriptors of the current batch
+    spare: Vec<usize>,
     rx_index: usize,
+    // segments of frames spanning multiple descriptors whose last descriptor is yet to come,
+    // stored at the index of the descriptor holding their next segment
+    partial: Vec<Option<Packet>>,
+    timestamping: bool,
+    stats: RxQueueStats,
 }
 
 struct IxgbeTxQueue {
     descriptors: *mut ixgbe_adv_tx_desc,
     num_descriptors: usize,
-    pool: Option<Rc<Mempool>>,
-    bufs_in_use: VecDeque<usize>,
+    // sent packets in the order of their descriptors, each one owns the buffer of one segment,
+    // context descriptors have no packet
+    bufs_in_use: VecDeque<Option<Packet>>,
     clean_index: usize,
     tx_index: usize,
+    // fields of the last context descriptor
+    context: Option<[u32; 3]>,
+    stats: TxQueueStats,
+}
+
+/// Rx queue of an `IxgbeDevice` after [`IxyDevice::split_queues`].
+struct IxgbeRxQueueHandle {
+    queue_id: u16,
+    queue: IxgbeRxQueue,
+    regs: Registers,
+    stats: StatsPoller,
+}
+
+/// Tx queue of an `IxgbeDevice` after [`IxyDevice::split_queues`].
+struct IxgbeTxQueueHandle {
+    queue_id: u16,
+    queue: IxgbeTxQueue,
+    regs: Registers,
+    stats: StatsPoller,
+    clean_batch: usize,
+}
+
+/// The remains of an `IxgbeDevice` after [`IxyDevice::split_queues`].
+struct IxgbeControl {
+    dev: IxgbeDevice,
 }
 
+// the descriptor rings are only accessed by the owner of the queue
+unsafe impl Send for IxgbeRxQueue {}
+unsafe impl Send for IxgbeTxQueue {}
+
 impl IxyDevice for IxgbeDevice {
     /// Returns the driver's name of this device.
     fn get_driver_name(&self) -> &str {
@@ -107,14 +480,10 @@ impl IxyDevice for IxgbeDevice {
 
     /// Sets the mac address of this device.
     fn set_mac_addr(&self, mac: [u8; 6]) {
-        let low: u32 = u32::from(mac[0])
-            + (u32::from(mac[1]) << 8)
-            + (u32::from(mac[2]) << 16)
-            + (u32::from(mac[3]) << 24);
-        let high: u32 = u32::from(mac[4]) + (u32::from(mac[5]) << 8);
+        let (low, high) = receive_address(mac);
 
         self.set_reg32(IXGBE_RAL(0), low);
-        self.set_reg32(IXGBE_RAH(0), high);
+        self.set_reg32(IXGBE_RAH(0), high | IXGBE_RAH_AV);
     }
 
     /// Pushes up to `num_packets` received `Packet`s onto `buffer`.
@@ -124,211 +493,110 @@ impl IxyDevice for IxgbeDevice {
         buffer: &mut VecDeque<Packet>,
         num_packets: usize,
     ) -> usize {
-        let mut rx_index;
-        let mut last_rx_index;
-        let mut received_packets = 0;
+        let queue = self
+            .rx_queues
+            .get_mut(queue_id as usize)
+            .expect("invalid rx queue id");
 
+        if self.interrupts.interrupts_enabled
+            && self.interrupts.queues[queue_id as usize].interrupt_enabled
         {
-            let queue = self
-                .rx_queues
-                .get_mut(queue_id as usize)
-                .expect("invalid rx queue id");
-
-            rx_index = queue.rx_index;
-            last_rx_index = queue.rx_index;
-
-            if self.interrupts.interrupts_enabled
-                && self.interrupts.queues[queue_id as usize].interrupt_enabled
+            if let Err(e) = self.interrupts.queues[queue_id as usize]
+                .vfio_epoll_wait(i32::from(self.interrupts.timeout_ms))
             {
-                self.interrupts.queues[queue_id as usize]
-                    .vfio_epoll_wait(i32::from(self.interrupts.timeout_ms))
-                    .unwrap();
+                error!("waiting for rx interrupt failed: {}", e);
             }
+        }
 
-            for i in 0..num_packets {
-                let desc = unsafe { queue.descriptors.add(rx_index) as *mut ixgbe_adv_rx_desc };
-                let status =
-                    unsafe { ptr::read_volatile(&mut (*desc).wb.upper.status_error as *mut u32) };
-
-                if (status & IXGBE_RXDADV_STAT_DD) == 0 {
-                    break;
-                }
-
-                if (status & IXGBE_RXDADV_STAT_EOP) == 0 {
-                    panic!("increase buffer size or decrease MTU")
-                }
-
-                let pool = &queue.pool;
-
-                // get a free buffer from the mempool
-                if let Some(buf) = pool.alloc_buf() {
-                    // replace currently used buffer with new buffer
-                    let buf = mem::replace(&mut queue.bufs_in_use[rx_index], buf);
-
-                    let p = Packet {
-                        addr_virt: pool.get_virt_addr(buf),
-                        addr_phys: pool.get_phys_addr(buf),
-                        len: unsafe {
-                            ptr::read_volatile(&(*desc).wb.upper.length as *const u16) as usize
-                        },
-                        pool: pool.clone(),
-                        pool_entry: buf,
-                    };
-
-                    #[cfg(all(
-                        any(target_arch = "x86", target_arch = "x86_64"),
-                        target_feature = "sse"
-                    ))]
-                    p.prefetch(Prefetch::Time1);
-
-                    buffer.push_back(p);
-
-                    unsafe {
-                        ptr::write_volatile(
-                            &mut (*desc).read.pkt_addr as *mut u64,
-                            pool.get_phys_addr(queue.bufs_in_use[rx_index]) as u64,
-                        );
-                        ptr::write_volatile(&mut (*desc).read.hdr_addr as *mut u64, 0);
-                    }
+        let received_packets = queue.rx_batch(&self.regs, queue_id, buffer, num_packets);
+        self.stats.poll(&self.regs);
 
-                    last_rx_index = rx_index;
-                    rx_index = wrap_ring(rx_index, queue.num_descriptors);
-                    received_packets = i + 1;
-                } else {
-                    // break if there was no free buffer
-                    break;
+        // the descriptors only report the id of the matching filter in perfect match mode
+        if self.config.fdir == Some(FdirMode::Perfect) && !self.fdir_filters.is_empty() {
+            for p in buffer.iter().rev().take(received_packets) {
+                let entry = p.fdir_id().and_then(|id| self.fdir_filters.get_mut(&id));
+                if let Some(hits) = entry.and_then(|entry| entry.hits.as_mut()) {
+                    *hits += 1;
                 }
             }
+        }
 
-            if self.interrupts.interrupts_enabled {
-                let interrupt = &mut self.interrupts.queues[queue_id as usize];
-                let int_en = interrupt.interrupt_enabled;
-                interrupt.rx_pkts += received_packets as u64;
-
-                interrupt.instr_counter += 1;
-                if (interrupt.instr_counter & 0xFFF) == 0 {
-                    interrupt.instr_counter = 0;
-                    let elapsed = interrupt.last_time_checked.elapsed();
-                    let diff =
-                        elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
-                    if diff > interrupt.interval {
-                        interrupt.check_interrupt(diff, received_packets, num_packets);
-                    }
+        if self.interrupts.interrupts_enabled {
+            let interrupt = &mut self.interrupts.queues[queue_id as usize];
+            let int_en = interrupt.interrupt_enabled;
+            interrupt.rx_pkts += received_packets as u64;
+
+            interrupt.instr_counter += 1;
+            if (interrupt.instr_counter & 0xFFF) == 0 {
+                interrupt.instr_counter = 0;
+                let elapsed = interrupt.last_time_checked.elapsed();
+                let diff = elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+                if diff > interrupt.interval {
+                    interrupt.check_interrupt(diff, received_packets, num_packets);
+                }
 
-                    if int_en != interrupt.interrupt_enabled {
-                        if interrupt.interrupt_enabled {
-                            self.enable_interrupt(queue_id).unwrap();
-                        } else {
-                            self.disable_interrupt(queue_id);
+                if int_en != interrupt.interrupt_enabled {
+                    if interrupt.interrupt_enabled {
+                        if let Err(e) = self.enable_interrupt(queue_id) {
+                            error!("enabling rx interrupt failed: {}", e);
                         }
+                    } else {
+                        self.disable_interrupt(queue_id);
                     }
                 }
             }
         }
 
-        if rx_index != last_rx_index {
-            self.set_reg32(IXGBE_RDT(u32::from(queue_id)), last_rx_index as u32);
-            self.rx_queues[queue_id as usize].rx_index = rx_index;
-        }
-
         received_packets
     }
 
     /// Pops as many packets as possible from `buffer` to put them into the device`s tx queue.
     fn tx_batch(&mut self, queue_id: u16, buffer: &mut VecDeque<Packet>) -> usize {
-        let mut sent = 0;
-
-        {
-            let mut queue = self
-                .tx_queues
-                .get_mut(queue_id as usize)
-                .expect("invalid tx queue id");
-
-            let mut cur_index = queue.tx_index;
-            let clean_index = clean_tx_queue(&mut queue);
-
-            if queue.pool.is_none() {
-                if let Some(packet) = buffer.get(0) {
-                    queue.pool = Some(packet.pool.clone());
-                }
-            }
-
-            while let Some(packet) = buffer.pop_front() {
-                assert!(
-                    Rc::ptr_eq(queue.pool.as_ref().unwrap(), &packet.pool),
-                    "distinct memory pools for a single tx queue are not supported yet"
-                );
-
-                let next_index = wrap_ring(cur_index, queue.num_descriptors);
-
-                if clean_index == next_index {
-                    // tx queue of device is full, push packet back onto the
-                    // queue of to-be-sent packets
-                    buffer.push_front(packet);
-                    break;
-                }
-
-                queue.tx_index = wrap_ring(queue.tx_index, queue.num_descriptors);
-
-                unsafe {
-                    ptr::write_volatile(
-                        &mut (*queue.descriptors.add(cur_index)).read.buffer_addr as *mut u64,
-                        packet.get_phys_addr() as u64,
-                    );
-                    ptr::write_volatile(
-                        &mut (*queue.descriptors.add(cur_index)).read.cmd_type_len as *mut u32,
-                        IXGBE_ADVTXD_DCMD_EOP
-                            | IXGBE_ADVTXD_DCMD_RS
-                            | IXGBE_ADVTXD_DCMD_IFCS
-                            | IXGBE_ADVTXD_DCMD_DEXT
-                            | IXGBE_ADVTXD_DTYP_DATA
-                            | packet.len() as u32,
-                    );
-                    ptr::write_volatile(
-                        &mut (*queue.descriptors.add(cur_index)).read.olinfo_status as *mut u32,
-                        (packet.len() as u32) << IXGBE_ADVTXD_PAYLEN_SHIFT,
-                    );
-                }
+        let queue = self
+            .tx_queues
+            .get_mut(queue_id as usize)
+            .expect("invalid tx queue id");
 
-                queue.bufs_in_use.push_back(packet.pool_entry);
-                mem::forget(packet);
+        let sent = queue.tx_batch(&self.regs, queue_id, buffer, self.config.tx_clean_batch);
+        self.stats.poll(&self.regs);
 
-                cur_index = next_index;
-                sent += 1;
-            }
-        }
+        sent
+    }
 
-        self.set_reg32(
-            IXGBE_TDT(u32::from(queue_id)),
-            self.tx_queues[queue_id as usize].tx_index as u32,
-        );
+    /// Returns the counters the driver kept for rx queue `queue_id`.
+    fn rx_queue_stats(&self, queue_id: u16) -> Option<RxQueueStats> {
+        self.rx_queues.get(queue_id as usize).map(|q| q.stats)
+    }
 
-        sent
+    /// Returns the counters the driver kept for tx queue `queue_id`.
+    fn tx_queue_stats(&self, queue_id: u16) -> Option<TxQueueStats> {
+        self.tx_queues.get(queue_id as usize).map(|q| q.stats)
     }
 
-    /// Reads the stats of this device into `stats`.
+    /// Reads the totals of the stats of this device since the last reset into `stats`.
+    ///
+    /// The stats registers are read on demand and every few seconds while the queues are
+    /// polled, so any number of readers get the same totals.
     fn read_stats(&self, stats: &mut DeviceStats) {
-        let rx_pkts = u64::from(self.get_reg32(IXGBE_GPRC));
-        let tx_pkts = u64::from(self.get_reg32(IXGBE_GPTC));
-        let rx_bytes =
-            u64::from(self.get_reg32(IXGBE_GORCL)) + (u64::from(self.get_reg32(IXGBE_GORCH)) << 32);
-        let tx_bytes =
-            u64::from(self.get_reg32(IXGBE_GOTCL)) + (u64::from(self.get_reg32(IXGBE_GOTCH)) << 32);
-
-        stats.rx_pkts += rx_pkts;
-        stats.tx_pkts += tx_pkts;
-        stats.rx_bytes += rx_bytes;
-        stats.tx_bytes += tx_bytes;
+        *stats = self.stats.update(&self.regs).stats;
     }
 
     /// Resets the stats of this device.
     fn reset_stats(&mut self) {
-        self.get_reg32(IXGBE_GPRC);
-        self.get_reg32(IXGBE_GPTC);
-        self.get_reg32(IXGBE_GORCL);
-        self.get_reg32(IXGBE_GORCH);
-        self.get_reg32(IXGBE_GOTCL);
-        self.get_reg32(IXGBE_GOTCH);
+        // clears the stats registers
+        let mut totals = self.stats.update(&self.regs);
+        totals.stats = DeviceStats::default();
+        totals.xstats = ExtendedStats::default();
+        totals.fdir = FdirStats::default();
+    }
+
+    /// Reads the totals of the drop, error and per-queue stats of this device since the last
+    /// reset into `stats`.
+    ///
+    /// Only the first 16 queues have their own queue stats, queue `i` is counted in the queue
+    /// stats of queue `i % 16`.
+    fn read_extended_stats(&self, stats: &mut ExtendedStats) {
+        stats.clone_from(&self.stats.update(&self.regs).xstats);
     }
 
     /// Returns the link speed of this device.
@@ -344,727 +612,2831 @@ impl IxyDevice for IxgbeDevice {
             _ => 0,
         }
     }
-}
-
-impl IxgbeDevice {
-    /// Returns an initialized `IxgbeDevice` on success.
-    ///
-    /// # Panics
-    /// Panics if `num_rx_queues` or `num_tx_queues` exceeds `MAX_QUEUES`.
-    pub fn init(
-        pci_addr: &str,
-        num_rx_queues: u16,
-        num_tx_queues: u16,
-        interrupt_timeout: i16,
-    ) -> Result<IxgbeDevice, Box<dyn Error>> {
-        assert!(
-            num_rx_queues <= MAX_QUEUES,
-            "cannot configure {} rx queues: limit is {}",
-            num_rx_queues,
-            MAX_QUEUES
-        );
-        assert!(
-            num_tx_queues <= MAX_QUEUES,
-            "cannot configure {} tx queues: limit is {}",
-            num_tx_queues,
-            MAX_QUEUES
-        );
 
-        // Check if the NIC is IOMMU enabled...
-        let vfio = Path::new(&format!("/sys/bus/pci/devices/{}/iommu_group", pci_addr)).exists();
+    /// Adds a flow director filter that applies `action` to received packets matching `filter`.
+    fn add_flow_filter(
+        &mut self,
+        filter: &FlowFilter,
+        action: FilterAction,
+    ) -> Result<u16, IxyError> {
+        let mode = self
+            .config
+            .fdir
+            .ok_or_else(|| IxyError::InvalidConfig("flow director is not enabled".to_string()))?;
+
+        let has_ports = filter.src_port.is_some() || filter.dst_port.is_some();
+        if has_ports
+            && (filter.protocol == FlowProtocol::Ip || filter.protocol == FlowProtocol::Sctp)
+        {
+            return Err(IxyError::InvalidConfig(format!(
+                "{:?} flow director filters can't match ports",
+                filter.protocol
+            )));
+        }
 
-        let device_fd: RawFd;
-        let (addr, len) = if vfio {
-            device_fd = vfio_init(pci_addr)?;
-            vfio_map_region(device_fd, VFIO_PCI_BAR0_REGION_INDEX)?
-        } else {
-            if unsafe { libc::getuid() } != 0 {
-                warn!("not running as root, this will probably fail");
+        match action {
+            FilterAction::Queue(queue) if queue >= self.num_rx_queues => {
+                return Err(IxyError::InvalidConfig(format!(
+                    "invalid rx queue {} for flow director filter",
+                    queue
+                )));
             }
+            FilterAction::Drop if mode == FdirMode::Signature => {
+                return Err(IxyError::InvalidConfig(
+                    "signature filters can't drop packets".to_string(),
+                ));
+            }
+            _ => {}
+        }
 
-            device_fd = -1;
-            pci_map_resource(pci_addr)?
+        let mask = filter.mask();
+        if let Some(fdir_mask) = self.fdir_mask.filter(|&fdir_mask| fdir_mask != mask) {
+            return Err(IxyError::InvalidConfig(format!(
+                "all flow director filters must match the same fields as {:?}",
+                fdir_mask
+            )));
+        }
+
+        let max_filters = match mode {
+            FdirMode::Perfect => FDIR_MAX_PERFECT_FILTERS,
+            FdirMode::Signature => FDIR_MAX_SIGNATURE_FILTERS,
         };
+        if self.fdir_filters.len() >= max_filters {
+            return Err(IxyError::InvalidConfig(format!(
+                "flow director filter limit of {} reached",
+                max_filters
+            )));
+        }
 
-        // initialize RX and TX queue
-        let rx_queues = Vec::with_capacity(num_rx_queues as usize);
-        let tx_queues = Vec::with_capacity(num_tx_queues as usize);
+        if self
+            .fdir_filters
+            .values()
+            .any(|entry| entry.filter == *filter)
+        {
+            return Err(IxyError::InvalidConfig(format!(
+                "flow director filter {:?} exists",
+                filter
+            )));
+        }
 
-        // create the IxyDevice
-        let mut dev = IxgbeDevice {
-            pci_addr: pci_addr.to_string(),
-            addr,
-            len,
-            num_rx_queues,
-            num_tx_queues,
-            rx_queues,
-            tx_queues,
-            vfio,
-            vfio_fd: unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR },
-            vfio_device_fd: device_fd,
-            interrupts: Default::default(),
-        };
+        // ids are the software index of perfect match filters that is reported on rx
+        let id = (0..=max_filters as u16)
+            .find(|id| !self.fdir_filters.contains_key(id))
+            .ok_or_else(|| {
+                IxyError::InvalidConfig("no free flow director filter id left".to_string())
+            })?;
 
-        if dev.vfio {
-            dev.interrupts.interrupts_enabled = interrupt_timeout != 0;
-            dev.interrupts.timeout_ms = interrupt_timeout;
-            dev.interrupts.itr_rate = 0x028;
-            dev.setup_interrupts()?;
+        // the mask is only kept once the first filter using it was added
+        if self.fdir_mask.is_none() {
+            self.set_fdir_mask(mask);
         }
 
-        if !dev.vfio && interrupt_timeout != 0 {
-            warn!("Interrupts requested but VFIO not available: Disabling Interrupts!");
-            dev.interrupts.interrupts_enabled = false;
+        // section 7.1.2.7.9 - perfect match filters are compared against these registers
+        if mode == FdirMode::Perfect {
+            self.set_reg32(IXGBE_FDIRIPSA, filter.src_addr.map_or(0, u32::from));
+            self.set_reg32(IXGBE_FDIRIPDA, filter.dst_addr.map_or(0, u32::from));
+            self.set_reg32(
+                IXGBE_FDIRPORT,
+                u32::from(filter.dst_port.unwrap_or(0)) << IXGBE_FDIRPORT_DESTINATION_SHIFT
+                    | u32::from(filter.src_port.unwrap_or(0)),
+            );
+            self.set_reg32(IXGBE_FDIRVLAN, u32::from(filter.vlan.unwrap_or(0)));
         }
 
-        dev.reset_and_init(pci_addr)?;
+        let mut fdircmd = IXGBE_FDIRCMD_CMD_ADD_FLOW
+            | IXGBE_FDIRCMD_FILTER_UPDATE
+            | IXGBE_FDIRCMD_LAST
+            | IXGBE_FDIRCMD_QUEUE_EN
+            | filter.protocol.flow_type() << IXGBE_FDIRCMD_FLOW_TYPE_SHIFT;
+        fdircmd |= match action {
+            FilterAction::Queue(queue) => u32::from(queue) << IXGBE_FDIRCMD_RX_QUEUE_SHIFT,
+            FilterAction::Drop => {
+                IXGBE_FDIRCMD_DROP | IXGBE_FDIR_DROP_QUEUE << IXGBE_FDIRCMD_RX_QUEUE_SHIFT
+            }
+        };
 
-        Ok(dev)
-    }
+        self.set_reg32(IXGBE_FDIRHASH, fdir_filter_hash(mode, filter, id));
+        self.fdir_command(fdircmd)?;
+        self.fdir_mask = Some(mask);
 
-    /// Resets and initializes this device.
-    fn reset_and_init(&mut self, pci_addr: &str) -> Result<(), Box<dyn Error>> {
-        info!("resetting device {}", pci_addr);
-        // section 4.6.3.1 - disable all interrupts
-        self.disable_interrupts();
+        // dropped packets are never received and the descriptors only report the id of the
+        // matching filter in perfect match mode
+        let hits = match (mode, action) {
+            (FdirMode::Perfect, FilterAction::Queue(_)) => Some(0),
+            _ => None,
+        };
+        self.fdir_filters.insert(
+            id,
+            FlowFilterEntry {
+                id,
+                filter: *filter,
+                action,
+                hits,
+            },
+        );
 
-        // section 4.6.3.2
-        self.set_reg32(IXGBE_CTRL, IXGBE_CTRL_RST_MASK);
-        self.wait_clear_reg32(IXGBE_CTRL, IXGBE_CTRL_RST_MASK);
-        thread::sleep(Duration::from_millis(10));
+        Ok(id)
+    }
 
-        // section 4.6.3.1 - disable interrupts again after reset
-        self.disable_interrupts();
+    /// Removes the flow director filter with id `id`.
+    fn remove_flow_filter(&mut self, id: u16) -> Result<(), IxyError> {
+        let (mode, entry) = match (self.config.fdir, self.fdir_filters.get(&id)) {
+            (Some(mode), Some(entry)) => (mode, entry),
+            _ => {
+                return Err(IxyError::InvalidConfig(format!(
+                    "no flow director filter with id {}",
+                    id
+                )))
+            }
+        };
 
-        let mac = self.get_mac_addr();
-        info!("initializing device {}", pci_addr);
-        info!(
-            "mac address: {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
-            mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
-        );
+        let fdirhash = fdir_filter_hash(mode, &entry.filter, id);
+        self.set_reg32(IXGBE_FDIRHASH, fdirhash);
+        let fdircmd = self.fdir_command(IXGBE_FDIRCMD_CMD_QUERY_REM_FILT)?;
 
-        // section 4.6.3 - wait for EEPROM auto read completion
-        self.wait_set_reg32(IXGBE_EEC, IXGBE_EEC_ARD);
+        if fdircmd & IXGBE_FDIRCMD_FILTER_VALID != 0 {
+            self.set_reg32(IXGBE_FDIRHASH, fdirhash);
+            self.fdir_command(IXGBE_FDIRCMD_CMD_REMOVE_FLOW)?;
+        }
 
-        // section 4.6.3 - wait for dma initialization done
-        self.wait_set_reg32(IXGBE_RDRXCTL, IXGBE_RDRXCTL_DMAIDONE);
+        self.fdir_filters.remove(&id);
 
-        // skip last step from 4.6.3 - we don't want interrupts
+        // the compared fields can change once all filters are removed
+        if self.fdir_filters.is_empty() {
+            self.fdir_mask = None;
+        }
 
-        // section 4.6.4 - initialize link (auto negotiation)
-        self.init_link();
+        Ok(())
+    }
 
-        // section 4.6.5 - statistical counters
-        // reset-on-read registers, just read them once
-        self.reset_stats();
+    /// Returns all flow director filters of this device ordered by their id.
+    fn flow_filters(&self) -> Vec<FlowFilterEntry> {
+        // packets received through the handles of split queues are not counted
+        let split = self.rx_queues.is_empty();
+
+        self.fdir_filters
+            .values()
+            .map(|entry| FlowFilterEntry {
+                hits: entry.hits.filter(|_| !split),
+                ..*entry
+            })
+            .collect()
+    }
 
-        // section 4.6.7 - init rx
-        self.init_rx()?;
+    /// Reads the totals of the flow director's stats since the last reset into `stats`.
+    fn read_fdir_stats(&self, stats: &mut FdirStats) {
+        *stats = self.stats.update(&self.regs).fdir;
+    }
 
-        // section 4.6.8 - init tx
-        self.init_tx()?;
+    /// Adds vlan id `vlan_id` to the vlan filter table and enables vlan filtering.
+    fn add_vlan_filter(&mut self, vlan_id: u16) -> Result<(), IxyError> {
+        offload::check_vlan_id(vlan_id)?;
 
-        for i in 0..self.num_rx_queues {
-            self.start_rx_queue(i)?;
-        }
+        let vlan_id = u32::from(vlan_id);
+        self.set_flags32(IXGBE_VFTA(vlan_id / 32), 1 << (vlan_id % 32));
+        self.set_flags32(IXGBE_VLNCTRL, IXGBE_VLNCTRL_VFE);
 
-        for i in 0..self.num_tx_queues {
-            self.start_tx_queue(i)?;
-        }
+        Ok(())
+    }
 
-        // enable interrupts
-        for queue in 0..self.num_rx_queues {
-            self.enable_interrupt(queue)?;
+    /// Removes vlan id `vlan_id` from the vlan filter table, vlan filtering is disabled with the
+    /// last filter.
+    fn remove_vlan_filter(&mut self, vlan_id: u16) -> Result<(), IxyError> {
+        offload::check_vlan_id(vlan_id)?;
+
+        let (reg, bit) = (u32::from(vlan_id) / 32, 1 << (vlan_id % 32));
+        if self.get_reg32(IXGBE_VFTA(reg)) & bit == 0 {
+            return Err(IxyError::InvalidConfig(format!(
+                "{} has no vlan filter {}",
+                self.get_driver_name(),
+                vlan_id
+            )));
         }
+        self.clear_flags32(IXGBE_VFTA(reg), bit);
 
-        // wait some time for the link to come up
-        self.wait_for_link();
+        if (0..VFTA_SIZE).all(|i| self.get_reg32(IXGBE_VFTA(i)) == 0) {
+            self.clear_flags32(IXGBE_VLNCTRL, IXGBE_VLNCTRL_VFE);
+        }
 
         Ok(())
     }
 
-    // sections 4.6.7
-    /// Initializes the rx queues of this device.
-    fn init_rx(&mut self) -> Result<(), Box<dyn Error>> {
-        // disable rx while re-configuring it
-        self.clear_flags32(IXGBE_RXCTRL, IXGBE_RXCTRL_RXEN);
-
-        // section 4.6.11.3.4 - allocate all queues and traffic to PB0
-        self.set_reg32(IXGBE_RXPBSIZE(0), IXGBE_RXPBSIZE_128KB);
-        for i in 1..8 {
-            self.set_reg32(IXGBE_RXPBSIZE(i), 0);
+    /// Enables or disables unicast and multicast promiscuous mode.
+    fn set_promiscuous(&mut self, enabled: bool) -> Result<(), IxyError> {
+        if enabled {
+            info!("enabling promisc mode");
+        } else {
+            info!("disabling promisc mode");
         }
+        self.promisc = enabled;
+        self.set_rx_mode();
 
-        // enable CRC offloading
-        self.set_flags32(IXGBE_HLREG0, IXGBE_HLREG0_RXCRCSTRP);
-        self.set_flags32(IXGBE_RDRXCTL, IXGBE_RDRXCTL_CRCSTRIP);
-
-        self.clear_flags32(IXGBE_RDRXCTL, IXGBE_RDRXCTL_RSCFRSTSIZE);
-
-        // RSCFRSTSIZE should be set to 0x0 as opposed to its hardware default
-        // RSCACKC and FCOE_WRFIX should be set to 0x1
-        let rdrxctl = self.get_reg32(IXGBE_RDRXCTL);
-        self.set_reg32(IXGBE_RDRXCTL, rdrxctl & !IXGBE_RDRXCTL_RSCFRSTSIZE | IXGBE_RDRXCTL_RSCACKC | IXGBE_RDRXCTL_FCOE_WRFIX);
-
-        // accept broadcast packets
-        self.set_flags32(IXGBE_FCTRL, IXGBE_FCTRL_BAM);
-
-        // configure queues, same for all queues
-        for i in 0..self.num_rx_queues {
-            debug!("initializing rx queue {}", i);
-            // enable advanced rx descriptors
-            self.set_reg32(
-                IXGBE_SRRCTL(u32::from(i)),
-                (self.get_reg32(IXGBE_SRRCTL(u32::from(i))) & !IXGBE_SRRCTL_DESCTYPE_MASK)
-                    | IXGBE_SRRCTL_DESCTYPE_ADV_ONEBUF,
-            );
-            // let nic drop packets if no rx descriptor is available instead of buffering them
-            self.set_flags32(IXGBE_SRRCTL(u32::from(i)), IXGBE_SRRCTL_DROP_EN);
-
-            // section 7.1.9 - setup descriptor ring
-            let ring_size_bytes =
-                (NUM_RX_QUEUE_ENTRIES) as usize * mem::size_of::<ixgbe_adv_rx_desc>();
-
-            let dma: Dma<ixgbe_adv_rx_desc> = Dma::allocate(ring_size_bytes, true)?;
-
-            // initialize to 0xff to prevent rogue memory accesses on premature dma activation
-            unsafe {
-                memset(dma.virt as *mut u8, ring_size_bytes, 0xff);
-            }
+        Ok(())
+    }
 
-            self.set_reg32(
-                IXGBE_RDBAL(u32::from(i)),
-                (dma.phys as u64 & 0xffff_ffff) as u32,
-            );
-            self.set_reg32(IXGBE_RDBAH(u32::from(i)), (dma.phys as u64 >> 32) as u32);
-            self.set_reg32(IXGBE_RDLEN(u32::from(i)), ring_size_bytes as u32);
+    /// Enables or disables multicast promiscuous mode.
+    fn set_all_multicast(&mut self, enabled: bool) -> Result<(), IxyError> {
+        self.all_multicast = enabled;
+        self.set_rx_mode();
 
-            debug!("rx ring {} phys addr: {:#x}", i, dma.phys);
-            debug!("rx ring {} virt addr: {:p}", i, dma.virt);
+        Ok(())
+    }
 
-            // set ring to empty at start
-            self.set_reg32(IXGBE_RDH(u32::from(i)), 0);
-            self.set_reg32(IXGBE_RDT(u32::from(i)), 0);
+    /// Writes `mac` to a free receive address register.
+    fn add_mac_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        crate::check_mac_addr(mac, false)?;
+        if self.find_mac_addr(mac).is_some() {
+            return Ok(());
+        }
 
-            let mempool_size = if NUM_RX_QUEUE_ENTRIES + NUM_TX_QUEUE_ENTRIES < MIN_MEMPOOL_SIZE {
-                MIN_MEMPOOL_SIZE
-            } else {
-                NUM_RX_QUEUE_ENTRIES + NUM_TX_QUEUE_ENTRIES
-            };
+        let index = (1..RAR_ENTRIES)
+            .find(|&i| self.get_reg32(IXGBE_RAH(i)) & IXGBE_RAH_AV == 0)
+            .ok_or_else(|| {
+                IxyError::InvalidConfig(format!(
+                    "all {} receive address registers are in use",
+                    RAR_ENTRIES - 1
+                ))
+            })?;
+
+        let (low, high) = receive_address(mac);
+        self.set_reg32(IXGBE_RAL(index), low);
+        self.set_reg32(IXGBE_RAH(index), high | IXGBE_RAH_AV);
+        // without virtualization all addresses belong to pool 0
+        self.set_reg32(IXGBE_MPSAR_LO(index), 1);
 
-            let mempool = Mempool::allocate(mempool_size as usize, PKT_BUF_ENTRY_SIZE).unwrap();
+        Ok(())
+    }
 
-            let rx_queue = IxgbeRxQueue {
-                descriptors: dma.virt,
-                pool: mempool,
-                num_descriptors: NUM_RX_QUEUE_ENTRIES,
-                rx_index: 0,
-                bufs_in_use: Vec::with_capacity(NUM_RX_QUEUE_ENTRIES),
-            };
+    /// Clears the receive address register holding `mac`.
+    fn remove_mac_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        let index = self.find_mac_addr(mac).ok_or_else(|| {
+            IxyError::InvalidConfig(format!(
+                "{} has no mac address {}",
+                self.get_driver_name(),
+                crate::format_mac_addr(mac)
+            ))
+        })?;
 
-            self.rx_queues.push(rx_queue);
-        }
+        self.set_reg32(IXGBE_RAH(index), 0);
+        self.set_reg32(IXGBE_RAL(index), 0);
+        self.set_reg32(IXGBE_MPSAR_LO(index), 0);
 
-        // last sentence of section 4.6.7 - set some magic bits
-        self.set_flags32(IXGBE_CTRL_EXT, IXGBE_CTRL_EXT_NS_DIS);
+        Ok(())
+    }
 
-        // probably a broken feature, this flag is initialized with 1 but has to be set to 0
-        for i in 0..self.num_rx_queues {
-            self.clear_flags32(IXGBE_DCA_RXCTRL(u32::from(i)), 1 << 12);
+    /// Adds the hash of `mac` to the multicast table array.
+    fn add_multicast_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        crate::check_mac_addr(mac, true)?;
+        if !self.multicast_addrs.contains(&mac) {
+            self.multicast_addrs.push(mac);
+            self.set_multicast_table();
         }
 
-        // enable promisc mode by default to make testing easier
-        self.set_promisc(true);
+        Ok(())
+    }
 
-        // start rx
-        self.set_flags32(IXGBE_RXCTRL, IXGBE_RXCTRL_RXEN);
+    /// Removes the hash of `mac` from the multicast table array unless another subscribed
+    /// address shares it.
+    fn remove_multicast_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        let index = self
+            .multicast_addrs
+            .iter()
+            .position(|&addr| addr == mac)
+            .ok_or_else(|| {
+                IxyError::InvalidConfig(format!(
+                    "{} has no multicast address {}",
+                    self.get_driver_name(),
+                    crate::format_mac_addr(mac)
+                ))
+            })?;
+        self.multicast_addrs.remove(index);
+        self.set_multicast_table();
 
         Ok(())
     }
 
-    // section 4.6.8
-    /// Initializes the tx queues of this device.
-    fn init_tx(&mut self) -> Result<(), Box<dyn Error>> {
-        // crc offload and small packet padding
-        self.set_flags32(IXGBE_HLREG0, IXGBE_HLREG0_TXCRCEN | IXGBE_HLREG0_TXPADEN);
-
-        // section 4.6.11.3.4 - set default buffer size allocations
-        self.set_reg32(IXGBE_TXPBSIZE(0), IXGBE_TXPBSIZE_40KB);
-        for i in 1..8 {
-            self.set_reg32(IXGBE_TXPBSIZE(i), 0);
+    /// Returns the timestamp latched for the last sent packet that requested one.
+    fn read_tx_timestamp(&mut self) -> Option<Timestamp> {
+        if self.get_reg32(IXGBE_TSYNCTXCTL) & IXGBE_TSYNCTXCTL_VALID == 0 {
+            return None;
         }
 
-        // required when not using DCB/VTd
-        self.set_reg32(IXGBE_DTXMXSZRQ, 0xfff);
-        self.clear_flags32(IXGBE_RTTDCS, IXGBE_RTTDCS_ARBDIS);
-
-        // configure queues
-        for i in 0..self.num_tx_queues {
-            debug!("initializing tx queue {}", i);
-            // section 7.1.9 - setup descriptor ring
-            let ring_size_bytes =
-                NUM_TX_QUEUE_ENTRIES as usize * mem::size_of::<ixgbe_adv_tx_desc>();
+        // reading the high register unlocks the timestamp for the next packet
+        let low = self.get_reg32(IXGBE_TXSTMPL);
+        let high = self.get_reg32(IXGBE_TXSTMPH);
 
-            let dma: Dma<ixgbe_adv_tx_desc> = Dma::allocate(ring_size_bytes, true)?;
-            unsafe {
-                memset(dma.virt as *mut u8, ring_size_bytes, 0xff);
-            }
+        Some(Timestamp::Hardware(u64::from(high) << 32 | u64::from(low)))
+    }
 
-            self.set_reg32(
-                IXGBE_TDBAL(u32::from(i)),
-                (dma.phys as u64 & 0xffff_ffff) as u32,
-            );
-            self.set_reg32(IXGBE_TDBAH(u32::from(i)), (dma.phys as u64 >> 32) as u32);
-            self.set_reg32(IXGBE_TDLEN(u32::from(i)), ring_size_bytes as u32);
+    /// Returns the time of the IEEE 1588 clock in nanoseconds.
+    fn read_clock(&self) -> Result<u64, IxyError> {
+        self.check_timestamping()?;
 
-            debug!("tx ring {} phys addr: {:#x}", i, dma.phys);
-            debug!("tx ring {} virt addr: {:p}", i, dma.virt);
+        // reading the low register latches the high register
+        let low = self.get_reg32(IXGBE_SYSTIML);
+        let high = self.get_reg32(IXGBE_SYSTIMH);
 
-            // descriptor writeback magic values, important to get good performance and low PCIe overhead
-            // see 7.2.3.4.1 and 7.2.3.5 for an explanation of these values and how to find good ones
-            // we just use the defaults from DPDK here, but this is a potentially interesting point for optimizations
-            let mut txdctl = self.get_reg32(IXGBE_TXDCTL(u32::from(i)));
-            // there are no defines for this in constants.rs for some reason
-            // pthresh: 6:0, hthresh: 14:8, wthresh: 22:16
-            txdctl &= !(0x7F | (0x7F << 8) | (0x7F << 16));
-            txdctl |= 36 | (8 << 8) | (4 << 16);
+        Ok(u64::from(high) << 32 | u64::from(low))
+    }
 
-            self.set_reg32(IXGBE_TXDCTL(u32::from(i)), txdctl);
+    /// Sets the IEEE 1588 clock to `nanos`.
+    fn set_clock(&mut self, nanos: u64) -> Result<(), IxyError> {
+        self.check_timestamping()?;
+        self.write_clock(nanos);
 
-            let tx_queue = IxgbeTxQueue {
-                descriptors: dma.virt,
-                bufs_in_use: VecDeque::with_capacity(NUM_TX_QUEUE_ENTRIES),
-                pool: None,
-                num_descriptors: NUM_TX_QUEUE_ENTRIES,
-                clean_index: 0,
-                tx_index: 0,
-            };
+        Ok(())
+    }
 
-            self.tx_queues.push(tx_queue);
+    /// Adds `delta` nanoseconds to the IEEE 1588 clock with its next increment.
+    fn adjust_clock(&mut self, delta: i64) -> Result<(), IxyError> {
+        self.check_timestamping()?;
+
+        // the offset has 63 bits and a sign bit
+        let offset = delta.unsigned_abs();
+        if offset >> 63 != 0 {
+            return Err(IxyError::InvalidConfig(format!(
+                "clock adjustment of {} ns is out of range",
+                delta
+            )));
         }
-
-        // final step: enable DMA
-        self.set_reg32(IXGBE_DMATXCTL, IXGBE_DMATXCTL_TE);
+        let sign = if delta < 0 { TIMADJH_SIGN } else { 0 };
+        self.set_reg32(IXGBE_TIMADJL, offset as u32);
+        self.set_reg32(IXGBE_TIMADJH, (offset >> 32) as u32 | sign);
 
         Ok(())
     }
 
-    /// Sets the rx queues` descriptors and enables the queues.
-    fn start_rx_queue(&mut self, queue_id: u16) -> Result<(), Box<dyn Error>> {
-        debug!("starting rx queue {}", queue_id);
-
-        {
-            let queue = &mut self.rx_queues[queue_id as usize];
-
-            if queue.num_descriptors & (queue.num_descriptors - 1) != 0 {
-                return Err("number of queue entries must be a power of 2".into());
-            }
-
-            for i in 0..queue.num_descriptors {
-                let pool = &queue.pool;
-
-                let buf = match pool.alloc_buf() {
-                    Some(x) => x,
-                    None => return Err("failed to allocate rx descriptor".into()),
-                };
+    /// Splits this device into a control handle and one handle per rx and tx queue.
+    fn split_queues(mut self: Box<Self>) -> Result<SplitQueues, IxyError> {
+        if self.interrupts.interrupts_enabled {
+            return Err(IxyError::InvalidConfig(
+                "queues can only be split in polling mode".to_string(),
+            ));
+        }
 
-                unsafe {
-                    ptr::write_volatile(
-                        &mut (*queue.descriptors.add(i)).read.pkt_addr as *mut u64,
-                        pool.get_phys_addr(buf) as u64,
-                    );
+        if self.num_rx_queues != self.num_tx_queues {
+            return Err(IxyError::InvalidConfig(format!(
+                "cannot split {} rx and {} tx queues into pairs",
+                self.num_rx_queues, self.num_tx_queues
+            )));
+        }
 
-                    ptr::write_volatile(
-                        &mut (*queue.descriptors.add(i)).read.hdr_addr as *mut u64,
-                        0,
-                    );
-                }
+        let rx_queues = mem::take(&mut self.rx_queues);
+        let tx_queues = mem::take(&mut self.tx_queues);
 
-                // we need to remember which descriptor entry belongs to which mempool entry
-                queue.bufs_in_use.push(buf);
-            }
+        // the buffers this thread cached while filling the rx rings are only available to it
+        for rx_queue in &rx_queues {
+            rx_queue.pool.flush_cache();
         }
 
-        let queue = &self.rx_queues[queue_id as usize];
+        let queues = rx_queues
+            .into_iter()
+            .zip(tx_queues)
+            .enumerate()
+            .map(|(i, (rx_queue, tx_queue))| {
+                let rx: Box<dyn RxQueue> = Box::new(IxgbeRxQueueHandle {
+                    queue_id: i as u16,
+                    queue: rx_queue,
+                    regs: self.regs.clone(),
+                    stats: self.stats.clone(),
+                });
+                let tx: Box<dyn TxQueue> = Box::new(IxgbeTxQueueHandle {
+                    queue_id: i as u16,
+                    queue: tx_queue,
+                    regs: self.regs.clone(),
+                    stats: self.stats.clone(),
+                    clean_batch: self.config.tx_clean_batch,
+                });
+                (rx, tx)
+            })
+            .collect();
+
+        Ok((Box::new(IxgbeControl { dev: *self }), queues))
+    }
+}
 
-        // enable queue and wait if necessary
-        self.set_flags32(IXGBE_RXDCTL(u32::from(queue_id)), IXGBE_RXDCTL_ENABLE);
-        self.wait_set_reg32(IXGBE_RXDCTL(u32::from(queue_id)), IXGBE_RXDCTL_ENABLE);
+impl RxQueue for IxgbeRxQueueHandle {
+    /// Returns the id of this queue.
+    fn queue_id(&self) -> u16 {
+        self.queue_id
+    }
 
-        // rx queue starts out full
-        self.set_reg32(IXGBE_RDH(u32::from(queue_id)), 0);
+    /// Pushes up to `num_packets` received `Packet`s onto `buffer`.
+    fn rx_batch(&mut self, buffer: &mut VecDeque<Packet>, num_packets: usize) -> usize {
+        let received_packets = self
+            .queue
+            .rx_batch(&self.regs, self.queue_id, buffer, num_packets);
+        self.stats.poll(&self.regs);
 
-        // was set to 0 before in the init function
-        self.set_reg32(
-            IXGBE_RDT(u32::from(queue_id)),
-            (queue.num_descriptors - 1) as u32,
-        );
+        received_packets
+    }
 
-        Ok(())
+    /// Returns the counters the driver kept for this queue.
+    fn stats(&self) -> RxQueueStats {
+        self.queue.stats
     }
+}
 
-    /// Enables the tx queues.
-    fn start_tx_queue(&mut self, queue_id: u16) -> Result<(), Box<dyn Error>> {
-        debug!("starting tx queue {}", queue_id);
+impl TxQueue for IxgbeTxQueueHandle {
+    /// Returns the id of this queue.
+    fn queue_id(&self) -> u16 {
+        self.queue_id
+    }
 
-        {
-            let queue = &mut self.tx_queues[queue_id as usize];
+    /// Pops as many packets as possible from `buffer` to put them into this queue.
+    fn tx_batch(&mut self, buffer: &mut VecDeque<Packet>) -> usize {
+        let sent = self
+            .queue
+            .tx_batch(&self.regs, self.queue_id, buffer, self.clean_batch);
+        self.stats.poll(&self.regs);
 
-            if queue.num_descriptors & (queue.num_descriptors - 1) != 0 {
-                return Err("number of queue entries must be a power of 2".into());
-            }
-        }
+        sent
+    }
 
-        // tx queue starts out empty
-        self.set_reg32(IXGBE_TDH(u32::from(queue_id)), 0);
-        self.set_reg32(IXGBE_TDT(u32::from(queue_id)), 0);
+    /// Returns the counters the driver kept for this queue.
+    fn stats(&self) -> TxQueueStats {
+        self.queue.stats
+    }
+}
 
-        // enable queue and wait if necessary
-        self.set_flags32(IXGBE_TXDCTL(u32::from(queue_id)), IXGBE_TXDCTL_ENABLE);
-        self.wait_set_reg32(IXGBE_TXDCTL(u32::from(queue_id)), IXGBE_TXDCTL_ENABLE);
+impl DeviceControl for IxgbeControl {
+    /// Returns the driver's name of this device.
+    fn get_driver_name(&self) -> &str {
+        self.dev.get_driver_name()
+    }
 
-        Ok(())
+    /// Returns the pci address of this device.
+    fn get_pci_addr(&self) -> &str {
+        self.dev.get_pci_addr()
     }
 
-    // see section 4.6.4
-    /// Initializes the link of this device.
-    fn init_link(&self) {
-        // link auto-configuration register should already be set correctly, we're resetting it anyway
-        self.set_reg32(
-            IXGBE_AUTOC,
-            (self.get_reg32(IXGBE_AUTOC) & !IXGBE_AUTOC_LMS_MASK) | IXGBE_AUTOC_LMS_10G_SERIAL,
-        );
-        self.set_reg32(
-            IXGBE_AUTOC,
-            (self.get_reg32(IXGBE_AUTOC) & !IXGBE_AUTOC_10G_PMA_PMD_MASK) | IXGBE_AUTOC_10G_XAUI,
-        );
-        // negotiate link
-        self.set_flags32(IXGBE_AUTOC, IXGBE_AUTOC_AN_RESTART);
-        // datasheet wants us to wait for the link here, but we can continue and wait afterwards
+    /// Returns the mac address of this device.
+    fn get_mac_addr(&self) -> [u8; 6] {
+        self.dev.get_mac_addr()
     }
 
-    /// Waits for the link to come up.
-    fn wait_for_link(&self) {
-        info!("waiting for link");
-        let time = Instant::now();
-        let mut speed = self.get_link_speed();
-        while speed == 0 && time.elapsed().as_secs() < 10 {
-            thread::sleep(Duration::from_millis(100));
-            speed = self.get_link_speed();
-        }
-        info!("link speed is {} Mbit/s", self.get_link_speed());
+    /// Sets the mac address of this device.
+    fn set_mac_addr(&self, mac: [u8; 6]) {
+        self.dev.set_mac_addr(mac)
     }
 
-    /// Enables or disables promisc mode of this device.
-    fn set_promisc(&self, enabled: bool) {
-        if enabled {
-            info!("enabling promisc mode");
-            self.set_flags32(IXGBE_FCTRL, IXGBE_FCTRL_MPE | IXGBE_FCTRL_UPE);
-        } else {
-            info!("disabling promisc mode");
-            self.clear_flags32(IXGBE_FCTRL, IXGBE_FCTRL_MPE | IXGBE_FCTRL_UPE);
-        }
+    /// Reads the totals of the stats of this device since the last reset into `stats`.
+    fn read_stats(&self, stats: &mut DeviceStats) {
+        self.dev.read_stats(stats)
     }
 
-    /// Returns the register at `self.addr` + `reg`.
-    ///
-    /// # Panics
-    ///
-    /// Panics if `self.addr` + `reg` does not belong to the mapped memory of the pci device.
-    fn get_reg32(&self, reg: u32) -> u32 {
-        assert!(reg as usize <= self.len - 4, "memory access out of bounds");
+    /// Resets the stats of this device.
+    fn reset_stats(&mut self) {
+        self.dev.reset_stats()
+    }
 
-        unsafe { ptr::read_volatile((self.addr as usize + reg as usize) as *mut u32) }
+    /// Returns the link speed of this device.
+    fn get_link_speed(&self) -> u16 {
+        self.dev.get_link_speed()
     }
+}
 
-    /// Sets the register at `self.addr` + `reg` to `value`.
+impl IxgbeDevice {
+    /// Returns an initialized `IxgbeDevice` on success.
     ///
-    /// # Panics
+    /// Fails with [`IxyError::InvalidConfig`] if `config` exceeds the limits of the device.
+    pub fn init(pci_addr: &str, config: &DeviceConfig) -> Result<IxgbeDevice, IxyError> {
+        config.validate(&LIMITS)?;
+
+        // Check if the NIC is IOMMU enabled...
+        let vfio = Path::new(&format!("/sys/bus/pci/devices/{}/iommu_group", pci_addr)).exists();
+
+        let device_fd: RawFd;
+        let (addr, len) = if vfio {
+            device_fd = vfio_init(pci_addr)?;
+            vfio_map_region(device_fd, VFIO_PCI_BAR0_REGION_INDEX)?
+        } else {
+            if unsafe { libc::getuid() } != 0 {
+                warn!("not running as root, this will probably fail");
+            }
+
+            device_fd = -1;
+            pci_map_resource(pci_addr)?
+        };
+
+        // create the IxyDevice
+        let mut dev = IxgbeDevice::new(pci_addr, addr, len, config, vfio, device_fd);
+
+        if dev.vfio {
+            dev.interrupts.interrupts_enabled = config.interrupt_timeout != 0;
+            dev.interrupts.timeout_ms = config.interrupt_timeout;
+            dev.interrupts.itr_rate = 0x028;
+            dev.setup_interrupts()?;
+        }
+
+        if !dev.vfio && config.interrupt_timeout != 0 {
+            warn!("Interrupts requested but VFIO not available: Disabling Interrupts!");
+            dev.interrupts.interrupts_enabled = false;
+        }
+
+        dev.reset_and_init(pci_addr)?;
+
+        Ok(dev)
+    }
+
+    /// Returns an uninitialized `IxgbeDevice` for the device memory mapped at `addr`.
+    fn new(
+        pci_addr: &str,
+        addr: *mut u8,
+        len: usize,
+        config: &DeviceConfig,
+        vfio: bool,
+        device_fd: RawFd,
+    ) -> IxgbeDevice {
+        IxgbeDevice {
+            pci_addr: pci_addr.to_string(),
+            regs: Registers {
+                addr,
+                len,
+                #[cfg(test)]
+                sim: None,
+            },
+            num_rx_queues: config.num_rx_queues,
+            num_tx_queues: config.num_tx_queues,
+            config: *config,
+            rx_queues: Vec::with_capacity(config.num_rx_queues as usize),
+            tx_queues: Vec::with_capacity(config.num_tx_queues as usize),
+            vfio,
+            vfio_fd: unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR },
+            vfio_device_fd: device_fd,
+            interrupts: Default::default(),
+            fdir_filters: BTreeMap::new(),
+            fdir_mask: None,
+            promisc: config.promiscuous,
+            all_multicast: false,
+            multicast_addrs: Vec::new(),
+            stats: StatsPoller {
+                totals: Arc::new(Mutex::new(StatsTotals {
+                    stats: DeviceStats::default(),
+                    xstats: ExtendedStats::default(),
+                    fdir: FdirStats::default(),
+                    updated: Instant::now(),
+                })),
+                num_queues: config.num_rx_queues.max(config.num_tx_queues),
+                polls: 0,
+            },
+        }
+    }
+
+    /// Resets and initializes this device.
+    fn reset_and_init(&mut self, pci_addr: &str) -> Result<(), IxyError> {
+        info!("resetting device {}", pci_addr);
+        // section 4.6.3.1 - disable all interrupts
+        self.disable_interrupts();
+
+        // section 4.6.3.2
+        self.set_reg32(IXGBE_CTRL, IXGBE_CTRL_RST_MASK);
+        self.wait_clear_reg32(IXGBE_CTRL, IXGBE_CTRL_RST_MASK);
+        thread::sleep(Duration::from_millis(10));
+
+        // section 4.6.3.1 - disable interrupts again after reset
+        self.disable_interrupts();
+
+        let mac = self.get_mac_addr();
+        info!("initializing device {}", pci_addr);
+        info!(
+            "mac address: {:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+        );
+
+        // section 4.6.3 - wait for EEPROM auto read completion
+        self.wait_set_reg32(IXGBE_EEC, IXGBE_EEC_ARD);
+
+        // section 4.6.3 - wait for dma initialization done
+        self.wait_set_reg32(IXGBE_RDRXCTL, IXGBE_RDRXCTL_DMAIDONE);
+
+        // skip last step from 4.6.3 - we don't want interrupts
+
+        // section 4.6.4 - initialize link (auto negotiation)
+        self.init_link();
+
+        // section 4.6.5 - statistical counters
+        // reset-on-read registers, just read them once
+        self.reset_stats();
+
+        // section 4.6.7 - init rx
+        self.init_rx()?;
+
+        // section 4.6.8 - init tx
+        self.init_tx()?;
+
+        for i in 0..self.num_rx_queues {
+            self.start_rx_queue(i)?;
+        }
+
+        for i in 0..self.num_tx_queues {
+            self.start_tx_queue(i)?;
+        }
+
+        // enable interrupts
+        for queue in 0..self.num_rx_queues {
+            self.enable_interrupt(queue)?;
+        }
+
+        // wait some time for the link to come up
+        self.wait_for_link();
+
+        // the increment of the clock depends on the link speed
+        if self.config.timestamping {
+            self.init_timestamping();
+        }
+
+        Ok(())
+    }
+
+    /// Section 7.9 - starts the IEEE 1588 clock at the system time and enables timestamping of
+    /// received PTP event messages and of sent packets that request it.
+    fn init_timestamping(&mut self) {
+        // the clock ticks every 6.4 ns at 10 Gbit/s, 64 ns at 1 Gbit/s and 640 ns at
+        // 100 Mbit/s, SYSTIM is incremented in nanoseconds every one or five ticks
+        let (period, increment) = match self.get_link_speed() {
+            100 => (1, 640),
+            1000 => (1, 64),
+            _ => (5, 32),
+        };
+        self.set_reg32(IXGBE_TIMINCA, period << 24 | increment);
+        self.write_clock(timestamp::system_time());
+
+        // packets without a hardware timestamp get a software one
+        timestamp::calibrate();
+
+        // PTP over ethernet is recognized by its ethertype, PTP over udp by its port
+        self.set_reg32(
+            IXGBE_ETQF(IXGBE_ETQF_FILTER_1588),
+            IXGBE_ETQF_FILTER_EN | IXGBE_ETQF_1588 | ETH_P_1588,
+        );
+        self.set_reg32(
+            IXGBE_TSYNCRXCTL,
+            IXGBE_TSYNCRXCTL_ENABLED | IXGBE_TSYNCRXCTL_TYPE_EVENT_V2,
+        );
+        self.set_reg32(IXGBE_TSYNCTXCTL, IXGBE_TSYNCTXCTL_ENABLED);
+
+        // unlock the timestamp registers in case they hold stale values
+        self.get_reg32(IXGBE_RXSTMPH);
+        self.get_reg32(IXGBE_TXSTMPH);
+    }
+
+    /// Sets the IEEE 1588 clock to `nanos`.
+    fn write_clock(&self, nanos: u64) {
+        self.set_reg32(IXGBE_SYSTIML, nanos as u32);
+        self.set_reg32(IXGBE_SYSTIMH, (nanos >> 32) as u32);
+    }
+
+    /// Maps queue `i` to the queue stats registers `i % 16` in the mapping registers `reg`, each
+    /// of which holds the indices of four queues.
+    fn map_queue_stats(&self, reg: fn(u32) -> u32) {
+        for i in 0..u32::from(MAX_QUEUES) / 4 {
+            let mapping = (0..4).fold(0, |mapping, j| {
+                mapping | ((4 * i + j) % QUEUE_STATS) << (8 * j)
+            });
+            self.set_reg32(reg(i), mapping);
+        }
+    }
+
+    /// Fails with [`IxyError::InvalidConfig`] if timestamping is disabled.
+    fn check_timestamping(&self) -> Result<(), IxyError> {
+        if !self.config.timestamping {
+            return Err(IxyError::InvalidConfig(
+                "the clock only runs with timestamping enabled".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Section 7.1.2.8 - sets up the hash key, redirection table and hashed fields of rss.
+    fn init_rss(&self, rss: &RssConfig) {
+        for (i, key) in rss.key.chunks(4).enumerate() {
+            self.set_reg32(
+                IXGBE_RSSRK(i as u32),
+                u32::from_le_bytes([key[0], key[1], key[2], key[3]]),
+            );
+        }
+
+        // each register holds four 4 bit entries in its lower nibbles
+        let reta = rss.redirection_table(self.config.num_rss_queues(&LIMITS));
+        for (i, entries) in reta.chunks(4).enumerate() {
+            self.set_reg32(
+                IXGBE_RETA(i as u32),
+                u32::from_le_bytes([entries[0], entries[1], entries[2], entries[3]]),
+            );
+        }
+
+        // the rss hash is written to the descriptor field of the ip payload checksum
+        self.set_flags32(IXGBE_RXCSUM, IXGBE_RXCSUM_PCSD);
+
+        let fields = RSS_FIELDS
+            .iter()
+            .filter(|(fields, _)| rss.hash_fields.contains(*fields))
+            .fold(0, |mrqc, (_, bits)| mrqc | bits);
+        self.set_reg32(IXGBE_MRQC, IXGBE_MRQC_RSSEN | fields);
+    }
+
+    /// Section 7.1.2.7 - enables the flow director, the filters are stored in 64 KB of the rx
+    /// packet buffer not used by rx queues.
+    fn init_fdir(&self, mode: FdirMode) {
+        self.set_reg32(IXGBE_FDIRHKEY, FDIR_BUCKET_HASH_KEY);
+        self.set_reg32(IXGBE_FDIRSKEY, FDIR_SIGNATURE_HASH_KEY);
+
+        // flexible bytes at offset 12, i.e. the ethertype, and at most 10 filters per bucket
+        let mut fdirctrl = IXGBE_FDIRCTRL_PBALLOC_64K
+            | IXGBE_FDIRCTRL_REPORT_STATUS
+            | 0x6 << IXGBE_FDIRCTRL_FLEX_SHIFT
+            | 0xa << IXGBE_FDIRCTRL_MAX_LENGTH_SHIFT
+            | 0x4 << IXGBE_FDIRCTRL_FULL_THRESH_SHIFT;
+        if mode == FdirMode::Perfect {
+            fdirctrl |=
+                IXGBE_FDIRCTRL_PERFECT_MATCH | IXGBE_FDIR_DROP_QUEUE << IXGBE_FDIRCTRL_DROP_Q_SHIFT;
+        }
+        self.set_reg32(IXGBE_FDIRCTRL, fdirctrl);
+
+        self.wait_set_reg32(IXGBE_FDIRCTRL, IXGBE_FDIRCTRL_INIT_DONE);
+    }
+
+    /// Section 7.1.2.7.6 - sets the fields compared by all flow director filters.
+    fn set_fdir_mask(&self, mask: FlowMask) {
+        // set bits exclude fields from the comparison
+        let ignore = |compared: bool, bits: u32| if compared { 0 } else { bits };
+
+        self.set_reg32(
+            IXGBE_FDIRM,
+            IXGBE_FDIRM_VLANP
+                | IXGBE_FDIRM_POOL
+                | IXGBE_FDIRM_FLEX
+                | ignore(mask.vlan, IXGBE_FDIRM_VLANID),
+        );
+
+        let ports = ignore(mask.dst_port, 0xffff << IXGBE_FDIRTCPM_DPORTM_SHIFT)
+            | ignore(mask.src_port, 0xffff);
+        self.set_reg32(IXGBE_FDIRTCPM, ports);
+        self.set_reg32(IXGBE_FDIRUDPM, ports);
+        self.set_reg32(IXGBE_FDIRSIP4M, ignore(mask.src_addr, 0xffff_ffff));
+        self.set_reg32(IXGBE_FDIRDIP4M, ignore(mask.dst_addr, 0xffff_ffff));
+    }
+
+    /// Issues the flow director command `fdircmd`, returns the register once it completed.
+    fn fdir_command(&self, fdircmd: u32) -> Result<u32, IxyError> {
+        self.set_reg32(IXGBE_FDIRCMD, fdircmd);
+
+        for _ in 0..IXGBE_FDIRCMD_CMD_POLL {
+            let fdircmd = self.get_reg32(IXGBE_FDIRCMD);
+            if fdircmd & IXGBE_FDIRCMD_CMD_MASK == 0 {
+                return Ok(fdircmd);
+            }
+            thread::sleep(Duration::from_micros(10));
+        }
+
+        Err(IxyError::Device(
+            "flow director command did not complete".to_string(),
+        ))
+    }
+
+    // sections 4.6.7
+    /// Initializes the rx queues of this device.
+    fn init_rx(&mut self) -> Result<(), IxyError> {
+        // disable rx while re-configuring it
+        self.clear_flags32(IXGBE_RXCTRL, IXGBE_RXCTRL_RXEN);
+
+        // section 4.6.11.3.4 - allocate all queues and traffic to PB0
+        self.set_reg32(IXGBE_RXPBSIZE(0), IXGBE_RXPBSIZE_128KB);
+        for i in 1..8 {
+            self.set_reg32(IXGBE_RXPBSIZE(i), 0);
+        }
+
+        // enable CRC offloading
+        self.set_flags32(IXGBE_HLREG0, IXGBE_HLREG0_RXCRCSTRP);
+        self.set_flags32(IXGBE_RDRXCTL, IXGBE_RDRXCTL_CRCSTRIP);
+
+        self.clear_flags32(IXGBE_RDRXCTL, IXGBE_RDRXCTL_RSCFRSTSIZE);
+
+        // RSCFRSTSIZE should be set to 0x0 as opposed to its hardware default
+        // RSCACKC and FCOE_WRFIX should be set to 0x1
+        let rdrxctl = self.get_reg32(IXGBE_RDRXCTL);
+        self.set_reg32(
+            IXGBE_RDRXCTL,
+            rdrxctl & !IXGBE_RDRXCTL_RSCFRSTSIZE | IXGBE_RDRXCTL_RSCACKC | IXGBE_RDRXCTL_FCOE_WRFIX,
+        );
+
+        // accept broadcast packets
+        self.set_flags32(IXGBE_FCTRL, IXGBE_FCTRL_BAM);
+
+        // section 8.2.3.22.13 - frames larger than 1518 bytes require jumbo frames
+        if self.config.jumbo_frames() {
+            self.set_flags32(IXGBE_HLREG0, IXGBE_HLREG0_JUMBOEN);
+        } else {
+            self.clear_flags32(IXGBE_HLREG0, IXGBE_HLREG0_JUMBOEN);
+        }
+        self.set_reg32(
+            IXGBE_MAXFRS,
+            (self.get_reg32(IXGBE_MAXFRS) & !IXGBE_MHADD_MFS_MASK)
+                | (self.config.max_frame_size() as u32) << IXGBE_MHADD_MFS_SHIFT,
+        );
+
+        if let Some(rss) = &self.config.rss {
+            self.init_rss(rss);
+        }
+
+        if let Some(mode) = self.config.fdir {
+            self.init_fdir(mode);
+        }
+
+        // the vlan filter table is undefined after a reset, filtering starts with the first filter
+        for i in 0..VFTA_SIZE {
+            self.set_reg32(IXGBE_VFTA(i), 0);
+        }
+        self.clear_flags32(IXGBE_VLNCTRL, IXGBE_VLNCTRL_VFE);
+
+        // configure queues, same for all queues
+        for i in 0..self.num_rx_queues {
+            debug!("initializing rx queue {}", i);
+            // enable advanced rx descriptors
+            self.set_reg32(
+                IXGBE_SRRCTL(u32::from(i)),
+                (self.get_reg32(IXGBE_SRRCTL(u32::from(i))) & !IXGBE_SRRCTL_DESCTYPE_MASK)
+                    | IXGBE_SRRCTL_DESCTYPE_ADV_ONEBUF,
+            );
+            // let nic drop packets if no rx descriptor is available instead of buffering them
+            self.set_flags32(IXGBE_SRRCTL(u32::from(i)), IXGBE_SRRCTL_DROP_EN);
+            // strip vlan tags and report them in the descriptor
+            if self.config.vlan_strip {
+                self.set_flags32(IXGBE_RXDCTL(u32::from(i)), IXGBE_RXDCTL_VME);
+            } else {
+                self.clear_flags32(IXGBE_RXDCTL(u32::from(i)), IXGBE_RXDCTL_VME);
+            }
+            // receive buffer size in 1 KB units
+            self.set_reg32(
+                IXGBE_SRRCTL(u32::from(i)),
+                (self.get_reg32(IXGBE_SRRCTL(u32::from(i))) & !IXGBE_SRRCTL_BSIZEPKT_MASK)
+                    | (self.config.buffer_size >> IXGBE_SRRCTL_BSIZEPKT_SHIFT) as u32,
+            );
+
+            // section 7.1.9 - setup descriptor ring
+            let ring_size_bytes = self.config.rx_ring_size * mem::size_of::<ixgbe_adv_rx_desc>();
+
+            let dma: Dma<ixgbe_adv_rx_desc> = self.allocate_dma(ring_size_bytes)?;
+
+            // initialize to 0xff to prevent rogue memory accesses on premature dma activation
+            unsafe {
+                memset(dma.virt as *mut u8, ring_size_bytes, 0xff);
+            }
+
+            self.set_reg32(
+                IXGBE_RDBAL(u32::from(i)),
+                (dma.phys as u64 & 0xffff_ffff) as u32,
+            );
+            self.set_reg32(IXGBE_RDBAH(u32::from(i)), (dma.phys as u64 >> 32) as u32);
+            self.set_reg32(IXGBE_RDLEN(u32::from(i)), ring_size_bytes as u32);
+
+            // section 7.11 - coalesce tcp segments into frames of at most 64 KB
+            if self.config.rsc_enabled(i) {
+                self.set_reg32(
+                    IXGBE_PSRTYPE(u32::from(i)),
+                    IXGBE_PSRTYPE_TCPHDR
+                        | IXGBE_PSRTYPE_IPV4HDR
+                        | IXGBE_PSRTYPE_IPV6HDR
+                        | IXGBE_PSRTYPE_L2HDR,
+                );
+                self.set_reg32(
+                    IXGBE_RSCCTL(u32::from(i)),
+                    IXGBE_RSCCTL_RSCEN | rsc_max_desc(self.config.buffer_size),
+                );
+            } else {
+                self.clear_flags32(IXGBE_RSCCTL(u32::from(i)), IXGBE_RSCCTL_RSCEN);
+            }
+
+            debug!("rx ring {} phys addr: {:#x}", i, dma.phys);
+            debug!("rx ring {} virt addr: {:p}", i, dma.virt);
+
+            // set ring to empty at start
+            self.set_reg32(IXGBE_RDH(u32::from(i)), 0);
+            self.set_reg32(IXGBE_RDT(u32::from(i)), 0);
+
+            let mempool =
+                self.allocate_mempool(self.config.rx_mempool_size(), self.config.buffer_size)?;
+
+            let rx_queue = IxgbeRxQueue {
+                descriptors: dma.virt,
+                pool: mempool,
+                num_descriptors: self.config.rx_ring_size,
+                rx_index: 0,
+                bufs_in_use: Vec::with_capacity(self.config.rx_ring_size),
+                spare: Vec::new(),
+                partial: (0..self.config.rx_ring_size).map(|_| None).collect(),
+                timestamping: self.config.timestamping,
+                stats: RxQueueStats::default(),
+            };
+
+            self.rx_queues.push(rx_queue);
+        }
+
+        // last sentence of section 4.6.7 - set some magic bits
+        self.set_flags32(IXGBE_CTRL_EXT, IXGBE_CTRL_EXT_NS_DIS);
+
+        // probably a broken feature, this flag is initialized with 1 but has to be set to 0
+        for i in 0..self.num_rx_queues {
+            self.clear_flags32(IXGBE_DCA_RXCTRL(u32::from(i)), 1 << 12);
+        }
+
+        // count each queue in its own queue stats registers
+        self.map_queue_stats(IXGBE_RQSMR);
+
+        // drop the secondary addresses of a previous user, pool 0 receives all packets
+        for i in 1..RAR_ENTRIES {
+            self.set_reg32(IXGBE_RAH(i), 0);
+            self.set_reg32(IXGBE_RAL(i), 0);
+            self.set_reg32(IXGBE_MPSAR_LO(i), 0);
+            self.set_reg32(IXGBE_MPSAR_HI(i), 0);
+        }
+        self.set_reg32(IXGBE_MPSAR_LO(0), 1);
+
+        // filter multicast packets by the hashes in the multicast table array
+        self.set_multicast_table();
+        self.set_reg32(IXGBE_MCSTCTRL, IXGBE_MCSTCTRL_MFE);
+
+        // accept broadcasts, promisc mode is enabled by default to make testing easier
+        self.set_flags32(IXGBE_FCTRL, IXGBE_FCTRL_BAM);
+        self.set_rx_mode();
+
+        // start rx
+        self.set_flags32(IXGBE_RXCTRL, IXGBE_RXCTRL_RXEN);
+
+        Ok(())
+    }
+
+    // section 4.6.8
+    /// Initializes the tx queues of this device.
+    fn init_tx(&mut self) -> Result<(), IxyError> {
+        // crc offload and small packet padding
+        self.set_flags32(IXGBE_HLREG0, IXGBE_HLREG0_TXCRCEN | IXGBE_HLREG0_TXPADEN);
+
+        // section 4.6.11.3.4 - set default buffer size allocations
+        self.set_reg32(IXGBE_TXPBSIZE(0), IXGBE_TXPBSIZE_40KB);
+        for i in 1..8 {
+            self.set_reg32(IXGBE_TXPBSIZE(i), 0);
+        }
+
+        // required when not using DCB/VTd
+        self.set_reg32(IXGBE_DTXMXSZRQ, 0xfff);
+
+        self.map_queue_stats(IXGBE_TQSM);
+        self.clear_flags32(IXGBE_RTTDCS, IXGBE_RTTDCS_ARBDIS);
+
+        // configure queues
+        for i in 0..self.num_tx_queues {
+            debug!("initializing tx queue {}", i);
+            // section 7.1.9 - setup descriptor ring
+            let ring_size_bytes = self.config.tx_ring_size * mem::size_of::<ixgbe_adv_tx_desc>();
+
+            let dma: Dma<ixgbe_adv_tx_desc> = self.allocate_dma(ring_size_bytes)?;
+            unsafe {
+                memset(dma.virt as *mut u8, ring_size_bytes, 0xff);
+            }
+
+            self.set_reg32(
+                IXGBE_TDBAL(u32::from(i)),
+                (dma.phys as u64 & 0xffff_ffff) as u32,
+            );
+            self.set_reg32(IXGBE_TDBAH(u32::from(i)), (dma.phys as u64 >> 32) as u32);
+            self.set_reg32(IXGBE_TDLEN(u32::from(i)), ring_size_bytes as u32);
+
+            debug!("tx ring {} phys addr: {:#x}", i, dma.phys);
+            debug!("tx ring {} virt addr: {:p}", i, dma.virt);
+
+            // descriptor writeback magic values, important to get good performance and low PCIe overhead
+            // see 7.2.3.4.1 and 7.2.3.5 for an explanation of these values and how to find good ones
+            // we just use the defaults from DPDK here, but this is a potentially interesting point for optimizations
+            let mut txdctl = self.get_reg32(IXGBE_TXDCTL(u32::from(i)));
+            // there are no defines for this in constants.rs for some reason
+            // pthresh: 6:0, hthresh: 14:8, wthresh: 22:16
+            txdctl &= !(0x7F | (0x7F << 8) | (0x7F << 16));
+            txdctl |= 36 | (8 << 8) | (4 << 16);
+
+            self.set_reg32(IXGBE_TXDCTL(u32::from(i)), txdctl);
+
+            let tx_queue = IxgbeTxQueue {
+                descriptors: dma.virt,
+                bufs_in_use: VecDeque::with_capacity(self.config.tx_ring_size),
+                num_descriptors: self.config.tx_ring_size,
+                clean_index: 0,
+                tx_index: 0,
+                context: None,
+                stats: TxQueueStats::default(),
+            };
+
+            self.tx_queues.push(tx_queue);
+        }
+
+        // final step: enable DMA
+        self.set_reg32(IXGBE_DMATXCTL, IXGBE_DMATXCTL_TE);
+
+        Ok(())
+    }
+
+    /// Sets the rx queues` descriptors and enables the queues.
+    fn start_rx_queue(&mut self, queue_id: u16) -> Result<(), IxyError> {
+        debug!("starting rx queue {}", queue_id);
+
+        {
+            let queue = &mut self.rx_queues[queue_id as usize];
+
+            if queue.num_descriptors & (queue.num_descriptors - 1) != 0 {
+                return Err(IxyError::InvalidConfig(
+                    "number of queue entries must be a power of 2".to_string(),
+                ));
+            }
+
+            for i in 0..queue.num_descriptors {
+                let pool = &queue.pool;
+
+                let buf = match pool.alloc_buf() {
+                    Some(x) => x,
+                    None => return Err(IxyError::MempoolExhausted),
+                };
+
+                unsafe {
+                    ptr::write_volatile(
+                        &mut (*queue.descriptors.add(i)).read.pkt_addr as *mut u64,
+                        pool.get_phys_addr(buf) as u64,
+                    );
+
+                    ptr::write_volatile(
+                        &mut (*queue.descriptors.add(i)).read.hdr_addr as *mut u64,
+                        0,
+                    );
+                }
+
+                // we need to remember which descriptor entry belongs to which mempool entry
+                queue.bufs_in_use.push(buf);
+            }
+        }
+
+        let queue = &self.rx_queues[queue_id as usize];
+
+        // enable queue and wait if necessary
+        self.set_flags32(IXGBE_RXDCTL(u32::from(queue_id)), IXGBE_RXDCTL_ENABLE);
+        self.wait_set_reg32(IXGBE_RXDCTL(u32::from(queue_id)), IXGBE_RXDCTL_ENABLE);
+
+        // rx queue starts out full
+        self.set_reg32(IXGBE_RDH(u32::from(queue_id)), 0);
+
+        // was set to 0 before in the init function
+        self.set_reg32(
+            IXGBE_RDT(u32::from(queue_id)),
+            (queue.num_descriptors - 1) as u32,
+        );
+
+        Ok(())
+    }
+
+    /// Enables the tx queues.
+    fn start_tx_queue(&mut self, queue_id: u16) -> Result<(), IxyError> {
+        debug!("starting tx queue {}", queue_id);
+
+        {
+            let queue = &mut self.tx_queues[queue_id as usize];
+
+            if queue.num_descriptors & (queue.num_descriptors - 1) != 0 {
+                return Err(IxyError::InvalidConfig(
+                    "number of queue entries must be a power of 2".to_string(),
+                ));
+            }
+        }
+
+        // tx queue starts out empty
+        self.set_reg32(IXGBE_TDH(u32::from(queue_id)), 0);
+        self.set_reg32(IXGBE_TDT(u32::from(queue_id)), 0);
+
+        // enable queue and wait if necessary
+        self.set_flags32(IXGBE_TXDCTL(u32::from(queue_id)), IXGBE_TXDCTL_ENABLE);
+        self.wait_set_reg32(IXGBE_TXDCTL(u32::from(queue_id)), IXGBE_TXDCTL_ENABLE);
+
+        Ok(())
+    }
+
+    // see section 4.6.4
+    /// Initializes the link of this device.
+    fn init_link(&self) {
+        // link auto-configuration register should already be set correctly, we're resetting it anyway
+        self.set_reg32(
+            IXGBE_AUTOC,
+            (self.get_reg32(IXGBE_AUTOC) & !IXGBE_AUTOC_LMS_MASK) | IXGBE_AUTOC_LMS_10G_SERIAL,
+        );
+        self.set_reg32(
+            IXGBE_AUTOC,
+            (self.get_reg32(IXGBE_AUTOC) & !IXGBE_AUTOC_10G_PMA_PMD_MASK) | IXGBE_AUTOC_10G_XAUI,
+        );
+        // negotiate link
+        self.set_flags32(IXGBE_AUTOC, IXGBE_AUTOC_AN_RESTART);
+        // datasheet wants us to wait for the link here, but we can continue and wait afterwards
+    }
+
+    /// Waits for the link to come up.
+    fn wait_for_link(&self) {
+        info!("waiting for link");
+        let time = Instant::now();
+        let mut speed = self.get_link_speed();
+        while speed == 0 && time.elapsed().as_secs() < 10 {
+            thread::sleep(Duration::from_millis(100));
+            speed = self.get_link_speed();
+        }
+        info!("link speed is {} Mbit/s", self.get_link_speed());
+    }
+
+    /// Sets the unicast and multicast promiscuous bits of this device according to its rx mode.
+    fn set_rx_mode(&self) {
+        let mut fctrl = self.get_reg32(IXGBE_FCTRL) & !(IXGBE_FCTRL_MPE | IXGBE_FCTRL_UPE);
+        if self.promisc {
+            fctrl |= IXGBE_FCTRL_MPE | IXGBE_FCTRL_UPE;
+        } else if self.all_multicast {
+            fctrl |= IXGBE_FCTRL_MPE;
+        }
+        self.set_reg32(IXGBE_FCTRL, fctrl);
+    }
+
+    /// Returns the index of the secondary receive address register holding `mac`.
+    fn find_mac_addr(&self, mac: [u8; 6]) -> Option<u32> {
+        let (low, high) = receive_address(mac);
+        (1..RAR_ENTRIES).find(|&i| {
+            self.get_reg32(IXGBE_RAH(i)) == high | IXGBE_RAH_AV
+                && self.get_reg32(IXGBE_RAL(i)) == low
+        })
+    }
+
+    /// Rebuilds the multicast table array from the subscribed multicast addresses.
+    fn set_multicast_table(&self) {
+        let mut mta = [0u32; MTA_SIZE];
+        for &mac in &self.multicast_addrs {
+            let vector = mta_vector(mac);
+            mta[(vector >> 5) as usize] |= 1 << (vector & 0x1f);
+        }
+        for (i, &value) in mta.iter().enumerate() {
+            self.set_reg32(IXGBE_MTA(i as u32), value);
+        }
+    }
+
+    /// Allocates physically contiguous dma memory for a descriptor ring, see [`Dma::allocate`].
+    fn allocate_dma<T>(&self, size: usize) -> Result<Dma<T>, IxyError> {
+        // the simulator has no IOMMU and reads the rings through their virtual addresses
+        #[cfg(test)]
+        {
+            if self.regs.sim.is_some() {
+                return Dma::allocate_anonymous(size);
+            }
+        }
+
+        Dma::allocate(size, true)
+    }
+
+    /// Allocates the packet buffers of a rx queue, see [`Mempool::allocate`].
+    fn allocate_mempool(&self, entries: usize, size: usize) -> Result<Arc<Mempool>, IxyError> {
+        #[cfg(test)]
+        {
+            if self.regs.sim.is_some() {
+                return Mempool::allocate_anonymous(entries, size);
+            }
+        }
+
+        Mempool::allocate(entries, size)
+    }
+
+    /// Returns the register at `reg`, see [`Registers::get_reg32`].
+    fn get_reg32(&self, reg: u32) -> u32 {
+        self.regs.get_reg32(reg)
+    }
+
+    /// Sets the register at `reg` to `value`, see [`Registers::set_reg32`].
+    fn set_reg32(&self, reg: u32, value: u32) {
+        self.regs.set_reg32(reg, value)
+    }
+
+    /// Sets the `flags` at `self.addr` + `reg`.
+    fn set_flags32(&self, reg: u32, flags: u32) {
+        self.set_reg32(reg, self.get_reg32(reg) | flags);
+    }
+
+    /// Clears the `flags` at `self.addr` + `reg`.
+    fn clear_flags32(&self, reg: u32, flags: u32) {
+        self.set_reg32(reg, self.get_reg32(reg) & !flags);
+    }
+
+    /// Waits for `self.addr` + `reg` to clear `value`.
+    fn wait_clear_reg32(&self, reg: u32, value: u32) {
+        loop {
+            let current = self.get_reg32(reg);
+            if (current & value) == 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Waits for `self.addr` + `reg` to set `value`.
+    fn wait_set_reg32(&self, reg: u32, value: u32) {
+        loop {
+            let current = self.get_reg32(reg);
+            if (current & value) == value {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Maps interrupt causes to vectors by specifying the `direction` (0 for Rx, 1 for Tx),
+    /// the `queue` ID and the corresponding `misx_vector`.
+    fn set_ivar(&self, direction: u32, queue: u16, mut msix_vector: u32) {
+        msix_vector |= IXGBE_IVAR_ALLOC_VAL;
+        let index = 16 * (u32::from(queue) & 1) + 8 * direction;
+        let mut ivar = self.get_reg32(IXGBE_IVAR(u32::from(queue) >> 1));
+        ivar &= !(0xFF << index);
+        ivar |= msix_vector << index;
+        self.set_reg32(IXGBE_IVAR(u32::from(queue) >> 1), ivar);
+    }
+
+    /// Clear all interrupt masks for all queues.
+    fn clear_interrupts(&self) {
+        // Clear interrupt mask
+        self.set_reg32(IXGBE_EIMC, IXGBE_IRQ_CLEAR_MASK);
+        self.get_reg32(IXGBE_EICR);
+    }
+
+    /// Clear interrupt for queue with `queue_id`.
+    fn clear_interrupt(&self, queue_id: u16) {
+        // Clear interrupt mask
+        self.set_reg32(IXGBE_EIMC, 1 << queue_id);
+        self.get_reg32(IXGBE_EICR);
+    }
+
+    /// Disable all interrupts for all queues.
+    fn disable_interrupts(&self) {
+        // Clear interrupt mask to stop from interrupts being generated
+        self.set_reg32(IXGBE_EIMS, 0x0000_0000);
+        self.clear_interrupts();
+    }
+
+    /// Disable interrupt for queue with `queue_id`.
+    fn disable_interrupt(&self, queue_id: u16) {
+        // Clear interrupt mask to stop from interrupts being generated
+        let mut mask: u32 = self.get_reg32(IXGBE_EIMS);
+        mask &= !(1 << queue_id);
+        self.set_reg32(IXGBE_EIMS, mask);
+        self.clear_interrupt(queue_id);
+        debug!("Using polling");
+    }
+
+    /// Enable MSI interrupt for queue with `queue_id`.
+    fn enable_msi_interrupt(&self, queue_id: u16) {
+        // Step 1: The software driver associates between Tx and Rx interrupt causes and the EICR
+        // register by setting the IVAR[n] registers.
+        self.set_ivar(0, queue_id, 0);
+
+        // Step 2: Program SRRCTL[n].RDMTS (per receive queue) if software uses the receive
+        // descriptor minimum threshold interrupt
+        // We don't use the minimum threshold interrupt
+
+        // Step 3: All interrupts should be set to 0b (no auto clear in the EIAC register). Following an
+        // interrupt, software might read the EICR register to check for the interrupt causes.
+        self.set_reg32(IXGBE_EIAC, 0x0000_0000);
+
+        // Step 4: Set the auto mask in the EIAM register according to the preferred mode of operation.
+        // In our case we prefer to not auto-mask the interrupts
+
+        // Step 5: Set the interrupt throttling in EITR[n] and GPIE according to the preferred mode of operation.
+        self.set_reg32(IXGBE_EITR(u32::from(queue_id)), self.interrupts.itr_rate);
+
+        // Step 6: Software clears EICR by writing all ones to clear old interrupt causes
+        self.clear_interrupts();
+
+        // Step 7: Software enables the required interrupt causes by setting the EIMS register
+        let mut mask: u32 = self.get_reg32(IXGBE_EIMS);
+        mask |= 1 << queue_id;
+        self.set_reg32(IXGBE_EIMS, mask);
+        debug!("Using MSI interrupts");
+    }
+
+    /// Enable MSI-X interrupt for queue with `queue_id`.
+    fn enable_msix_interrupt(&self, queue_id: u16) {
+        // Step 1: The software driver associates between interrupt causes and MSI-X vectors and the
+        //throttling timers EITR[n] by programming the IVAR[n] and IVAR_MISC registers.
+        let mut gpie: u32 = self.get_reg32(IXGBE_GPIE);
+        gpie |= IXGBE_GPIE_MSIX_MODE | IXGBE_GPIE_PBA_SUPPORT | IXGBE_GPIE_EIAME;
+        self.set_reg32(IXGBE_GPIE, gpie);
+        self.set_ivar(0, queue_id, u32::from(queue_id));
+
+        // Step 2: Program SRRCTL[n].RDMTS (per receive queue) if software uses the receive
+        // descriptor minimum threshold interrupt
+        // We don't use the minimum threshold interrupt
+
+        // Step 3: The EIAC[n] registers should be set to auto clear for transmit and receive interrupt
+        // causes (for best performance). The EIAC bits that control the other and TCP timer
+        // interrupt causes should be set to 0b (no auto clear).
+        self.set_reg32(IXGBE_EIAC, IXGBE_EIMS_RTX_QUEUE);
+
+        // Step 4: Set the auto mask in the EIAM register according to the preferred mode of operation.
+        // In our case we prefer to not auto-mask the interrupts
+
+        // Step 5: Set the interrupt throttling in EITR[n] and GPIE according to the preferred mode of operation.
+        // 0x000 (0us) => ... INT/s
+        // 0x008 (2us) => 488200 INT/s
+        // 0x010 (4us) => 244000 INT/s
+        // 0x028 (10us) => 97600 INT/s
+        // 0x0C8 (50us) => 20000 INT/s
+        // 0x190 (100us) => 9766 INT/s
+        // 0x320 (200us) => 4880 INT/s
+        // 0x4B0 (300us) => 3255 INT/s
+        // 0x640 (400us) => 2441 INT/s
+        // 0x7D0 (500us) => 2000 INT/s
+        // 0x960 (600us) => 1630 INT/s
+        // 0xAF0 (700us) => 1400 INT/s
+        // 0xC80 (800us) => 1220 INT/s
+        // 0xE10 (900us) => 1080 INT/s
+        // 0xFA7 (1000us) => 980 INT/s
+        // 0xFFF (1024us) => 950 INT/s
+        self.set_reg32(IXGBE_EITR(u32::from(queue_id)), self.interrupts.itr_rate);
+
+        // Step 6: Software enables the required interrupt causes by setting the EIMS register
+        let mut mask: u32 = self.get_reg32(IXGBE_EIMS);
+        mask |= 1 << queue_id;
+        self.set_reg32(IXGBE_EIMS, mask);
+        debug!("Using MSIX interrupts");
+    }
+
+    /// Enable MSI or MSI-X interrupt for queue with `queue_id` depending on which is supported (Prefer MSI-x).
+    fn enable_interrupt(&self, queue_id: u16) -> Result<(), IxyError> {
+        if !self.interrupts.interrupts_enabled {
+            return Ok(());
+        }
+        match self.interrupts.interrupt_type {
+            VFIO_PCI_MSIX_IRQ_INDEX => self.enable_msix_interrupt(queue_id),
+            VFIO_PCI_MSI_IRQ_INDEX => self.enable_msi_interrupt(queue_id),
+            _ => {
+                return Err(IxyError::Device(format!(
+                    "interrupt type not supported: {}",
+                    self.interrupts.interrupt_type
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Setup interrupts by enabling VFIO interrupts.
+    fn setup_interrupts(&mut self) -> Result<(), IxyError> {
+        if !self.interrupts.interrupts_enabled {
+            self.interrupts.queues = Vec::with_capacity(0);
+            return Ok(());
+        }
+        self.interrupts.queues = Vec::with_capacity(self.num_rx_queues as usize);
+        self.interrupts.vfio_setup_interrupt(self.vfio_device_fd)?;
+        match self.interrupts.interrupt_type {
+            VFIO_PCI_MSIX_IRQ_INDEX => {
+                for rx_queue in 0..self.num_rx_queues {
+                    let mut queue = InterruptsQueue {
+                        vfio_event_fd: 0,
+                        vfio_epoll_fd: 0,
+                        last_time_checked: Instant::now(),
+                        rx_pkts: 0,
+                        moving_avg: Default::default(),
+                        interrupt_enabled: true,
+                        interval: INTERRUPT_INITIAL_INTERVAL,
+                        instr_counter: 0,
+                    };
+                    info!("enabling MSIX interrupts for queue {}", rx_queue);
+                    queue.vfio_enable_msix(self.vfio_device_fd, u32::from(rx_queue))?;
+                    queue.vfio_epoll_ctl(queue.vfio_event_fd)?;
+                    self.interrupts.queues.push(queue);
+                }
+            }
+            VFIO_PCI_MSI_IRQ_INDEX => {
+                for _rx_queue in 0..self.num_rx_queues {
+                    let mut queue = InterruptsQueue {
+                        vfio_event_fd: 0,
+                        vfio_epoll_fd: 0,
+                        last_time_checked: Instant::now(),
+                        rx_pkts: 0,
+                        moving_avg: Default::default(),
+                        interrupt_enabled: true,
+                        interval: INTERRUPT_INITIAL_INTERVAL,
+                        instr_counter: 0,
+                    };
+                    info!("enabling MSI interrupts for queue {}", _rx_queue);
+                    queue.vfio_enable_msi(self.vfio_device_fd)?;
+                    queue.vfio_epoll_ctl(queue.vfio_event_fd)?;
+                    self.interrupts.queues.push(queue);
+                }
+            }
+            _ => {
+                return Err(IxyError::Device(format!(
+                    "interrupt type not supported: {}",
+                    self.interrupts.interrupt_type
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl IxgbeRxQueue {
+    /// Pushes up to `num_packets` received `Packet`s onto `buffer` and hands the processed
+    /// descriptors back to the device.
+    fn rx_batch(
+        &mut self,
+        regs: &Registers,
+        queue_id: u16,
+        buffer: &mut VecDeque<Packet>,
+        num_packets: usize,
+    ) -> usize {
+        let mut rx_index = self.rx_index;
+        let mut last_rx_index = self.rx_index;
+        let mut received_packets = 0;
+
+        while received_packets < num_packets {
+            let desc = unsafe { self.descriptors.add(rx_index) };
+            let status =
+                unsafe { ptr::read_volatile(&mut (*desc).wb.upper.status_error as *mut u32) };
+
+            if (status & IXGBE_RXDADV_STAT_DD) == 0 {
+                break;
+            }
+
+            // the write-back fields are overwritten when the descriptor is reset
+            let (lo_dword, rss_hash, vlan) = unsafe {
+                (
+                    ptr::read_volatile(&(*desc).wb.lower.lo_dword.data as *const u32),
+                    ptr::read_volatile(&(*desc).wb.lower.hi_dword.rss as *const u32),
+                    ptr::read_volatile(&(*desc).wb.upper.vlan as *const u16),
+                )
+            };
+            let rss_type = lo_dword & IXGBE_RXDADV_RSSTYPE_MASK;
+            let rsc_count = (lo_dword & IXGBE_RXDADV_RSCCNT_MASK) >> IXGBE_RXDADV_RSCCNT_SHIFT;
+
+            let pool = &self.pool;
+
+            // get the free buffers for all ready descriptors of this batch at once
+            if self.spare.is_empty() {
+                let ready = ready_rx_descriptors(
+                    self.descriptors,
+                    self.num_descriptors,
+                    rx_index,
+                    num_packets - received_packets,
+                );
+                let spare = &mut self.spare;
+                pool.alloc_bufs(ready, |id| spare.push(id));
+            }
+
+            if let Some(buf) = self.spare.pop() {
+                // replace currently used buffer with new buffer
+                let buf = mem::replace(&mut self.bufs_in_use[rx_index], buf);
+
+                let p = Packet {
+                    addr_virt: pool.get_virt_addr(buf),
+                    addr_phys: pool.get_phys_addr(buf),
+                    len: unsafe {
+                        ptr::read_volatile(&(*desc).wb.upper.length as *const u16) as usize
+                    },
+                    pool: pool.clone(),
+                    pool_entry: buf,
+                    next: None,
+                    meta: Default::default(),
+                };
+
+                #[cfg(all(
+                    any(target_arch = "x86", target_arch = "x86_64"),
+                    target_feature = "sse"
+                ))]
+                p.prefetch(Prefetch::Time1);
+
+                unsafe {
+                    ptr::write_volatile(
+                        &mut (*desc).read.pkt_addr as *mut u64,
+                        pool.get_phys_addr(self.bufs_in_use[rx_index]) as u64,
+                    );
+                    ptr::write_volatile(&mut (*desc).read.hdr_addr as *mut u64, 0);
+                }
+
+                // frames that don't fit into one buffer span multiple descriptors, the
+                // segments are chained until the descriptor with the end of packet bit
+                let mut p = match self.partial[rx_index].take() {
+                    Some(mut head) => {
+                        head.push_segment(p);
+                        head
+                    }
+                    None => p,
+                };
+
+                last_rx_index = rx_index;
+                rx_index = wrap_ring(rx_index, self.num_descriptors);
+
+                if rsc_count != 0 {
+                    p.meta.coalesced_segments =
+                        Some(p.meta.coalesced_segments.unwrap_or(0) + rsc_count as u16);
+                }
+
+                if (status & IXGBE_RXDADV_STAT_EOP) == 0 {
+                    // section 7.11 - the descriptors of coalesced frames are not consecutive,
+                    // each one points to the descriptor holding the next segment
+                    let next = if rsc_count != 0 {
+                        ((status & IXGBE_RXDADV_NEXTP_MASK) >> IXGBE_RXDADV_NEXTP_SHIFT) as usize
+                    } else {
+                        rx_index
+                    };
+                    self.partial[next] = Some(p);
+                    continue;
+                }
+
+                // the rss hash field holds the filter id if a flow director filter matched
+                if status & IXGBE_RXDADV_STAT_FLM != 0 {
+                    p.meta.fdir_id = Some(rss_hash as u16);
+                } else if rss_type != IXGBE_RXDADV_RSSTYPE_NONE {
+                    p.meta.rss_hash = Some(rss_hash);
+                }
+
+                p.meta.ip_checksum =
+                    checksum_status(status, IXGBE_RXD_STAT_IPCS, IXGBE_RXDADV_ERR_IPE);
+                p.meta.l4_checksum =
+                    checksum_status(status, IXGBE_RXD_STAT_L4CS, IXGBE_RXDADV_ERR_TCPE);
+
+                if status & IXGBE_RXDADV_STAT_VP != 0 {
+                    p.meta.vlan_tci = Some(vlan);
+                }
+
+                // the timestamp of a PTP event message is latched in RXSTMP until read
+                if status & IXGBE_RXDADV_STAT_TS != 0 {
+                    let low = regs.get_reg32(IXGBE_RXSTMPL);
+                    let high = regs.get_reg32(IXGBE_RXSTMPH);
+                    p.meta.timestamp =
+                        Some(Timestamp::Hardware(u64::from(high) << 32 | u64::from(low)));
+                } else if self.timestamping {
+                    p.meta.timestamp = Some(Timestamp::Software(timestamp::now()));
+                }
+
+                self.stats.pkts += 1;
+                self.stats.bytes += p.total_len() as u64;
+
+                buffer.push_back(p);
+                received_packets += 1;
+            } else {
+                // break if there was no free buffer
+                self.stats.mempool_exhausted += 1;
+                break;
+            }
+        }
+
+        if received_packets == 0 {
+            self.stats.empty_polls += 1;
+        }
+
+        if rx_index != last_rx_index {
+            regs.set_reg32(IXGBE_RDT(u32::from(queue_id)), last_rx_index as u32);
+            self.rx_index = rx_index;
+        }
+
+        received_packets
+    }
+}
+
+impl IxgbeTxQueue {
+    /// Pops as many packets as possible from `buffer` to put them into this queue, freeing sent
+    /// packets in batches of `clean_batch`.
+    fn tx_batch(
+        &mut self,
+        regs: &Registers,
+        queue_id: u16,
+        buffer: &mut VecDeque<Packet>,
+        clean_batch: usize,
+    ) -> usize {
+        let mut sent = 0;
+
+        let mut cur_index = self.tx_index;
+        let clean_index = clean_tx_queue(self, clean_batch);
+
+        while let Some(mut packet) = buffer.pop_front() {
+            if packet.tx_offload().is_some_and(|o| !o.fits(&packet)) {
+                warn!("dropping packet whose offloaded headers are not in its first segment");
+                self.stats.dropped += 1;
+                continue;
+            }
+
+            // segmented frames are only limited by the ring, one descriptor stays empty and one
+            // may hold the context
+            let max_segments = if packet.tx_offload().is_some_and(|o| o.mss.is_some()) {
+                self.num_descriptors - 2
+            } else {
+                MAX_TX_SEGMENTS
+            };
+            if packet.num_segments() > max_segments {
+                match packet
+                    .linearize()
+                    .filter(|p| p.num_segments() <= max_segments)
+                {
+                    Some(linearized) => packet = linearized,
+                    None => {
+                        warn!(
+                            "dropping packet with {} segments, at most {} are supported",
+                            packet.num_segments(),
+                            max_segments
+                        );
+                        self.stats.dropped += 1;
+                        continue;
+                    }
+                }
+            }
+            let num_segments = packet.num_segments();
+
+            // offloads different from the ones of the previous packet need a context descriptor
+            let offload = packet.tx_offload();
+            let vlan_tci = packet.vlan_tci();
+            let context = tx_context(offload.as_ref(), vlan_tci);
+            let new_context = matches!(context, Some((fields, _)) if self.context != Some(fields));
+
+            // one descriptor between the tail and the first uncleaned descriptor stays empty
+            let free_descriptors =
+                (clean_index + self.num_descriptors - cur_index - 1) % self.num_descriptors;
+
+            if num_segments + usize::from(new_context) > free_descriptors {
+                // tx queue of device is full, push packet back onto the
+                // queue of to-be-sent packets
+                buffer.push_front(packet);
+                self.stats.ring_full += 1;
+                break;
+            }
+
+            self.stats.pkts += 1;
+            self.stats.bytes += packet.total_len() as u64;
+
+            let mut payload_len = packet.total_len();
+            let mut olinfo_status = 0;
+            let mut tso = 0;
+
+            if let Some((fields, popts)) = context {
+                if new_context {
+                    let desc = unsafe {
+                        self.descriptors.add(cur_index) as *mut ixgbe_adv_tx_context_desc
+                    };
+                    unsafe {
+                        ptr::write_volatile(&mut (*desc).vlan_macip_lens as *mut u32, fields[0]);
+                        ptr::write_volatile(&mut (*desc).seqnum_seed as *mut u32, 0);
+                        ptr::write_volatile(&mut (*desc).type_tucmd_mlhl as *mut u32, fields[1]);
+                        ptr::write_volatile(&mut (*desc).mss_l4len_idx as *mut u32, fields[2]);
+                    }
+
+                    self.bufs_in_use.push_back(None);
+                    self.context = Some(fields);
+                    cur_index = wrap_ring(cur_index, self.num_descriptors);
+                }
+
+                olinfo_status |= IXGBE_ADVTXD_CC | popts;
+            }
+
+            if let Some(offload) = offload {
+                offload.prepare(&mut packet);
+
+                // the payload length of segmented packets excludes the headers
+                if offload.mss.is_some() {
+                    payload_len -= offload.header_len();
+                    tso = IXGBE_ADVTXD_DCMD_TSE;
+                }
+            }
+
+            olinfo_status |= (payload_len as u32) << IXGBE_ADVTXD_PAYLEN_SHIFT;
+
+            // the device inserts the vlan tag of the context descriptor
+            let vle = if vlan_tci.is_some() {
+                IXGBE_ADVTXD_DCMD_VLE
+            } else {
+                0
+            };
+
+            let tstamp = if packet.meta.tx_timestamp {
+                IXGBE_ADVTXD_MAC_TSTAMP
+            } else {
+                0
+            };
+
+            // each segment gets its own data descriptor, only the last one ends the frame
+            let mut segment = Some(packet);
+
+            while let Some(mut packet) = segment {
+                segment = packet.take_next_segment();
+
+                let eop = if segment.is_none() {
+                    IXGBE_ADVTXD_DCMD_EOP
+                } else {
+                    0
+                };
+
+                unsafe {
+                    ptr::write_volatile(
+                        &mut (*self.descriptors.add(cur_index)).read.buffer_addr as *mut u64,
+                        packet.get_phys_addr() as u64,
+                    );
+                    ptr::write_volatile(
+                        &mut (*self.descriptors.add(cur_index)).read.cmd_type_len as *mut u32,
+                        eop | tso
+                            | vle
+                            | tstamp
+                            | IXGBE_ADVTXD_DCMD_RS
+                            | IXGBE_ADVTXD_DCMD_IFCS
+                            | IXGBE_ADVTXD_DCMD_DEXT
+                            | IXGBE_ADVTXD_DTYP_DATA
+                            | packet.len() as u32,
+                    );
+                    ptr::write_volatile(
+                        &mut (*self.descriptors.add(cur_index)).read.olinfo_status as *mut u32,
+                        olinfo_status,
+                    );
+                }
+
+                self.bufs_in_use.push_back(Some(packet));
+
+                cur_index = wrap_ring(cur_index, self.num_descriptors);
+            }
+
+            self.tx_index = cur_index;
+            sent += 1;
+        }
+
+        regs.set_reg32(IXGBE_TDT(u32::from(queue_id)), self.tx_index as u32);
+
+        sent
+    }
+}
+
+impl Registers {
+    /// Returns the register at `self.addr` + `reg`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `self.addr` + `reg` does not belong to the mapped memory of the pci device.
+    fn get_reg32(&self, reg: u32) -> u32 {
+        assert!(reg as usize <= self.len - 4, "memory access out of bounds");
+
+        #[cfg(test)]
+        {
+            if let Some(ref sim) = self.sim {
+                return sim.read_reg32(reg);
+            }
+        }
+
+        unsafe { ptr::read_volatile((self.addr as usize + reg as usize) as *mut u32) }
+    }
+
+    /// Sets the register at `self.addr` + `reg` to `value`.
+    ///
+    /// # Panics
     ///
     /// Panics if `self.addr` + `reg` does not belong to the mapped memory of the pci device.
     fn set_reg32(&self, reg: u32, value: u32) {
         assert!(reg as usize <= self.len - 4, "memory access out of bounds");
 
+        #[cfg(test)]
+        {
+            if let Some(ref sim) = self.sim {
+                return sim.write_reg32(reg, value);
+            }
+        }
+
         unsafe {
             ptr::write_volatile((self.addr as usize + reg as usize) as *mut u32, value);
         }
     }
+}
 
-    /// Sets the `flags` at `self.addr` + `reg`.
-    fn set_flags32(&self, reg: u32, flags: u32) {
-        self.set_reg32(reg, self.get_reg32(reg) | flags);
+/// Removes multiples of `batch` packets from `queue`.
+fn clean_tx_queue(queue: &mut IxgbeTxQueue, batch: usize) -> usize {
+    let mut clean_index = queue.clean_index;
+    let cur_index = queue.tx_index;
+
+    loop {
+        let mut cleanable = cur_index as i32 - clean_index as i32;
+
+        if cleanable < 0 {
+            cleanable += queue.num_descriptors as i32;
+        }
+
+        if cleanable < batch as i32 {
+            break;
+        }
+
+        // context descriptors are not written back, the batch is extended to the data
+        // descriptor that follows one
+        let num_cleaned = match queue.bufs_in_use.get(batch - 1) {
+            Some(None) => batch + 1,
+            _ => batch,
+        };
+
+        let mut cleanup_to = clean_index + num_cleaned - 1;
+
+        if cleanup_to >= queue.num_descriptors {
+            cleanup_to -= queue.num_descriptors;
+        }
+
+        let status = unsafe {
+            ptr::read_volatile(&(*queue.descriptors.add(cleanup_to)).wb.status as *const u32)
+        };
+
+        if (status & IXGBE_ADVTXD_STAT_DD) != 0 {
+            // dropping the packets returns their buffers to the pools they were allocated from
+            let num_cleaned = num_cleaned.min(queue.bufs_in_use.len());
+            free_pkt_batch(queue.bufs_in_use.drain(..num_cleaned).flatten());
+            queue.stats.clean_batches += 1;
+
+            clean_index = wrap_ring(cleanup_to, queue.num_descriptors);
+        } else {
+            break;
+        }
     }
 
-    /// Clears the `flags` at `self.addr` + `reg`.
-    fn clear_flags32(&self, reg: u32, flags: u32) {
-        self.set_reg32(reg, self.get_reg32(reg) & !flags);
+    queue.clean_index = clean_index;
+
+    clean_index
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::offload::{verify_checksums, vlan_tci};
+    use std::net::Ipv4Addr;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_init() {
+        let (dev, _sim) = IxgbeDevice::init_simulated(2, 2).unwrap();
+
+        assert_eq!(dev.get_mac_addr(), sim::MAC_ADDR);
+        assert_eq!(dev.get_link_speed(), 10000);
+        assert_ne!(dev.get_reg32(IXGBE_RXCTRL) & IXGBE_RXCTRL_RXEN, 0);
+
+        for i in 0..2 {
+            let queue = &dev.rx_queues[i as usize];
+            assert_eq!(queue.bufs_in_use.len(), dev.config.rx_ring_size);
+            assert_eq!(
+                dev.get_reg32(IXGBE_RDBAL(i)),
+                (queue.descriptors as usize & 0xffff_ffff) as u32
+            );
+            assert_eq!(
+                dev.get_reg32(IXGBE_RDLEN(i)) as usize,
+                dev.config.rx_ring_size * mem::size_of::<ixgbe_adv_rx_desc>()
+            );
+            assert_eq!(dev.get_reg32(IXGBE_RDH(i)), 0);
+            assert_eq!(
+                dev.get_reg32(IXGBE_RDT(i)) as usize,
+                dev.config.rx_ring_size - 1
+            );
+            assert_ne!(dev.get_reg32(IXGBE_RXDCTL(i)) & IXGBE_RXDCTL_ENABLE, 0);
+            assert_ne!(dev.get_reg32(IXGBE_TXDCTL(i)) & IXGBE_TXDCTL_ENABLE, 0);
+        }
     }
 
-    /// Waits for `self.addr` + `reg` to clear `value`.
-    fn wait_clear_reg32(&self, reg: u32, value: u32) {
-        loop {
-            let current = self.get_reg32(reg);
-            if (current & value) == 0 {
-                break;
-            }
-            thread::sleep(Duration::from_millis(100));
+    #[test]
+    fn test_rx() {
+        let (mut dev, sim) = IxgbeDevice::init_simulated(2, 1).unwrap();
+        let mut buffer = VecDeque::new();
+
+        for i in 0..10u8 {
+            assert!(sim.receive(1, &[i; 60]));
+        }
+
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 0);
+        assert_eq!(dev.rx_batch(1, &mut buffer, 4), 4);
+        assert_eq!(dev.rx_batch(1, &mut buffer, 32), 6);
+        assert_eq!(dev.rx_batch(1, &mut buffer, 32), 0);
+
+        for (i, p) in buffer.iter().enumerate() {
+            assert_eq!(&p[..], &[i as u8; 60][..]);
+        }
+        assert_eq!(dev.get_reg32(IXGBE_RDT(1)), 9);
+
+        let mut stats = DeviceStats::default();
+        dev.read_stats(&mut stats);
+        assert_eq!(stats.rx_pkts, 10);
+        assert_eq!(stats.rx_bytes, 640);
+    }
+
+    #[test]
+    fn test_rx_ring_full() {
+        let (mut dev, sim) = IxgbeDevice::init_simulated(1, 1).unwrap();
+        let mut buffer = VecDeque::new();
+
+        // the ring starts with all but one descriptor available to the device
+        for _ in 0..dev.config.rx_ring_size - 1 {
+            assert!(sim.receive(0, &[0; 60]));
+        }
+        assert!(!sim.receive(0, &[0; 60]));
+
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 32);
+        assert!(sim.receive(0, &[0; 60]));
+    }
+
+    #[test]
+    fn test_rx_oversized_frame_dropped() {
+        let (mut dev, sim) = IxgbeDevice::init_simulated(1, 1).unwrap();
+        let mut buffer = VecDeque::new();
+
+        assert_eq!(dev.get_reg32(IXGBE_HLREG0) & IXGBE_HLREG0_JUMBOEN, 0);
+
+        assert!(!sim.receive(0, &[1; 1600]));
+        assert!(sim.receive(0, &[2; 60]));
+
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 1);
+        assert_eq!(&buffer[0][..], &[2; 60][..]);
+        assert_eq!(dev.get_reg32(IXGBE_ROC), 1);
+    }
+
+    #[test]
+    fn test_stats_totals() {
+        let (mut dev, sim) = IxgbeDevice::init_simulated(1, 1).unwrap();
+        let mut buffer = VecDeque::new();
+
+        for _ in 0..3 {
+            assert!(sim.receive(0, &[0; 60]));
+        }
+
+        // readers don't take counts from each other
+        let mut monitor = DeviceStats::default();
+        let mut printer = DeviceStats::default();
+        dev.read_stats(&mut monitor);
+        dev.read_stats(&mut printer);
+        assert_eq!((monitor.rx_pkts, printer.rx_pkts), (3, 3));
+        assert_eq!((monitor.rx_bytes, printer.rx_bytes), (192, 192));
+
+        // the registers are read periodically while the queues are polled
+        assert!(sim.receive(0, &[0; 60]));
+        dev.stats.totals.lock().unwrap().updated = Instant::now() - 2 * STATS_UPDATE_INTERVAL;
+        for _ in 0..0x1000 {
+            dev.rx_batch(0, &mut buffer, 32);
+            buffer.clear();
         }
+        assert_eq!(dev.stats.totals.lock().unwrap().stats.rx_pkts, 4);
+        assert_eq!(dev.get_reg32(IXGBE_GPRC), 0);
+
+        dev.reset_stats();
+        dev.read_stats(&mut monitor);
+        assert_eq!(monitor.rx_pkts, 0);
     }
 
-    /// Waits for `self.addr` + `reg` to set `value`.
-    fn wait_set_reg32(&self, reg: u32, value: u32) {
-        loop {
-            let current = self.get_reg32(reg);
-            if (current & value) == value {
-                break;
-            }
-            thread::sleep(Duration::from_millis(100));
+    #[test]
+    fn test_extended_stats() {
+        let (mut dev, sim) = IxgbeDevice::init_simulated(2, 2).unwrap();
+        let pool = Mempool::allocate_anonymous(dev.config.tx_ring_size, 0).unwrap();
+        let mut buffer = VecDeque::new();
+
+        assert!(sim.receive(1, &[0xff; 60]));
+        assert!(sim.receive(1, &[0x01; 200]));
+        assert!(sim.receive(1, &[0x00; 1000]));
+        assert!(!sim.receive(1, &[0x00; 1600]));
+        for _ in 0..dev.config.rx_ring_size - 1 {
+            assert!(sim.receive(0, &[0; 60]));
         }
+        assert!(!sim.receive(0, &[0; 60]));
+
+        alloc_pkt_batch(&pool, &mut buffer, 3, 60);
+        assert_eq!(dev.tx_batch(1, &mut buffer), 3);
+
+        let mut stats = ExtendedStats::default();
+        dev.read_extended_stats(&mut stats);
+        assert_eq!((stats.rx_broadcast, stats.rx_multicast), (1, 1));
+        assert_eq!(stats.rx_oversize, 1);
+        assert_eq!(stats.rx_no_buffer, 1);
+        assert_eq!(stats.rx_dma_pkts, dev.config.rx_ring_size as u64 + 2);
+        assert_eq!(
+            stats.rx_sizes,
+            [dev.config.rx_ring_size as u64, 0, 1, 0, 1, 0]
+        );
+        assert_eq!(stats.tx_sizes, [3, 0, 0, 0, 0, 0]);
+        assert_eq!(stats.queues.len(), 2);
+        assert_eq!(stats.queues[0].rx_no_buffer, 1);
+        assert_eq!(
+            (stats.queues[1].rx_pkts, stats.queues[1].rx_bytes),
+            (3, 1272)
+        );
+        assert_eq!(
+            (stats.queues[1].tx_pkts, stats.queues[1].tx_bytes),
+            (3, 192)
+        );
+
+        // counters accumulate until they are reset
+        sim.receive(1, &[0xff; 60]);
+        dev.read_extended_stats(&mut stats);
+        assert_eq!(stats.rx_broadcast, 2);
+
+        sim.receive(1, &[0xff; 60]);
+        dev.reset_stats();
+        let mut stats = ExtendedStats::default();
+        dev.read_extended_stats(&mut stats);
+        assert_eq!(stats.rx_broadcast, 0);
+        assert_eq!(stats.queues[1].rx_pkts, 0);
     }
 
-    /// Maps interrupt causes to vectors by specifying the `direction` (0 for Rx, 1 for Tx),
-    /// the `queue` ID and the corresponding `misx_vector`.
-    fn set_ivar(&self, direction: u32, queue: u16, mut msix_vector: u32) {
-        let mut ivar: u32;
-        let index: u32;
-        msix_vector |= IXGBE_IVAR_ALLOC_VAL;
-        index = 16 * (u32::from(queue) & 1) + 8 * direction;
-        ivar = self.get_reg32(IXGBE_IVAR(u32::from(queue) >> 1));
-        ivar &= !(0xFF << index);
-        ivar |= msix_vector << index;
-        self.set_reg32(IXGBE_IVAR(u32::from(queue) >> 1), ivar);
+    #[test]
+    fn test_rx_jumbo_frame() {
+        let config = DeviceConfig::new().mtu(9000);
+        let (mut dev, sim) = IxgbeDevice::init_simulated_with_config(&config).unwrap();
+        let mut buffer = VecDeque::new();
+
+        assert_ne!(dev.get_reg32(IXGBE_HLREG0) & IXGBE_HLREG0_JUMBOEN, 0);
+        assert_eq!(dev.get_reg32(IXGBE_MAXFRS) >> IXGBE_MHADD_MFS_SHIFT, 9018);
+
+        let frame: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        assert!(sim.receive(0, &frame));
+        assert!(sim.receive(0, &[2; 60]));
+
+        // a packet counts once against the batch size regardless of its segments
+        assert_eq!(dev.rx_batch(0, &mut buffer, 1), 1);
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 1);
+
+        let p = &buffer[0];
+        assert_eq!(p.num_segments(), 3);
+        assert_eq!(p.total_len(), 5000);
+        let data: Vec<u8> = p.segments().flat_map(|s| s.iter().copied()).collect();
+        assert_eq!(data, frame);
+        assert_eq!(&buffer[1][..], &[2; 60][..]);
+        assert_eq!(dev.get_reg32(IXGBE_RDT(0)), 3);
     }
 
-    /// Clear all interrupt masks for all queues.
-    fn clear_interrupts(&self) {
-        // Clear interrupt mask
-        self.set_reg32(IXGBE_EIMC, IXGBE_IRQ_CLEAR_MASK);
-        self.get_reg32(IXGBE_EICR);
+    #[test]
+    fn test_rsc() {
+        let config = DeviceConfig::new().num_rx_queues(2).rsc(0b01);
+        let (mut dev, sim) = IxgbeDevice::init_simulated_with_config(&config).unwrap();
+        let mut buffer = VecDeque::new();
+
+        assert_eq!(
+            dev.get_reg32(IXGBE_RSCCTL(0)),
+            IXGBE_RSCCTL_RSCEN | IXGBE_RSCCTL_MAXDESC_16
+        );
+        assert_eq!(dev.get_reg32(IXGBE_RSCCTL(1)), 0);
+
+        let first: Vec<u8> = (0..5000).map(|i| i as u8).collect();
+        let second: Vec<u8> = (0..3000).map(|i| (i * 7) as u8).collect();
+        assert!(!sim.receive_coalesced(1, &[(&first, 4)]));
+        assert!(sim.receive_coalesced(0, &[(&first, 4), (&second, 3)]));
+        assert!(sim.receive(0, &[2; 60]));
+
+        // the descriptors of both frames are interleaved, the second one completes first
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 3);
+
+        let data =
+            |p: &Packet| -> Vec<u8> { p.segments().flat_map(|s| s.iter().copied()).collect() };
+        assert_eq!(buffer[0].num_segments(), 2);
+        assert_eq!(data(&buffer[0]), second);
+        assert_eq!(buffer[0].coalesced_segments(), Some(3));
+        assert_eq!(buffer[1].num_segments(), 3);
+        assert_eq!(data(&buffer[1]), first);
+        assert_eq!(buffer[1].coalesced_segments(), Some(4));
+        assert_eq!(&buffer[2][..], &[2; 60][..]);
+        assert_eq!(buffer[2].coalesced_segments(), None);
+        assert_eq!(dev.get_reg32(IXGBE_RDT(0)), 5);
     }
 
-    /// Clear interrupt for queue with `queue_id`.
-    fn clear_interrupt(&self, queue_id: u16) {
-        // Clear interrupt mask
-        self.set_reg32(IXGBE_EIMC, 1 << queue_id);
-        self.get_reg32(IXGBE_EICR);
+    #[test]
+    fn test_rss() {
+        let config = DeviceConfig::new()
+            .num_rx_queues(4)
+            .rss(RssConfig::new().hash_fields(RssHashFields::IPV4 | RssHashFields::IPV4_TCP));
+        let (mut dev, sim) = IxgbeDevice::init_simulated_with_config(&config).unwrap();
+        let mut buffer = VecDeque::new();
+
+        assert_eq!(
+            dev.get_reg32(IXGBE_MRQC),
+            IXGBE_MRQC_RSSEN | IXGBE_MRQC_RSS_FIELD_IPV4 | IXGBE_MRQC_RSS_FIELD_IPV4_TCP
+        );
+        assert_eq!(dev.get_reg32(IXGBE_RSSRK(0)), 0xda56_5a6d);
+        assert_eq!(dev.get_reg32(IXGBE_RETA(0)), 0x0302_0100);
+
+        // tcp segment from 66.9.149.187:2794 to 161.142.100.80:1766
+        let mut frame = vec![0; 60];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        frame[14] = 0x45;
+        frame[23] = 6;
+        frame[26..34].copy_from_slice(&[66, 9, 149, 187, 161, 142, 100, 80]);
+        frame[34..38].copy_from_slice(&[0x0a, 0xea, 0x06, 0xe6]);
+
+        // the hash is verified with the test vectors of the rss specification
+        let hash = 0x51cc_c178;
+        let queue = sim.receive_steered(&frame).unwrap();
+        assert_eq!(queue, (hash % 128 % 4) as u16);
+        assert_eq!(dev.rx_batch(queue, &mut buffer, 32), 1);
+        assert_eq!(buffer[0].rss_hash(), Some(hash));
+
+        // udp is only hashed over the addresses
+        frame[23] = 17;
+        let hash = 0x323e_8fc2;
+        let queue = sim.receive_steered(&frame).unwrap();
+        assert_eq!(queue, (hash % 128 % 4) as u16);
+        assert_eq!(dev.rx_batch(queue, &mut buffer, 32), 1);
+        assert_eq!(buffer[1].rss_hash(), Some(hash));
+
+        // frames that are not hashed go to queue 0
+        frame[12..14].copy_from_slice(&[0x08, 0x06]);
+        assert_eq!(sim.receive_steered(&frame), Some(0));
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 1);
+        assert_eq!(buffer[2].rss_hash(), None);
     }
 
-    /// Disable all interrupts for all queues.
-    fn disable_interrupts(&self) {
-        // Clear interrupt mask to stop from interrupts being generated
-        self.set_reg32(IXGBE_EIMS, 0x0000_0000);
-        self.clear_interrupts();
+    /// Returns a tcp segment from 10.0.0.2:`src_port` to `dst`:179.
+    fn bgp_frame(dst: [u8; 4], src_port: u16) -> Vec<u8> {
+        let mut frame = vec![0; 60];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        frame[14] = 0x45;
+        frame[23] = 6;
+        frame[26..30].copy_from_slice(&[10, 0, 0, 2]);
+        frame[30..34].copy_from_slice(&dst);
+        frame[34..36].copy_from_slice(&src_port.to_be_bytes());
+        frame[36..38].copy_from_slice(&179u16.to_be_bytes());
+        frame
     }
 
-    /// Disable interrupt for queue with `queue_id`.
-    fn disable_interrupt(&self, queue_id: u16) {
-        // Clear interrupt mask to stop from interrupts being generated
-        let mut mask: u32 = self.get_reg32(IXGBE_EIMS);
-        mask &= !(1 << queue_id);
-        self.set_reg32(IXGBE_EIMS, mask);
-        self.clear_interrupt(queue_id);
-        debug!("Using polling");
+    #[test]
+    fn test_fdir_perfect() {
+        let config = DeviceConfig::new()
+            .num_rx_queues(4)
+            .flow_director(FdirMode::Perfect);
+        let (mut dev, sim) = IxgbeDevice::init_simulated_with_config(&config).unwrap();
+        let mut buffer = VecDeque::new();
+
+        let bgp = FlowFilter::new(FlowProtocol::Tcp)
+            .dst_addr(Ipv4Addr::new(10, 0, 0, 1))
+            .dst_port(179);
+        let blocked = bgp.dst_addr(Ipv4Addr::new(10, 0, 0, 3));
+        let queue_id = dev.add_flow_filter(&bgp, FilterAction::Queue(3)).unwrap();
+        let drop_id = dev.add_flow_filter(&blocked, FilterAction::Drop).unwrap();
+        assert_ne!(queue_id, drop_id);
+
+        let invalid = [
+            (bgp, FilterAction::Queue(1)),
+            (bgp.src_port(1234), FilterAction::Queue(1)),
+            (bgp.dst_port(22), FilterAction::Queue(4)),
+            (
+                FlowFilter::new(FlowProtocol::Ip).dst_port(179),
+                FilterAction::Drop,
+            ),
+        ];
+        for (filter, action) in &invalid {
+            assert!(dev.add_flow_filter(filter, *action).is_err());
+        }
+
+        // the source port is not compared
+        assert_eq!(
+            sim.receive_steered(&bgp_frame([10, 0, 0, 1], 1000)),
+            Some(3)
+        );
+        assert_eq!(
+            sim.receive_steered(&bgp_frame([10, 0, 0, 1], 2000)),
+            Some(3)
+        );
+        assert_eq!(sim.receive_steered(&bgp_frame([10, 0, 0, 3], 1000)), None);
+        assert_eq!(
+            sim.receive_steered(&bgp_frame([10, 0, 0, 4], 1000)),
+            Some(0)
+        );
+
+        assert_eq!(dev.rx_batch(3, &mut buffer, 32), 2);
+        assert!(buffer.iter().all(|p| p.fdir_id() == Some(queue_id)));
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 1);
+        assert_eq!(buffer[2].fdir_id(), None);
+
+        let filters = dev.flow_filters();
+        assert_eq!(filters.len(), 2);
+        assert_eq!(filters[0].filter, bgp);
+        assert_eq!(filters[0].hits, Some(2));
+        assert_eq!(filters[1].action, FilterAction::Drop);
+        assert_eq!(filters[1].hits, None);
+
+        let mut stats = FdirStats::default();
+        dev.read_fdir_stats(&mut stats);
+        assert_eq!(stats.matched, 3);
+        assert_eq!(stats.missed, 1);
+
+        dev.remove_flow_filter(queue_id).unwrap();
+        assert!(dev.remove_flow_filter(queue_id).is_err());
+        assert_eq!(
+            sim.receive_steered(&bgp_frame([10, 0, 0, 1], 1000)),
+            Some(0)
+        );
+
+        // the compared fields can change once all filters are removed
+        dev.remove_flow_filter(drop_id).unwrap();
+        let ssh = FlowFilter::new(FlowProtocol::Tcp).dst_port(22);
+        assert_eq!(
+            dev.add_flow_filter(&ssh, FilterAction::Queue(2)).unwrap(),
+            0
+        );
     }
 
-    /// Enable MSI interrupt for queue with `queue_id`.
-    fn enable_msi_interrupt(&self, queue_id: u16) {
-        // Step 1: The software driver associates between Tx and Rx interrupt causes and the EICR
-        // register by setting the IVAR[n] registers.
-        self.set_ivar(0, queue_id, 0);
+    #[test]
+    fn test_fdir_signature() {
+        let config = DeviceConfig::new()
+            .num_rx_queues(2)
+            .flow_director(FdirMode::Signature);
+        let (mut dev, sim) = IxgbeDevice::init_simulated_with_config(&config).unwrap();
+
+        let bgp = FlowFilter::new(FlowProtocol::Tcp)
+            .dst_addr(Ipv4Addr::new(10, 0, 0, 1))
+            .dst_port(179);
+        assert!(dev.add_flow_filter(&bgp, FilterAction::Drop).is_err());
+        dev.add_flow_filter(&bgp, FilterAction::Queue(1)).unwrap();
+        assert_eq!(dev.flow_filters()[0].hits, None);
+
+        assert_eq!(
+            sim.receive_steered(&bgp_frame([10, 0, 0, 1], 1000)),
+            Some(1)
+        );
+        assert_eq!(
+            sim.receive_steered(&bgp_frame([10, 0, 0, 4], 1000)),
+            Some(0)
+        );
 
-        // Step 2: Program SRRCTL[n].RDMTS (per receive queue) if software uses the receive
-        // descriptor minimum threshold interrupt
-        // We don't use the minimum threshold interrupt
+        // flow director filters require it to be enabled
+        let (mut dev, _sim) = IxgbeDevice::init_simulated(1, 1).unwrap();
+        assert!(dev.add_flow_filter(&bgp, FilterAction::Queue(0)).is_err());
+    }
 
-        // Step 3: All interrupts should be set to 0b (no auto clear in the EIAC register). Following an
-        // interrupt, software might read the EICR register to check for the interrupt causes.
-        self.set_reg32(IXGBE_EIAC, 0x0000_0000);
+    #[test]
+    fn test_config() {
+        let config = DeviceConfig::new()
+            .rx_ring_size(64)
+            .tx_ring_size(128)
+            .buffer_size(4096)
+            .mtu(3000);
+        let (mut dev, sim) = IxgbeDevice::init_simulated_with_config(&config).unwrap();
+        let mut buffer = VecDeque::new();
+
+        assert_eq!(dev.get_reg32(IXGBE_RDT(0)), 63);
+        assert_eq!(dev.get_reg32(IXGBE_TDLEN(0)), 128 * 16);
+        assert_eq!(dev.rx_queues[0].pool.entry_size(), 4096);
+
+        assert!(sim.receive(0, &[1; 3000]));
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 1);
+        assert_eq!(buffer[0].len(), 3000);
+
+        let invalid = DeviceConfig::new().rx_ring_size(16384);
+        match IxgbeDevice::init_simulated_with_config(&invalid) {
+            Err(IxyError::InvalidConfig(_)) => {}
+            _ => panic!("ring size should be rejected"),
+        }
+    }
 
-        // Step 4: Set the auto mask in the EIAM register according to the preferred mode of operation.
-        // In our case we prefer to not auto-mask the interrupts
+    #[test]
+    fn test_tx() {
+        let (mut dev, sim) = IxgbeDevice::init_simulated(1, 2).unwrap();
+        let pool = Mempool::allocate_anonymous(2 * dev.config.tx_ring_size, 0).unwrap();
+        let mut buffer = VecDeque::new();
 
-        // Step 5: Set the interrupt throttling in EITR[n] and GPIE according to the preferred mode of operation.
-        self.set_reg32(IXGBE_EITR(u32::from(queue_id)), self.interrupts.itr_rate);
+        alloc_pkt_batch(&pool, &mut buffer, 4, 60);
+        for (i, p) in buffer.iter_mut().enumerate() {
+            p[0] = i as u8;
+        }
 
-        // Step 6: Software clears EICR by writing all ones to clear old interrupt causes
-        self.clear_interrupts();
+        assert_eq!(dev.tx_batch(1, &mut buffer), 4);
 
-        // Step 7: Software enables the required interrupt causes by setting the EIMS register
-        let mut mask: u32 = self.get_reg32(IXGBE_EIMS);
-        mask |= 1 << queue_id;
-        self.set_reg32(IXGBE_EIMS, mask);
-        debug!("Using MSI interrupts");
+        let frames = sim.transmitted(1);
+        assert!(sim.transmitted(0).is_empty());
+        assert_eq!(frames.len(), 4);
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.len(), 60);
+            assert_eq!(frame[0], i as u8);
+        }
+
+        let mut stats = DeviceStats::default();
+        dev.read_stats(&mut stats);
+        assert_eq!(stats.tx_pkts, 4);
+        assert_eq!(stats.tx_bytes, 256);
+
+        // sent buffers are returned to the pool in batches once the ring wraps around
+        for _ in 0..4 * dev.config.tx_ring_size / 32 {
+            alloc_pkt_batch(&pool, &mut buffer, 32, 60);
+            assert_eq!(dev.tx_batch(1, &mut buffer), 32);
+        }
+        assert!(pool.num_free_entries() >= dev.config.tx_ring_size);
+        assert_eq!(sim.transmitted(1).len(), 4 * dev.config.tx_ring_size);
     }
 
-    /// Enable MSI-X interrupt for queue with `queue_id`.
-    fn enable_msix_interrupt(&self, queue_id: u16) {
-        // Step 1: The software driver associates between interrupt causes and MSI-X vectors and the
-        //throttling timers EITR[n] by programming the IVAR[n] and IVAR_MISC registers.
-        let mut gpie: u32 = self.get_reg32(IXGBE_GPIE);
-        gpie |= IXGBE_GPIE_MSIX_MODE | IXGBE_GPIE_PBA_SUPPORT | IXGBE_GPIE_EIAME;
-        self.set_reg32(IXGBE_GPIE, gpie);
-        self.set_ivar(0, queue_id, u32::from(queue_id));
+    #[test]
+    fn test_queue_stats() {
+        // the mempool has no buffers left once the rx ring is filled and the cached ones are held
+        let config = DeviceConfig::new()
+            .rx_ring_size(64)
+            .tx_ring_size(64)
+            .mempool_size(64 + CACHE_SIZE);
+        let (mut dev, sim) = IxgbeDevice::init_simulated_with_config(&config).unwrap();
+        let pool = Mempool::allocate_anonymous(128, 0).unwrap();
+        let mut held = VecDeque::new();
+        let rx_pool = Arc::clone(&dev.rx_queues[0].pool);
+        assert_eq!(
+            alloc_pkt_batch(&rx_pool, &mut held, 2 * CACHE_SIZE, 60),
+            CACHE_SIZE
+        );
+        let mut buffer = VecDeque::new();
+
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 0);
+        assert!(sim.receive(0, &[0; 60]));
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 0);
+        assert_eq!(
+            dev.rx_queue_stats(0),
+            Some(RxQueueStats {
+                empty_polls: 2,
+                mempool_exhausted: 1,
+                ..Default::default()
+            })
+        );
 
-        // Step 2: Program SRRCTL[n].RDMTS (per receive queue) if software uses the receive
-        // descriptor minimum threshold interrupt
-        // We don't use the minimum threshold interrupt
+        // the first batch fills the ring, the second one cleans a batch to make room
+        alloc_pkt_batch(&pool, &mut buffer, 100, 60);
+        assert_eq!(dev.tx_batch(0, &mut buffer), 63);
+        assert_eq!(dev.tx_batch(0, &mut buffer), 32);
+        assert_eq!(
+            dev.tx_queue_stats(0),
+            Some(TxQueueStats {
+                pkts: 95,
+                bytes: 95 * 60,
+                ring_full: 2,
+                clean_batches: 1,
+                dropped: 0,
+            })
+        );
+        assert_eq!(dev.tx_queue_stats(1), None);
+    }
 
-        // Step 3: The EIAC[n] registers should be set to auto clear for transmit and receive interrupt
-        // causes (for best performance). The EIAC bits that control the other and TCP timer
-        // interrupt causes should be set to 0b (no auto clear).
-        self.set_reg32(IXGBE_EIAC, IXGBE_EIMS_RTX_QUEUE);
+    #[test]
+    fn test_tx_multi_segment() {
+        let (mut dev, sim) = IxgbeDevice::init_simulated(1, 1).unwrap();
+        let pool = Mempool::allocate_anonymous(2 * dev.config.tx_ring_size, 0).unwrap();
+        let mut buffer = VecDeque::new();
+
+        for i in 0..dev.config.tx_ring_size {
+            let mut header = alloc_pkt(&pool, 14).unwrap();
+            let mut payload = alloc_pkt(&pool, 1000).unwrap();
+            header[0] = i as u8;
+            payload[0] = 0xab;
+            header.push_segment(payload);
+            buffer.push_back(header);
+        }
 
-        // Step 4: Set the auto mask in the EIAM register according to the preferred mode of operation.
-        // In our case we prefer to not auto-mask the interrupts
+        // every frame needs two descriptors and one descriptor always stays empty
+        let sent = dev.tx_batch(0, &mut buffer);
+        assert_eq!(sent, dev.config.tx_ring_size / 2 - 1);
+        assert_eq!(buffer.len(), dev.config.tx_ring_size - sent);
+        assert_eq!(buffer[0].num_segments(), 2);
+
+        let frames = sim.transmitted(0);
+        assert_eq!(frames.len(), sent);
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.len(), 1014);
+            assert_eq!(frame[0], i as u8);
+            assert_eq!(frame[14], 0xab);
+        }
 
-        // Step 5: Set the interrupt throttling in EITR[n] and GPIE according to the preferred mode of operation.
-        // 0x000 (0us) => ... INT/s
-        // 0x008 (2us) => 488200 INT/s
-        // 0x010 (4us) => 244000 INT/s
-        // 0x028 (10us) => 97600 INT/s
-        // 0x0C8 (50us) => 20000 INT/s
-        // 0x190 (100us) => 9766 INT/s
-        // 0x320 (200us) => 4880 INT/s
-        // 0x4B0 (300us) => 3255 INT/s
-        // 0x640 (400us) => 2441 INT/s
-        // 0x7D0 (500us) => 2000 INT/s
-        // 0x960 (600us) => 1630 INT/s
-        // 0xAF0 (700us) => 1400 INT/s
-        // 0xC80 (800us) => 1220 INT/s
-        // 0xE10 (900us) => 1080 INT/s
-        // 0xFA7 (1000us) => 980 INT/s
-        // 0xFFF (1024us) => 950 INT/s
-        self.set_reg32(IXGBE_EITR(u32::from(queue_id)), self.interrupts.itr_rate);
+        // all segments are returned to the pool
+        buffer.clear();
+        for _ in 0..8 {
+            let mut header = alloc_pkt(&pool, 14).unwrap();
+            header.push_segment(alloc_pkt(&pool, 1000).unwrap());
+            buffer.push_back(header);
+            dev.tx_batch(0, &mut buffer);
+        }
+        dev.tx_batch(0, &mut buffer);
+        assert!(pool.num_free_entries() >= pool.num_entries() - dev.config.tx_ring_size);
+    }
 
-        // Step 6: Software enables the required interrupt causes by setting the EIMS register
-        let mut mask: u32 = self.get_reg32(IXGBE_EIMS);
-        mask |= 1 << queue_id;
-        self.set_reg32(IXGBE_EIMS, mask);
-        debug!("Using MSIX interrupts");
+    #[test]
+    fn test_tx_too_many_segments() {
+        let (mut dev, sim) = IxgbeDevice::init_simulated(1, 1).unwrap();
+        let mut buffer = VecDeque::new();
+
+        let packet = |pool: &Arc<Mempool>| {
+            let mut packet = alloc_pkt(pool, 10).unwrap();
+            for i in 1..MAX_TX_SEGMENTS + 10 {
+                let mut segment = alloc_pkt(pool, 10).unwrap();
+                segment[0] = i as u8;
+                packet.push_segment(segment);
+            }
+            packet
+        };
+
+        // the packet is copied into a single buffer of its pool
+        let pool = Mempool::allocate_anonymous(2 * (MAX_TX_SEGMENTS + 10), 0).unwrap();
+        buffer.push_back(packet(&pool));
+        assert_eq!(dev.tx_batch(0, &mut buffer), 1);
+
+        let frames = sim.transmitted(0);
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].len(), (MAX_TX_SEGMENTS + 10) * 10);
+        assert_eq!(frames[0][10 * MAX_TX_SEGMENTS], MAX_TX_SEGMENTS as u8);
+
+        // the packet is dropped if its pool has no buffer left for the copy
+        let pool = Mempool::allocate_anonymous(MAX_TX_SEGMENTS + 10, 0).unwrap();
+        buffer.push_back(packet(&pool));
+        assert_eq!(dev.tx_batch(0, &mut buffer), 0);
+        assert!(buffer.is_empty());
+        assert_eq!(dev.tx_queue_stats(0).unwrap().dropped, 1);
+        assert_eq!(pool.num_free_entries(), pool.num_entries());
     }
 
-    /// Enable MSI or MSI-X interrupt for queue with `queue_id` depending on which is supported (Prefer MSI-x).
-    fn enable_interrupt(&self, queue_id: u16) -> Result<(), Box<dyn Error>> {
-        if !self.interrupts.interrupts_enabled {
-            return Ok(());
+    #[test]
+    fn test_checksum_offload() {
+        let (mut dev, sim) = IxgbeDevice::init_simulated(1, 1).unwrap();
+        let pool = Mempool::allocate_anonymous(2 * dev.config.tx_ring_size, 0).unwrap();
+        let mut buffer = VecDeque::new();
+        let num_packets = 4 * dev.config.tx_ring_size;
+
+        // tcp and udp packets alternate, i.e. every packet needs a new context descriptor
+        for i in 0..num_packets {
+            let mut frame = bgp_frame([10, 0, 0, 1], i as u16);
+            let l4_type = if i % 2 == 0 {
+                L4Type::Tcp
+            } else {
+                frame[23] = 17;
+                L4Type::Udp
+            };
+
+            let mut p = alloc_pkt(&pool, frame.len()).unwrap();
+            p.copy_from_slice(&frame);
+            p.set_tx_offload(Some(
+                TxOffload::ipv4(14, 20).ip_checksum().l4_checksum(l4_type),
+            ));
+            buffer.push_back(p);
+
+            if buffer.len() == 32 {
+                dev.tx_batch(0, &mut buffer);
+            }
         }
-        match self.interrupts.interrupt_type {
-            VFIO_PCI_MSIX_IRQ_INDEX => self.enable_msix_interrupt(queue_id),
-            VFIO_PCI_MSI_IRQ_INDEX => self.enable_msi_interrupt(queue_id),
-            _ => {
-                return Err(format!(
-                    "interrupt type not supported: {}",
-                    self.interrupts.interrupt_type
-                )
-                .into());
+        for _ in 0..num_packets {
+            if buffer.is_empty() {
+                break;
             }
+            dev.tx_batch(0, &mut buffer);
         }
-        Ok(())
+        assert!(buffer.is_empty());
+        assert!(pool.num_free_entries() >= pool.num_entries() - dev.config.tx_ring_size);
+
+        let frames = sim.transmitted(0);
+        assert_eq!(frames.len(), num_packets);
+        for frame in &frames {
+            assert_eq!(
+                verify_checksums(frame),
+                (ChecksumStatus::Good, ChecksumStatus::Good)
+            );
+        }
+
+        // received checksums are validated
+        let mut corrupted = frames[1].clone();
+        corrupted[50] ^= 0xff;
+        let mut arp = vec![0; 60];
+        arp[12..14].copy_from_slice(&[0x08, 0x06]);
+
+        assert!(sim.receive(0, &frames[0]));
+        assert!(sim.receive(0, &corrupted));
+        assert!(sim.receive(0, &arp));
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 3);
+
+        let status = buffer
+            .iter()
+            .map(|p| (p.ip_checksum(), p.l4_checksum()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            status,
+            [
+                (ChecksumStatus::Good, ChecksumStatus::Good),
+                (ChecksumStatus::Good, ChecksumStatus::Bad),
+                (ChecksumStatus::Unknown, ChecksumStatus::Unknown),
+            ]
+        );
     }
 
-    /// Setup interrupts by enabling VFIO interrupts.
-    fn setup_interrupts(&mut self) -> Result<(), Box<dyn Error>> {
-        if !self.interrupts.interrupts_enabled {
-            self.interrupts.queues = Vec::with_capacity(0);
-            return Ok(());
+    #[test]
+    fn test_tso() {
+        let (mut dev, sim) = IxgbeDevice::init_simulated(1, 1).unwrap();
+        let pool = Mempool::allocate_anonymous(64, 0).unwrap();
+        let mut buffer = VecDeque::new();
+
+        // a tcp packet with the fin and psh flags and 3000 bytes of payload in two buffers
+        let mut header = alloc_pkt(&pool, 54).unwrap();
+        header.copy_from_slice(&bgp_frame([10, 0, 0, 1], 4242)[..54]);
+        header[38..42].copy_from_slice(&1000u32.to_be_bytes());
+        header[46] = 0x50;
+        header[47] = 0x19;
+        let mut payload = alloc_pkt(&pool, 2000).unwrap();
+        payload.copy_from_slice(&[0xab; 2000]);
+        header.push_segment(payload);
+        header.push_segment(alloc_pkt(&pool, 1000).unwrap());
+        header.set_tx_offload(Some(TxOffload::ipv4(14, 20).tso(1400, 20)));
+        buffer.push_back(header);
+
+        assert_eq!(dev.tx_batch(0, &mut buffer), 1);
+
+        let frames = sim.transmitted(0);
+        assert_eq!(
+            frames.iter().map(|frame| frame.len()).collect::<Vec<_>>(),
+            [1454, 1454, 254]
+        );
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(
+                verify_checksums(frame),
+                (ChecksumStatus::Good, ChecksumStatus::Good)
+            );
+            let seq = u32::from_be_bytes([frame[38], frame[39], frame[40], frame[41]]);
+            assert_eq!(seq, 1000 + 1400 * i as u32);
+            assert_eq!(frame[47], if i == 2 { 0x19 } else { 0x10 });
         }
-        self.interrupts.queues = Vec::with_capacity(self.num_rx_queues as usize);
-        self.interrupts.vfio_setup_interrupt(self.vfio_device_fd)?;
-        match self.interrupts.interrupt_type {
-            VFIO_PCI_MSIX_IRQ_INDEX => {
-                for rx_queue in 0..self.num_rx_queues {
-                    let mut queue = InterruptsQueue {
-                        vfio_event_fd: 0,
-                        vfio_epoll_fd: 0,
-                        last_time_checked: Instant::now(),
-                        rx_pkts: 0,
-                        moving_avg: Default::default(),
-                        interrupt_enabled: true,
-                        interval: INTERRUPT_INITIAL_INTERVAL,
-                        instr_counter: 0,
-                    };
-                    info!("enabling MSIX interrupts for queue {}", rx_queue);
-                    queue.vfio_enable_msix(self.vfio_device_fd, u32::from(rx_queue))?;
-                    queue.vfio_epoll_ctl(queue.vfio_event_fd)?;
-                    self.interrupts.queues.push(queue);
-                }
-            }
-            VFIO_PCI_MSI_IRQ_INDEX => {
-                for _rx_queue in 0..self.num_rx_queues {
-                    let mut queue = InterruptsQueue {
-                        vfio_event_fd: 0,
-                        vfio_epoll_fd: 0,
-                        last_time_checked: Instant::now(),
-                        rx_pkts: 0,
-                        moving_avg: Default::default(),
-                        interrupt_enabled: true,
-                        interval: INTERRUPT_INITIAL_INTERVAL,
-                        instr_counter: 0,
-                    };
-                    info!("enabling MSI interrupts for queue {}", _rx_queue);
-                    queue.vfio_enable_msi(self.vfio_device_fd)?;
-                    queue.vfio_epoll_ctl(queue.vfio_event_fd)?;
-                    self.interrupts.queues.push(queue);
-                }
+        assert_eq!((frames[1][54 + 599], frames[1][54 + 600]), (0xab, 0));
+
+        let mut stats = DeviceStats::default();
+        dev.read_stats(&mut stats);
+        assert_eq!(stats.tx_pkts, 3);
+    }
+
+    #[test]
+    fn test_vlan() {
+        let config = DeviceConfig::new().vlan_strip(true);
+        let (mut dev, sim) = IxgbeDevice::init_simulated_with_config(&config).unwrap();
+        let pool = Mempool::allocate_anonymous(64, 0).unwrap();
+        let mut buffer = VecDeque::new();
+
+        // the device inserts the tag in front of the offloaded headers
+        let frame = bgp_frame([10, 0, 0, 1], 179);
+        let mut p = alloc_pkt(&pool, frame.len()).unwrap();
+        p.copy_from_slice(&frame);
+        p.set_tx_offload(Some(
+            TxOffload::ipv4(14, 20)
+                .ip_checksum()
+                .l4_checksum(L4Type::Tcp),
+        ));
+        p.set_vlan_tci(Some(0x2005));
+        buffer.push_back(p);
+
+        let mut p = alloc_pkt(&pool, 60).unwrap();
+        p.copy_from_slice(&[1; 60]);
+        p.set_vlan_tci(Some(6));
+        buffer.push_back(p);
+
+        assert_eq!(dev.tx_batch(0, &mut buffer), 2);
+
+        let frames = sim.transmitted(0);
+        assert_eq!(frames.len(), 2);
+        assert_eq!(vlan_tci(&frames[0]), Some(0x2005));
+        let untagged = [&frames[0][..12], &frames[0][16..]].concat();
+        assert_eq!(
+            verify_checksums(&untagged),
+            (ChecksumStatus::Good, ChecksumStatus::Good)
+        );
+        assert_eq!(vlan_tci(&frames[1]), Some(6));
+        assert_eq!(frames[1].len(), 64);
+
+        // tags are stripped and only filtered vlans are accepted once a filter was added
+        assert!(sim.receive(0, &frames[1]));
+        dev.add_vlan_filter(5).unwrap();
+        assert_ne!(dev.get_reg32(IXGBE_VLNCTRL) & IXGBE_VLNCTRL_VFE, 0);
+        assert!(!sim.receive(0, &frames[1]));
+        assert!(sim.receive(0, &frames[0]));
+        assert!(sim.receive(0, &[2; 60]));
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 3);
+
+        assert_eq!(buffer[0].vlan_tci(), Some(6));
+        assert_eq!(&buffer[0][..], &[1; 60][..]);
+        assert_eq!(buffer[1].vlan_tci(), Some(0x2005));
+        assert_eq!(&buffer[1][..], &untagged[..]);
+        assert_eq!(buffer[2].vlan_tci(), None);
+
+        dev.remove_vlan_filter(5).unwrap();
+        assert_eq!(dev.get_reg32(IXGBE_VLNCTRL) & IXGBE_VLNCTRL_VFE, 0);
+        assert!(dev.remove_vlan_filter(5).is_err());
+        assert!(dev.add_vlan_filter(4096).is_err());
+    }
+
+    #[test]
+    fn test_mac_filters() {
+        let config = DeviceConfig::new().promiscuous(false);
+        let (mut dev, sim) = IxgbeDevice::init_simulated_with_config(&config).unwrap();
+        let mut buffer = VecDeque::new();
+
+        let frame_to = |dst: [u8; 6]| {
+            let mut frame = vec![0; 60];
+            frame[..6].copy_from_slice(&dst);
+            frame
+        };
+        let secondary = [0x02, 0, 0, 0, 0, 0x42];
+        let multicast = [0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb];
+        let other_multicast = [0x01, 0x00, 0x5e, 0x00, 0x00, 0x01];
+
+        assert!(sim.receive(0, &frame_to(sim::MAC_ADDR)));
+        assert!(sim.receive(0, &frame_to([0xff; 6])));
+        assert!(!sim.receive(0, &frame_to(secondary)));
+        assert!(!sim.receive(0, &frame_to(multicast)));
+
+        dev.add_mac_addr(secondary).unwrap();
+        dev.add_multicast_addr(multicast).unwrap();
+        assert!(sim.receive(0, &frame_to(secondary)));
+        assert!(sim.receive(0, &frame_to(multicast)));
+        assert!(!sim.receive(0, &frame_to(other_multicast)));
+
+        dev.set_all_multicast(true).unwrap();
+        assert!(sim.receive(0, &frame_to(other_multicast)));
+        dev.set_all_multicast(false).unwrap();
+
+        dev.remove_mac_addr(secondary).unwrap();
+        dev.remove_multicast_addr(multicast).unwrap();
+        assert!(!sim.receive(0, &frame_to(secondary)));
+        assert!(!sim.receive(0, &frame_to(multicast)));
+        assert!(dev.remove_mac_addr(secondary).is_err());
+        assert!(dev.add_mac_addr(multicast).is_err());
+        assert!(dev.add_multicast_addr(secondary).is_err());
+
+        dev.set_promiscuous(true).unwrap();
+        assert!(sim.receive(0, &frame_to(secondary)));
+        assert!(sim.receive(0, &frame_to(multicast)));
+
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 7);
+    }
+
+    #[test]
+    fn test_timestamps() {
+        let config = DeviceConfig::new().timestamping(true);
+        let (mut dev, sim) = IxgbeDevice::init_simulated_with_config(&config).unwrap();
+        let pool = Mempool::allocate_anonymous(64, 0).unwrap();
+        let mut buffer = VecDeque::new();
+
+        dev.set_clock(1_000_000).unwrap();
+        dev.adjust_clock(-1000).unwrap();
+        assert_eq!(dev.read_clock().unwrap(), 999_000);
+
+        // only one PTP event message is timestamped by the device until its timestamp is read,
+        // all other packets are timestamped by the driver
+        let mut sync = vec![0; 60];
+        sync[12..14].copy_from_slice(&[0x88, 0xf7]);
+        let start = timestamp::now();
+        assert!(sim.receive(0, &sync));
+        assert!(sim.receive(0, &sync));
+        assert!(sim.receive(0, &[0; 60]));
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 3);
+
+        assert_eq!(buffer[0].timestamp(), Some(Timestamp::Hardware(999_000)));
+        for p in buffer.iter().skip(1) {
+            match p.timestamp() {
+                Some(Timestamp::Software(nanos)) => assert!(nanos >= start),
+                other => panic!("expected a software timestamp, got {:?}", other),
             }
-            _ => {
-                return Err(format!(
-                    "interrupt type not supported: {}",
-                    self.interrupts.interrupt_type
-                )
-                .into());
+        }
+        buffer.clear();
+
+        let mut p = alloc_pkt(&pool, 60).unwrap();
+        p.set_tx_timestamp(true);
+        buffer.push_back(p);
+        assert_eq!(dev.read_tx_timestamp(), None);
+        assert_eq!(dev.tx_batch(0, &mut buffer), 1);
+        dev.adjust_clock(500).unwrap();
+        assert_eq!(dev.read_tx_timestamp(), Some(Timestamp::Hardware(999_000)));
+        assert_eq!(dev.read_tx_timestamp(), None);
+        assert_eq!(sim.clock(), 999_500);
+
+        let (mut dev, _sim) = IxgbeDevice::init_simulated(1, 1).unwrap();
+        assert!(dev.read_clock().is_err());
+        assert!(dev.adjust_clock(1).is_err());
+    }
+
+    #[test]
+    fn test_tx_multiple_pools() {
+        let (mut dev, sim) = IxgbeDevice::init_simulated(1, 1).unwrap();
+        let pools = [
+            Mempool::allocate_anonymous(dev.config.tx_ring_size, 0).unwrap(),
+            Mempool::allocate_anonymous(dev.config.tx_ring_size, 0).unwrap(),
+        ];
+        let mut buffer = VecDeque::new();
+
+        // forwarded packets and their headers may come from different pools
+        for i in 0..4 * dev.config.tx_ring_size {
+            let mut p = alloc_pkt(&pools[i % 2], 60).unwrap();
+            p[0] = i as u8;
+            if i % 3 == 0 {
+                p.push_segment(alloc_pkt(&pools[(i + 1) % 2], 60).unwrap());
             }
+            buffer.push_back(p);
+            dev.tx_batch(0, &mut buffer);
         }
-        Ok(())
+
+        let frames = sim.transmitted(0);
+        assert_eq!(frames.len(), 4 * dev.config.tx_ring_size);
+        assert_eq!(frames[2].len(), 60);
+        assert_eq!(frames[3].len(), 120);
+        assert_eq!(frames[3][0], 3);
+
+        let in_flight: usize = pools
+            .iter()
+            .map(|p| p.num_entries() - p.num_free_entries())
+            .sum();
+        assert!(in_flight < dev.config.tx_ring_size);
     }
-}
 
-/// Removes multiples of `TX_CLEAN_BATCH` packets from `queue`.
-fn clean_tx_queue(queue: &mut IxgbeTxQueue) -> usize {
-    let mut clean_index = queue.clean_index;
-    let cur_index = queue.tx_index;
+    #[test]
+    fn test_split_queues() {
+        let (dev, sim) = IxgbeDevice::init_simulated(2, 2).unwrap();
+        let (control, queues) = Box::new(dev).split_queues().unwrap();
+        assert_eq!(queues.len(), 2);
 
-    loop {
-        let mut cleanable = cur_index as i32 - clean_index as i32;
+        for queue_id in 0..2 {
+            for i in 0..10 {
+                assert!(sim.receive(queue_id, &[queue_id as u8, i, 0, 0]));
+            }
+        }
 
-        if cleanable < 0 {
-            cleanable += queue.num_descriptors as i32;
+        // each thread forwards the frames of its rx queue to its tx queue
+        let threads: Vec<_> = queues
+            .into_iter()
+            .map(|(mut rx, mut tx)| {
+                thread::spawn(move || {
+                    assert_eq!(rx.queue_id(), tx.queue_id());
+
+                    let mut buffer = VecDeque::new();
+                    let mut received = 0;
+                    while received < 10 {
+                        received += rx.rx_batch(&mut buffer, 4);
+                    }
+                    tx.tx_batch_busy_wait(&mut buffer);
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
         }
 
-        if cleanable < TX_CLEAN_BATCH as i32 {
-            break;
+        for queue_id in 0..2 {
+            let frames = sim.transmitted(queue_id);
+            assert_eq!(frames.len(), 10);
+            for (i, frame) in frames.iter().enumerate() {
+                assert_eq!(&frame[..], &[queue_id as u8, i as u8, 0, 0][..]);
+            }
         }
 
-        let mut cleanup_to = clean_index + TX_CLEAN_BATCH - 1;
+        let mut stats = DeviceStats::default();
+        control.read_stats(&mut stats);
+        assert_eq!(stats.rx_pkts, 20);
+        assert_eq!(stats.tx_pkts, 20);
+        assert_eq!(control.get_mac_addr(), sim::MAC_ADDR);
+    }
 
-        if cleanup_to >= queue.num_descriptors {
-            cleanup_to -= queue.num_descriptors;
+    #[test]
+    fn test_split_queues_poll_stats() {
+        let (dev, sim) = IxgbeDevice::init_simulated(1, 1).unwrap();
+        let totals = dev.stats.totals.clone();
+        let (control, mut queues) = Box::new(dev).split_queues().unwrap();
+        let (mut rx, _tx) = queues.pop().unwrap();
+        let mut buffer = VecDeque::new();
+
+        // the queue handles keep updating the totals while nobody reads them
+        assert!(sim.receive(0, &[0; 60]));
+        totals.lock().unwrap().updated = Instant::now() - 2 * STATS_UPDATE_INTERVAL;
+        for _ in 0..0x1000 {
+            rx.rx_batch(&mut buffer, 32);
+            buffer.clear();
         }
+        assert_eq!(totals.lock().unwrap().stats.rx_pkts, 1);
+        assert_eq!(sim.read_reg32(IXGBE_GPRC), 0);
 
-        let status = unsafe {
-            ptr::read_volatile(&(*queue.descriptors.add(cleanup_to)).wb.status as *const u32)
-        };
+        let mut stats = DeviceStats::default();
+        control.read_stats(&mut stats);
+        assert_eq!(stats.rx_pkts, 1);
+    }
 
-        if (status & IXGBE_ADVTXD_STAT_DD) != 0 {
-            if let Some(ref p) = queue.pool {
-                if TX_CLEAN_BATCH as usize >= queue.bufs_in_use.len() {
-                    p.free_stack
-                        .borrow_mut()
-                        .extend(queue.bufs_in_use.drain(..))
-                } else {
-                    p.free_stack
-                        .borrow_mut()
-                        .extend(queue.bufs_in_use.drain(..TX_CLEAN_BATCH))
+    #[test]
+    fn test_split_queues_packets_across_threads() {
+        let (mut dev, sim) = IxgbeDevice::init_simulated(1, 1).unwrap();
+        let mut held = VecDeque::new();
+
+        assert!(sim.receive(0, &[0; 60]));
+        assert_eq!(dev.rx_batch(0, &mut held, 32), 1);
+
+        // packets received before splitting stay usable while the queue runs on another thread
+        let (_control, mut queues) = Box::new(dev).split_queues().unwrap();
+        let (mut rx, _tx) = queues.pop().unwrap();
+
+        // the rx thread refills the ring while this thread frees the received packets
+        let (sender, receiver) = mpsc::channel();
+        let rx_thread = thread::spawn(move || {
+            let mut buffer = VecDeque::new();
+            let mut received = 0;
+            while received < 1000 {
+                received += rx.rx_batch(&mut buffer, 32);
+                for p in buffer.drain(..) {
+                    sender.send(p).unwrap();
                 }
             }
+        });
 
-            clean_index = wrap_ring(cleanup_to, queue.num_descriptors);
-        } else {
-            break;
+        for i in 0..1000 {
+            while !sim.receive(0, &[i as u8; 60]) {
+                thread::yield_now();
+            }
         }
-    }
 
-    queue.clean_index = clean_index;
+        for (i, p) in receiver.iter().enumerate() {
+            assert_eq!(&p[..], &[i as u8; 60][..]);
+        }
+        rx_thread.join().unwrap();
 
-    clean_index
+        assert_eq!(&held[0][..], &[0; 60][..]);
+        held.clear();
+    }
 }
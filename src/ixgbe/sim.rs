@@ -0,0 +1,924 @@
+//! A small behavioural model of the 82599 for unit tests.
+//!
+//! The simulator owns an anonymous memory region that is used as the device's BAR0. All register
+//! accesses of an [`IxgbeDevice`] created by [`IxgbeDevice::init_simulated`] go through the
+//! simulator which applies the side effects the driver relies on: reset bits clear themselves,
+//! statistics registers are cleared on read and writes to the tx tail registers run a DMA engine
+//! that processes the descriptor ring. Descriptor rings and packet buffers are plain anonymous
+//! memory in tests, i.e. their physical address is their virtual address.
+
+use std::net::Ipv4Addr;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+
+use super::{mta_vector, IxgbeDevice, MAX_QUEUES, RAR_ENTRIES, RX_SIZE_COUNTERS, TX_SIZE_COUNTERS};
+use crate::constants::*;
+use crate::error::IxyError;
+use crate::fdir::{
+    fdir_hash, FlowFilter, FlowMask, FlowProtocol, FDIR_BUCKET_HASH_KEY, FDIR_SIGNATURE_HASH_KEY,
+};
+use crate::memory::Dma;
+use crate::offload::{verify_checksums, vlan_tci, ChecksumStatus, L4Type, TxOffload};
+use crate::rss::{toeplitz_hash, RssConfig, RssHashFields, RETA_SIZE, RSS_KEY_SIZE};
+use crate::DeviceConfig;
+
+const BAR_SIZE: usize = 512 * 1024;
+
+pub(super) const MAC_ADDR: [u8; 6] = [0x00, 0x1b, 0x21, 0x00, 0x00, 0x01];
+
+// hashed fields and reported rss type of each packet type
+const RSS_FIELDS: [(u32, RssHashFields, u32); 6] = [
+    (
+        IXGBE_MRQC_RSS_FIELD_IPV4,
+        RssHashFields::IPV4,
+        IXGBE_RXDADV_RSSTYPE_IPV4,
+    ),
+    (
+        IXGBE_MRQC_RSS_FIELD_IPV4_TCP,
+        RssHashFields::IPV4_TCP,
+        IXGBE_RXDADV_RSSTYPE_IPV4_TCP,
+    ),
+    (
+        IXGBE_MRQC_RSS_FIELD_IPV4_UDP,
+        RssHashFields::IPV4_UDP,
+        IXGBE_RXDADV_RSSTYPE_IPV4_UDP,
+    ),
+    (
+        IXGBE_MRQC_RSS_FIELD_IPV6,
+        RssHashFields::IPV6,
+        IXGBE_RXDADV_RSSTYPE_IPV6,
+    ),
+    (
+        IXGBE_MRQC_RSS_FIELD_IPV6_TCP,
+        RssHashFields::IPV6_TCP,
+        IXGBE_RXDADV_RSSTYPE_IPV6_TCP,
+    ),
+    (
+        IXGBE_MRQC_RSS_FIELD_IPV6_UDP,
+        RssHashFields::IPV6_UDP,
+        IXGBE_RXDADV_RSSTYPE_IPV6_UDP,
+    ),
+];
+
+pub(super) struct Simulator {
+    addr: *mut u8,
+    state: Mutex<State>,
+}
+
+// the register memory is only accessed while holding the state lock
+unsafe impl Send for Simulator {}
+unsafe impl Sync for Simulator {}
+
+struct State {
+    // frames sent per tx queue
+    transmitted: Vec<Vec<Vec<u8>>>,
+    // partial frames of tx queues whose last descriptor was not an end of packet
+    pending: Vec<Vec<u8>>,
+    // offloads requested by the first descriptor of the pending frames
+    pending_offload: Vec<Option<TxOffload>>,
+    // vlan tags to insert into the pending frames
+    pending_vlan: Vec<Option<u16>>,
+    // fields vlan_macip_lens, type_tucmd_mlhl and mss_l4len_idx of the last context descriptor
+    // of tx queues
+    contexts: Vec<(u32, u32, u32)>,
+    // flow director filters
+    fdir: Vec<FdirFilter>,
+}
+
+struct FdirFilter {
+    // value of FDIRHASH, i.e. the bucket hash and software index or signature
+    hash: u32,
+    fdircmd: u32,
+    // the compared fields of perfect match filters
+    flow: Option<FlowFilter>,
+}
+
+impl State {
+    fn new() -> State {
+        State {
+            transmitted: vec![Vec::new(); MAX_QUEUES as usize],
+            pending: vec![Vec::new(); MAX_QUEUES as usize],
+            pending_offload: vec![None; MAX_QUEUES as usize],
+            pending_vlan: vec![None; MAX_QUEUES as usize],
+            contexts: vec![(0, 0, 0); MAX_QUEUES as usize],
+            fdir: Vec::new(),
+        }
+    }
+}
+
+impl IxgbeDevice {
+    /// Returns an initialized `IxgbeDevice` with the default configuration backed by a
+    /// [`Simulator`].
+    pub(super) fn init_simulated(
+        num_rx_queues: u16,
+        num_tx_queues: u16,
+    ) -> Result<(IxgbeDevice, Arc<Simulator>), IxyError> {
+        let config = DeviceConfig::new()
+            .num_rx_queues(num_rx_queues)
+            .num_tx_queues(num_tx_queues);
+
+        IxgbeDevice::init_simulated_with_config(&config)
+    }
+
+    /// Returns an initialized `IxgbeDevice` backed by a [`Simulator`].
+    pub(super) fn init_simulated_with_config(
+        config: &DeviceConfig,
+    ) -> Result<(IxgbeDevice, Arc<Simulator>), IxyError> {
+        config.validate(&super::LIMITS)?;
+
+        let sim = Arc::new(Simulator::new()?);
+
+        let mut dev = IxgbeDevice::new("simulated", sim.addr, BAR_SIZE, config, false, -1);
+        dev.regs.sim = Some(Arc::clone(&sim));
+        dev.reset_and_init("simulated")?;
+
+        Ok((dev, sim))
+    }
+}
+
+impl Simulator {
+    fn new() -> Result<Simulator, IxyError> {
+        let bar: Dma<u8> = Dma::allocate_anonymous(BAR_SIZE)?;
+
+        let sim = Simulator {
+            addr: bar.virt,
+            state: Mutex::new(State::new()),
+        };
+        sim.reset();
+
+        Ok(sim)
+    }
+
+    /// Returns the value of register `reg`, clearing it if it is a statistics register.
+    pub(super) fn read_reg32(&self, reg: u32) -> u32 {
+        let _state = self.state.lock().unwrap();
+
+        let value = self.get(reg);
+        if is_clear_on_read(reg) {
+            self.set(reg, 0);
+        }
+
+        // reading the high half of a latched timestamp unlocks it
+        match reg {
+            IXGBE_RXSTMPH => self.set(
+                IXGBE_TSYNCRXCTL,
+                self.get(IXGBE_TSYNCRXCTL) & !IXGBE_TSYNCRXCTL_VALID,
+            ),
+            IXGBE_TXSTMPH => self.set(
+                IXGBE_TSYNCTXCTL,
+                self.get(IXGBE_TSYNCTXCTL) & !IXGBE_TSYNCTXCTL_VALID,
+            ),
+            _ => {}
+        }
+
+        value
+    }
+
+    /// Sets register `reg` to `value` and applies the side effects of the write.
+    pub(super) fn write_reg32(&self, reg: u32, value: u32) {
+        let mut state = self.state.lock().unwrap();
+
+        if reg == IXGBE_CTRL && value & IXGBE_CTRL_RST_MASK != 0 {
+            self.reset();
+            *state = State::new();
+            self.set(reg, value & !IXGBE_CTRL_RST_MASK);
+            return;
+        }
+
+        self.set(reg, value);
+
+        match reg {
+            IXGBE_FDIRCTRL => self.set(reg, value | IXGBE_FDIRCTRL_INIT_DONE),
+            IXGBE_FDIRCMD => self.process_fdir_command(&mut state, value),
+            IXGBE_TIMADJH => {
+                let offset =
+                    u64::from(value & !(1 << 31)) << 32 | u64::from(self.get(IXGBE_TIMADJL));
+                let systim = if value & (1 << 31) != 0 {
+                    self.clock().wrapping_sub(offset)
+                } else {
+                    self.clock().wrapping_add(offset)
+                };
+                self.set(IXGBE_SYSTIML, systim as u32);
+                self.set(IXGBE_SYSTIMH, (systim >> 32) as u32);
+            }
+            _ => {}
+        }
+
+        if let Some(queue) = (0..u32::from(MAX_QUEUES)).find(|&i| IXGBE_TDT(i) == reg) {
+            self.process_tx(&mut state, queue);
+        }
+    }
+
+    /// Returns the value of the IEEE 1588 clock, which does not advance in the simulator.
+    pub(super) fn clock(&self) -> u64 {
+        u64::from(self.get(IXGBE_SYSTIMH)) << 32 | u64::from(self.get(IXGBE_SYSTIML))
+    }
+
+    /// Latches the clock into the timestamp registers `low` and `high` unless the timestamp
+    /// valid bit of `tsyncctl` is still set, returns whether the timestamp was taken.
+    fn latch_timestamp(&self, tsyncctl: u32, low: u32, high: u32) -> bool {
+        let ctl = self.get(tsyncctl);
+        // the valid bits of TSYNCRXCTL and TSYNCTXCTL are the same
+        if ctl & IXGBE_TSYNCRXCTL_ENABLED == 0 || ctl & IXGBE_TSYNCRXCTL_VALID != 0 {
+            return false;
+        }
+
+        let clock = self.clock();
+        self.set(low, clock as u32);
+        self.set(high, (clock >> 32) as u32);
+        self.set(tsyncctl, ctl | IXGBE_TSYNCRXCTL_VALID);
+
+        true
+    }
+
+    /// Returns and removes all frames sent on tx queue `queue_id`.
+    pub(super) fn transmitted(&self, queue_id: u16) -> Vec<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        state.transmitted[queue_id as usize].drain(..).collect()
+    }
+
+    /// Receives `frame` on the rx queue selected by the flow director or rss, returns the queue
+    /// or [`None`] if the frame was dropped.
+    ///
+    /// Frames are received on queue 0 if they match no filter and rss is disabled or the frame
+    /// is not hashed.
+    pub(super) fn receive_steered(&self, frame: &[u8]) -> Option<u16> {
+        let fdir = {
+            let state = self.state.lock().unwrap();
+            self.fdir_lookup(&state, frame)
+        };
+
+        let queue_id = match (fdir, self.rss_hash(frame)) {
+            (Some((fdircmd, _)), _) if fdircmd & IXGBE_FDIRCMD_DROP != 0 => return None,
+            (Some((fdircmd, _)), _) => ((fdircmd >> IXGBE_FDIRCMD_RX_QUEUE_SHIFT) & 0x7f) as u16,
+            (None, Some((_, hash))) => {
+                let entry = hash as usize % RETA_SIZE;
+                let reta = self.get(IXGBE_RETA(entry as u32 / 4));
+                ((reta >> (8 * (entry % 4))) & 0xf) as u16
+            }
+            (None, None) => 0,
+        };
+
+        // the descriptor reports the software index or signature and the bucket hash
+        let fdir_id = fdir.map(|(_, hash)| (hash >> 16) | (hash & 0x7fff) << 16);
+
+        if self.deliver(queue_id, frame, fdir_id) {
+            Some(queue_id)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the FDIRCMD and FDIRHASH values of the flow director filter matching `frame`.
+    fn fdir_lookup(&self, state: &State, frame: &[u8]) -> Option<(u32, u32)> {
+        let fdirctrl = self.get(IXGBE_FDIRCTRL);
+        if fdirctrl & IXGBE_FDIRCTRL_INIT_DONE == 0 {
+            return None;
+        }
+
+        let found = FlowFilter::from_frame(frame).and_then(|flow| {
+            let flow = flow.masked(self.fdir_mask());
+            let input = flow.hash_input();
+            let bucket = fdir_hash(&input, FDIR_BUCKET_HASH_KEY);
+
+            let filter = if fdirctrl & IXGBE_FDIRCTRL_PERFECT_MATCH != 0 {
+                state
+                    .fdir
+                    .iter()
+                    .find(|f| f.hash & 0x1fff == bucket & 0x1fff && f.flow == Some(flow))
+            } else {
+                let signature = fdir_hash(&input, FDIR_SIGNATURE_HASH_KEY);
+                let hash = bucket & 0x7fff | (signature & 0x7fff) << 16;
+                state.fdir.iter().find(|f| {
+                    f.hash == hash
+                        && FlowProtocol::from_flow_type(f.fdircmd >> IXGBE_FDIRCMD_FLOW_TYPE_SHIFT)
+                            == flow.protocol
+                })
+            };
+
+            filter.map(|f| (f.fdircmd, f.hash))
+        });
+
+        if found.is_some() {
+            self.add(IXGBE_FDIRMATCH, 1);
+        } else {
+            self.add(IXGBE_FDIRMISS, 1);
+        }
+
+        found
+    }
+
+    /// Returns the fields compared by the flow director.
+    fn fdir_mask(&self) -> FlowMask {
+        let ports = self.get(IXGBE_FDIRTCPM);
+
+        FlowMask {
+            src_addr: self.get(IXGBE_FDIRSIP4M) == 0,
+            dst_addr: self.get(IXGBE_FDIRDIP4M) == 0,
+            src_port: ports & 0xffff == 0,
+            dst_port: ports >> 16 == 0,
+            vlan: self.get(IXGBE_FDIRM) & IXGBE_FDIRM_VLANID == 0,
+        }
+    }
+
+    /// Adds, queries or removes the flow director filter in FDIRHASH.
+    fn process_fdir_command(&self, state: &mut State, fdircmd: u32) {
+        let hash = self.get(IXGBE_FDIRHASH);
+        let existing = state.fdir.iter().position(|f| f.hash == hash);
+        let mut status = fdircmd & !IXGBE_FDIRCMD_CMD_MASK;
+
+        match fdircmd & IXGBE_FDIRCMD_CMD_MASK {
+            IXGBE_FDIRCMD_CMD_ADD_FLOW => {
+                let mask = self.fdir_mask();
+                let port = self.get(IXGBE_FDIRPORT);
+                let protocol =
+                    FlowProtocol::from_flow_type(fdircmd >> IXGBE_FDIRCMD_FLOW_TYPE_SHIFT);
+
+                let flow = FlowFilter {
+                    protocol,
+                    src_addr: Some(Ipv4Addr::from(self.get(IXGBE_FDIRIPSA))),
+                    dst_addr: Some(Ipv4Addr::from(self.get(IXGBE_FDIRIPDA))),
+                    src_port: Some(port as u16),
+                    dst_port: Some((port >> IXGBE_FDIRPORT_DESTINATION_SHIFT) as u16),
+                    vlan: Some(self.get(IXGBE_FDIRVLAN) as u16 & 0xfff),
+                };
+                let perfect = self.get(IXGBE_FDIRCTRL) & IXGBE_FDIRCTRL_PERFECT_MATCH != 0;
+
+                if let Some(i) = existing {
+                    state.fdir.remove(i);
+                }
+                state.fdir.push(FdirFilter {
+                    hash,
+                    fdircmd,
+                    flow: if perfect {
+                        Some(flow.masked(mask))
+                    } else {
+                        None
+                    },
+                });
+            }
+            IXGBE_FDIRCMD_CMD_REMOVE_FLOW => {
+                if let Some(i) = existing {
+                    state.fdir.remove(i);
+                }
+            }
+            IXGBE_FDIRCMD_CMD_QUERY_REM_FILT if existing.is_some() => {
+                status |= IXGBE_FDIRCMD_FILTER_VALID;
+            }
+            _ => {}
+        }
+
+        self.set(IXGBE_FDIRCMD, status);
+    }
+
+    /// Returns the rss type and hash of `frame` if rss is enabled and the frame is hashed.
+    fn rss_hash(&self, frame: &[u8]) -> Option<(u32, u32)> {
+        let mrqc = self.get(IXGBE_MRQC);
+        if mrqc & IXGBE_MRQC_MRQE_MASK != IXGBE_MRQC_RSSEN {
+            return None;
+        }
+
+        let mut key = [0; RSS_KEY_SIZE];
+        for (i, bytes) in key.chunks_mut(4).enumerate() {
+            bytes.copy_from_slice(&self.get(IXGBE_RSSRK(i as u32)).to_le_bytes());
+        }
+
+        let fields = RSS_FIELDS
+            .iter()
+            .filter(|(bits, _, _)| mrqc & bits != 0)
+            .fold(RssHashFields::NONE, |fields, &(_, field, _)| fields | field);
+
+        let (field, input) = RssConfig::new()
+            .key(key)
+            .hash_fields(fields)
+            .hash_input(frame)?;
+        let rss_type = RSS_FIELDS.iter().find(|(_, f, _)| *f == field)?.2;
+
+        Some((rss_type, toeplitz_hash(&key, &input)))
+    }
+
+    /// Receives `frame` on rx queue `queue_id`, returns `false` if the frame was dropped.
+    ///
+    /// Frames larger than the queue's buffer size are spread over multiple descriptors.
+    pub(super) fn receive(&self, queue_id: u16, frame: &[u8]) -> bool {
+        self.deliver(queue_id, frame, None)
+    }
+
+    /// Receives `frame` on rx queue `queue_id`, reporting `fdir_id` if it matched a flow
+    /// director filter.
+    /// Returns whether the destination address of `frame` passes the receive address filters.
+    fn accepts_addr(&self, frame: &[u8]) -> bool {
+        let fctrl = self.get(IXGBE_FCTRL);
+        // some tests send frames shorter than an ethernet header
+        let mut dst = [0; 6];
+        match frame.get(..6) {
+            Some(addr) => dst.copy_from_slice(addr),
+            None => return fctrl & IXGBE_FCTRL_UPE != 0,
+        }
+
+        if dst == [0xff; 6] {
+            return fctrl & IXGBE_FCTRL_BAM != 0;
+        }
+
+        if dst[0] & 1 != 0 {
+            let vector = mta_vector(dst);
+            return fctrl & IXGBE_FCTRL_MPE != 0
+                || self.get(IXGBE_MCSTCTRL) & IXGBE_MCSTCTRL_MFE != 0
+                    && self.get(IXGBE_MTA(vector >> 5)) & (1 << (vector & 0x1f)) != 0;
+        }
+
+        let (low, high) = super::receive_address(dst);
+        fctrl & IXGBE_FCTRL_UPE != 0
+            || (0..RAR_ENTRIES).any(|i| {
+                self.get(IXGBE_RAH(i)) == high | IXGBE_RAH_AV && self.get(IXGBE_RAL(i)) == low
+            })
+    }
+
+    fn deliver(&self, queue_id: u16, frame: &[u8], fdir_id: Option<u32>) -> bool {
+        let _state = self.state.lock().unwrap();
+        let q = u32::from(queue_id);
+
+        if self.get(IXGBE_RXCTRL) & IXGBE_RXCTRL_RXEN == 0
+            || self.get(IXGBE_RXDCTL(q)) & IXGBE_RXDCTL_ENABLE == 0
+        {
+            return false;
+        }
+
+        // the max frame size includes the crc, frames above 1518 bytes require jumbo frames
+        let max_frame_size = if self.get(IXGBE_HLREG0) & IXGBE_HLREG0_JUMBOEN != 0 {
+            self.get(IXGBE_MAXFRS) >> IXGBE_MHADD_MFS_SHIFT
+        } else {
+            1518
+        };
+
+        if frame.len() as u32 + 4 > max_frame_size {
+            self.add(IXGBE_ROC, 1);
+            return false;
+        }
+
+        if !self.accepts_addr(frame) {
+            return false;
+        }
+
+        // tagged frames need a vlan filter once vlan filtering is enabled
+        let tci = vlan_tci(frame);
+        if let Some(tci) = tci {
+            let vlan_id = u32::from(tci & 0xfff);
+            if self.get(IXGBE_VLNCTRL) & IXGBE_VLNCTRL_VFE != 0
+                && self.get(IXGBE_VFTA(vlan_id / 32)) & (1 << (vlan_id % 32)) == 0
+            {
+                return false;
+            }
+        }
+
+        let stripped;
+        let (frame, vlan_status, vlan) = match tci {
+            Some(tci) if self.get(IXGBE_RXDCTL(q)) & IXGBE_RXDCTL_VME != 0 => {
+                stripped = [&frame[..12], &frame[16..]].concat();
+                (&stripped[..], IXGBE_RXDADV_STAT_VP, tci)
+            }
+            _ => (frame, 0, 0),
+        };
+
+        let (rss_type, rss_hash) = match fdir_id {
+            Some(fdir_id) => (IXGBE_RXDADV_RSSTYPE_NONE, fdir_id),
+            None => self
+                .rss_hash(frame)
+                .unwrap_or((IXGBE_RXDADV_RSSTYPE_NONE, 0)),
+        };
+        let fdir_status = if fdir_id.is_some() {
+            IXGBE_RXDADV_STAT_FLM
+        } else {
+            0
+        };
+
+        let checksum_status = rx_checksum_status(frame);
+        let (ring, ring_size, buf_size) = self.rx_ring(q);
+
+        // check if there are enough descriptors for the whole frame
+        let needed = frame.len().div_ceil(buf_size).max(1) as u32;
+        let head = self.get(IXGBE_RDH(q));
+        let tail = self.get(IXGBE_RDT(q));
+        let available = (tail + ring_size - head) % ring_size;
+
+        if available < needed {
+            self.add(IXGBE_QPRDC(q), 1);
+            return false;
+        }
+
+        let ts_status = if is_ptp_event(frame)
+            && self.latch_timestamp(IXGBE_TSYNCRXCTL, IXGBE_RXSTMPL, IXGBE_RXSTMPH)
+        {
+            IXGBE_RXDADV_STAT_TS
+        } else {
+            0
+        };
+
+        let mut head = head;
+        for (i, segment) in frame.chunks(buf_size).enumerate() {
+            let eop = i as u32 == needed - 1;
+
+            let status = fdir_status
+                | if eop {
+                    IXGBE_RXDADV_STAT_EOP | checksum_status | vlan_status | ts_status
+                } else {
+                    0
+                };
+            unsafe {
+                write_rx_desc(
+                    ring.add(head as usize),
+                    segment,
+                    rss_type,
+                    rss_hash,
+                    status,
+                    vlan,
+                );
+            }
+
+            head = (head + 1) % ring_size;
+        }
+        self.set(IXGBE_RDH(q), head);
+
+        self.count_rx(q, frame);
+
+        true
+    }
+
+    /// Receives `frames` on rx queue `queue_id` as if the device had coalesced the given number
+    /// of tcp segments into each of them, returns `false` if they were dropped.
+    ///
+    /// The descriptors of the frames are interleaved and linked by their next descriptor
+    /// pointers. Each descriptor reports at least one of the coalesced segments.
+    pub(super) fn receive_coalesced(&self, queue_id: u16, frames: &[(&[u8], u32)]) -> bool {
+        let _state = self.state.lock().unwrap();
+        let q = u32::from(queue_id);
+
+        if self.get(IXGBE_RXCTRL) & IXGBE_RXCTRL_RXEN == 0
+            || self.get(IXGBE_RXDCTL(q)) & IXGBE_RXDCTL_ENABLE == 0
+            || self.get(IXGBE_RSCCTL(q)) & IXGBE_RSCCTL_RSCEN == 0
+        {
+            return false;
+        }
+
+        let (ring, ring_size, buf_size) = self.rx_ring(q);
+
+        let mut segments: Vec<_> = frames
+            .iter()
+            .map(|(frame, _)| frame.chunks(buf_size).collect::<Vec<_>>())
+            .collect();
+        let needed = segments.iter().map(Vec::len).sum::<usize>() as u32;
+        let head = self.get(IXGBE_RDH(q));
+        let tail = self.get(IXGBE_RDT(q));
+
+        if (tail + ring_size - head) % ring_size < needed {
+            self.add(IXGBE_QPRDC(q), 1);
+            return false;
+        }
+
+        // assign the descriptors round robin to the frames that have segments left
+        let mut descriptors = vec![Vec::new(); frames.len()];
+        let mut index = head;
+        while index != (head + needed) % ring_size {
+            for (frame_descriptors, frame_segments) in descriptors.iter_mut().zip(&segments) {
+                if frame_descriptors.len() < frame_segments.len() {
+                    frame_descriptors.push(index);
+                    index = (index + 1) % ring_size;
+                }
+            }
+        }
+
+        for (i, (frame, count)) in frames.iter().enumerate() {
+            let (rss_type, rss_hash) = self
+                .rss_hash(frame)
+                .unwrap_or((IXGBE_RXDADV_RSSTYPE_NONE, 0));
+            let checksum_status = rx_checksum_status(frame);
+            let num_segments = segments[i].len() as u32;
+            assert!(*count >= num_segments, "too few segments coalesced");
+
+            for (j, segment) in segments[i].drain(..).enumerate() {
+                let rsc_count = if j == 0 {
+                    count - (num_segments - 1)
+                } else {
+                    1
+                };
+                let status = match descriptors[i].get(j + 1) {
+                    Some(next) => next << IXGBE_RXDADV_NEXTP_SHIFT,
+                    None => IXGBE_RXDADV_STAT_EOP | checksum_status,
+                };
+                unsafe {
+                    write_rx_desc(
+                        ring.add(descriptors[i][j] as usize),
+                        segment,
+                        rss_type | rsc_count << IXGBE_RXDADV_RSCCNT_SHIFT,
+                        rss_hash,
+                        status,
+                        0,
+                    );
+                }
+            }
+
+            self.count_rx(q, frame);
+        }
+        self.set(IXGBE_RDH(q), index);
+
+        true
+    }
+
+    /// Counts a received frame on rx queue `queue`.
+    fn count_rx(&self, queue: u32, frame: &[u8]) {
+        // byte counters include the crc which is stripped by the device
+        let bytes = frame.len() as u64 + 4;
+        self.add(IXGBE_GPRC, 1);
+        self.add64(IXGBE_GORCL, IXGBE_GORCH, bytes);
+        self.add(IXGBE_QPRC(queue), 1);
+        self.add64(IXGBE_QBRC_L(queue), IXGBE_QBRC_H(queue), bytes);
+        self.add(IXGBE_RXDGPC, 1);
+        self.count_frame(frame, (IXGBE_BPRC, IXGBE_MPRC), &RX_SIZE_COUNTERS);
+    }
+
+    /// Counts `frame` in the broadcast and multicast counters `xcast` and the size counters
+    /// `sizes`.
+    fn count_frame(&self, frame: &[u8], xcast: (u32, u32), sizes: &[u32; 6]) {
+        match frame.get(..6) {
+            Some([0xff, 0xff, 0xff, 0xff, 0xff, 0xff]) => self.add(xcast.0, 1),
+            Some(dst) if dst[0] & 1 != 0 => self.add(xcast.1, 1),
+            _ => {}
+        }
+
+        let bucket = [64, 127, 255, 511, 1023]
+            .iter()
+            .position(|&bound| frame.len() + 4 <= bound)
+            .unwrap_or(5);
+        self.add(sizes[bucket], 1);
+    }
+
+    /// Returns the descriptor ring, its size and the buffer size of rx queue `queue`.
+    fn rx_ring(&self, queue: u32) -> (*mut ixgbe_adv_rx_desc, u32, usize) {
+        let ring = self.ring_addr(IXGBE_RDBAL(queue), IXGBE_RDBAH(queue)) as *mut ixgbe_adv_rx_desc;
+        let ring_size = self.get(IXGBE_RDLEN(queue)) / 16;
+        let buf_size = match self.get(IXGBE_SRRCTL(queue)) & IXGBE_SRRCTL_BSIZEPKT_MASK {
+            0 => 2048,
+            x => (x << IXGBE_SRRCTL_BSIZEPKT_SHIFT) as usize,
+        };
+
+        (ring, ring_size, buf_size)
+    }
+
+    /// Processes all descriptors between the head and the tail of tx queue `queue`.
+    fn process_tx(&self, state: &mut State, queue: u32) {
+        if self.get(IXGBE_DMATXCTL) & IXGBE_DMATXCTL_TE == 0
+            || self.get(IXGBE_TXDCTL(queue)) & IXGBE_TXDCTL_ENABLE == 0
+        {
+            return;
+        }
+
+        let ring = self.ring_addr(IXGBE_TDBAL(queue), IXGBE_TDBAH(queue)) as *mut ixgbe_adv_tx_desc;
+        let ring_size = self.get(IXGBE_TDLEN(queue)) / 16;
+        let tail = self.get(IXGBE_TDT(queue));
+        let mut head = self.get(IXGBE_TDH(queue));
+
+        while head != tail {
+            let desc = unsafe { ring.add(head as usize) };
+            let (addr, cmd_type_len, olinfo_status) = unsafe {
+                (
+                    ptr::read_volatile(&(*desc).read.buffer_addr as *const u64),
+                    ptr::read_volatile(&(*desc).read.cmd_type_len as *const u32),
+                    ptr::read_volatile(&(*desc).read.olinfo_status as *const u32),
+                )
+            };
+
+            if cmd_type_len & IXGBE_ADVTXD_DTYP_MASK == IXGBE_ADVTXD_DTYP_CTXT {
+                let desc = desc as *const ixgbe_adv_tx_context_desc;
+                state.contexts[queue as usize] = unsafe {
+                    (
+                        ptr::read_volatile(&(*desc).vlan_macip_lens as *const u32),
+                        ptr::read_volatile(&(*desc).type_tucmd_mlhl as *const u32),
+                        ptr::read_volatile(&(*desc).mss_l4len_idx as *const u32),
+                    )
+                };
+            }
+
+            if cmd_type_len & IXGBE_ADVTXD_DTYP_MASK == IXGBE_ADVTXD_DTYP_DATA {
+                if state.pending[queue as usize].is_empty() && olinfo_status & IXGBE_ADVTXD_CC != 0
+                {
+                    let context = state.contexts[queue as usize];
+                    // contexts of packets that only have a vlan tag request no offloads
+                    if context.1 & IXGBE_ADVTXD_TUCMD_L4T_RSV != IXGBE_ADVTXD_TUCMD_L4T_RSV
+                        || olinfo_status & IXGBE_ADVTXD_POPTS_IXSM != 0
+                    {
+                        state.pending_offload[queue as usize] =
+                            Some(tx_offload(context, cmd_type_len, olinfo_status));
+                    }
+                    if cmd_type_len & IXGBE_ADVTXD_DCMD_VLE != 0 {
+                        state.pending_vlan[queue as usize] =
+                            Some((context.0 >> IXGBE_ADVTXD_VLAN_SHIFT) as u16);
+                    }
+                }
+
+                let len = (cmd_type_len & 0xffff) as usize;
+                let data = unsafe { std::slice::from_raw_parts(addr as *const u8, len) };
+                state.pending[queue as usize].extend_from_slice(data);
+
+                if cmd_type_len & IXGBE_ADVTXD_DCMD_EOP != 0 {
+                    let mut frame = std::mem::take(&mut state.pending[queue as usize]);
+
+                    if cmd_type_len & IXGBE_ADVTXD_MAC_TSTAMP != 0 {
+                        self.latch_timestamp(IXGBE_TSYNCTXCTL, IXGBE_TXSTMPL, IXGBE_TXSTMPH);
+                    }
+
+                    let frames = match state.pending_offload[queue as usize].take() {
+                        Some(offload) if offload.mss.is_some() => offload.segment_frame(&frame),
+                        Some(offload) => {
+                            offload.insert_ip_checksum(&mut frame);
+                            offload.insert_l4_checksum(&mut frame, None);
+                            vec![frame]
+                        }
+                        None => vec![frame],
+                    };
+
+                    let vlan = state.pending_vlan[queue as usize].take();
+
+                    for mut frame in frames {
+                        if let Some(tci) = vlan {
+                            let tag = [0x81, 0x00, (tci >> 8) as u8, tci as u8];
+                            frame.splice(12..12, tag.iter().copied());
+                        }
+
+                        let bytes = frame.len() as u64 + 4;
+                        self.add(IXGBE_GPTC, 1);
+                        self.add64(IXGBE_GOTCL, IXGBE_GOTCH, bytes);
+                        self.add(IXGBE_QPTC(queue), 1);
+                        self.add64(IXGBE_QBTC_L(queue), IXGBE_QBTC_H(queue), bytes);
+                        self.count_frame(&frame, (IXGBE_BPTC, IXGBE_MPTC), &TX_SIZE_COUNTERS);
+
+                        state.transmitted[queue as usize].push(frame);
+                    }
+                }
+            }
+
+            if cmd_type_len & IXGBE_ADVTXD_DCMD_RS != 0 {
+                unsafe {
+                    ptr::write_volatile(&mut (*desc).wb.status as *mut u32, IXGBE_ADVTXD_STAT_DD);
+                }
+            }
+
+            head = (head + 1) % ring_size;
+        }
+
+        self.set(IXGBE_TDH(queue), head);
+    }
+
+    /// Sets all registers to their power-on values.
+    fn reset(&self) {
+        unsafe {
+            ptr::write_bytes(self.addr, 0, BAR_SIZE);
+        }
+
+        self.set(IXGBE_EEC, IXGBE_EEC_ARD);
+        self.set(IXGBE_RDRXCTL, IXGBE_RDRXCTL_DMAIDONE);
+        self.set(IXGBE_LINKS, IXGBE_LINKS_UP | IXGBE_LINKS_SPEED_10G_82599);
+        self.set(IXGBE_MAXFRS, 1518 << IXGBE_MHADD_MFS_SHIFT);
+
+        for i in 0..u32::from(MAX_QUEUES) {
+            self.set(IXGBE_SRRCTL(i), 2);
+        }
+
+        let mac = MAC_ADDR;
+        self.set(
+            IXGBE_RAL(0),
+            u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]),
+        );
+        self.set(
+            IXGBE_RAH(0),
+            u32::from(mac[4]) | (u32::from(mac[5]) << 8) | IXGBE_RAH_AV,
+        );
+    }
+
+    /// Returns the address of the descriptor ring configured in `bal` and `bah`.
+    fn ring_addr(&self, bal: u32, bah: u32) -> usize {
+        (u64::from(self.get(bal)) | (u64::from(self.get(bah)) << 32)) as usize
+    }
+
+    fn get(&self, reg: u32) -> u32 {
+        unsafe { ptr::read_volatile(self.addr.add(reg as usize) as *const u32) }
+    }
+
+    fn set(&self, reg: u32, value: u32) {
+        unsafe { ptr::write_volatile(self.addr.add(reg as usize) as *mut u32, value) }
+    }
+
+    fn add(&self, reg: u32, value: u32) {
+        self.set(reg, self.get(reg).wrapping_add(value));
+    }
+
+    fn add64(&self, low: u32, high: u32, value: u64) {
+        let current = u64::from(self.get(low)) | (u64::from(self.get(high)) << 32);
+        let new = current.wrapping_add(value);
+        self.set(low, new as u32);
+        self.set(high, (new >> 32) as u32);
+    }
+}
+
+/// Returns the offloads requested by a context descriptor with the fields `vlan_macip_lens`,
+/// `type_tucmd_mlhl` and `mss_l4len_idx` in `context` for a data descriptor with `cmd_type_len`
+/// and `olinfo_status`.
+/// Returns the checksum status and error bits the device reports for a received `frame`.
+/// Returns whether `frame` is a PTP event message sent over ethernet or udp.
+fn is_ptp_event(frame: &[u8]) -> bool {
+    match frame.get(12..14) {
+        Some([0x88, 0xf7]) => frame.get(14).is_some_and(|msg_type| msg_type & 0x0f < 4),
+        Some([0x08, 0x00]) => {
+            let ihl = frame.get(14).map_or(0, |ihl| usize::from(ihl & 0x0f) * 4);
+            frame.get(23) == Some(&17)
+                && frame.get(14 + ihl + 2..14 + ihl + 4) == Some(&319u16.to_be_bytes()[..])
+        }
+        _ => false,
+    }
+}
+
+fn rx_checksum_status(frame: &[u8]) -> u32 {
+    let (ip_checksum, l4_checksum) = verify_checksums(frame);
+    let checksum_status = |status, checksum_bit, error_bit| match status {
+        ChecksumStatus::Unknown => 0,
+        ChecksumStatus::Good => checksum_bit,
+        ChecksumStatus::Bad => checksum_bit | error_bit,
+    };
+
+    checksum_status(ip_checksum, IXGBE_RXD_STAT_IPCS, IXGBE_RXDADV_ERR_IPE)
+        | checksum_status(l4_checksum, IXGBE_RXD_STAT_L4CS, IXGBE_RXDADV_ERR_TCPE)
+}
+
+/// Copies `segment` into the buffer of the rx descriptor `desc` and writes back the descriptor.
+unsafe fn write_rx_desc(
+    desc: *mut ixgbe_adv_rx_desc,
+    segment: &[u8],
+    lo_dword: u32,
+    rss_hash: u32,
+    status: u32,
+    vlan: u16,
+) {
+    let buf = ptr::read_volatile(&(*desc).read.pkt_addr as *const u64) as *mut u8;
+    ptr::copy_nonoverlapping(segment.as_ptr(), buf, segment.len());
+
+    ptr::write_volatile(&mut (*desc).wb.lower.lo_dword.data as *mut u32, lo_dword);
+    ptr::write_volatile(&mut (*desc).wb.lower.hi_dword.rss as *mut u32, rss_hash);
+    ptr::write_volatile(
+        &mut (*desc).wb.upper.length as *mut u16,
+        segment.len() as u16,
+    );
+    ptr::write_volatile(&mut (*desc).wb.upper.vlan as *mut u16, vlan);
+    ptr::write_volatile(
+        &mut (*desc).wb.upper.status_error as *mut u32,
+        IXGBE_RXDADV_STAT_DD | status,
+    );
+}
+
+fn tx_offload(context: (u32, u32, u32), cmd_type_len: u32, olinfo_status: u32) -> TxOffload {
+    let (macip_lens, type_tucmd, mss_l4len) = context;
+    let l2_len = (macip_lens >> IXGBE_ADVTXD_MACLEN_SHIFT & 0x7f) as usize;
+    let l3_len = (macip_lens & 0x1ff) as usize;
+
+    let mut offload = if type_tucmd & IXGBE_ADVTXD_TUCMD_IPV4 != 0 {
+        TxOffload::ipv4(l2_len, l3_len)
+    } else {
+        TxOffload::ipv6(l2_len, l3_len)
+    };
+
+    if olinfo_status & IXGBE_ADVTXD_POPTS_IXSM != 0 {
+        offload = offload.ip_checksum();
+    }
+
+    if olinfo_status & IXGBE_ADVTXD_POPTS_TXSM != 0 {
+        match type_tucmd & IXGBE_ADVTXD_TUCMD_L4T_RSV {
+            IXGBE_ADVTXD_TUCMD_L4T_TCP => offload = offload.l4_checksum(L4Type::Tcp),
+            IXGBE_ADVTXD_TUCMD_L4T_UDP => offload = offload.l4_checksum(L4Type::Udp),
+            _ => {}
+        }
+    }
+
+    if cmd_type_len & IXGBE_ADVTXD_DCMD_TSE != 0 {
+        let mss = (mss_l4len >> IXGBE_ADVTXD_MSS_SHIFT) as u16;
+        let l4_len = (mss_l4len >> IXGBE_ADVTXD_L4LEN_SHIFT) & 0xff;
+        offload = offload.tso(mss, l4_len as usize);
+    }
+
+    offload
+}
+
+/// Returns whether `reg` is a statistics register that is cleared when read.
+fn is_clear_on_read(reg: u32) -> bool {
+    let per_queue =
+        |base: u32| reg >= base && reg < base + 16 * 0x40 && (reg - base).is_multiple_of(0x40);
+
+    (IXGBE_CRCERRS..0x04200).contains(&reg)
+        || (IXGBE_MPC(0)..=IXGBE_RNBC(7)).contains(&reg)
+        || (IXGBE_QBTC_L(0)..=IXGBE_QBTC_H(15)).contains(&reg)
+        || per_queue(IXGBE_QPRC(0))
+        || per_queue(IXGBE_QPTC(0))
+        || per_queue(IXGBE_QBRC_L(0))
+        || per_queue(IXGBE_QBRC_H(0))
+        || per_queue(IXGBE_QPRDC(0))
+        || reg == IXGBE_RXDGPC
+        || reg == IXGBE_FDIRMATCH
+        || reg == IXGBE_FDIRMISS
+}
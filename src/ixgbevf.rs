@@ -1,23 +1,32 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::cmp::min;
 use std::collections::VecDeque;
-use std::error::Error;
 use std::fs::File;
 use std::io::Read;
 use std::mem;
 use std::os::unix::io::RawFd;
 use std::path::Path;
 use std::ptr;
-use std::rc::Rc;
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use crate::constants::*;
 use crate::memory::*;
 use crate::vfio::*;
 
+use crate::config::DeviceLimits;
+use crate::error::IxyError;
+use crate::ixgbe::{
+    checksum_status, mta_vector, ready_rx_descriptors, tx_context, MAX_TX_SEGMENTS,
+    STATS_UPDATE_INTERVAL,
+};
+use crate::offload;
 use crate::pci::pci_map_resource;
+use crate::stats::{ExtendedStats, RxQueueStats, TxQueueStats};
+use crate::timestamp::{self, Timestamp};
 use crate::vfio::VFIO_PCI_BAR0_REGION_INDEX;
+use crate::DeviceConfig;
 use crate::DeviceStats;
 use crate::IxyDevice;
 
@@ -25,12 +34,19 @@ const DRIVER_NAME: &str = "ixy-ixgbevf";
 
 const MAX_QUEUES: u16 = 8;
 
-const PKT_BUF_ENTRY_SIZE: usize = 2048;
-const MIN_MEMPOOL_SIZE: usize = 4096;
+// the multicast hashes are sent to the PF in the 15 remaining words of one mailbox message
+const MAX_MULTICAST_ADDRS: usize = 30;
 
-const NUM_RX_QUEUE_ENTRIES: usize = 512;
-const NUM_TX_QUEUE_ENTRIES: usize = 512;
-const TX_CLEAN_BATCH: usize = 32;
+const LIMITS: DeviceLimits = DeviceLimits {
+    max_queues: MAX_QUEUES,
+    min_ring_size: 64,
+    max_ring_size: 4096,
+    // largest frame is 9728 bytes including ethernet header and crc
+    max_mtu: 9710,
+    max_rss_queues: 0,
+    flow_director: false,
+    rsc: false,
+};
 
 fn wrap_ring(index: usize, ring_size: usize) -> usize {
     (index + 1) & (ring_size - 1)
@@ -78,30 +94,51 @@ pub struct IxgbeVFDevice {
     len: usize,
     num_rx_queues: u16,
     num_tx_queues: u16,
+    config: DeviceConfig,
     rx_queues: Vec<IxgbeRxQueue>,
     tx_queues: Vec<IxgbeTxQueue>,
     mbx: RefCell<Mailbox>,
     mac: RefCell<[u8; 6]>,
+    // totals since the last reset and the last values of the wrapping stats registers, see
+    // `update_stats`
     stats: RefCell<DeviceStats>,
+    xstats: RefCell<ExtendedStats>,
+    stats_regs: RefCell<DeviceStats>,
+    rx_multicast_reg: Cell<u64>,
+    stats_updated: Cell<Instant>,
+    stats_polls: u32,
     vfio: bool,
     vfio_fd: RawFd,
+    promisc: bool,
+    all_multicast: bool,
+    mac_addrs: Vec<[u8; 6]>,
+    multicast_addrs: Vec<[u8; 6]>,
 }
 
 struct IxgbeRxQueue {
     descriptors: *mut ixgbe_adv_rx_desc,
     num_descriptors: usize,
-    pool: Rc<Mempool>,
+    pool: Arc<Mempool>,
     bufs_in_use: Vec<usize>,
+    // buffers allocated for the ready descriptors of the current batch
+    spare: Vec<usize>,
     rx_index: usize,
+    // segments of a frame spanning multiple descriptors whose last descriptor is yet to come
+    partial: Option<Packet>,
+    stats: RxQueueStats,
 }
 
 struct IxgbeTxQueue {
     descriptors: *mut ixgbe_adv_tx_desc,
     num_descriptors: usize,
-    pool: Option<Rc<Mempool>>,
-    bufs_in_use: VecDeque<usize>,
+    // sent packets in the order of their descriptors, each one owns the buffer of one segment,
+    // context descriptors have no packet
+    bufs_in_use: VecDeque<Option<Packet>>,
     clean_index: usize,
     tx_index: usize,
+    // fields of the last context descriptor
+    context: Option<[u32; 3]>,
+    stats: TxQueueStats,
 }
 
 impl IxyDevice for IxgbeVFDevice {
@@ -144,7 +181,10 @@ impl IxyDevice for IxgbeVFDevice {
             + (u32::from(mac[3]) << 24);
         msg[2] = u32::from(mac[4]) + (u32::from(mac[5]) << 8);
 
-        self.wait_write_read_msg_mbx(&mut msg).unwrap();
+        if let Err(e) = self.wait_write_read_msg_mbx(&mut msg) {
+            error!("failed to set mac address: {}", e);
+            return;
+        }
 
         msg[0] &= !IXGBE_VT_MSGTYPE_CTS;
 
@@ -173,8 +213,8 @@ impl IxyDevice for IxgbeVFDevice {
             rx_index = queue.rx_index;
             last_rx_index = queue.rx_index;
 
-            for i in 0..num_packets {
-                let desc = unsafe { queue.descriptors.add(rx_index) as *mut ixgbe_adv_rx_desc };
+            while received_packets < num_packets {
+                let desc = unsafe { queue.descriptors.add(rx_index) };
                 let status =
                     unsafe { ptr::read_volatile(&mut (*desc).wb.upper.status_error as *mut u32) };
 
@@ -182,14 +222,24 @@ impl IxyDevice for IxgbeVFDevice {
                     break;
                 }
 
-                if (status & IXGBE_RXDADV_STAT_EOP) == 0 {
-                    panic!("increase buffer size or decrease MTU")
-                }
+                // the vlan field is overwritten when the descriptor is reset
+                let vlan = unsafe { ptr::read_volatile(&(*desc).wb.upper.vlan as *const u16) };
 
                 let pool = &queue.pool;
 
-                // get a free buffer from the mempool
-                if let Some(buf) = pool.alloc_buf() {
+                // get the free buffers for all ready descriptors of this batch at once
+                if queue.spare.is_empty() {
+                    let ready = ready_rx_descriptors(
+                        queue.descriptors,
+                        queue.num_descriptors,
+                        rx_index,
+                        num_packets - received_packets,
+                    );
+                    let spare = &mut queue.spare;
+                    pool.alloc_bufs(ready, |id| spare.push(id));
+                }
+
+                if let Some(buf) = queue.spare.pop() {
                     // replace currently used buffer with new buffer
                     let buf = mem::replace(&mut queue.bufs_in_use[rx_index], buf);
 
@@ -201,6 +251,8 @@ impl IxyDevice for IxgbeVFDevice {
                         },
                         pool: pool.clone(),
                         pool_entry: buf,
+                        next: None,
+                        meta: Default::default(),
                     };
 
                     #[cfg(all(
@@ -209,8 +261,6 @@ impl IxyDevice for IxgbeVFDevice {
                     ))]
                     p.prefetch(Prefetch::Time1);
 
-                    buffer.push_back(p);
-
                     unsafe {
                         ptr::write_volatile(
                             &mut (*desc).read.pkt_addr as *mut u64,
@@ -221,18 +271,58 @@ impl IxyDevice for IxgbeVFDevice {
 
                     last_rx_index = rx_index;
                     rx_index = wrap_ring(rx_index, queue.num_descriptors);
-                    received_packets = i + 1;
+
+                    // frames that don't fit into one buffer span multiple descriptors, the
+                    // segments are chained until the descriptor with the end of packet bit
+                    let mut p = match queue.partial.take() {
+                        Some(mut head) => {
+                            head.push_segment(p);
+                            head
+                        }
+                        None => p,
+                    };
+
+                    if (status & IXGBE_RXDADV_STAT_EOP) == 0 {
+                        queue.partial = Some(p);
+                        continue;
+                    }
+
+                    p.meta.ip_checksum =
+                        checksum_status(status, IXGBE_RXD_STAT_IPCS, IXGBE_RXDADV_ERR_IPE);
+                    p.meta.l4_checksum =
+                        checksum_status(status, IXGBE_RXD_STAT_L4CS, IXGBE_RXDADV_ERR_TCPE);
+
+                    if status & IXGBE_RXDADV_STAT_VP != 0 {
+                        p.meta.vlan_tci = Some(vlan);
+                    }
+
+                    // VFs have no access to the 1588 clock of the PF
+                    if self.config.timestamping {
+                        p.meta.timestamp = Some(Timestamp::Software(timestamp::now()));
+                    }
+
+                    queue.stats.pkts += 1;
+                    queue.stats.bytes += p.total_len() as u64;
+
+                    buffer.push_back(p);
+                    received_packets += 1;
                 } else {
                     // break if there was no free buffer
+                    queue.stats.mempool_exhausted += 1;
                     break;
                 }
             }
+
+            if received_packets == 0 {
+                queue.stats.empty_polls += 1;
+            }
         }
 
         if rx_index != last_rx_index {
             self.set_reg32(IXGBE_VFRDT(u32::from(queue_id)), last_rx_index as u32);
             self.rx_queues[queue_id as usize].rx_index = rx_index;
         }
+        self.poll_stats();
 
         received_packets
     }
@@ -242,61 +332,148 @@ impl IxyDevice for IxgbeVFDevice {
         let mut sent = 0;
 
         {
-            let mut queue = self
+            let queue = self
                 .tx_queues
                 .get_mut(queue_id as usize)
                 .expect("invalid tx queue id");
 
             let mut cur_index = queue.tx_index;
-            let clean_index = clean_tx_queue(&mut queue);
+            let clean_index = clean_tx_queue(queue, self.config.tx_clean_batch);
 
-            if queue.pool.is_none() {
-                if let Some(packet) = buffer.get(0) {
-                    queue.pool = Some(packet.pool.clone());
+            while let Some(mut packet) = buffer.pop_front() {
+                if packet.tx_offload().is_some_and(|o| !o.fits(&packet)) {
+                    warn!("dropping packet whose offloaded headers are not in its first segment");
+                    queue.stats.dropped += 1;
+                    continue;
                 }
-            }
 
-            while let Some(packet) = buffer.pop_front() {
-                assert!(
-                    Rc::ptr_eq(queue.pool.as_ref().unwrap(), &packet.pool),
-                    "distinct memory pools for a single tx queue are not supported yet"
-                );
+                // the driver doesn't segment packets in hardware, the segments are sent instead
+                if let Some(offload) = packet.tx_offload().filter(|o| o.mss.is_some()) {
+                    if !offload.segment_into(packet, buffer) {
+                        break;
+                    }
+                    continue;
+                }
+
+                if packet.num_segments() > MAX_TX_SEGMENTS {
+                    match packet
+                        .linearize()
+                        .filter(|p| p.num_segments() <= MAX_TX_SEGMENTS)
+                    {
+                        Some(linearized) => packet = linearized,
+                        None => {
+                            warn!(
+                                "dropping packet with {} segments, at most {} are supported",
+                                packet.num_segments(),
+                                MAX_TX_SEGMENTS
+                            );
+                            queue.stats.dropped += 1;
+                            continue;
+                        }
+                    }
+                }
+                let num_segments = packet.num_segments();
 
-                let next_index = wrap_ring(cur_index, queue.num_descriptors);
+                // offloads different from the ones of the previous packet need a context
+                // descriptor
+                let offload = packet.tx_offload();
+                let vlan_tci = packet.vlan_tci();
+                let context = tx_context(offload.as_ref(), vlan_tci);
+                let new_context =
+                    matches!(context, Some((fields, _)) if queue.context != Some(fields));
 
-                if clean_index == next_index {
+                // one descriptor between the tail and the first uncleaned descriptor stays empty
+                let free_descriptors =
+                    (clean_index + queue.num_descriptors - cur_index - 1) % queue.num_descriptors;
+
+                if num_segments + usize::from(new_context) > free_descriptors {
                     // tx queue of device is full, push packet back onto the
                     // queue of to-be-sent packets
                     buffer.push_front(packet);
+                    queue.stats.ring_full += 1;
                     break;
                 }
 
-                queue.tx_index = wrap_ring(queue.tx_index, queue.num_descriptors);
+                let total_len = packet.total_len();
+                queue.stats.pkts += 1;
+                queue.stats.bytes += total_len as u64;
+                let mut olinfo_status = (total_len as u32) << IXGBE_ADVTXD_PAYLEN_SHIFT;
+
+                if let Some((fields, popts)) = context {
+                    if new_context {
+                        let desc = unsafe {
+                            queue.descriptors.add(cur_index) as *mut ixgbe_adv_tx_context_desc
+                        };
+                        unsafe {
+                            ptr::write_volatile(
+                                &mut (*desc).vlan_macip_lens as *mut u32,
+                                fields[0],
+                            );
+                            ptr::write_volatile(&mut (*desc).seqnum_seed as *mut u32, 0);
+                            ptr::write_volatile(
+                                &mut (*desc).type_tucmd_mlhl as *mut u32,
+                                fields[1],
+                            );
+                            ptr::write_volatile(&mut (*desc).mss_l4len_idx as *mut u32, fields[2]);
+                        }
+
+                        queue.bufs_in_use.push_back(None);
+                        queue.context = Some(fields);
+                        cur_index = wrap_ring(cur_index, queue.num_descriptors);
+                    }
 
-                unsafe {
-                    ptr::write_volatile(
-                        &mut (*queue.descriptors.add(cur_index)).read.buffer_addr as *mut u64,
-                        packet.get_phys_addr() as u64,
-                    );
-                    ptr::write_volatile(
-                        &mut (*queue.descriptors.add(cur_index)).read.cmd_type_len as *mut u32,
-                        IXGBE_ADVTXD_DCMD_EOP
-                            | IXGBE_ADVTXD_DCMD_RS
-                            | IXGBE_ADVTXD_DCMD_IFCS
-                            | IXGBE_ADVTXD_DCMD_DEXT
-                            | IXGBE_ADVTXD_DTYP_DATA
-                            | packet.len() as u32,
-                    );
-                    ptr::write_volatile(
-                        &mut (*queue.descriptors.add(cur_index)).read.olinfo_status as *mut u32,
-                        (packet.len() as u32) << IXGBE_ADVTXD_PAYLEN_SHIFT,
-                    );
+                    olinfo_status |= IXGBE_ADVTXD_CC | popts;
                 }
 
-                queue.bufs_in_use.push_back(packet.pool_entry);
-                mem::forget(packet);
+                if let Some(offload) = offload {
+                    offload.prepare(&mut packet);
+                }
+
+                // the device inserts the vlan tag of the context descriptor
+                let vle = if vlan_tci.is_some() {
+                    IXGBE_ADVTXD_DCMD_VLE
+                } else {
+                    0
+                };
+
+                // each segment gets its own data descriptor, only the last one ends the frame
+                let mut segment = Some(packet);
+
+                while let Some(mut packet) = segment {
+                    segment = packet.take_next_segment();
+
+                    let eop = if segment.is_none() {
+                        IXGBE_ADVTXD_DCMD_EOP
+                    } else {
+                        0
+                    };
+
+                    unsafe {
+                        ptr::write_volatile(
+                            &mut (*queue.descriptors.add(cur_index)).read.buffer_addr as *mut u64,
+                            packet.get_phys_addr() as u64,
+                        );
+                        ptr::write_volatile(
+                            &mut (*queue.descriptors.add(cur_index)).read.cmd_type_len as *mut u32,
+                            eop | vle
+                                | IXGBE_ADVTXD_DCMD_RS
+                                | IXGBE_ADVTXD_DCMD_IFCS
+                                | IXGBE_ADVTXD_DCMD_DEXT
+                                | IXGBE_ADVTXD_DTYP_DATA
+                                | packet.len() as u32,
+                        );
+                        ptr::write_volatile(
+                            &mut (*queue.descriptors.add(cur_index)).read.olinfo_status as *mut u32,
+                            olinfo_status,
+                        );
+                    }
+
+                    queue.bufs_in_use.push_back(Some(packet));
 
-                cur_index = next_index;
+                    cur_index = wrap_ring(cur_index, queue.num_descriptors);
+                }
+
+                queue.tx_index = cur_index;
                 sent += 1;
             }
         }
@@ -305,43 +482,42 @@ impl IxyDevice for IxgbeVFDevice {
             IXGBE_VFTDT(u32::from(queue_id)),
             self.tx_queues[queue_id as usize].tx_index as u32,
         );
+        self.poll_stats();
 
         sent
     }
 
-    /// Reads the stats of this device into `stats`.
-    fn read_stats(&self, stats: &mut DeviceStats) {
-        let mut dev_stats = self.stats.borrow_mut();
-
-        let rx_pkts = u64::from(self.get_reg32(IXGBE_VFGPRC));
-        let tx_pkts = u64::from(self.get_reg32(IXGBE_VFGPTC));
-        let rx_bytes = u64::from(self.get_reg32(IXGBE_VFGORC_LSB))
-            + (u64::from(self.get_reg32(IXGBE_VFGORC_MSB)) << 32);
-        let tx_bytes = u64::from(self.get_reg32(IXGBE_VFGOTC_LSB))
-            + (u64::from(self.get_reg32(IXGBE_VFGOTC_MSB)) << 32);
+    /// Returns the counters the driver kept for rx queue `queue_id`.
+    fn rx_queue_stats(&self, queue_id: u16) -> Option<RxQueueStats> {
+        self.rx_queues.get(queue_id as usize).map(|q| q.stats)
+    }
 
-        // stat registers wrap around, pkts have a 32 bit and bytes a 36 bit counter
-        stats.rx_pkts += rx_pkts.wrapping_sub(dev_stats.rx_pkts) & ((1 << 32) - 1);
-        stats.tx_pkts += tx_pkts.wrapping_sub(dev_stats.tx_pkts) & ((1 << 32) - 1);
-        stats.rx_bytes += rx_bytes.wrapping_sub(dev_stats.rx_bytes) & ((1 << 36) - 1);
-        stats.tx_bytes += tx_bytes.wrapping_sub(dev_stats.tx_bytes) & ((1 << 36) - 1);
+    /// Returns the counters the driver kept for tx queue `queue_id`.
+    fn tx_queue_stats(&self, queue_id: u16) -> Option<TxQueueStats> {
+        self.tx_queues.get(queue_id as usize).map(|q| q.stats)
+    }
 
-        dev_stats.rx_pkts = rx_pkts;
-        dev_stats.tx_pkts = tx_pkts;
-        dev_stats.rx_bytes = rx_bytes;
-        dev_stats.tx_bytes = tx_bytes;
+    /// Reads the totals of the stats of this device since the last reset into `stats`.
+    ///
+    /// The stats registers are read on demand and every few seconds while the queues are
+    /// polled, so any number of readers get the same totals.
+    fn read_stats(&self, stats: &mut DeviceStats) {
+        self.update_stats();
+        *stats = *self.stats.borrow();
     }
 
     /// Resets the stats of this device.
     fn reset_stats(&mut self) {
-        let mut dev_stats = self.stats.borrow_mut();
+        self.update_stats();
+        *self.stats.borrow_mut() = DeviceStats::default();
+        *self.xstats.borrow_mut() = ExtendedStats::default();
+    }
 
-        dev_stats.rx_pkts = u64::from(self.get_reg32(IXGBE_VFGPRC));
-        dev_stats.tx_pkts = u64::from(self.get_reg32(IXGBE_VFGPTC));
-        dev_stats.rx_bytes = u64::from(self.get_reg32(IXGBE_VFGORC_LSB))
-            + (u64::from(self.get_reg32(IXGBE_VFGORC_MSB)) << 32);
-        dev_stats.tx_bytes = u64::from(self.get_reg32(IXGBE_VFGOTC_LSB))
-            + (u64::from(self.get_reg32(IXGBE_VFGOTC_MSB)) << 32);
+    /// Reads the total of received multicast packets since the last reset into `stats`, the
+    /// only extended counter of a virtual function.
+    fn read_extended_stats(&self, stats: &mut ExtendedStats) {
+        self.update_stats();
+        stats.clone_from(&self.xstats.borrow());
     }
 
     /// Returns the link speed of this device.
@@ -357,34 +533,128 @@ impl IxyDevice for IxgbeVFDevice {
             _ => 0,
         }
     }
+
+    /// Requests the PF to add vlan id `vlan_id` to the vlan filter of this VF.
+    fn add_vlan_filter(&mut self, vlan_id: u16) -> Result<(), IxyError> {
+        self.set_vlan_filter(vlan_id, true)
+    }
+
+    /// Requests the PF to remove vlan id `vlan_id` from the vlan filter of this VF.
+    fn remove_vlan_filter(&mut self, vlan_id: u16) -> Result<(), IxyError> {
+        self.set_vlan_filter(vlan_id, false)
+    }
+
+    /// Requests the PF to enable or disable promiscuous mode, which requires a trusted VF.
+    fn set_promiscuous(&mut self, enabled: bool) -> Result<(), IxyError> {
+        self.update_xcast_mode(enabled, self.all_multicast)?;
+        self.promisc = enabled;
+
+        Ok(())
+    }
+
+    /// Requests the PF to enable or disable receiving all multicast packets.
+    fn set_all_multicast(&mut self, enabled: bool) -> Result<(), IxyError> {
+        self.update_xcast_mode(self.promisc, enabled)?;
+        self.all_multicast = enabled;
+
+        Ok(())
+    }
+
+    /// Requests the PF to add the secondary unicast address `mac` to this VF.
+    fn add_mac_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        crate::check_mac_addr(mac, false)?;
+        if self.mac_addrs.contains(&mac) {
+            return Ok(());
+        }
+
+        self.set_macvlan(self.mac_addrs.len() + 1, mac)?;
+        self.mac_addrs.push(mac);
+
+        Ok(())
+    }
+
+    /// Requests the PF to remove the secondary unicast address `mac` from this VF.
+    fn remove_mac_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        let index = self
+            .mac_addrs
+            .iter()
+            .position(|&addr| addr == mac)
+            .ok_or_else(|| {
+                IxyError::InvalidConfig(format!(
+                    "{} has no mac address {}",
+                    self.get_driver_name(),
+                    crate::format_mac_addr(mac)
+                ))
+            })?;
+        let mut mac_addrs = self.mac_addrs.clone();
+        mac_addrs.remove(index);
+
+        if let Err(e) = self.set_macvlans(&mac_addrs) {
+            // try to restore the addresses the PF had before
+            let _ = self.set_macvlans(&self.mac_addrs);
+            return Err(e);
+        }
+        self.mac_addrs = mac_addrs;
+
+        Ok(())
+    }
+
+    /// Requests the PF to accept packets sent to the multicast address `mac`.
+    fn add_multicast_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        crate::check_mac_addr(mac, true)?;
+        if self.multicast_addrs.contains(&mac) {
+            return Ok(());
+        }
+        if self.multicast_addrs.len() == MAX_MULTICAST_ADDRS {
+            return Err(IxyError::InvalidConfig(format!(
+                "{} supports at most {} multicast addresses",
+                self.get_driver_name(),
+                MAX_MULTICAST_ADDRS
+            )));
+        }
+
+        let mut multicast_addrs = self.multicast_addrs.clone();
+        multicast_addrs.push(mac);
+
+        self.set_multicast_list(&multicast_addrs)?;
+        self.multicast_addrs = multicast_addrs;
+
+        Ok(())
+    }
+
+    /// Requests the PF to no longer accept packets sent to the multicast address `mac`.
+    fn remove_multicast_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        let index = self
+            .multicast_addrs
+            .iter()
+            .position(|&addr| addr == mac)
+            .ok_or_else(|| {
+                IxyError::InvalidConfig(format!(
+                    "{} has no multicast address {}",
+                    self.get_driver_name(),
+                    crate::format_mac_addr(mac)
+                ))
+            })?;
+        let mut multicast_addrs = self.multicast_addrs.clone();
+        multicast_addrs.remove(index);
+
+        self.set_multicast_list(&multicast_addrs)?;
+        self.multicast_addrs = multicast_addrs;
+
+        Ok(())
+    }
 }
 
 impl IxgbeVFDevice {
     /// Returns an initialized `IxgbeVFDevice` on success.
     ///
-    /// # Panics
-    /// Panics if `num_rx_queues` or `num_tx_queues` exceeds `MAX_QUEUES`.
-    pub fn init(
-        pci_addr: &str,
-        num_rx_queues: u16,
-        num_tx_queues: u16,
-    ) -> Result<IxgbeVFDevice, Box<dyn Error>> {
+    /// Fails with [`IxyError::InvalidConfig`] if `config` exceeds the limits of the device.
+    pub fn init(pci_addr: &str, config: &DeviceConfig) -> Result<IxgbeVFDevice, IxyError> {
         if unsafe { libc::getuid() } != 0 {
             warn!("not running as root, this will probably fail");
         }
 
-        assert!(
-            num_rx_queues <= MAX_QUEUES,
-            "cannot configure {} rx queues: limit is {}",
-            num_rx_queues,
-            MAX_QUEUES
-        );
-        assert!(
-            num_tx_queues <= MAX_QUEUES,
-            "cannot configure {} tx queues: limit is {}",
-            num_tx_queues,
-            MAX_QUEUES
-        );
+        config.validate(&LIMITS)?;
 
         // Check if the NIC is IOMMU enabled...
         let vfio = Path::new(&format!("/sys/bus/pci/devices/{}/iommu_group", pci_addr)).exists();
@@ -401,27 +671,38 @@ impl IxgbeVFDevice {
         };
 
         // initialize RX and TX queue
-        let rx_queues = Vec::with_capacity(num_rx_queues as usize);
-        let tx_queues = Vec::with_capacity(num_tx_queues as usize);
+        let rx_queues = Vec::with_capacity(config.num_rx_queues as usize);
+        let tx_queues = Vec::with_capacity(config.num_tx_queues as usize);
 
         let mbx = RefCell::new(Mailbox::init());
         let mac = RefCell::new([0; 6]);
         let stats = RefCell::new(DeviceStats::default());
+        let xstats = RefCell::new(ExtendedStats::default());
 
         // create the IxyDevice
         let mut dev = IxgbeVFDevice {
             pci_addr: pci_addr.to_string(),
             addr,
             len,
-            num_rx_queues,
-            num_tx_queues,
+            num_rx_queues: config.num_rx_queues,
+            num_tx_queues: config.num_tx_queues,
+            config: *config,
             rx_queues,
             tx_queues,
             mbx,
             mac,
             stats,
+            xstats,
+            stats_regs: RefCell::new(DeviceStats::default()),
+            rx_multicast_reg: Cell::new(0),
+            stats_updated: Cell::new(Instant::now()),
+            stats_polls: 0,
             vfio,
             vfio_fd: unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR },
+            promisc: config.promiscuous,
+            all_multicast: false,
+            mac_addrs: Vec::new(),
+            multicast_addrs: Vec::new(),
         };
 
         dev.reset_and_init(pci_addr)?;
@@ -430,7 +711,7 @@ impl IxgbeVFDevice {
     }
 
     /// Resets and initializes this device.
-    fn reset_and_init(&mut self, pci_addr: &str) -> Result<(), Box<dyn Error>> {
+    fn reset_and_init(&mut self, pci_addr: &str) -> Result<(), IxyError> {
         info!("resetting device {}", pci_addr);
 
         // disable all interrupts
@@ -461,6 +742,16 @@ impl IxgbeVFDevice {
 
         self.negotiate_api()?;
 
+        self.set_max_frame_size()?;
+
+        // the PF only allows trusted VFs to enable promisc mode
+        if self.promisc {
+            if let Err(e) = self.update_xcast_mode(true, self.all_multicast) {
+                warn!("cannot enable promisc mode: {}", e);
+                self.promisc = false;
+            }
+        }
+
         self.init_tx()?;
 
         self.init_rx()?;
@@ -473,12 +764,59 @@ impl IxgbeVFDevice {
             self.start_rx_queue(i)?;
         }
 
+        if self.config.timestamping {
+            timestamp::calibrate();
+        }
+
         // setup done, what is our link speed?
         info!("link speed is {} Mbit/s", self.get_link_speed());
 
         Ok(())
     }
 
+    /// Adds the increments of the wrapping stats registers since the last update to the totals
+    /// of this device.
+    fn update_stats(&self) {
+        let reg = |reg| u64::from(self.get_reg32(reg));
+
+        let rx_pkts = reg(IXGBE_VFGPRC);
+        let tx_pkts = reg(IXGBE_VFGPTC);
+        let rx_bytes = reg(IXGBE_VFGORC_LSB) + (reg(IXGBE_VFGORC_MSB) << 32);
+        let tx_bytes = reg(IXGBE_VFGOTC_LSB) + (reg(IXGBE_VFGOTC_MSB) << 32);
+        let rx_multicast = reg(IXGBE_VFMPRC);
+
+        let mut stats = self.stats.borrow_mut();
+        let mut regs = self.stats_regs.borrow_mut();
+
+        // stat registers wrap around, pkts have a 32 bit and bytes a 36 bit counter
+        stats.rx_pkts += rx_pkts.wrapping_sub(regs.rx_pkts) & ((1 << 32) - 1);
+        stats.tx_pkts += tx_pkts.wrapping_sub(regs.tx_pkts) & ((1 << 32) - 1);
+        stats.rx_bytes += rx_bytes.wrapping_sub(regs.rx_bytes) & ((1 << 36) - 1);
+        stats.tx_bytes += tx_bytes.wrapping_sub(regs.tx_bytes) & ((1 << 36) - 1);
+        self.xstats.borrow_mut().rx_multicast +=
+            rx_multicast.wrapping_sub(self.rx_multicast_reg.get()) & ((1 << 32) - 1);
+
+        regs.rx_pkts = rx_pkts;
+        regs.tx_pkts = tx_pkts;
+        regs.rx_bytes = rx_bytes;
+        regs.tx_bytes = tx_bytes;
+        self.rx_multicast_reg.set(rx_multicast);
+
+        self.stats_updated.set(Instant::now());
+    }
+
+    /// Updates the stats totals every `STATS_UPDATE_INTERVAL` while the queues are polled.
+    fn poll_stats(&mut self) {
+        self.stats_polls = self.stats_polls.wrapping_add(1);
+
+        // don't poll the time unnecessarily
+        if self.stats_polls & 0xfff == 0
+            && self.stats_updated.get().elapsed() > STATS_UPDATE_INTERVAL
+        {
+            self.update_stats();
+        }
+    }
+
     /// Resets the VF registers.
     fn reset_vf_registers(&mut self) {
         // VRSRRCTL default values (BSIZEPACKET = 2048, BSIZEHEADER = 256)
@@ -515,7 +853,7 @@ impl IxgbeVFDevice {
     }
 
     /// Negotiates the mailbox API version.
-    fn negotiate_api(&mut self) -> Result<(), Box<dyn Error>> {
+    fn negotiate_api(&mut self) -> Result<(), IxyError> {
         let api_versions = [
             ixgbe_pfvf_api_rev::ixgbe_mbox_api_13,
             ixgbe_pfvf_api_rev::ixgbe_mbox_api_12,
@@ -539,9 +877,153 @@ impl IxgbeVFDevice {
         Ok(())
     }
 
+    /// Requests the PF to accept frames up to the configured MTU on this VF.
+    fn set_max_frame_size(&mut self) -> Result<(), IxyError> {
+        let mut msg = [IXGBE_VF_SET_LPE, self.config.max_frame_size() as u32];
+
+        self.wait_write_read_msg_mbx(&mut msg)?;
+
+        msg[0] &= !IXGBE_VT_MSGTYPE_CTS;
+
+        if msg[0] == (IXGBE_VF_SET_LPE | IXGBE_VT_MSGTYPE_NACK) {
+            return Err(IxyError::InvalidConfig(format!(
+                "mtu {} rejected by PF",
+                self.config.mtu
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Requests the PF to add or remove vlan id `vlan_id` to or from the vlan filter of this VF.
+    fn set_vlan_filter(&self, vlan_id: u16, add: bool) -> Result<(), IxyError> {
+        offload::check_vlan_id(vlan_id)?;
+
+        let mut msg = [
+            IXGBE_VF_SET_VLAN | u32::from(add) << IXGBE_VT_MSGINFO_SHIFT,
+            u32::from(vlan_id),
+        ];
+
+        self.wait_write_read_msg_mbx(&mut msg)?;
+
+        // the reply still holds whether the vlan was added
+        msg[0] &= !(IXGBE_VT_MSGTYPE_CTS | IXGBE_VT_MSGINFO_MASK);
+
+        if msg[0] != (IXGBE_VF_SET_VLAN | IXGBE_VT_MSGTYPE_ACK) {
+            return Err(IxyError::InvalidConfig(format!(
+                "vlan filter {} rejected by PF",
+                vlan_id
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Requests the PF to switch the rx mode of this VF to promisc, all-multicast or only
+    /// subscribed multicast addresses, which requires mailbox API 1.2 or 1.3 for promisc mode.
+    fn update_xcast_mode(&self, promisc: bool, all_multicast: bool) -> Result<(), IxyError> {
+        let api_version = self.mbx.borrow().api_version;
+        let supported = match api_version {
+            ixgbe_pfvf_api_rev::ixgbe_mbox_api_13 => true,
+            ixgbe_pfvf_api_rev::ixgbe_mbox_api_12 => !promisc,
+            _ => false,
+        };
+        if !supported {
+            return Err(IxyError::InvalidConfig(format!(
+                "mailbox api {:?} does not support changing the rx mode",
+                api_version
+            )));
+        }
+
+        let mode = if promisc {
+            ixgbevf_xcast_modes::IXGBEVF_XCAST_MODE_PROMISC
+        } else if all_multicast {
+            ixgbevf_xcast_modes::IXGBEVF_XCAST_MODE_ALLMULTI
+        } else {
+            ixgbevf_xcast_modes::IXGBEVF_XCAST_MODE_MULTI
+        };
+        let mut msg = [IXGBE_VF_UPDATE_XCAST_MODE, mode as u32];
+
+        self.wait_write_read_msg_mbx(&mut msg)?;
+
+        msg[0] &= !IXGBE_VT_MSGTYPE_CTS;
+
+        if msg[0] != (IXGBE_VF_UPDATE_XCAST_MODE | IXGBE_VT_MSGTYPE_ACK) {
+            return Err(IxyError::InvalidConfig(
+                "rx mode rejected by PF, is the VF trusted?".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Requests the PF to set the secondary unicast address `index` of this VF to `mac`, index 0
+    /// clears all secondary addresses.
+    fn set_macvlan(&self, index: usize, mac: [u8; 6]) -> Result<(), IxyError> {
+        let mut msg = [
+            IXGBE_VF_SET_MACVLAN | (index as u32) << IXGBE_VT_MSGINFO_SHIFT,
+            0,
+            0,
+        ];
+
+        msg[1] = u32::from(mac[0])
+            + (u32::from(mac[1]) << 8)
+            + (u32::from(mac[2]) << 16)
+            + (u32::from(mac[3]) << 24);
+        msg[2] = u32::from(mac[4]) + (u32::from(mac[5]) << 8);
+
+        self.wait_write_read_msg_mbx(&mut msg)?;
+
+        msg[0] &= !(IXGBE_VT_MSGTYPE_CTS | IXGBE_VT_MSGINFO_MASK);
+
+        if msg[0] != (IXGBE_VF_SET_MACVLAN | IXGBE_VT_MSGTYPE_ACK) {
+            return Err(IxyError::InvalidConfig(format!(
+                "mac address {} rejected by PF",
+                crate::format_mac_addr(mac)
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Requests the PF to replace the secondary unicast addresses of this VF with `mac_addrs`.
+    fn set_macvlans(&self, mac_addrs: &[[u8; 6]]) -> Result<(), IxyError> {
+        // the PF can only clear all secondary addresses at once
+        self.set_macvlan(0, [0; 6])?;
+        for (i, &addr) in mac_addrs.iter().enumerate() {
+            self.set_macvlan(i + 1, addr)?;
+        }
+
+        Ok(())
+    }
+
+    /// Sends the multicast table hashes of `multicast_addrs` to the PF, replacing the previous
+    /// ones.
+    fn set_multicast_list(&self, multicast_addrs: &[[u8; 6]]) -> Result<(), IxyError> {
+        let mut msg = [0; IXGBE_VFMAILBOX_SIZE as usize];
+        msg[0] = IXGBE_VF_SET_MULTICAST | (multicast_addrs.len() as u32) << IXGBE_VT_MSGINFO_SHIFT;
+
+        // the 12 bit hashes are packed as two 16 bit values per word
+        for (i, &mac) in multicast_addrs.iter().enumerate() {
+            msg[1 + i / 2] |= mta_vector(mac) << (16 * (i % 2));
+        }
+
+        self.wait_write_read_msg_mbx(&mut msg)?;
+
+        msg[0] &= !(IXGBE_VT_MSGTYPE_CTS | IXGBE_VT_MSGINFO_MASK);
+
+        if msg[0] != (IXGBE_VF_SET_MULTICAST | IXGBE_VT_MSGTYPE_ACK) {
+            return Err(IxyError::InvalidConfig(
+                "multicast addresses rejected by PF".to_string(),
+            ));
+        }
+
+        Ok(())
+    }
+
     /// Initializes the mac address of this device appropriately, i.e. by
     /// using the PF set mac address or generating a new one.
-    fn init_mac_addr(&mut self) -> Result<(), Box<dyn Error>> {
+    fn init_mac_addr(&mut self) -> Result<(), IxyError> {
         // permanent address
         let mut msg_buf = [3; IXGBE_VF_PERMADDR_MSG_LEN as usize];
         self.wait_read_msg_from_mbx(&mut msg_buf)?;
@@ -549,7 +1031,7 @@ impl IxgbeVFDevice {
         if msg_buf[0] != (IXGBE_VF_RESET | IXGBE_VT_MSGTYPE_ACK)
             && msg_buf[0] != (IXGBE_VF_RESET | IXGBE_VT_MSGTYPE_NACK)
         {
-            return Err("invalid mac address".into());
+            return Err(IxyError::Mailbox("invalid reply to reset, no mac address"));
         }
 
         if msg_buf[0] == (IXGBE_VF_RESET | IXGBE_VT_MSGTYPE_ACK) {
@@ -589,7 +1071,7 @@ impl IxgbeVFDevice {
 
     // sections 4.6.7
     /// Initializes the rx queues of this device.
-    fn init_rx(&mut self) -> Result<(), Box<dyn Error>> {
+    fn init_rx(&mut self) -> Result<(), IxyError> {
         // configure queues, same for all queues
         for i in 0..self.num_rx_queues {
             debug!("initializing rx queue {}", i);
@@ -601,10 +1083,21 @@ impl IxgbeVFDevice {
             );
             // let nic drop packets if no rx descriptor is available instead of buffering them
             self.set_flags32(IXGBE_VFSRRCTL(u32::from(i)), IXGBE_SRRCTL_DROP_EN);
+            // strip vlan tags and report them in the descriptor
+            if self.config.vlan_strip {
+                self.set_flags32(IXGBE_VFRXDCTL(u32::from(i)), IXGBE_RXDCTL_VME);
+            } else {
+                self.clear_flags32(IXGBE_VFRXDCTL(u32::from(i)), IXGBE_RXDCTL_VME);
+            }
+            // receive buffer size in 1 KB units
+            self.set_reg32(
+                IXGBE_VFSRRCTL(u32::from(i)),
+                (self.get_reg32(IXGBE_VFSRRCTL(u32::from(i))) & !IXGBE_SRRCTL_BSIZEPKT_MASK)
+                    | (self.config.buffer_size >> IXGBE_SRRCTL_BSIZEPKT_SHIFT) as u32,
+            );
 
             // section 7.1.9 - setup descriptor ring
-            let ring_size_bytes =
-                (NUM_RX_QUEUE_ENTRIES) as usize * mem::size_of::<ixgbe_adv_rx_desc>();
+            let ring_size_bytes = self.config.rx_ring_size * mem::size_of::<ixgbe_adv_rx_desc>();
 
             let dma: Dma<ixgbe_adv_rx_desc> = Dma::allocate(ring_size_bytes, true)?;
 
@@ -627,20 +1120,18 @@ impl IxgbeVFDevice {
             self.set_reg32(IXGBE_VFRDH(u32::from(i)), 0);
             self.set_reg32(IXGBE_VFRDT(u32::from(i)), 0);
 
-            let mempool_size = if NUM_RX_QUEUE_ENTRIES + NUM_TX_QUEUE_ENTRIES < MIN_MEMPOOL_SIZE {
-                MIN_MEMPOOL_SIZE
-            } else {
-                NUM_RX_QUEUE_ENTRIES + NUM_TX_QUEUE_ENTRIES
-            };
-
-            let mempool = Mempool::allocate(mempool_size as usize, PKT_BUF_ENTRY_SIZE).unwrap();
+            let mempool =
+                Mempool::allocate(self.config.rx_mempool_size(), self.config.buffer_size)?;
 
             let rx_queue = IxgbeRxQueue {
                 descriptors: dma.virt,
                 pool: mempool,
-                num_descriptors: NUM_RX_QUEUE_ENTRIES,
+                num_descriptors: self.config.rx_ring_size,
                 rx_index: 0,
-                bufs_in_use: Vec::with_capacity(NUM_RX_QUEUE_ENTRIES),
+                bufs_in_use: Vec::with_capacity(self.config.rx_ring_size),
+                spare: Vec::new(),
+                partial: None,
+                stats: RxQueueStats::default(),
             };
 
             self.rx_queues.push(rx_queue);
@@ -656,13 +1147,12 @@ impl IxgbeVFDevice {
 
     // section 4.6.8
     /// Initializes the tx queues of this device.
-    fn init_tx(&mut self) -> Result<(), Box<dyn Error>> {
+    fn init_tx(&mut self) -> Result<(), IxyError> {
         // configure queues
         for i in 0..self.num_tx_queues {
             debug!("initializing tx queue {}", i);
             // section 7.1.9 - setup descriptor ring
-            let ring_size_bytes =
-                NUM_TX_QUEUE_ENTRIES as usize * mem::size_of::<ixgbe_adv_tx_desc>();
+            let ring_size_bytes = self.config.tx_ring_size * mem::size_of::<ixgbe_adv_tx_desc>();
 
             let dma: Dma<ixgbe_adv_tx_desc> = Dma::allocate(ring_size_bytes, true)?;
             unsafe {
@@ -692,11 +1182,12 @@ impl IxgbeVFDevice {
 
             let tx_queue = IxgbeTxQueue {
                 descriptors: dma.virt,
-                bufs_in_use: VecDeque::with_capacity(NUM_TX_QUEUE_ENTRIES),
-                pool: None,
-                num_descriptors: NUM_TX_QUEUE_ENTRIES,
+                bufs_in_use: VecDeque::with_capacity(self.config.tx_ring_size),
+                num_descriptors: self.config.tx_ring_size,
                 clean_index: 0,
                 tx_index: 0,
+                context: None,
+                stats: TxQueueStats::default(),
             };
 
             self.tx_queues.push(tx_queue);
@@ -706,13 +1197,15 @@ impl IxgbeVFDevice {
     }
 
     /// Sets the rx queues` descriptors and enables the queues.
-    fn start_rx_queue(&mut self, queue_id: u16) -> Result<(), Box<dyn Error>> {
+    fn start_rx_queue(&mut self, queue_id: u16) -> Result<(), IxyError> {
         debug!("starting rx queue {}", queue_id);
 
         let queue = &mut self.rx_queues[queue_id as usize];
 
         if queue.num_descriptors & (queue.num_descriptors - 1) != 0 {
-            return Err("number of queue entries must be a power of 2".into());
+            return Err(IxyError::InvalidConfig(
+                "number of queue entries must be a power of 2".to_string(),
+            ));
         }
 
         for i in 0..queue.num_descriptors {
@@ -720,7 +1213,7 @@ impl IxgbeVFDevice {
 
             let buf = match pool.alloc_buf() {
                 Some(x) => x,
-                None => return Err("failed to allocate rx descriptor".into()),
+                None => return Err(IxyError::MempoolExhausted),
             };
 
             unsafe {
@@ -758,13 +1251,15 @@ impl IxgbeVFDevice {
     }
 
     /// Enables the tx queues.
-    fn start_tx_queue(&mut self, queue_id: u16) -> Result<(), Box<dyn Error>> {
+    fn start_tx_queue(&mut self, queue_id: u16) -> Result<(), IxyError> {
         debug!("starting tx queue {}", queue_id);
 
         let queue = &mut self.tx_queues[queue_id as usize];
 
         if queue.num_descriptors & (queue.num_descriptors - 1) != 0 {
-            return Err("number of queue entries must be a power of 2".into());
+            return Err(IxyError::InvalidConfig(
+                "number of queue entries must be a power of 2".to_string(),
+            ));
         }
 
         // tx queue starts out empty
@@ -778,12 +1273,6 @@ impl IxgbeVFDevice {
         Ok(())
     }
 
-    /// Enables or disables promiscuous mode of this device.
-    #[allow(dead_code)]
-    fn set_promisc(&self, _enabled: bool) {
-        unimplemented!("PF driver do not support promiscuous mode for VFs yet, see chapter 7.1 in the Intel 82599 SR-IOV driver companion guide");
-    }
-
     /// Returns the register at `self.addr` + `reg`.
     ///
     /// # Panics
@@ -884,7 +1373,7 @@ impl IxgbeVFDevice {
     }
 
     /// Waits for reset from PF.
-    fn wait_check_for_rst(&mut self) -> Result<(), Box<dyn Error>> {
+    fn wait_check_for_rst(&mut self) -> Result<(), IxyError> {
         let mut countdown = self.mbx.borrow().timeout;
 
         while countdown > 0 && !self.check_for_rst() {
@@ -893,7 +1382,7 @@ impl IxgbeVFDevice {
         }
 
         if countdown == 0 {
-            Err("timeout while checking for reset".into())
+            Err(IxyError::MailboxTimeout("checking for reset"))
         } else {
             Ok(())
         }
@@ -949,7 +1438,7 @@ impl IxgbeVFDevice {
     }
 
     /// Writes a message to the mailbox, waits for ack, reads a message from the mailbox.
-    fn wait_write_read_msg_mbx(&self, msg: &mut [u32]) -> Result<(), Box<dyn Error>> {
+    fn wait_write_read_msg_mbx(&self, msg: &mut [u32]) -> Result<(), IxyError> {
         self.wait_write_msg_to_mbx(msg)?;
         self.wait_read_msg_from_mbx(msg)?;
 
@@ -957,7 +1446,7 @@ impl IxgbeVFDevice {
     }
 
     /// Writes a message to the mailbox, waits for ack.
-    fn wait_write_msg_to_mbx(&self, msg: &[u32]) -> Result<(), Box<dyn Error>> {
+    fn wait_write_msg_to_mbx(&self, msg: &[u32]) -> Result<(), IxyError> {
         self.write_msg_to_mbx(msg)?;
         self.wait_for_ack()?;
 
@@ -965,7 +1454,7 @@ impl IxgbeVFDevice {
     }
 
     /// Waits for ack from PF.
-    fn wait_for_ack(&self) -> Result<(), Box<dyn Error>> {
+    fn wait_for_ack(&self) -> Result<(), IxyError> {
         let mut countdown = self.mbx.borrow().timeout;
 
         while countdown > 0 && self.check_for_ack() {
@@ -974,14 +1463,14 @@ impl IxgbeVFDevice {
         }
 
         if countdown == 0 {
-            Err("timeout while polling for ack".into())
+            Err(IxyError::MailboxTimeout("polling for ack"))
         } else {
             Ok(())
         }
     }
 
     /// Waits for message from PF.
-    fn wait_for_msg(&self) -> Result<(), Box<dyn Error>> {
+    fn wait_for_msg(&self) -> Result<(), IxyError> {
         let mut countdown = self.mbx.borrow().timeout;
 
         while countdown > 0 && self.check_for_msg() {
@@ -990,14 +1479,14 @@ impl IxgbeVFDevice {
         }
 
         if countdown == 0 {
-            Err("timeout while polling for message".into())
+            Err(IxyError::MailboxTimeout("polling for message"))
         } else {
             Ok(())
         }
     }
 
     /// Writes a message to the mailbox.
-    fn write_msg_to_mbx(&self, msg: &[u32]) -> Result<(), Box<dyn Error>> {
+    fn write_msg_to_mbx(&self, msg: &[u32]) -> Result<(), IxyError> {
         assert!(
             msg.len() <= self.mbx.borrow().size as usize,
             "invalid mailbox message size"
@@ -1025,7 +1514,7 @@ impl IxgbeVFDevice {
     }
 
     /// Receives (and waits for) a message from the mailbox.
-    fn wait_read_msg_from_mbx(&self, msg: &mut [u32]) -> Result<(), Box<dyn Error>> {
+    fn wait_read_msg_from_mbx(&self, msg: &mut [u32]) -> Result<(), IxyError> {
         self.wait_for_msg()?;
         self.read_msg_from_mbx(msg)?;
 
@@ -1033,7 +1522,7 @@ impl IxgbeVFDevice {
     }
 
     /// Reads a message from the mailbox.
-    fn read_msg_from_mbx(&self, msg: &mut [u32]) -> Result<(), Box<dyn Error>> {
+    fn read_msg_from_mbx(&self, msg: &mut [u32]) -> Result<(), IxyError> {
         let len = min(msg.len(), self.mbx.borrow().size as usize);
 
         // lock mailbox to prevent pf/vf race condition
@@ -1054,7 +1543,7 @@ impl IxgbeVFDevice {
     }
 
     /// Obtains the mailbox lock.
-    fn obtain_mbx_lock(&self) -> Result<(), Box<dyn Error>> {
+    fn obtain_mbx_lock(&self) -> Result<(), IxyError> {
         // take ownership of the buffer
         self.set_reg32(IXGBE_VFMAILBOX, IXGBE_VFMAILBOX_VFU);
 
@@ -1062,13 +1551,13 @@ impl IxgbeVFDevice {
         if (self.read_v2p_mbx() & IXGBE_VFMAILBOX_VFU) != 0x0 {
             Ok(())
         } else {
-            Err("failed to obtain mailbox lock".into())
+            Err(IxyError::Mailbox("failed to obtain mailbox lock"))
         }
     }
 }
 
-/// Removes multiples of `TX_CLEAN_BATCH` packets from `queue`.
-fn clean_tx_queue(queue: &mut IxgbeTxQueue) -> usize {
+/// Removes multiples of `batch` packets from `queue`.
+fn clean_tx_queue(queue: &mut IxgbeTxQueue, batch: usize) -> usize {
     let mut clean_index = queue.clean_index;
     let cur_index = queue.tx_index;
 
@@ -1079,11 +1568,18 @@ fn clean_tx_queue(queue: &mut IxgbeTxQueue) -> usize {
             cleanable += queue.num_descriptors as i32;
         }
 
-        if cleanable < TX_CLEAN_BATCH as i32 {
+        if cleanable < batch as i32 {
             break;
         }
 
-        let mut cleanup_to = clean_index + TX_CLEAN_BATCH - 1;
+        // context descriptors are not written back, the batch is extended to the data
+        // descriptor that follows one
+        let num_cleaned = match queue.bufs_in_use.get(batch - 1) {
+            Some(None) => batch + 1,
+            _ => batch,
+        };
+
+        let mut cleanup_to = clean_index + num_cleaned - 1;
 
         if cleanup_to >= queue.num_descriptors {
             cleanup_to -= queue.num_descriptors;
@@ -1094,17 +1590,10 @@ fn clean_tx_queue(queue: &mut IxgbeTxQueue) -> usize {
         };
 
         if (status & IXGBE_ADVTXD_STAT_DD) != 0 {
-            if let Some(ref p) = queue.pool {
-                if TX_CLEAN_BATCH as usize >= queue.bufs_in_use.len() {
-                    p.free_stack
-                        .borrow_mut()
-                        .extend(queue.bufs_in_use.drain(..))
-                } else {
-                    p.free_stack
-                        .borrow_mut()
-                        .extend(queue.bufs_in_use.drain(..TX_CLEAN_BATCH))
-                }
-            }
+            // dropping the packets returns their buffers to the pools they were allocated from
+            let num_cleaned = num_cleaned.min(queue.bufs_in_use.len());
+            free_pkt_batch(queue.bufs_in_use.drain(..num_cleaned).flatten());
+            queue.stats.clean_batches += 1;
 
             clean_index = wrap_ring(cleanup_to, queue.num_descriptors);
         } else {
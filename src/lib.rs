@@ -11,25 +11,39 @@ extern crate log;
 
 #[rustfmt::skip]
 mod constants;
+mod config;
+mod error;
+pub mod fdir;
 mod interrupts;
 mod ixgbe;
 mod ixgbevf;
+pub mod loopback;
 pub mod memory;
+pub mod offload;
 mod pci;
+pub mod rss;
+pub mod stats;
+pub mod timestamp;
 mod vfio;
 mod virtio;
 #[rustfmt::skip]
 mod virtio_constants;
 
+pub use self::config::DeviceConfig;
+pub use self::error::IxyError;
+pub use self::rss::{RssConfig, RssHashFields};
+pub use self::stats::{ExtendedStats, RxQueueStats, TxQueueStats};
+
+use self::fdir::{FdirStats, FilterAction, FlowFilter, FlowFilterEntry};
 use self::interrupts::*;
 use self::ixgbe::*;
 use self::ixgbevf::*;
 use self::memory::*;
 use self::pci::*;
+use self::timestamp::Timestamp;
 use self::virtio::VirtioDevice;
 
 use std::collections::VecDeque;
-use std::error::Error;
 use std::os::unix::io::RawFd;
 
 /// Used for implementing an ixy device driver like ixgbe or virtio.
@@ -91,7 +105,11 @@ pub trait IxyDevice {
     /// ```
     fn tx_batch(&mut self, queue_id: u16, buffer: &mut VecDeque<Packet>) -> usize;
 
-    /// Reads the network card's stats registers into `stats`.
+    /// Overwrites `stats` with the totals of the network card's stats since the last
+    /// [`reset_stats`](IxyDevice::reset_stats).
+    ///
+    /// The driver keeps the totals, so any number of readers can read them without taking
+    /// counts from each other.
     ///
     /// # Examples
     ///
@@ -105,7 +123,7 @@ pub trait IxyDevice {
     /// ```
     fn read_stats(&self, stats: &mut DeviceStats);
 
-    /// Resets the network card's stats registers.
+    /// Resets the totals of the network card's stats to zero.
     ///
     /// # Examples
     ///
@@ -136,8 +154,281 @@ pub trait IxyDevice {
             self.tx_batch(queue_id, buffer);
         }
     }
+
+    /// Splits this device into a handle for device wide operations and one pair of rx and tx
+    /// queue handles per queue. Each queue handle can be moved to its own thread.
+    ///
+    /// Requires the same number of rx and tx queues and polling mode. Packets received before or
+    /// after splitting can be kept, forwarded or dropped on any thread.
+    ///
+    /// Fails with [`IxyError::InvalidConfig`] if the driver does not support splitting queues
+    /// or the requirements above are not met.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use ixy::*;
+    /// use ixy::memory::Packet;
+    /// use std::collections::VecDeque;
+    /// use std::thread;
+    ///
+    /// let dev = ixy_init("0000:01:00.0", 2, 2, 0).unwrap();
+    /// let (control, queues) = dev.split_queues().unwrap();
+    ///
+    /// for (mut rx, mut tx) in queues {
+    ///     thread::spawn(move || {
+    ///         let mut buf: VecDeque<Packet> = VecDeque::new();
+    ///         loop {
+    ///             rx.rx_batch(&mut buf, 32);
+    ///             tx.tx_batch(&mut buf);
+    ///             buf.clear();
+    ///         }
+    ///     });
+    /// }
+    ///
+    /// println!("Link speed is {} Mbit/s", control.get_link_speed());
+    /// ```
+    fn split_queues(self: Box<Self>) -> Result<SplitQueues, IxyError> {
+        Err(IxyError::InvalidConfig(format!(
+            "{} does not support splitting queues",
+            self.get_driver_name()
+        )))
+    }
+
+    /// Adds a flow director filter that applies `action` to received packets matching `filter`.
+    /// Returns the id of the filter.
+    ///
+    /// The flow director has to be enabled with [`DeviceConfig::flow_director`], see
+    /// [`FlowFilter`](fdir::FlowFilter) for an example.
+    fn add_flow_filter(
+        &mut self,
+        _filter: &FlowFilter,
+        _action: FilterAction,
+    ) -> Result<u16, IxyError> {
+        Err(IxyError::InvalidConfig(format!(
+            "{} does not support flow director filters",
+            self.get_driver_name()
+        )))
+    }
+
+    /// Removes the flow director filter with id `id`.
+    fn remove_flow_filter(&mut self, id: u16) -> Result<(), IxyError> {
+        Err(IxyError::InvalidConfig(format!(
+            "{} has no flow director filter {}",
+            self.get_driver_name(),
+            id
+        )))
+    }
+
+    /// Returns all flow director filters of this device ordered by their id.
+    fn flow_filters(&self) -> Vec<FlowFilterEntry> {
+        Vec::new()
+    }
+
+    /// Reads the flow director's stats into `stats`, in the same way as
+    /// [`read_stats`](IxyDevice::read_stats).
+    fn read_fdir_stats(&self, _stats: &mut FdirStats) {}
+
+    /// Reads the drop, error and per-queue counters of this device into `stats`, in the same
+    /// way as [`read_stats`](IxyDevice::read_stats).
+    fn read_extended_stats(&self, _stats: &mut ExtendedStats) {}
+
+    /// Returns the counters the driver kept for rx queue `queue_id` since the device was
+    /// initialized, [`None`] if there is no such queue.
+    ///
+    /// # Examples
+    ///
+    /// ```rust,no_run
+    /// use ixy::*;
+    ///
+    /// let dev = ixy_init("0000:01:00.0", 1, 1, 0).unwrap();
+    /// if let Some(stats) = dev.rx_queue_stats(0) {
+    ///     println!("{} empty polls", stats.empty_polls);
+    /// }
+    /// ```
+    fn rx_queue_stats(&self, _queue_id: u16) -> Option<RxQueueStats> {
+        None
+    }
+
+    /// Returns the counters the driver kept for tx queue `queue_id` since the device was
+    /// initialized, [`None`] if there is no such queue.
+    fn tx_queue_stats(&self, _queue_id: u16) -> Option<TxQueueStats> {
+        None
+    }
+
+    /// Accepts received packets tagged with vlan id `vlan_id`.
+    ///
+    /// Once a vlan filter was added, the device drops received tagged packets whose vlan id has
+    /// no filter. Untagged packets are not affected.
+    fn add_vlan_filter(&mut self, _vlan_id: u16) -> Result<(), IxyError> {
+        Err(IxyError::InvalidConfig(format!(
+            "{} does not support vlan filters",
+            self.get_driver_name()
+        )))
+    }
+
+    /// Removes the vlan filter for vlan id `vlan_id`.
+    fn remove_vlan_filter(&mut self, vlan_id: u16) -> Result<(), IxyError> {
+        Err(IxyError::InvalidConfig(format!(
+            "{} has no vlan filter {}",
+            self.get_driver_name(),
+            vlan_id
+        )))
+    }
+
+    /// Enables or disables promiscuous mode, i.e. receiving packets regardless of their
+    /// destination address.
+    fn set_promiscuous(&mut self, _enabled: bool) -> Result<(), IxyError> {
+        Err(IxyError::InvalidConfig(format!(
+            "{} does not support changing promiscuous mode",
+            self.get_driver_name()
+        )))
+    }
+
+    /// Enables or disables receiving all multicast packets, not only those sent to subscribed
+    /// multicast addresses.
+    fn set_all_multicast(&mut self, _enabled: bool) -> Result<(), IxyError> {
+        Err(IxyError::InvalidConfig(format!(
+            "{} does not support changing all-multicast mode",
+            self.get_driver_name()
+        )))
+    }
+
+    /// Accepts received packets sent to the secondary unicast address `mac`.
+    fn add_mac_addr(&mut self, _mac: [u8; 6]) -> Result<(), IxyError> {
+        Err(IxyError::InvalidConfig(format!(
+            "{} does not support secondary mac addresses",
+            self.get_driver_name()
+        )))
+    }
+
+    /// Removes the secondary unicast address `mac`.
+    fn remove_mac_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        Err(IxyError::InvalidConfig(format!(
+            "{} has no mac address {}",
+            self.get_driver_name(),
+            format_mac_addr(mac)
+        )))
+    }
+
+    /// Subscribes to packets sent to the multicast address `mac`.
+    fn add_multicast_addr(&mut self, _mac: [u8; 6]) -> Result<(), IxyError> {
+        Err(IxyError::InvalidConfig(format!(
+            "{} does not support multicast filters",
+            self.get_driver_name()
+        )))
+    }
+
+    /// Unsubscribes from the multicast address `mac`.
+    fn remove_multicast_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        Err(IxyError::InvalidConfig(format!(
+            "{} has no multicast address {}",
+            self.get_driver_name(),
+            format_mac_addr(mac)
+        )))
+    }
+
+    /// Returns the timestamp of the last sent packet that requested one with
+    /// [`Packet::set_tx_timestamp`], [`None`] if it was not sent yet.
+    ///
+    /// Only one timestamp is held at a time, packets sent before the timestamp was read are not
+    /// timestamped.
+    fn read_tx_timestamp(&mut self) -> Option<Timestamp> {
+        None
+    }
+
+    /// Returns the time of the device's clock in nanoseconds.
+    fn read_clock(&self) -> Result<u64, IxyError> {
+        Err(IxyError::InvalidConfig(format!(
+            "{} has no hardware clock",
+            self.get_driver_name()
+        )))
+    }
+
+    /// Sets the device's clock to `nanos`.
+    fn set_clock(&mut self, _nanos: u64) -> Result<(), IxyError> {
+        Err(IxyError::InvalidConfig(format!(
+            "{} has no hardware clock",
+            self.get_driver_name()
+        )))
+    }
+
+    /// Adds `delta` nanoseconds to the device's clock.
+    fn adjust_clock(&mut self, _delta: i64) -> Result<(), IxyError> {
+        Err(IxyError::InvalidConfig(format!(
+            "{} has no hardware clock",
+            self.get_driver_name()
+        )))
+    }
+}
+
+/// Device wide operations of a device that was split with [`IxyDevice::split_queues`].
+pub trait DeviceControl: Send {
+    /// Returns the driver's name.
+    fn get_driver_name(&self) -> &str;
+
+    /// Returns the pci address of this device.
+    fn get_pci_addr(&self) -> &str;
+
+    /// Returns the layer 2 address of this device.
+    fn get_mac_addr(&self) -> [u8; 6];
+
+    /// Sets the layer 2 address of this device.
+    fn set_mac_addr(&self, mac: [u8; 6]);
+
+    /// Overwrites `stats` with the totals of the network card's stats since the last reset, see
+    /// [`IxyDevice::read_stats`]. The driver keeps the totals up to date while the queues are
+    /// polled.
+    fn read_stats(&self, stats: &mut DeviceStats);
+
+    /// Resets the totals of the network card's stats to zero.
+    fn reset_stats(&mut self);
+
+    /// Returns the network card's link speed.
+    fn get_link_speed(&self) -> u16;
+}
+
+/// A single rx queue of a device that was split with [`IxyDevice::split_queues`].
+pub trait RxQueue: Send {
+    /// Returns the id of this queue.
+    fn queue_id(&self) -> u16;
+
+    /// Pushes up to `num_packets` received `Packet`s onto `buffer`, see
+    /// [`IxyDevice::rx_batch`].
+    fn rx_batch(&mut self, buffer: &mut VecDeque<Packet>, num_packets: usize) -> usize;
+
+    /// Returns the counters the driver kept for this queue, see [`IxyDevice::rx_queue_stats`].
+    fn stats(&self) -> RxQueueStats;
+}
+
+/// A single tx queue of a device that was split with [`IxyDevice::split_queues`].
+pub trait TxQueue: Send {
+    /// Returns the id of this queue.
+    fn queue_id(&self) -> u16;
+
+    /// Takes `Packet`s out of `buffer` until `buffer` is empty or the queue is full, see
+    /// [`IxyDevice::tx_batch`].
+    fn tx_batch(&mut self, buffer: &mut VecDeque<Packet>) -> usize;
+
+    /// Returns the counters the driver kept for this queue, see [`IxyDevice::tx_queue_stats`].
+    fn stats(&self) -> TxQueueStats;
+
+    /// Takes `Packet`s out of `buffer` to send out. This will busy wait until all packets from
+    /// `buffer` are queued.
+    fn tx_batch_busy_wait(&mut self, buffer: &mut VecDeque<Packet>) {
+        while !buffer.is_empty() {
+            self.tx_batch(buffer);
+        }
+    }
 }
 
+pub type ControlHandle = Box<dyn DeviceControl>;
+pub type RxQueueHandle = Box<dyn RxQueue>;
+pub type TxQueueHandle = Box<dyn TxQueue>;
+
+/// The handles returned by [`IxyDevice::split_queues`], the queue handles are ordered by their id.
+pub type SplitQueues = (ControlHandle, Vec<(RxQueueHandle, TxQueueHandle)>);
+
 /// Holds network card stats about sent and received packets.
 #[derive(Default, Copy, Clone)]
 pub struct DeviceStats {
@@ -195,51 +486,90 @@ impl DeviceStats {
 /// Initializes the network card at `pci_addr`.
 ///
 /// `rx_queues` and `tx_queues` specify the number of queues that will be initialized and used
-/// while `interrupt_timeout` enables interrupts if greater or less than zero.
+/// while `interrupt_timeout` enables interrupts if greater or less than zero. All other settings
+/// use the defaults of [`DeviceConfig`].
 pub fn ixy_init(
     pci_addr: &str,
     rx_queues: u16,
     tx_queues: u16,
     interrupt_timeout: i16,
-) -> Result<Box<dyn IxyDevice>, Box<dyn Error>> {
-    let mut vendor_file = pci_open_resource_ro(pci_addr, "vendor").expect("wrong pci address");
-    let mut device_file = pci_open_resource_ro(pci_addr, "device").expect("wrong pci address");
-    let mut config_file = pci_open_resource_ro(pci_addr, "config").expect("wrong pci address");
+) -> Result<Box<dyn IxyDevice>, IxyError> {
+    let config = DeviceConfig::new()
+        .num_rx_queues(rx_queues)
+        .num_tx_queues(tx_queues)
+        .interrupt_timeout(interrupt_timeout);
 
-    let vendor_id = read_hex(&mut vendor_file)?;
-    let device_id = read_hex(&mut device_file)?;
-    let class_id = read_io32(&mut config_file, 8)? >> 24;
+    ixy_init_with_config(pci_addr, &config)
+}
+
+/// Initializes the network card at `pci_addr` with the settings in `config`.
+pub fn ixy_init_with_config(
+    pci_addr: &str,
+    config: &DeviceConfig,
+) -> Result<Box<dyn IxyDevice>, IxyError> {
+    let mut vendor_file = pci_open_resource_ro(pci_addr, "vendor")?;
+    let mut device_file = pci_open_resource_ro(pci_addr, "device")?;
+    let mut config_file = pci_open_resource_ro(pci_addr, "config")?;
+
+    let vendor_id = read_hex(&mut vendor_file).map_err(pci_error(pci_addr))?;
+    let device_id = read_hex(&mut device_file).map_err(pci_error(pci_addr))?;
+    let class_id = read_io32(&mut config_file, 8).map_err(pci_error(pci_addr))? >> 24;
 
     if class_id != 2 {
-        return Err(format!("device {} is not a network card", pci_addr).into());
+        return Err(IxyError::UnsupportedDevice {
+            pci_addr: pci_addr.to_string(),
+            reason: "not a network card".to_string(),
+        });
     }
 
-    if vendor_id == 0x1af4 && device_id == 0x1000 {
-        // `device_id == 0x1041` would be for non-transitional devices which we don't support atm
-        if rx_queues > 1 || tx_queues > 1 {
-            warn!("cannot configure multiple rx/tx queues: we don't support multiqueue (VIRTIO_NET_F_MQ)");
-        }
-        if interrupt_timeout != 0 {
-            warn!("interrupts requested but virtio does not support interrupts yet");
-        }
-        let device = VirtioDevice::init(pci_addr)?;
+    // transitional (0x1000) and modern (0x1041) virtio network devices
+    if vendor_id == 0x1af4 && (device_id == 0x1000 || device_id == 0x1041) {
+        let device = VirtioDevice::init(pci_addr, config)?;
         Ok(Box::new(device))
     } else if vendor_id == 0x8086
         && (device_id == 0x10ed || device_id == 0x1515 || device_id == 0x1565)
     {
         // looks like a virtual function
-        if interrupt_timeout != 0 {
+        if config.interrupt_timeout != 0 {
             warn!("interrupts requested but ixgbevf does not support interrupts yet");
         }
-        let device = IxgbeVFDevice::init(pci_addr, rx_queues, tx_queues)?;
+        let device = IxgbeVFDevice::init(pci_addr, config)?;
         Ok(Box::new(device))
     } else {
         // let's give it a try with ixgbe
-        let device = IxgbeDevice::init(pci_addr, rx_queues, tx_queues, interrupt_timeout)?;
+        let device = IxgbeDevice::init(pci_addr, config)?;
         Ok(Box::new(device))
     }
 }
 
+/// Formats `mac` as colon separated hex bytes.
+pub(crate) fn format_mac_addr(mac: [u8; 6]) -> String {
+    format!(
+        "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+    )
+}
+
+/// Fails with [`IxyError::InvalidConfig`] if `mac` is not a unicast address, or not a multicast
+/// address if `multicast` is set.
+pub(crate) fn check_mac_addr(mac: [u8; 6], multicast: bool) -> Result<(), IxyError> {
+    if mac == [0xff; 6] || mac == [0; 6] {
+        return Err(IxyError::InvalidConfig(format!(
+            "{} cannot be used as a filter address",
+            format_mac_addr(mac)
+        )));
+    }
+    if (mac[0] & 1 != 0) != multicast {
+        return Err(IxyError::InvalidConfig(format!(
+            "{} is not a {} address",
+            format_mac_addr(mac),
+            if multicast { "multicast" } else { "unicast" }
+        )));
+    }
+
+    Ok(())
+}
+
 impl IxyDevice for Box<dyn IxyDevice> {
     fn get_driver_name(&self) -> &str {
         (**self).get_driver_name()
@@ -289,4 +619,84 @@ impl IxyDevice for Box<dyn IxyDevice> {
     fn get_link_speed(&self) -> u16 {
         (**self).get_link_speed()
     }
+
+    fn add_flow_filter(
+        &mut self,
+        filter: &FlowFilter,
+        action: FilterAction,
+    ) -> Result<u16, IxyError> {
+        (**self).add_flow_filter(filter, action)
+    }
+
+    fn remove_flow_filter(&mut self, id: u16) -> Result<(), IxyError> {
+        (**self).remove_flow_filter(id)
+    }
+
+    fn flow_filters(&self) -> Vec<FlowFilterEntry> {
+        (**self).flow_filters()
+    }
+
+    fn read_fdir_stats(&self, stats: &mut FdirStats) {
+        (**self).read_fdir_stats(stats)
+    }
+
+    fn read_extended_stats(&self, stats: &mut ExtendedStats) {
+        (**self).read_extended_stats(stats)
+    }
+
+    fn rx_queue_stats(&self, queue_id: u16) -> Option<RxQueueStats> {
+        (**self).rx_queue_stats(queue_id)
+    }
+
+    fn tx_queue_stats(&self, queue_id: u16) -> Option<TxQueueStats> {
+        (**self).tx_queue_stats(queue_id)
+    }
+
+    fn add_vlan_filter(&mut self, vlan_id: u16) -> Result<(), IxyError> {
+        (**self).add_vlan_filter(vlan_id)
+    }
+
+    fn remove_vlan_filter(&mut self, vlan_id: u16) -> Result<(), IxyError> {
+        (**self).remove_vlan_filter(vlan_id)
+    }
+
+    fn set_promiscuous(&mut self, enabled: bool) -> Result<(), IxyError> {
+        (**self).set_promiscuous(enabled)
+    }
+
+    fn set_all_multicast(&mut self, enabled: bool) -> Result<(), IxyError> {
+        (**self).set_all_multicast(enabled)
+    }
+
+    fn add_mac_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        (**self).add_mac_addr(mac)
+    }
+
+    fn remove_mac_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        (**self).remove_mac_addr(mac)
+    }
+
+    fn add_multicast_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        (**self).add_multicast_addr(mac)
+    }
+
+    fn remove_multicast_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        (**self).remove_multicast_addr(mac)
+    }
+
+    fn read_tx_timestamp(&mut self) -> Option<Timestamp> {
+        (**self).read_tx_timestamp()
+    }
+
+    fn read_clock(&self) -> Result<u64, IxyError> {
+        (**self).read_clock()
+    }
+
+    fn set_clock(&mut self, nanos: u64) -> Result<(), IxyError> {
+        (**self).set_clock(nanos)
+    }
+
+    fn adjust_clock(&mut self, delta: i64) -> Result<(), IxyError> {
+        (**self).adjust_clock(delta)
+    }
 }
@@ -0,0 +1,554 @@
+//! A software-only device that is not backed by any hardware.
+//!
+//! Packets sent on a tx queue of a [`LoopbackDevice`] are received on the rx queue with the same
+//! id, either on the same device or on the other end of a [`LoopbackDevice::pair`]. This allows
+//! testing applications built on [`IxyDevice`] without a network card or huge pages.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::os::unix::io::RawFd;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::memory::{alloc_pkt, Mempool, Packet};
+use crate::offload;
+use crate::stats::{RxQueueStats, TxQueueStats};
+use crate::{DeviceStats, IxyDevice, IxyError};
+
+const DRIVER_NAME: &str = "ixy-loopback";
+
+const PKT_BUF_ENTRY_SIZE: usize = 2048;
+const MIN_MEMPOOL_SIZE: usize = 4096;
+
+const NUM_QUEUE_ENTRIES: usize = 512;
+
+static LOOPBACK_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// Decides whether a packet is dropped on the wire instead of being delivered.
+type DropFilter = Box<dyn FnMut(&Packet) -> bool>;
+
+pub struct LoopbackDevice {
+    name: String,
+    mac: Cell<[u8; 6]>,
+    rx_queues: Vec<LoopbackRxQueue>,
+    tx_queues: Vec<LoopbackTxQueue>,
+    drop_filter: Option<DropFilter>,
+    delay: Duration,
+
+    // statistics
+    rx_pkts: u64,
+    tx_pkts: u64,
+    rx_bytes: u64,
+    tx_bytes: u64,
+}
+
+struct LoopbackRxQueue {
+    pool: Arc<Mempool>,
+    wire: Rc<RefCell<Wire>>,
+    stats: RxQueueStats,
+}
+
+struct LoopbackTxQueue {
+    wire: Rc<RefCell<Wire>>,
+    stats: TxQueueStats,
+}
+
+/// Packets in flight between a tx queue and an rx queue together with the time they arrive.
+struct Wire {
+    packets: VecDeque<(Instant, Packet)>,
+    capacity: usize,
+}
+
+impl Wire {
+    fn new() -> Rc<RefCell<Wire>> {
+        Rc::new(RefCell::new(Wire {
+            packets: VecDeque::with_capacity(NUM_QUEUE_ENTRIES),
+            capacity: NUM_QUEUE_ENTRIES,
+        }))
+    }
+}
+
+impl IxyDevice for LoopbackDevice {
+    /// Returns the driver's name of this device.
+    fn get_driver_name(&self) -> &str {
+        DRIVER_NAME
+    }
+
+    /// Returns the card's iommu capability.
+    fn is_card_iommu_capable(&self) -> bool {
+        false
+    }
+
+    /// Returns VFIO container file descriptor or [`None`] if IOMMU is not available.
+    fn get_vfio_container(&self) -> Option<RawFd> {
+        None
+    }
+
+    /// Returns the name of this device as it has no pci address.
+    fn get_pci_addr(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the mac address of this device.
+    fn get_mac_addr(&self) -> [u8; 6] {
+        self.mac.get()
+    }
+
+    /// Sets the mac address of this device.
+    fn set_mac_addr(&self, mac: [u8; 6]) {
+        self.mac.set(mac);
+    }
+
+    /// Pushes up to `num_packets` packets that arrived on the wire onto `buffer`.
+    fn rx_batch(
+        &mut self,
+        queue_id: u16,
+        buffer: &mut VecDeque<Packet>,
+        num_packets: usize,
+    ) -> usize {
+        let queue = self
+            .rx_queues
+            .get_mut(queue_id as usize)
+            .expect("invalid rx queue id");
+
+        let mut wire = queue.wire.borrow_mut();
+        let now = Instant::now();
+        let mut received_packets = 0;
+
+        while received_packets < num_packets {
+            match wire.packets.front() {
+                Some((arrival, _)) if *arrival <= now => {}
+                _ => break,
+            }
+
+            // copy the packet into buffers of the rx queue's mempool like a nic would, break if
+            // there are not enough free buffers and leave the packet on the wire
+            let p = match copy_segments(&queue.pool, &wire.packets[0].1) {
+                Some(p) => p,
+                None => {
+                    queue.stats.mempool_exhausted += 1;
+                    break;
+                }
+            };
+
+            // the sent packet has been processed and its buffer is freed
+            wire.packets.pop_front();
+
+            self.rx_bytes += p.total_len() as u64;
+            self.rx_pkts += 1;
+            queue.stats.pkts += 1;
+            queue.stats.bytes += p.total_len() as u64;
+
+            buffer.push_back(p);
+            received_packets += 1;
+        }
+
+        if received_packets == 0 {
+            queue.stats.empty_polls += 1;
+        }
+
+        received_packets
+    }
+
+    /// Pops as many packets as possible from `buffer` to put them onto the wire.
+    fn tx_batch(&mut self, queue_id: u16, buffer: &mut VecDeque<Packet>) -> usize {
+        let queue = self
+            .tx_queues
+            .get_mut(queue_id as usize)
+            .expect("invalid tx queue id");
+
+        let mut wire = queue.wire.borrow_mut();
+        let arrival = Instant::now() + self.delay;
+        let mut sent = 0;
+
+        while let Some(mut packet) = buffer.pop_front() {
+            if packet.tx_offload().is_some_and(|o| !o.fits(&packet)) {
+                warn!("dropping packet whose offloaded headers are not in its first segment");
+                queue.stats.dropped += 1;
+                continue;
+            }
+
+            // there is no device to segment packets, the segments are sent instead
+            if let Some(offload) = packet.tx_offload().filter(|o| o.mss.is_some()) {
+                if !offload.segment_into(packet, buffer) {
+                    break;
+                }
+                continue;
+            }
+
+            if wire.packets.len() >= wire.capacity {
+                // wire is full, push packet back onto the queue of to-be-sent packets
+                buffer.push_front(packet);
+                queue.stats.ring_full += 1;
+                break;
+            }
+
+            // there is no device to insert vlan tags either
+            if !offload::insert_vlan_tag(&mut packet) {
+                warn!("dropping packet without room for its vlan tag");
+                continue;
+            }
+
+            self.tx_bytes += packet.total_len() as u64;
+            self.tx_pkts += 1;
+            queue.stats.pkts += 1;
+            queue.stats.bytes += packet.total_len() as u64;
+            sent += 1;
+
+            // there is no device to offload checksums to
+            if let Some(offload) = packet.tx_offload() {
+                offload.apply(&mut packet);
+            }
+
+            if let Some(ref mut drop_filter) = self.drop_filter {
+                if drop_filter(&packet) {
+                    continue;
+                }
+            }
+
+            wire.packets.push_back((arrival, packet));
+        }
+
+        sent
+    }
+
+    /// Reads the totals of the stats of this device since the last reset into `stats`.
+    fn read_stats(&self, stats: &mut DeviceStats) {
+        stats.rx_pkts = self.rx_pkts;
+        stats.tx_pkts = self.tx_pkts;
+        stats.rx_bytes = self.rx_bytes;
+        stats.tx_bytes = self.tx_bytes;
+    }
+
+    /// Resets the stats of this device.
+    fn reset_stats(&mut self) {
+        self.rx_pkts = 0;
+        self.tx_pkts = 0;
+        self.rx_bytes = 0;
+        self.tx_bytes = 0;
+    }
+
+    /// Returns the counters the driver kept for rx queue `queue_id`.
+    fn rx_queue_stats(&self, queue_id: u16) -> Option<RxQueueStats> {
+        self.rx_queues.get(queue_id as usize).map(|q| q.stats)
+    }
+
+    /// Returns the counters the driver kept for tx queue `queue_id`, sent packets are freed
+    /// when they arrive so there are no clean batches.
+    fn tx_queue_stats(&self, queue_id: u16) -> Option<TxQueueStats> {
+        self.tx_queues.get(queue_id as usize).map(|q| q.stats)
+    }
+
+    /// Returns the link speed of this device.
+    fn get_link_speed(&self) -> u16 {
+        // there is no real link so we just return something reasonable
+        10000
+    }
+}
+
+/// Returns a copy of all segments of `packet` in buffers allocated from `pool`.
+fn copy_segments(pool: &Arc<Mempool>, packet: &Packet) -> Option<Packet> {
+    let mut copy: Option<Packet> = None;
+
+    for segment in packet.segments() {
+        let mut p = alloc_pkt(pool, segment.len())?;
+        p.copy_from_slice(segment);
+
+        match copy {
+            Some(ref mut head) => head.push_segment(p),
+            None => copy = Some(p),
+        }
+    }
+
+    copy
+}
+
+impl LoopbackDevice {
+    /// Returns a `LoopbackDevice` with `num_queues` rx and tx queues. Packets sent on a tx queue
+    /// are received on the rx queue with the same id.
+    pub fn new(num_queues: u16) -> Result<LoopbackDevice, IxyError> {
+        let wires = (0..num_queues).map(|_| Wire::new()).collect::<Vec<_>>();
+
+        LoopbackDevice::with_wires(&wires, &wires)
+    }
+
+    /// Returns two `LoopbackDevice`s with `num_queues` rx and tx queues each that are connected
+    /// like two network cards with a cable. Packets sent on a tx queue of one device are received
+    /// on the rx queue with the same id of the other device.
+    pub fn pair(num_queues: u16) -> Result<(LoopbackDevice, LoopbackDevice), IxyError> {
+        let wires_a = (0..num_queues).map(|_| Wire::new()).collect::<Vec<_>>();
+        let wires_b = (0..num_queues).map(|_| Wire::new()).collect::<Vec<_>>();
+
+        Ok((
+            LoopbackDevice::with_wires(&wires_b, &wires_a)?,
+            LoopbackDevice::with_wires(&wires_a, &wires_b)?,
+        ))
+    }
+
+    /// Returns a `LoopbackDevice` receiving from `rx_wires` and sending to `tx_wires`.
+    fn with_wires(
+        rx_wires: &[Rc<RefCell<Wire>>],
+        tx_wires: &[Rc<RefCell<Wire>>],
+    ) -> Result<LoopbackDevice, IxyError> {
+        let id = LOOPBACK_ID.fetch_add(1, Ordering::SeqCst);
+
+        let mempool_size = if 2 * NUM_QUEUE_ENTRIES < MIN_MEMPOOL_SIZE {
+            MIN_MEMPOOL_SIZE
+        } else {
+            2 * NUM_QUEUE_ENTRIES
+        };
+
+        let mut rx_queues = Vec::with_capacity(rx_wires.len());
+        for wire in rx_wires {
+            rx_queues.push(LoopbackRxQueue {
+                pool: Mempool::allocate_anonymous(mempool_size, PKT_BUF_ENTRY_SIZE)?,
+                wire: Rc::clone(wire),
+                stats: RxQueueStats::default(),
+            });
+        }
+
+        let tx_queues = tx_wires
+            .iter()
+            .map(|wire| LoopbackTxQueue {
+                wire: Rc::clone(wire),
+                stats: TxQueueStats::default(),
+            })
+            .collect();
+
+        Ok(LoopbackDevice {
+            name: format!("loopback{}", id),
+            // locally administered address
+            mac: Cell::new([0x02, 0x00, 0x00, 0x00, (id >> 8) as u8, id as u8]),
+            rx_queues,
+            tx_queues,
+            drop_filter: None,
+            delay: Duration::from_secs(0),
+            rx_pkts: 0,
+            tx_pkts: 0,
+            rx_bytes: 0,
+            tx_bytes: 0,
+        })
+    }
+
+    /// Drops all sent packets for which `filter` returns `true` instead of delivering them.
+    ///
+    /// Dropped packets are still counted as sent.
+    pub fn set_drop_filter<F>(&mut self, filter: F)
+    where
+        F: FnMut(&Packet) -> bool + 'static,
+    {
+        self.drop_filter = Some(Box::new(filter));
+    }
+
+    /// Removes the filter set with [`set_drop_filter`](LoopbackDevice::set_drop_filter).
+    pub fn clear_drop_filter(&mut self) {
+        self.drop_filter = None;
+    }
+
+    /// Delays all packets sent from now on by `delay` before they can be received.
+    pub fn set_delay(&mut self, delay: Duration) {
+        self.delay = delay;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::memory::{alloc_pkt_batch, PACKET_HEADROOM};
+    use crate::offload::{verify_checksums, ChecksumStatus, L4Type, TxOffload};
+    use std::thread;
+
+    fn tx_pool() -> Arc<Mempool> {
+        Mempool::allocate_anonymous(64, 0).unwrap()
+    }
+
+    #[test]
+    fn test_echo() {
+        let mut dev = LoopbackDevice::new(2).unwrap();
+        let pool = tx_pool();
+        let mut buffer = VecDeque::new();
+
+        alloc_pkt_batch(&pool, &mut buffer, 4, 60);
+        for (i, p) in buffer.iter_mut().enumerate() {
+            p[0] = i as u8;
+        }
+
+        assert_eq!(dev.tx_batch(1, &mut buffer), 4);
+        assert!(buffer.is_empty());
+        assert_eq!(pool.num_free_entries(), 60);
+
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 0);
+        assert_eq!(dev.rx_batch(1, &mut buffer, 2), 2);
+        assert_eq!(dev.rx_batch(1, &mut buffer, 32), 2);
+        assert_eq!(pool.num_free_entries(), 64);
+
+        for (i, p) in buffer.iter().enumerate() {
+            assert_eq!(p.len(), 60);
+            assert_eq!(p[0], i as u8);
+            assert!(!Arc::ptr_eq(p.get_pool(), &pool));
+        }
+
+        let mut stats = DeviceStats::default();
+        dev.read_stats(&mut stats);
+        assert_eq!(stats.tx_pkts, 4);
+        assert_eq!(stats.rx_pkts, 4);
+        assert_eq!(stats.tx_bytes, 240);
+        assert_eq!(stats.rx_bytes, 240);
+    }
+
+    #[test]
+    fn test_pair() {
+        let (mut dev1, mut dev2) = LoopbackDevice::pair(1).unwrap();
+        let pool = tx_pool();
+        let mut buffer = VecDeque::new();
+
+        alloc_pkt_batch(&pool, &mut buffer, 8, 64);
+        assert_eq!(dev1.tx_batch(0, &mut buffer), 8);
+
+        assert_eq!(dev1.rx_batch(0, &mut buffer, 32), 0);
+        assert_eq!(dev2.rx_batch(0, &mut buffer, 32), 8);
+
+        assert_eq!(dev2.tx_batch(0, &mut buffer), 8);
+        assert_eq!(dev2.rx_batch(0, &mut buffer, 32), 0);
+        assert_eq!(dev1.rx_batch(0, &mut buffer, 32), 8);
+    }
+
+    #[test]
+    fn test_offload_out_of_bounds() {
+        let mut dev = LoopbackDevice::new(1).unwrap();
+        let pool = tx_pool();
+        let mut buffer = VecDeque::new();
+
+        // the tcp checksum field is behind the end of the packet
+        let mut p = alloc_pkt(&pool, 40).unwrap();
+        p.set_tx_offload(Some(TxOffload::ipv4(14, 20).l4_checksum(L4Type::Tcp)));
+        buffer.push_back(p);
+
+        assert_eq!(dev.tx_batch(0, &mut buffer), 0);
+        assert!(buffer.is_empty());
+        assert_eq!(dev.tx_queue_stats(0).unwrap().dropped, 1);
+        assert_eq!(pool.num_free_entries(), 64);
+    }
+
+    #[test]
+    fn test_software_tso() {
+        let mut dev = LoopbackDevice::new(1).unwrap();
+        let pool = tx_pool();
+        let mut buffer = VecDeque::new();
+
+        // an ipv6 tcp packet with 3000 bytes of payload
+        let mut p = alloc_pkt(&pool, 74).unwrap();
+        p[12..14].copy_from_slice(&[0x86, 0xdd]);
+        p[14] = 0x60;
+        p[20] = 6;
+        p[66] = 0x50;
+        p.push_segment(alloc_pkt(&pool, 1500).unwrap());
+        p.push_segment(alloc_pkt(&pool, 1500).unwrap());
+        p.set_tx_offload(Some(TxOffload::ipv6(14, 40).tso(1000, 20)));
+        buffer.push_back(p);
+
+        // the segments replace the packet
+        assert_eq!(dev.tx_batch(0, &mut buffer), 3);
+        assert_eq!(pool.num_free_entries(), 61);
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 3);
+        assert_eq!(pool.num_free_entries(), 64);
+
+        for (i, p) in buffer.iter().enumerate() {
+            assert_eq!(p.total_len(), 1074);
+            assert_eq!(&p[18..20], &1020u16.to_be_bytes());
+            assert_eq!(&p[58..62], &(1000 * i as u32).to_be_bytes());
+            assert_eq!(
+                verify_checksums(p),
+                (ChecksumStatus::Unknown, ChecksumStatus::Good)
+            );
+        }
+    }
+
+    #[test]
+    fn test_software_vlan() {
+        let mut dev = LoopbackDevice::new(1).unwrap();
+        let pool = tx_pool();
+        let mut buffer = VecDeque::new();
+
+        // an ipv6 udp packet whose checksum is inserted behind the tag
+        let mut p = alloc_pkt(&pool, 100).unwrap();
+        p[12..14].copy_from_slice(&[0x86, 0xdd]);
+        p[14] = 0x60;
+        p[18..20].copy_from_slice(&46u16.to_be_bytes());
+        p[20] = 17;
+        p[58..60].copy_from_slice(&46u16.to_be_bytes());
+        p.set_tx_offload(Some(TxOffload::ipv6(14, 40).l4_checksum(L4Type::Udp)));
+        p.set_vlan_tci(Some(0xe00a));
+        buffer.push_back(p);
+
+        // a full buffer has no room for the tag
+        let mut p = alloc_pkt(&pool, pool.entry_size() - PACKET_HEADROOM).unwrap();
+        p.set_vlan_tci(Some(0xe00a));
+        buffer.push_back(p);
+
+        assert_eq!(dev.tx_batch(0, &mut buffer), 1);
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 1);
+
+        let p = &mut buffer[0];
+        assert_eq!(p.len(), 104);
+        assert_eq!(&p[12..16], &[0x81, 0x00, 0xe0, 0x0a]);
+        assert_eq!(p.vlan_tci(), None);
+
+        offload::strip_vlan_tag(p);
+        assert_eq!(p.len(), 100);
+        assert_eq!(p.vlan_tci(), Some(0xe00a));
+        assert_eq!(
+            verify_checksums(p),
+            (ChecksumStatus::Unknown, ChecksumStatus::Good)
+        );
+    }
+
+    #[test]
+    fn test_full_wire() {
+        let mut dev = LoopbackDevice::new(1).unwrap();
+        let pool = Mempool::allocate_anonymous(NUM_QUEUE_ENTRIES + 8, 0).unwrap();
+        let mut buffer = VecDeque::new();
+
+        alloc_pkt_batch(&pool, &mut buffer, NUM_QUEUE_ENTRIES + 8, 60);
+        assert_eq!(dev.tx_batch(0, &mut buffer), NUM_QUEUE_ENTRIES);
+        assert_eq!(buffer.len(), 8);
+
+        let mut received = VecDeque::new();
+        assert_eq!(dev.rx_batch(0, &mut received, 8), 8);
+        assert_eq!(dev.tx_batch(0, &mut buffer), 8);
+
+        let stats = dev.tx_queue_stats(0).unwrap();
+        assert_eq!(
+            (stats.pkts, stats.ring_full),
+            (NUM_QUEUE_ENTRIES as u64 + 8, 1)
+        );
+        assert_eq!(dev.rx_queue_stats(0).unwrap().pkts, 8);
+    }
+
+    #[test]
+    fn test_drop_and_delay() {
+        let mut dev = LoopbackDevice::new(1).unwrap();
+        let pool = tx_pool();
+        let mut buffer = VecDeque::new();
+
+        let mut counter = 0;
+        dev.set_drop_filter(move |_| {
+            counter += 1;
+            counter % 2 == 0
+        });
+        dev.set_delay(Duration::from_millis(50));
+
+        alloc_pkt_batch(&pool, &mut buffer, 10, 60);
+        assert_eq!(dev.tx_batch(0, &mut buffer), 10);
+        // dropped packets are freed immediately
+        assert_eq!(pool.num_free_entries(), 59);
+
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 0);
+        thread::sleep(Duration::from_millis(60));
+        assert_eq!(dev.rx_batch(0, &mut buffer, 32), 5);
+        assert_eq!(pool.num_free_entries(), 64);
+    }
+}
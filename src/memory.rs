@@ -1,15 +1,16 @@
 use std::cell::RefCell;
 use std::collections::{HashMap, VecDeque};
-use std::error::Error;
 use std::fmt::{self, Debug};
 use std::io::{self, Read, Seek};
 use std::ops::{Deref, DerefMut};
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::rc::Rc;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Mutex;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
 use std::{fs, mem, process, ptr, slice};
 
+use crate::error::IxyError;
+use crate::offload::{ChecksumStatus, TxOffload};
+use crate::timestamp::Timestamp;
 use crate::vfio::vfio_map_dma;
 
 use lazy_static::lazy_static;
@@ -26,6 +27,18 @@ pub const IOVA_WIDTH: u8 = X86_VA_WIDTH;
 // which results in a different alignment requirement
 pub const PACKET_HEADROOM: usize = 32;
 
+/// Maximum number of free buffers each thread keeps for a [`Mempool`].
+///
+/// Cached buffers are not available to other threads, so a pool needs up to this many buffers
+/// more for every thread that allocates or frees its packets.
+pub const CACHE_SIZE: usize = 256;
+
+// number of buffers freed at once by `free_pkt_batch`
+const FREE_BATCH: usize = 64;
+
+// marks the end of a pool's free stack
+const EMPTY: u32 = u32::MAX;
+
 static HUGEPAGE_ID: AtomicUsize = AtomicUsize::new(0);
 
 // we want one VFIO Container for all NICs, so every NIC can read from every
@@ -33,6 +46,10 @@ static HUGEPAGE_ID: AtomicUsize = AtomicUsize::new(0);
 // this variable is unused.
 pub(crate) static mut VFIO_CONTAINER_FILE_DESCRIPTOR: RawFd = -1;
 
+thread_local! {
+    static CACHES: RefCell<Vec<Cache>> = const { RefCell::new(Vec::new()) };
+}
+
 lazy_static! {
     pub(crate) static ref VFIO_GROUP_FILE_DESCRIPTORS: Mutex<HashMap<i32, RawFd>> =
         Mutex::new(HashMap::new());
@@ -47,8 +64,8 @@ const MAP_HUGE_2MB: i32 = 0x5400_0000; // 21 << 26
 
 impl<T> Dma<T> {
     /// Allocates dma memory on a huge page.
-    pub fn allocate(size: usize, require_contiguous: bool) -> Result<Dma<T>, Box<dyn Error>> {
-        let size = if size % HUGE_PAGE_SIZE != 0 {
+    pub fn allocate(size: usize, require_contiguous: bool) -> Result<Dma<T>, IxyError> {
+        let size = if !size.is_multiple_of(HUGE_PAGE_SIZE) {
             ((size >> HUGE_PAGE_BITS) + 1) << HUGE_PAGE_BITS
         } else {
             size
@@ -92,7 +109,7 @@ impl<T> Dma<T> {
                 // finally map huge pages at the huge page size aligned 32 bit address
                 unsafe {
                     libc::mmap(
-                        aligned_addr as *mut libc::c_void,
+                        aligned_addr,
                         size,
                         libc::PROT_READ | libc::PROT_WRITE,
                         libc::MAP_SHARED
@@ -119,11 +136,7 @@ impl<T> Dma<T> {
 
             // This is the main IOMMU work: IOMMU DMA MAP the memory...
             if ptr == libc::MAP_FAILED {
-                Err(format!(
-                    "failed to memory map DMA-memory. Errno: {}",
-                    std::io::Error::last_os_error()
-                )
-                .into())
+                Err(IxyError::last_dma_error("failed to memory map DMA-memory"))
             } else {
                 let iova = vfio_map_dma(ptr as usize, size)?;
 
@@ -138,7 +151,9 @@ impl<T> Dma<T> {
             debug!("allocating dma memory via huge page");
 
             if require_contiguous && size > HUGE_PAGE_SIZE {
-                return Err("failed to map physically contiguous memory".into());
+                return Err(IxyError::Dma(
+                    "failed to map physically contiguous memory".to_string(),
+                ));
             }
 
             let id = HUGEPAGE_ID.fetch_add(1, Ordering::SeqCst);
@@ -148,6 +163,7 @@ impl<T> Dma<T> {
                 .read(true)
                 .write(true)
                 .create(true)
+                .truncate(false)
                 .open(path.clone())
             {
                 Ok(f) => {
@@ -163,8 +179,10 @@ impl<T> Dma<T> {
                     };
 
                     if ptr == libc::MAP_FAILED {
-                        Err("failed to memory map huge page - huge pages enabled and free?".into())
-                    } else if unsafe { libc::mlock(ptr as *mut libc::c_void, size) } == 0 {
+                        Err(IxyError::last_dma_error(
+                            "failed to memory map huge page - huge pages enabled and free?",
+                        ))
+                    } else if unsafe { libc::mlock(ptr, size) } == 0 {
                         let memory = Dma {
                             virt: ptr as *mut T,
                             phys: virt_to_phys(ptr as usize)?,
@@ -172,34 +190,88 @@ impl<T> Dma<T> {
 
                         Ok(memory)
                     } else {
-                        Err("failed to memory lock huge page".into())
+                        Err(IxyError::last_dma_error("failed to memory lock huge page"))
                     }
                 }
-                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Err(Box::new(io::Error::new(
-                    io::ErrorKind::NotFound,
-                    format!(
-                        "huge page {} could not be created - huge pages enabled?",
-                        path
-                    ),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => Err(IxyError::Dma(format!(
+                    "huge page {} could not be created - huge pages enabled?",
+                    path
+                ))),
+                Err(e) => Err(IxyError::Dma(format!(
+                    "huge page {} could not be created: {}",
+                    path, e
                 ))),
-                Err(e) => Err(Box::new(e)),
             }
         }
     }
+
+    /// Allocates memory that is not backed by huge pages and can not be accessed by devices.
+    ///
+    /// The physical address of this memory is its virtual address. It is meant for software
+    /// devices like [`LoopbackDevice`](crate::loopback::LoopbackDevice) which never hand the
+    /// memory to real hardware.
+    pub fn allocate_anonymous(size: usize) -> Result<Dma<T>, IxyError> {
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            Err(IxyError::last_dma_error(
+                "failed to memory map anonymous memory",
+            ))
+        } else {
+            Ok(Dma {
+                virt: ptr as *mut T,
+                phys: ptr as usize,
+            })
+        }
+    }
 }
 
 pub struct Packet {
     pub(crate) addr_virt: *mut u8,
     pub(crate) addr_phys: usize,
     pub(crate) len: usize,
-    pub(crate) pool: Rc<Mempool>,
+    pub(crate) pool: Arc<Mempool>,
     pub(crate) pool_entry: usize,
+    pub(crate) next: Option<Box<Packet>>,
+    pub(crate) meta: Metadata,
+}
+
+// a packet owns the buffers of its segments exclusively
+unsafe impl Send for Packet {}
+
+/// Information about a received packet reported by the device and offloads requested for a
+/// sent packet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Metadata {
+    pub rss_hash: Option<u32>,
+    pub fdir_id: Option<u16>,
+    pub ip_checksum: ChecksumStatus,
+    pub l4_checksum: ChecksumStatus,
+    pub coalesced_segments: Option<u16>,
+    pub vlan_tci: Option<u16>,
+    pub timestamp: Option<Timestamp>,
+    pub tx_offload: Option<TxOffload>,
+    pub tx_timestamp: bool,
 }
 
 impl Clone for Packet {
     fn clone(&self) -> Self {
         let mut p = alloc_pkt(&self.pool, self.len).expect("no buffer available");
-        p.clone_from_slice(&self);
+        p.clone_from_slice(self);
+        p.meta = self.meta;
+
+        if let Some(ref next) = self.next {
+            p.next = Some(Box::new((**next).clone()));
+        }
 
         p
     }
@@ -237,7 +309,7 @@ impl Packet {
         addr_virt: *mut u8,
         addr_phys: usize,
         len: usize,
-        pool: Rc<Mempool>,
+        pool: Arc<Mempool>,
         pool_entry: usize,
     ) -> Packet {
         Packet {
@@ -246,9 +318,133 @@ impl Packet {
             len,
             pool,
             pool_entry,
+            next: None,
+            meta: Default::default(),
+        }
+    }
+
+    /// Returns the next segment of a packet that spans multiple buffers, or [`None`] if this is
+    /// the last segment.
+    ///
+    /// Dereferencing a `Packet` only yields the data of its own buffer, see
+    /// [`segments`](Packet::segments) to access all of it.
+    pub fn next_segment(&self) -> Option<&Packet> {
+        self.next.as_deref()
+    }
+
+    /// Returns the next segment as mutable reference, see [`next_segment`](Packet::next_segment).
+    pub fn next_segment_mut(&mut self) -> Option<&mut Packet> {
+        self.next.as_deref_mut()
+    }
+
+    /// Appends `segment` and all of its segments to the end of this packet.
+    pub fn push_segment(&mut self, segment: Packet) {
+        match self.next {
+            Some(ref mut next) => next.push_segment(segment),
+            None => self.next = Some(Box::new(segment)),
+        }
+    }
+
+    /// Returns the pool, the buffer and the next segment of this packet without freeing the
+    /// buffer.
+    fn into_parts(self) -> (Arc<Mempool>, usize, Option<Packet>) {
+        let mut packet = mem::ManuallyDrop::new(self);
+        let next = packet.take_next_segment();
+        // the packet is never dropped, so its pool is moved out exactly once
+        let pool = unsafe { ptr::read(&packet.pool) };
+
+        (pool, packet.pool_entry, next)
+    }
+
+    /// Detaches and returns all segments after this one.
+    pub fn take_next_segment(&mut self) -> Option<Packet> {
+        self.next.take().map(|next| *next)
+    }
+
+    /// Returns an iterator over this segment and all following segments.
+    pub fn segments(&self) -> Segments<'_> {
+        Segments {
+            segment: Some(self),
         }
     }
 
+    /// Returns the number of segments of this packet.
+    pub fn num_segments(&self) -> usize {
+        self.segments().count()
+    }
+
+    /// Returns the length of the packet's data in all segments.
+    pub fn total_len(&self) -> usize {
+        self.segments().map(|segment| segment.len).sum()
+    }
+
+    /// Returns the RSS hash computed by the device, or [`None`] if the packet was not hashed.
+    pub fn rss_hash(&self) -> Option<u32> {
+        self.meta.rss_hash
+    }
+
+    /// Returns the id of the flow director filter that matched the packet in perfect match mode
+    /// or the signature of the filter in signature mode, [`None`] if no filter matched.
+    pub fn fdir_id(&self) -> Option<u16> {
+        self.meta.fdir_id
+    }
+
+    /// Returns whether the device found the IPv4 header checksum of the received packet valid.
+    pub fn ip_checksum(&self) -> ChecksumStatus {
+        self.meta.ip_checksum
+    }
+
+    /// Returns whether the device found the TCP or UDP checksum of the received packet valid.
+    pub fn l4_checksum(&self) -> ChecksumStatus {
+        self.meta.l4_checksum
+    }
+
+    /// Returns the number of TCP segments the device coalesced into this packet, [`None`] if the
+    /// packet was not coalesced.
+    pub fn coalesced_segments(&self) -> Option<u16> {
+        self.meta.coalesced_segments
+    }
+
+    /// Returns the tag control information of the 802.1Q tag the device stripped from the
+    /// received packet, [`None`] if the packet was untagged or stripping is disabled.
+    ///
+    /// The vlan id is in the lower 12 bits, the priority in the upper 3 bits.
+    pub fn vlan_tci(&self) -> Option<u16> {
+        self.meta.vlan_tci
+    }
+
+    /// Requests the device to insert an 802.1Q tag with tag control information `tci` when
+    /// sending this packet.
+    ///
+    /// Stripped tags of received packets are inserted again if the packets are forwarded. Devices
+    /// without tag insertion insert the tag in software and drop the packet if its first segment
+    /// has no room for the tag.
+    pub fn set_vlan_tci(&mut self, tci: Option<u16>) {
+        self.meta.vlan_tci = tci;
+    }
+
+    /// Returns the time the packet was received, [`None`] if timestamping is disabled.
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.meta.timestamp
+    }
+
+    /// Requests the device to timestamp this packet when sending it, see
+    /// [`IxyDevice::read_tx_timestamp`](crate::IxyDevice::read_tx_timestamp).
+    pub fn set_tx_timestamp(&mut self, enabled: bool) {
+        self.meta.tx_timestamp = enabled;
+    }
+
+    /// Returns the offloads requested for sending this packet.
+    pub fn tx_offload(&self) -> Option<TxOffload> {
+        self.meta.tx_offload
+    }
+
+    /// Requests the device to apply `offload` when sending this packet, devices without
+    /// checksum offloading compute the requested checksums in software.
+    pub fn set_tx_offload(&mut self, offload: Option<TxOffload>) {
+        self.meta.tx_offload = offload;
+    }
+
     /// Returns the virtual address of the packet.
     pub fn get_virt_addr(&self) -> *mut u8 {
         self.addr_virt
@@ -260,7 +456,7 @@ impl Packet {
     }
 
     /// Returns a reference to the packet`s pool.
-    pub fn get_pool(&self) -> &Rc<Mempool> {
+    pub fn get_pool(&self) -> &Arc<Mempool> {
         &self.pool
     }
 
@@ -305,6 +501,38 @@ impl Packet {
         self.len = self.len.min(len)
     }
 
+    /// Returns a copy of this packet that fills as few buffers of its pool as possible, or
+    /// [`None`] if the pool has not enough free buffers.
+    pub(crate) fn linearize(&self) -> Option<Packet> {
+        let frame = self
+            .segments()
+            .flat_map(|segment| segment.iter().copied())
+            .collect::<Vec<_>>();
+        let capacity = self.pool.entry_size() - PACKET_HEADROOM;
+
+        let mut packet: Option<Packet> = None;
+        for chunk in frame.chunks(capacity) {
+            let mut p = alloc_pkt(&self.pool, chunk.len())?;
+            p.copy_from_slice(chunk);
+
+            match packet {
+                Some(ref mut head) => head.push_segment(p),
+                None => packet = Some(p),
+            }
+        }
+
+        packet.map(|mut packet| {
+            packet.meta = self.meta;
+            packet
+        })
+    }
+
+    /// Returns the number of bytes the buffer of this segment can hold from the start of its data.
+    pub(crate) fn capacity(&self) -> usize {
+        self.pool.get_virt_addr(self.pool_entry) as usize + self.pool.entry_size()
+            - self.addr_virt as usize
+    }
+
     /// Returns a mutable slice to the headroom of the packet.
     ///
     /// The `len` parameter controls how much of the headroom is returned.
@@ -318,6 +546,21 @@ impl Packet {
     }
 }
 
+/// Iterator over the segments of a [`Packet`].
+pub struct Segments<'a> {
+    segment: Option<&'a Packet>,
+}
+
+impl<'a> Iterator for Segments<'a> {
+    type Item = &'a Packet;
+
+    fn next(&mut self) -> Option<&'a Packet> {
+        let segment = self.segment?;
+        self.segment = segment.next_segment();
+        Some(segment)
+    }
+}
+
 /// Common representation for prefetch strategies.
 #[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Prefetch {
@@ -334,68 +577,204 @@ pub enum Prefetch {
     NonTemporal,
 }
 
+/// A pool of equally sized dma buffers for packets.
+///
+/// Free buffers are kept on a lock-free stack shared by all threads, so packets can be freed on
+/// any thread. Each thread caches up to [`CACHE_SIZE`] buffers of every pool it uses, most
+/// allocations and frees never touch the shared stack. Cached buffers return to the shared stack
+/// in batches, when the thread exits or when [`flush_cache`](Mempool::flush_cache) is called.
+///
+/// The drivers allocate and free the buffers of a batch of packets with a single lookup of the
+/// thread's cache.
 pub struct Mempool {
     base_addr: *mut u8,
     num_entries: usize,
     entry_size: usize,
     phys_addresses: Vec<usize>,
-    pub(crate) free_stack: RefCell<Vec<usize>>,
+    free_stack: FreeStack,
 }
 
+// the buffers are only accessed through packets which are owned by one thread at a time
+unsafe impl Send for Mempool {}
+unsafe impl Sync for Mempool {}
+
 impl Mempool {
     /// Allocates a new `Mempool`.
     ///
-    /// # Panics
-    ///
-    /// Panics if `size` is not a divisor of the page size.
-    pub fn allocate(entries: usize, size: usize) -> Result<Rc<Mempool>, Box<dyn Error>> {
-        let entry_size = match size {
-            0 => 2048,
-            x => x,
-        };
+    /// Fails with [`IxyError::InvalidConfig`] if `size` is not a divisor of the page size.
+    pub fn allocate(entries: usize, size: usize) -> Result<Arc<Mempool>, IxyError> {
+        let (dma, entry_size, phys_addresses) = allocate_buffers(entries, size)?;
 
-        if (get_vfio_container() == -1) && HUGE_PAGE_SIZE % entry_size != 0 {
-            panic!("entry size must be a divisor of the page size");
-        }
+        Ok(Mempool::new(dma, entries, entry_size, phys_addresses))
+    }
 
-        let dma: Dma<u8> = Dma::allocate(entries * entry_size, false)?;
-        let mut phys_addresses = Vec::with_capacity(entries);
+    /// Allocates a new `Mempool` that is not backed by huge pages, see
+    /// [`Dma::allocate_anonymous`].
+    ///
+    /// Packets from this pool can only be used with software devices.
+    pub fn allocate_anonymous(entries: usize, size: usize) -> Result<Arc<Mempool>, IxyError> {
+        let (dma, entry_size, phys_addresses) = allocate_buffers_anonymous(entries, size)?;
 
-        for i in 0..entries {
-            if get_vfio_container() != -1 {
-                phys_addresses.push(dma.phys + (i * entry_size));
-            } else {
-                phys_addresses
-                    .push(unsafe { virt_to_phys(dma.virt.add(i * entry_size) as usize)? });
-            }
-        }
+        Ok(Mempool::new(dma, entries, entry_size, phys_addresses))
+    }
 
+    /// Returns a new `Mempool` with all buffers of `dma` marked as free.
+    fn new(
+        dma: Dma<u8>,
+        entries: usize,
+        entry_size: usize,
+        phys_addresses: Vec<usize>,
+    ) -> Arc<Mempool> {
         let pool = Mempool {
             base_addr: dma.virt,
             num_entries: entries,
             entry_size,
             phys_addresses,
-            free_stack: RefCell::new(Vec::with_capacity(entries)),
+            free_stack: FreeStack::new(entries),
         };
 
         unsafe { memset(pool.base_addr, pool.num_entries * pool.entry_size, 0x00) }
 
-        let pool = Rc::new(pool);
-        pool.free_stack.borrow_mut().extend(0..entries);
+        Arc::new(pool)
+    }
+
+    /// Returns the position of a free buffer in the memory pool, or [`None`] if neither the
+    /// cache of the current thread nor the shared stack have a free buffer.
+    pub(crate) fn alloc_buf(self: &Arc<Self>) -> Option<usize> {
+        let cached = self.with_cache(|entries| {
+            if entries.is_empty() {
+                // refill half of the cache to leave room for frees
+                while entries.len() < CACHE_SIZE / 2 {
+                    match self.free_stack.pop() {
+                        Some(id) => entries.push(id),
+                        None => break,
+                    }
+                }
+            }
+
+            entries.pop()
+        });
 
-        Ok(pool)
+        // the cache is not available while the thread exits
+        cached.unwrap_or_else(|| self.free_stack.pop())
     }
 
-    /// Returns the position of a free buffer in the memory pool, or [`None`] if the pool is empty.
-    pub(crate) fn alloc_buf(&self) -> Option<usize> {
-        self.free_stack.borrow_mut().pop()
+    /// Calls `f` with up to `n` free buffers, fewer if the pool runs out of buffers, with a single
+    /// lookup of the current thread's cache. Returns the number of buffers.
+    pub(crate) fn alloc_bufs(self: &Arc<Self>, n: usize, mut f: impl FnMut(usize)) -> usize {
+        let mut allocated = 0;
+
+        let cached = self.with_cache(|entries| {
+            while allocated < n {
+                if entries.is_empty() {
+                    while entries.len() < CACHE_SIZE / 2 {
+                        match self.free_stack.pop() {
+                            Some(id) => entries.push(id),
+                            None => break,
+                        }
+                    }
+                }
+
+                match entries.pop() {
+                    Some(id) => f(id),
+                    None => break,
+                }
+                allocated += 1;
+            }
+        });
+
+        if cached.is_none() {
+            while allocated < n {
+                match self.free_stack.pop() {
+                    Some(id) => f(id),
+                    None => break,
+                }
+                allocated += 1;
+            }
+        }
+
+        allocated
     }
 
     /// Marks a buffer in the memory pool as free.
-    pub(crate) fn free_buf(&self, id: usize) {
+    pub(crate) fn free_buf(self: &Arc<Self>, id: usize) {
         assert!(id < self.num_entries, "buffer outside of memory pool");
 
-        self.free_stack.borrow_mut().push(id);
+        let cached = self.with_cache(|entries| {
+            if entries.len() >= CACHE_SIZE {
+                for id in entries.drain(CACHE_SIZE / 2..) {
+                    self.free_stack.push(id);
+                }
+            }
+
+            entries.push(id);
+        });
+
+        if cached.is_none() {
+            self.free_stack.push(id);
+        }
+    }
+
+    /// Marks the buffers `ids` as free with a single lookup of the current thread's cache.
+    pub(crate) fn free_bufs(self: &Arc<Self>, ids: &[usize]) {
+        for &id in ids {
+            assert!(id < self.num_entries, "buffer outside of memory pool");
+        }
+
+        let cached = self.with_cache(|entries| {
+            for &id in ids {
+                if entries.len() >= CACHE_SIZE {
+                    for id in entries.drain(CACHE_SIZE / 2..) {
+                        self.free_stack.push(id);
+                    }
+                }
+
+                entries.push(id);
+            }
+        });
+
+        if cached.is_none() {
+            for &id in ids {
+                self.free_stack.push(id);
+            }
+        }
+    }
+
+    /// Calls `f` with the buffers of this pool cached by the current thread, returns [`None`] if
+    /// the thread local caches have already been destroyed.
+    fn with_cache<T>(self: &Arc<Self>, f: impl FnOnce(&mut Vec<usize>) -> T) -> Option<T> {
+        CACHES
+            .try_with(|caches| {
+                let mut caches = caches.borrow_mut();
+
+                let index = match caches
+                    .iter()
+                    .position(|cache| ptr::eq(cache.pool.as_ptr(), Arc::as_ptr(self)))
+                {
+                    Some(index) => index,
+                    None => {
+                        // forget caches of pools that no longer exist
+                        caches.retain(|cache| cache.pool.strong_count() > 0);
+                        caches.push(Cache {
+                            pool: Arc::downgrade(self),
+                            entries: Vec::with_capacity(CACHE_SIZE),
+                        });
+                        caches.len() - 1
+                    }
+                };
+
+                f(&mut caches[index].entries)
+            })
+            .ok()
+    }
+
+    /// Returns all buffers of this pool cached by the current thread to the shared stack.
+    pub fn flush_cache(self: &Arc<Self>) {
+        self.with_cache(|entries| {
+            for id in entries.drain(..) {
+                self.free_stack.push(id);
+            }
+        });
     }
 
     /// Returns the virtual address of a buffer from the memory pool.
@@ -414,32 +793,174 @@ impl Mempool {
     pub fn entry_size(&self) -> usize {
         self.entry_size
     }
+
+    /// Returns the number of buffers in the memory pool.
+    pub fn num_entries(&self) -> usize {
+        self.num_entries
+    }
+
+    /// Returns the number of buffers in the memory pool that are currently not in use, buffers
+    /// cached by other threads are not included.
+    pub fn num_free_entries(self: &Arc<Self>) -> usize {
+        self.free_stack.len() + self.with_cache(|entries| entries.len()).unwrap_or(0)
+    }
 }
 
-/// Returns `num_packets` free packets from the `pool` with size `packet_size`.
+/// Free buffers of one pool cached by the current thread.
+struct Cache {
+    pool: Weak<Mempool>,
+    entries: Vec<usize>,
+}
+
+impl Drop for Cache {
+    fn drop(&mut self) {
+        if let Some(pool) = self.pool.upgrade() {
+            for id in self.entries.drain(..) {
+                pool.free_stack.push(id);
+            }
+        }
+    }
+}
+
+/// Lock-free stack of free buffers, linked via the buffer indices.
+struct FreeStack {
+    // top of the stack in the lower 32 bits, a counter against ABA in the upper 32 bits
+    head: AtomicU64,
+    next: Box<[AtomicU32]>,
+    len: AtomicUsize,
+}
+
+impl FreeStack {
+    /// Returns a stack containing the buffers `0..entries`.
+    fn new(entries: usize) -> FreeStack {
+        assert!(
+            entries < EMPTY as usize,
+            "too many entries for a memory pool"
+        );
+
+        let stack = FreeStack {
+            head: AtomicU64::new(u64::from(EMPTY)),
+            next: (0..entries).map(|_| AtomicU32::new(EMPTY)).collect(),
+            len: AtomicUsize::new(0),
+        };
+
+        for id in (0..entries).rev() {
+            stack.push(id);
+        }
+
+        stack
+    }
+
+    fn push(&self, id: usize) {
+        // count first so that a concurrent pop of this buffer never sees a negative length
+        self.len.fetch_add(1, Ordering::Relaxed);
+
+        let mut head = self.head.load(Ordering::Relaxed);
+        loop {
+            self.next[id].store(head as u32, Ordering::Relaxed);
+            let new = ((head >> 32).wrapping_add(1) << 32) | id as u64;
+
+            match self
+                .head
+                .compare_exchange_weak(head, new, Ordering::Release, Ordering::Relaxed)
+            {
+                Ok(_) => break,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn pop(&self) -> Option<usize> {
+        let mut head = self.head.load(Ordering::Acquire);
+        loop {
+            let id = head as u32;
+            if id == EMPTY {
+                return None;
+            }
+
+            // may read a stale link if another thread popped this buffer in the meantime, the
+            // counter in head makes the exchange fail in that case
+            let next = self.next[id as usize].load(Ordering::Relaxed);
+            let new = ((head >> 32).wrapping_add(1) << 32) | u64::from(next);
+
+            match self
+                .head
+                .compare_exchange_weak(head, new, Ordering::Acquire, Ordering::Acquire)
+            {
+                Ok(_) => {
+                    self.len.fetch_sub(1, Ordering::Relaxed);
+                    return Some(id as usize);
+                }
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::Relaxed)
+    }
+}
+
+/// Pushes up to `num_packets` free packets from the `pool` with size `packet_size` onto `buffer`
+/// and returns their number, fewer if the pool runs out of buffers.
 pub fn alloc_pkt_batch(
-    pool: &Rc<Mempool>,
+    pool: &Arc<Mempool>,
     buffer: &mut VecDeque<Packet>,
     num_packets: usize,
     packet_size: usize,
 ) -> usize {
-    let mut allocated = 0;
+    if packet_size > pool.entry_size - PACKET_HEADROOM {
+        return 0;
+    }
+
+    pool.alloc_bufs(num_packets, |id| unsafe {
+        buffer.push_back(Packet::new(
+            pool.get_virt_addr(id).add(PACKET_HEADROOM),
+            pool.get_phys_addr(id) + PACKET_HEADROOM,
+            packet_size,
+            Arc::clone(pool),
+            id,
+        ));
+    })
+}
 
-    while let Some(p) = alloc_pkt(pool, packet_size) {
-        buffer.push_back(p);
+/// Frees all segments of `packets`, consecutive buffers of the same pool with a single lookup of
+/// the current thread's cache.
+pub(crate) fn free_pkt_batch(packets: impl IntoIterator<Item = Packet>) {
+    let mut pool: Option<Arc<Mempool>> = None;
+    let mut ids = [0; FREE_BATCH];
+    let mut len = 0;
+
+    for packet in packets {
+        let mut segment = Some(packet);
+        while let Some(packet) = segment {
+            let (segment_pool, id, next) = packet.into_parts();
+
+            let same_pool = pool
+                .as_ref()
+                .is_some_and(|pool| Arc::ptr_eq(pool, &segment_pool));
+            if !same_pool || len == FREE_BATCH {
+                if let Some(pool) = pool.as_ref() {
+                    pool.free_bufs(&ids[..len]);
+                }
+                pool = Some(segment_pool);
+                len = 0;
+            }
 
-        allocated += 1;
-        if allocated >= num_packets {
-            break;
+            ids[len] = id;
+            len += 1;
+            segment = next;
         }
     }
 
-    allocated
+    if let Some(pool) = pool {
+        pool.free_bufs(&ids[..len]);
+    }
 }
 
 /// Returns a free packet from the `pool`, or [`None`] if the requested packet size exceeds the
 /// maximum size for that pool or if the pool is empty.
-pub fn alloc_pkt(pool: &Rc<Mempool>, size: usize) -> Option<Packet> {
+pub fn alloc_pkt(pool: &Arc<Mempool>, size: usize) -> Option<Packet> {
     if size > pool.entry_size - PACKET_HEADROOM {
         return None;
     }
@@ -449,21 +970,67 @@ pub fn alloc_pkt(pool: &Rc<Mempool>, size: usize) -> Option<Packet> {
             pool.get_virt_addr(id).add(PACKET_HEADROOM),
             pool.get_phys_addr(id) + PACKET_HEADROOM,
             size,
-            Rc::clone(pool),
+            Arc::clone(pool),
             id,
         )
     })
 }
 
+/// Allocates dma memory for `entries` buffers of `size` bytes, 2048 if `size` is 0. Returns the
+/// memory, the actual buffer size and the physical address of each buffer.
+fn allocate_buffers(entries: usize, size: usize) -> Result<(Dma<u8>, usize, Vec<usize>), IxyError> {
+    let entry_size = match size {
+        0 => 2048,
+        x => x,
+    };
+
+    if (get_vfio_container() == -1) && !HUGE_PAGE_SIZE.is_multiple_of(entry_size) {
+        return Err(IxyError::InvalidConfig(format!(
+            "mempool entry size {} must be a divisor of the page size",
+            entry_size
+        )));
+    }
+
+    let dma: Dma<u8> = Dma::allocate(entries * entry_size, false)?;
+    let mut phys_addresses = Vec::with_capacity(entries);
+
+    for i in 0..entries {
+        if get_vfio_container() != -1 {
+            phys_addresses.push(dma.phys + (i * entry_size));
+        } else {
+            phys_addresses.push(unsafe { virt_to_phys(dma.virt.add(i * entry_size) as usize)? });
+        }
+    }
+
+    Ok((dma, entry_size, phys_addresses))
+}
+
+/// Like [`allocate_buffers`] but with memory that is not backed by huge pages, see
+/// [`Dma::allocate_anonymous`].
+fn allocate_buffers_anonymous(
+    entries: usize,
+    size: usize,
+) -> Result<(Dma<u8>, usize, Vec<usize>), IxyError> {
+    let entry_size = match size {
+        0 => 2048,
+        x => x,
+    };
+
+    let dma: Dma<u8> = Dma::allocate_anonymous(entries * entry_size)?;
+    let phys_addresses = (0..entries).map(|i| dma.phys + i * entry_size).collect();
+
+    Ok((dma, entry_size, phys_addresses))
+}
+
 /// Initializes `len` fields of type `T` at `addr` with `value`.
 pub(crate) unsafe fn memset<T: Copy>(addr: *mut T, len: usize, value: T) {
     for i in 0..len {
-        ptr::write_volatile(addr.add(i) as *mut T, value);
+        ptr::write_volatile(addr.add(i), value);
     }
 }
 
 /// Translates a virtual address to its physical counterpart.
-pub(crate) fn virt_to_phys(addr: usize) -> Result<usize, Box<dyn Error>> {
+pub(crate) fn virt_to_phys(addr: usize) -> Result<usize, IxyError> {
     let pagesize = unsafe { libc::sysconf(libc::_SC_PAGE_SIZE) } as usize;
 
     let mut file = fs::OpenOptions::new()
@@ -477,7 +1044,7 @@ pub(crate) fn virt_to_phys(addr: usize) -> Result<usize, Box<dyn Error>> {
     let mut buffer = [0; mem::size_of::<usize>()];
     file.read_exact(&mut buffer)?;
 
-    let phys = unsafe { mem::transmute::<[u8; mem::size_of::<usize>()], usize>(buffer) };
+    let phys = usize::from_ne_bytes(buffer);
     Ok((phys & 0x007f_ffff_ffff_ffff) * pagesize + addr % pagesize)
 }
 
@@ -488,3 +1055,143 @@ pub(crate) fn get_vfio_container() -> RawFd {
 pub(crate) fn set_vfio_container(cfd: RawFd) {
     unsafe { VFIO_CONTAINER_FILE_DESCRIPTOR = cfd }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+    use std::thread;
+
+    const ENTRIES: usize = 4096;
+
+    #[test]
+    fn test_alloc_free() {
+        let pool = Mempool::allocate_anonymous(ENTRIES, 0).unwrap();
+        let mut buffer = VecDeque::new();
+
+        assert_eq!(
+            alloc_pkt_batch(&pool, &mut buffer, 2 * ENTRIES, 60),
+            ENTRIES
+        );
+        assert!(alloc_pkt(&pool, 60).is_none());
+        assert_eq!(pool.num_free_entries(), 0);
+
+        let mut ids: Vec<usize> = buffer.iter().map(|p| p.pool_entry).collect();
+        ids.sort_unstable();
+        ids.dedup();
+        assert_eq!(ids.len(), ENTRIES);
+
+        // freed buffers stay in the cache of this thread until it is flushed
+        buffer.clear();
+        assert_eq!(pool.num_free_entries(), ENTRIES);
+        assert!(pool.free_stack.len() >= ENTRIES - CACHE_SIZE);
+        pool.flush_cache();
+        assert_eq!(pool.free_stack.len(), ENTRIES);
+    }
+
+    #[test]
+    fn test_free_pkt_batch() {
+        let pools = [
+            Mempool::allocate_anonymous(ENTRIES, 0).unwrap(),
+            Mempool::allocate_anonymous(ENTRIES, 0).unwrap(),
+        ];
+        let mut buffer = VecDeque::new();
+
+        // runs of segments from both pools, longer than a single batch
+        for i in 0..ENTRIES / 2 {
+            let mut p = alloc_pkt(&pools[i / 100 % 2], 60).unwrap();
+            p.push_segment(alloc_pkt(&pools[1], 60).unwrap());
+            buffer.push_back(p);
+        }
+
+        free_pkt_batch(buffer.drain(..));
+        for pool in &pools {
+            assert_eq!(pool.num_free_entries(), ENTRIES);
+            pool.flush_cache();
+            assert_eq!(pool.free_stack.len(), ENTRIES);
+        }
+    }
+
+    #[test]
+    fn test_concurrent_alloc_free() {
+        let pool = Mempool::allocate_anonymous(ENTRIES, 0).unwrap();
+
+        let threads: Vec<_> = (0..8u8)
+            .map(|t| {
+                let pool = Arc::clone(&pool);
+                thread::spawn(move || {
+                    let mut buffer = VecDeque::new();
+
+                    for round in 0..1000 {
+                        let n = alloc_pkt_batch(&pool, &mut buffer, 1 + round % 300, 64);
+
+                        // no other thread may use the same buffers at the same time
+                        for p in buffer.iter_mut() {
+                            for b in p.iter_mut() {
+                                *b = t;
+                            }
+                        }
+                        thread::yield_now();
+                        for p in buffer.iter() {
+                            assert!(p.iter().all(|&b| b == t));
+                        }
+
+                        buffer.drain(..n);
+                    }
+                })
+            })
+            .collect();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // the caches of the threads were flushed when they exited
+        assert_eq!(pool.free_stack.len(), ENTRIES);
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let pool = Mempool::allocate_anonymous(ENTRIES, 0).unwrap();
+        let (tx, rx) = mpsc::sync_channel::<Packet>(1024);
+
+        let producers: Vec<_> = (0..4u32)
+            .map(|t| {
+                let pool = Arc::clone(&pool);
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let mut sent = 0u32;
+                    while sent < 100_000 {
+                        // buffers might all be in flight
+                        if let Some(mut p) = alloc_pkt(&pool, 8) {
+                            p[..4].copy_from_slice(&t.to_le_bytes());
+                            p[4..].copy_from_slice(&sent.to_le_bytes());
+                            tx.send(p).unwrap();
+                            sent += 1;
+                        } else {
+                            thread::yield_now();
+                        }
+                    }
+                })
+            })
+            .collect();
+        drop(tx);
+
+        let consumer = thread::spawn(move || {
+            let mut expected = [0u32; 4];
+            for p in rx {
+                let t = u32::from_le_bytes([p[0], p[1], p[2], p[3]]) as usize;
+                let seq = u32::from_le_bytes([p[4], p[5], p[6], p[7]]);
+                assert_eq!(seq, expected[t]);
+                expected[t] += 1;
+            }
+            expected
+        });
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        assert_eq!(consumer.join().unwrap(), [100_000; 4]);
+        assert_eq!(pool.free_stack.len(), ENTRIES);
+    }
+}
@@ -0,0 +1,619 @@
+//! Checksum, TCP segmentation and VLAN tag offloading.
+//!
+//! Devices report whether the checksums of received packets are valid, see
+//! [`Packet::ip_checksum`] and [`Packet::l4_checksum`], and insert the checksums of sent packets
+//! or split them into TCP segments as requested with [`Packet::set_tx_offload`].
+//!
+//! 802.1Q tags are stripped from received packets if enabled with
+//! [`DeviceConfig::vlan_strip`](crate::DeviceConfig::vlan_strip), see [`Packet::vlan_tci`], and
+//! inserted into sent packets as requested with [`Packet::set_vlan_tci`].
+
+use std::collections::VecDeque;
+
+use crate::error::IxyError;
+use crate::memory::{alloc_pkt, Packet, PACKET_HEADROOM};
+
+const IP_PROTO_TCP: u8 = 6;
+const IP_PROTO_UDP: u8 = 17;
+
+// minimum header lengths
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const TCP_HEADER_LEN: usize = 20;
+
+const TCP_FLAG_FIN: u8 = 0x01;
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_CWR: u8 = 0x80;
+
+const ETHERTYPE_VLAN: u16 = 0x8100;
+
+// destination and source mac address in front of the 802.1Q tag
+const MAC_ADDRS_LEN: usize = 12;
+const VLAN_TAG_LEN: usize = 4;
+
+const MAX_VLAN_ID: u16 = 4095;
+
+/// Result of the checksum validation of a received packet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumStatus {
+    /// The checksum was not validated, e.g. because the device does not support it or the
+    /// packet has no such checksum.
+    #[default]
+    Unknown,
+    Good,
+    Bad,
+}
+
+/// Network layer protocol of a sent packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum L3Type {
+    Ipv4,
+    Ipv6,
+}
+
+/// Transport layer protocol of a sent packet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum L4Type {
+    Tcp,
+    Udp,
+}
+
+impl L4Type {
+    /// Returns the ip protocol number of this protocol.
+    fn protocol(self) -> u8 {
+        match self {
+            L4Type::Tcp => IP_PROTO_TCP,
+            L4Type::Udp => IP_PROTO_UDP,
+        }
+    }
+
+    /// Returns the offset of the checksum in the header of this protocol.
+    pub(crate) fn checksum_offset(self) -> usize {
+        match self {
+            L4Type::Tcp => 16,
+            L4Type::Udp => 6,
+        }
+    }
+}
+
+/// Offloads requested for a sent packet, see [`Packet::set_tx_offload`].
+///
+/// The headers of the packet must be in its first segment, devices drop packets whose headers
+/// are not.
+///
+/// # Examples
+///
+/// ```rust
+/// use ixy::offload::{L4Type, TxOffload};
+///
+/// // an ethernet frame with an ipv4 header without options and a tcp header
+/// let offload = TxOffload::ipv4(14, 20)
+///     .ip_checksum()
+///     .l4_checksum(L4Type::Tcp);
+///
+/// // the same frame with up to 64 KB of payload sent as segments of 1460 bytes
+/// let offload = TxOffload::ipv4(14, 20).tso(1460, 20);
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TxOffload {
+    pub(crate) l2_len: usize,
+    pub(crate) l3_len: usize,
+    pub(crate) l3_type: L3Type,
+    pub(crate) ip_checksum: bool,
+    pub(crate) l4_checksum: Option<L4Type>,
+    pub(crate) l4_len: usize,
+    pub(crate) mss: Option<u16>,
+}
+
+impl TxOffload {
+    /// Returns an offload request without any offloads for an IPv4 packet with an `l2_len`
+    /// bytes long link layer header and an `l3_len` bytes long IPv4 header.
+    pub fn ipv4(l2_len: usize, l3_len: usize) -> Self {
+        TxOffload {
+            l2_len,
+            l3_len,
+            l3_type: L3Type::Ipv4,
+            ip_checksum: false,
+            l4_checksum: None,
+            l4_len: 0,
+            mss: None,
+        }
+    }
+
+    /// Returns an offload request without any offloads for an IPv6 packet with an `l2_len`
+    /// bytes long link layer header and `l3_len` bytes of IPv6 headers.
+    pub fn ipv6(l2_len: usize, l3_len: usize) -> Self {
+        TxOffload {
+            l3_type: L3Type::Ipv6,
+            ..TxOffload::ipv4(l2_len, l3_len)
+        }
+    }
+
+    /// Inserts the IPv4 header checksum, has no effect on IPv6 packets.
+    pub fn ip_checksum(mut self) -> Self {
+        self.ip_checksum = self.l3_type == L3Type::Ipv4;
+        self
+    }
+
+    /// Inserts the checksum of the `l4_type` header.
+    pub fn l4_checksum(mut self, l4_type: L4Type) -> Self {
+        self.l4_checksum = Some(l4_type);
+        self
+    }
+
+    /// Splits the packet into TCP segments with up to `mss` bytes of payload each, `l4_len` is
+    /// the length of the TCP header. Implies the TCP and IPv4 header checksums.
+    ///
+    /// Devices that can't segment packets do so in software, the segments replace the packet
+    /// in the buffer passed to [`IxyDevice::tx_batch`](crate::IxyDevice::tx_batch) and are
+    /// counted as sent packets individually.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `mss` is 0.
+    pub fn tso(mut self, mss: u16, l4_len: usize) -> Self {
+        assert!(mss > 0, "mss must not be 0");
+        self.mss = Some(mss);
+        self.l4_len = l4_len;
+        self.l4_checksum(L4Type::Tcp).ip_checksum()
+    }
+
+    /// Returns the offset of the transport layer header.
+    pub(crate) fn l4_offset(&self) -> usize {
+        self.l2_len + self.l3_len
+    }
+
+    /// Returns the length of all headers of a TCP segment.
+    pub(crate) fn header_len(&self) -> usize {
+        self.l4_offset() + self.l4_len
+    }
+
+    /// Returns whether the headers this offload refers to lie within `first`, the first segment
+    /// of a packet. Devices and the software fallbacks only look for the headers there.
+    pub(crate) fn fits(&self, first: &[u8]) -> bool {
+        if !self.ip_checksum && self.l4_checksum.is_none() {
+            return true;
+        }
+
+        let min_l3_len = match self.l3_type {
+            L3Type::Ipv4 => IPV4_HEADER_LEN,
+            L3Type::Ipv6 => IPV6_HEADER_LEN,
+        };
+        let headers_len = match (self.mss, self.l4_checksum) {
+            (Some(_), _) if self.l4_len < TCP_HEADER_LEN => return false,
+            (Some(_), _) => self.header_len(),
+            (None, Some(l4_type)) => self.l4_offset() + l4_type.checksum_offset() + 2,
+            (None, None) => self.l4_offset(),
+        };
+
+        self.l3_len >= min_l3_len && first.len() >= headers_len
+    }
+
+    /// Prepares `packet` for checksum insertion by the device, i.e. clears the IPv4 header
+    /// checksum and sets the l4 checksum to the sum of the pseudo header.
+    pub(crate) fn prepare(&self, packet: &mut Packet) {
+        // the checksum of packets to be segmented covers a pseudo header without length
+        let l4_len = match self.mss {
+            Some(_) => 0,
+            None => packet.total_len() - self.l4_offset(),
+        };
+
+        self.prepare_frame(packet, l4_len);
+    }
+
+    /// Prepares the headers of `frame` with an `l4_len` bytes long l4 packet, see
+    /// [`prepare`](TxOffload::prepare).
+    fn prepare_frame(&self, frame: &mut [u8], l4_len: usize) {
+        if self.ip_checksum {
+            frame[self.l2_len + 10..self.l2_len + 12].copy_from_slice(&[0, 0]);
+        }
+
+        if let Some(l4_type) = self.l4_checksum {
+            let sum = pseudo_header_sum(&frame[self.l2_len..], self.l3_type, l4_type, l4_len);
+            let offset = self.l4_offset() + l4_type.checksum_offset();
+            frame[offset..offset + 2].copy_from_slice(&sum.fold().to_be_bytes());
+        }
+    }
+
+    /// Inserts the IPv4 header checksum into the prepared `frame`.
+    pub(crate) fn insert_ip_checksum(&self, frame: &mut [u8]) {
+        if self.ip_checksum {
+            let mut sum = Checksum::default();
+            sum.add(&frame[self.l2_len..self.l4_offset()]);
+            frame[self.l2_len + 10..self.l2_len + 12].copy_from_slice(&(!sum.fold()).to_be_bytes());
+        }
+    }
+
+    /// Inserts the l4 checksum into the prepared frame consisting of `first` followed by `rest`.
+    pub(crate) fn insert_l4_checksum<'a>(
+        &self,
+        first: &mut [u8],
+        rest: impl IntoIterator<Item = &'a [u8]>,
+    ) {
+        if let Some(l4_type) = self.l4_checksum {
+            let mut sum = Checksum::default();
+            sum.add(&first[self.l4_offset()..]);
+            for segment in rest {
+                sum.add(segment);
+            }
+
+            let mut checksum = !sum.fold();
+            // a zero udp checksum means that the packet has no checksum
+            if l4_type == L4Type::Udp && checksum == 0 {
+                checksum = 0xffff;
+            }
+
+            let offset = self.l4_offset() + l4_type.checksum_offset();
+            first[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+        }
+    }
+
+    /// Computes and inserts all requested checksums of `packet` in software.
+    pub(crate) fn apply(&self, packet: &mut Packet) {
+        self.prepare(packet);
+        self.insert_ip_checksum(packet);
+
+        let rest = packet.take_next_segment();
+        self.insert_l4_checksum(
+            packet,
+            rest.iter().flat_map(|rest| rest.segments()).map(|s| &s[..]),
+        );
+        if let Some(rest) = rest {
+            packet.push_segment(rest);
+        }
+    }
+
+    /// Returns the TCP segments of the ethernet `frame` including all checksums like a device
+    /// would send them.
+    pub(crate) fn segment_frame(&self, frame: &[u8]) -> Vec<Vec<u8>> {
+        let mss = usize::from(self.mss.unwrap_or(u16::MAX));
+        let (headers, payload) = frame.split_at(self.header_len());
+        let ip = self.l2_len;
+        let tcp = self.l4_offset();
+
+        let segment_offload = TxOffload { mss: None, ..*self };
+        let num_segments = payload.len().div_ceil(mss).max(1);
+        let id = u16::from_be_bytes([headers[ip + 4], headers[ip + 5]]);
+        let seq = u32::from_be_bytes([
+            headers[tcp + 4],
+            headers[tcp + 5],
+            headers[tcp + 6],
+            headers[tcp + 7],
+        ]);
+
+        (0..num_segments)
+            .map(|i| {
+                let chunk =
+                    &payload[(i * mss).min(payload.len())..((i + 1) * mss).min(payload.len())];
+                let mut segment = [headers, chunk].concat();
+                let l4_len = self.l4_len + chunk.len();
+
+                match self.l3_type {
+                    L3Type::Ipv4 => {
+                        let total_len = (self.l3_len + l4_len) as u16;
+                        segment[ip + 2..ip + 4].copy_from_slice(&total_len.to_be_bytes());
+                        let id = id.wrapping_add(i as u16);
+                        segment[ip + 4..ip + 6].copy_from_slice(&id.to_be_bytes());
+                    }
+                    L3Type::Ipv6 => {
+                        let payload_len = (self.l3_len - 40 + l4_len) as u16;
+                        segment[ip + 4..ip + 6].copy_from_slice(&payload_len.to_be_bytes());
+                    }
+                }
+
+                let seq = seq.wrapping_add((i * mss) as u32);
+                segment[tcp + 4..tcp + 8].copy_from_slice(&seq.to_be_bytes());
+                // only the last segment finishes or pushes, only the first reduces the window
+                if i + 1 < num_segments {
+                    segment[tcp + 13] &= !(TCP_FLAG_FIN | TCP_FLAG_PSH);
+                }
+                if i > 0 {
+                    segment[tcp + 13] &= !TCP_FLAG_CWR;
+                }
+
+                segment_offload.prepare_frame(&mut segment, l4_len);
+                segment_offload.insert_ip_checksum(&mut segment);
+                segment_offload.insert_l4_checksum(&mut segment, None);
+                segment
+            })
+            .collect()
+    }
+
+    /// Replaces `packet` by its TCP segments at the front of `buffer`, for devices that can't
+    /// segment packets. Returns `false` and puts `packet` back if its pool has not enough free
+    /// buffers for the segments.
+    pub(crate) fn segment_into(&self, packet: Packet, buffer: &mut VecDeque<Packet>) -> bool {
+        let frame = packet
+            .segments()
+            .flat_map(|segment| segment.iter().copied())
+            .collect::<Vec<_>>();
+        let pool = packet.get_pool();
+        let capacity = pool.entry_size() - PACKET_HEADROOM;
+
+        let mut segments = Vec::new();
+        for frame in self.segment_frame(&frame) {
+            let mut segment: Option<Packet> = None;
+
+            for chunk in frame.chunks(capacity) {
+                let mut p = match alloc_pkt(pool, chunk.len()) {
+                    Some(p) => p,
+                    None => {
+                        buffer.push_front(packet);
+                        return false;
+                    }
+                };
+                p.copy_from_slice(chunk);
+
+                match segment {
+                    Some(ref mut head) => head.push_segment(p),
+                    None => segment = Some(p),
+                }
+            }
+
+            // the segments keep the vlan tag of the segmented packet
+            if let Some(ref mut segment) = segment {
+                segment.meta.vlan_tci = packet.vlan_tci();
+            }
+
+            segments.extend(segment);
+        }
+
+        for segment in segments.into_iter().rev() {
+            buffer.push_front(segment);
+        }
+
+        true
+    }
+}
+
+/// Removes the 802.1Q tag of a received `packet` and reports its tag control information.
+pub(crate) fn strip_vlan_tag(packet: &mut Packet) {
+    if packet.len() < MAC_ADDRS_LEN + VLAN_TAG_LEN
+        || packet[MAC_ADDRS_LEN..MAC_ADDRS_LEN + 2] != ETHERTYPE_VLAN.to_be_bytes()
+    {
+        return;
+    }
+
+    packet.meta.vlan_tci = Some(u16::from_be_bytes([
+        packet[MAC_ADDRS_LEN + 2],
+        packet[MAC_ADDRS_LEN + 3],
+    ]));
+
+    // move the mac addresses onto the tag, the buffer is still freed by its pool entry
+    packet.copy_within(..MAC_ADDRS_LEN, VLAN_TAG_LEN);
+    packet.addr_virt = unsafe { packet.addr_virt.add(VLAN_TAG_LEN) };
+    packet.addr_phys += VLAN_TAG_LEN;
+    packet.len -= VLAN_TAG_LEN;
+}
+
+/// Inserts the 802.1Q tag requested for `packet` in software and moves the offloaded headers
+/// behind it. Returns `false` if the first segment has no room for the tag.
+pub(crate) fn insert_vlan_tag(packet: &mut Packet) -> bool {
+    let tci = match packet.meta.vlan_tci.take() {
+        Some(tci) => tci,
+        None => return true,
+    };
+
+    let len = packet.len();
+    if len < MAC_ADDRS_LEN || len + VLAN_TAG_LEN > packet.capacity() {
+        return false;
+    }
+
+    packet.len += VLAN_TAG_LEN;
+    packet.copy_within(MAC_ADDRS_LEN..len, MAC_ADDRS_LEN + VLAN_TAG_LEN);
+    packet[MAC_ADDRS_LEN..MAC_ADDRS_LEN + 2].copy_from_slice(&ETHERTYPE_VLAN.to_be_bytes());
+    packet[MAC_ADDRS_LEN + 2..MAC_ADDRS_LEN + 4].copy_from_slice(&tci.to_be_bytes());
+
+    if let Some(ref mut offload) = packet.meta.tx_offload {
+        offload.l2_len += VLAN_TAG_LEN;
+    }
+
+    true
+}
+
+/// Returns the tag control information of the 802.1Q tagged ethernet `frame`, [`None`] if it is
+/// untagged.
+#[cfg(test)]
+pub(crate) fn vlan_tci(frame: &[u8]) -> Option<u16> {
+    match frame.get(MAC_ADDRS_LEN..MAC_ADDRS_LEN + VLAN_TAG_LEN) {
+        Some(tag) if tag[..2] == ETHERTYPE_VLAN.to_be_bytes() => {
+            Some(u16::from_be_bytes([tag[2], tag[3]]))
+        }
+        _ => None,
+    }
+}
+
+/// Fails with [`IxyError::InvalidConfig`] if `vlan_id` is not a valid 12 bit vlan id.
+pub(crate) fn check_vlan_id(vlan_id: u16) -> Result<(), IxyError> {
+    if vlan_id > MAX_VLAN_ID {
+        return Err(IxyError::InvalidConfig(format!(
+            "vlan id {} must be at most {}",
+            vlan_id, MAX_VLAN_ID
+        )));
+    }
+
+    Ok(())
+}
+
+/// Inserts the l4 checksum of a packet whose checksum field at `start` + `offset` holds the sum
+/// of the pseudo header, as reported by virtio devices for partially checksummed packets.
+/// Returns `false` and leaves the packet unchanged if the checksum field is not within the first
+/// segment.
+pub(crate) fn complete_checksum(packet: &mut Packet, start: usize, offset: usize) -> bool {
+    if start + offset + 2 > packet.len() {
+        return false;
+    }
+
+    let mut sum = Checksum::default();
+    sum.add(&packet[start..]);
+    for segment in packet.segments().skip(1) {
+        sum.add(segment);
+    }
+
+    let checksum = !sum.fold();
+    let offset = start + offset;
+    packet[offset..offset + 2].copy_from_slice(&checksum.to_be_bytes());
+
+    true
+}
+
+/// Returns the sum of the pseudo header of the ip packet `ip` with an `l4_len` bytes long
+/// `l4_type` payload.
+fn pseudo_header_sum(ip: &[u8], l3_type: L3Type, l4_type: L4Type, l4_len: usize) -> Checksum {
+    let mut sum = Checksum::default();
+
+    match l3_type {
+        L3Type::Ipv4 => {
+            sum.add(&ip[12..20]);
+            sum.add(&[0, l4_type.protocol()]);
+            sum.add(&(l4_len as u16).to_be_bytes());
+        }
+        L3Type::Ipv6 => {
+            sum.add(&ip[8..40]);
+            sum.add(&(l4_len as u32).to_be_bytes());
+            sum.add(&[0, 0, 0, l4_type.protocol()]);
+        }
+    }
+
+    sum
+}
+
+/// Returns the status of the IPv4 header checksum and the TCP or UDP checksum of the ethernet
+/// `frame` as a device validating them would report it.
+#[cfg(test)]
+pub(crate) fn verify_checksums(frame: &[u8]) -> (ChecksumStatus, ChecksumStatus) {
+    let status = |sum: Checksum| {
+        if sum.fold() == 0xffff {
+            ChecksumStatus::Good
+        } else {
+            ChecksumStatus::Bad
+        }
+    };
+
+    let ip = frame.get(14..).unwrap_or_default();
+    let (l3_type, ip_status, proto, l4) = match frame.get(12..14) {
+        Some([0x08, 0x00]) if ip.len() >= 20 => {
+            let header_len = usize::from(ip[0] & 0xf) * 4;
+            let mut sum = Checksum::default();
+            sum.add(&ip[..header_len.min(ip.len())]);
+            // only unfragmented packets contain the whole l4 payload
+            let fragmented = u16::from_be_bytes([ip[6], ip[7]]) & 0x3fff != 0;
+            let proto = if fragmented { 0 } else { ip[9] };
+            (L3Type::Ipv4, status(sum), proto, ip.get(header_len..))
+        }
+        Some([0x86, 0xdd]) if ip.len() >= 40 => {
+            (L3Type::Ipv6, ChecksumStatus::Unknown, ip[6], ip.get(40..))
+        }
+        _ => return (ChecksumStatus::Unknown, ChecksumStatus::Unknown),
+    };
+
+    let l4_type = match (proto, l4.and_then(|l4| l4.get(..8))) {
+        (IP_PROTO_TCP, Some(_)) => L4Type::Tcp,
+        // a zero udp checksum means that the packet has no checksum
+        (IP_PROTO_UDP, Some(udp)) if udp[6..8] != [0, 0] => L4Type::Udp,
+        _ => return (ip_status, ChecksumStatus::Unknown),
+    };
+
+    let l4 = l4.unwrap_or_default();
+    let mut sum = pseudo_header_sum(ip, l3_type, l4_type, l4.len());
+    sum.add(l4);
+    (ip_status, status(sum))
+}
+
+/// Ones' complement sum of 16 bit words of data that may be split at odd offsets.
+#[derive(Default)]
+struct Checksum {
+    sum: u64,
+    odd: bool,
+}
+
+impl Checksum {
+    /// Adds `data` to the sum.
+    fn add(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.sum += if self.odd {
+                u64::from(byte)
+            } else {
+                u64::from(byte) << 8
+            };
+            self.odd = !self.odd;
+        }
+    }
+
+    /// Returns the sum folded to 16 bits.
+    fn fold(&self) -> u16 {
+        let mut sum = self.sum;
+        while sum >> 16 != 0 {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum as u16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_checksum() {
+        // example of RFC 1071
+        let mut sum = Checksum::default();
+        sum.add(&[0x00, 0x01, 0xf2]);
+        sum.add(&[0x03, 0xf4, 0xf5, 0xf6, 0xf7]);
+        assert_eq!(sum.fold(), 0xddf2);
+
+        // ipv4 header of a udp packet from 192.168.0.1 to 192.168.0.199
+        let mut frame = vec![0; 14 + 20 + 8 + 2];
+        frame[12..14].copy_from_slice(&[0x08, 0x00]);
+        frame[14..34].copy_from_slice(&[
+            0x45, 0x00, 0x00, 0x1e, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0xb8, 0xb6, 0xc0, 0xa8,
+            0x00, 0x01, 0xc0, 0xa8, 0x00, 0xc7,
+        ]);
+        frame[34..44].copy_from_slice(&[0x04, 0x00, 0x00, 0x35, 0x00, 0x0a, 0x00, 0x00, 0, 0]);
+        assert_eq!(
+            verify_checksums(&frame),
+            (ChecksumStatus::Good, ChecksumStatus::Unknown)
+        );
+
+        let offload = TxOffload::ipv4(14, 20)
+            .ip_checksum()
+            .l4_checksum(L4Type::Udp);
+        frame[24..26].copy_from_slice(&[0, 0]);
+        offload.insert_ip_checksum(&mut frame);
+
+        // the payload is split at an odd offset
+        let sum = pseudo_header_sum(&frame[14..], L3Type::Ipv4, L4Type::Udp, 10);
+        frame[40..42].copy_from_slice(&sum.fold().to_be_bytes());
+        let (first, rest) = frame.split_at_mut(43);
+        offload.insert_l4_checksum(first, Some(&rest[..]));
+
+        assert_eq!(&frame[24..26], &[0xb8, 0xb6]);
+        assert_eq!(
+            verify_checksums(&frame),
+            (ChecksumStatus::Good, ChecksumStatus::Good)
+        );
+    }
+    #[test]
+    fn test_offload_bounds() {
+        let offload = TxOffload::ipv4(14, 20).l4_checksum(L4Type::Tcp);
+        assert!(offload.fits(&[0; 14 + 20 + 18]));
+        assert!(!offload.fits(&[0; 14 + 20 + 17]));
+        assert!(!TxOffload::ipv4(14, 8).ip_checksum().fits(&[0; 64]));
+        assert!(!TxOffload::ipv6(14, 20)
+            .l4_checksum(L4Type::Udp)
+            .fits(&[0; 64]));
+        assert!(TxOffload::ipv4(14, 0).fits(&[]));
+
+        let tso = TxOffload::ipv4(14, 20).tso(1460, 20);
+        assert!(tso.fits(&[0; 54]));
+        assert!(!tso.fits(&[0; 53]));
+        assert!(!TxOffload::ipv4(14, 20).tso(1460, 8).fits(&[0; 64]));
+
+        // checksum fields reported by a device outside of the packet are ignored
+        let pool = crate::Mempool::allocate_anonymous(1, 0).unwrap();
+        let mut packet = alloc_pkt(&pool, 60).unwrap();
+        assert!(!complete_checksum(&mut packet, 50, 16));
+        assert!(!complete_checksum(&mut packet, usize::from(u16::MAX), 0));
+        assert!(complete_checksum(&mut packet, 34, 16));
+    }
+}
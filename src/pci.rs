@@ -1,4 +1,3 @@
-use std::error::Error;
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, Read, Seek, SeekFrom, Write};
 use std::os::unix::prelude::AsRawFd;
@@ -6,46 +5,48 @@ use std::ptr;
 
 use byteorder::{NativeEndian, ReadBytesExt, WriteBytesExt};
 
+use crate::error::IxyError;
+
 // write to the command register (offset 4) in the PCIe config space
 pub const COMMAND_REGISTER_OFFSET: u64 = 4;
 // bit 2 is "bus master enable", see PCIe 3.0 specification section 7.5.1.1
 pub const BUS_MASTER_ENABLE_BIT: u64 = 2;
 
 /// Unbinds the driver from the device at `pci_addr`.
-pub fn unbind_driver(pci_addr: &str) -> Result<(), Box<dyn Error>> {
+pub fn unbind_driver(pci_addr: &str) -> Result<(), IxyError> {
     let path = format!("/sys/bus/pci/devices/{}/driver/unbind", pci_addr);
 
     match fs::OpenOptions::new().write(true).open(path) {
-        Ok(mut f) => {
-            write!(f, "{}", pci_addr)?;
-            Ok(())
-        }
+        Ok(mut f) => write!(f, "{}", pci_addr).map_err(pci_error(pci_addr)),
         Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
-        Err(e) => Err(Box::new(e)),
+        Err(e) => Err(pci_error(pci_addr)(e)),
     }
 }
 
 /// Enables direct memory access for the device at `pci_addr`.
-pub fn enable_dma(pci_addr: &str) -> Result<(), Box<dyn Error>> {
-    let path = format!("/sys/bus/pci/devices/{}/config", pci_addr);
-    let mut file = fs::OpenOptions::new().read(true).write(true).open(&path)?;
+pub fn enable_dma(pci_addr: &str) -> Result<(), IxyError> {
+    let mut file = pci_open_resource(pci_addr, "config")?;
 
-    let mut dma = read_io16(&mut file, COMMAND_REGISTER_OFFSET)?;
+    let mut dma = read_io16(&mut file, COMMAND_REGISTER_OFFSET).map_err(pci_error(pci_addr))?;
     dma |= 1 << BUS_MASTER_ENABLE_BIT;
-    write_io16(&mut file, dma, COMMAND_REGISTER_OFFSET)?;
+    write_io16(&mut file, dma, COMMAND_REGISTER_OFFSET).map_err(pci_error(pci_addr))?;
 
     Ok(())
 }
 
 /// Mmaps a pci resource and returns a pointer to the mapped memory.
-pub fn pci_map_resource(pci_addr: &str) -> Result<(*mut u8, usize), Box<dyn Error>> {
-    let path = format!("/sys/bus/pci/devices/{}/resource0", pci_addr);
-
+pub fn pci_map_resource(pci_addr: &str) -> Result<(*mut u8, usize), IxyError> {
     unbind_driver(pci_addr)?;
     enable_dma(pci_addr)?;
 
-    let file = fs::OpenOptions::new().read(true).write(true).open(&path)?;
-    let len = fs::metadata(&path)?.len() as usize;
+    pci_map_bar(pci_addr, 0)
+}
+
+/// Mmaps the memory bar `bar` of the device at `pci_addr` and returns a pointer to the mapped
+/// memory.
+pub fn pci_map_bar(pci_addr: &str, bar: u8) -> Result<(*mut u8, usize), IxyError> {
+    let file = pci_open_resource(pci_addr, &format!("resource{}", bar))?;
+    let len = file.metadata().map_err(pci_error(pci_addr))?.len() as usize;
 
     let ptr = unsafe {
         libc::mmap(
@@ -58,23 +59,42 @@ pub fn pci_map_resource(pci_addr: &str) -> Result<(*mut u8, usize), Box<dyn Erro
         ) as *mut u8
     };
 
-    if ptr.is_null() || len == 0 {
-        Err("pci mapping failed".into())
+    if ptr as *mut libc::c_void == libc::MAP_FAILED || len == 0 {
+        Err(IxyError::Pci {
+            pci_addr: pci_addr.to_string(),
+            source: io::Error::other("pci mapping failed"),
+        })
     } else {
         Ok((ptr, len))
     }
 }
 
 /// Opens a pci resource file at the given address.
-pub fn pci_open_resource(pci_addr: &str, resource: &str) -> Result<File, Box<dyn Error>> {
+pub fn pci_open_resource(pci_addr: &str, resource: &str) -> Result<File, IxyError> {
     let path = format!("/sys/bus/pci/devices/{}/{}", pci_addr, resource);
-    Ok(OpenOptions::new().read(true).write(true).open(path)?)
+    OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .map_err(pci_error(pci_addr))
 }
 
 /// Opens a pci resource file at the given address in read-only mode.
-pub fn pci_open_resource_ro(pci_addr: &str, resource: &str) -> Result<File, Box<dyn Error>> {
+pub fn pci_open_resource_ro(pci_addr: &str, resource: &str) -> Result<File, IxyError> {
     let path = format!("/sys/bus/pci/devices/{}/{}", pci_addr, resource);
-    Ok(OpenOptions::new().read(true).write(false).open(path)?)
+    OpenOptions::new()
+        .read(true)
+        .write(false)
+        .open(path)
+        .map_err(pci_error(pci_addr))
+}
+
+/// Returns a function that wraps an `io::Error` into an `IxyError::Pci` for `pci_addr`.
+pub fn pci_error(pci_addr: &str) -> impl Fn(io::Error) -> IxyError + '_ {
+    move |source| IxyError::Pci {
+        pci_addr: pci_addr.to_string(),
+        source,
+    }
 }
 
 /// Reads and returns an u8 at `offset` in `file`.
@@ -114,12 +134,10 @@ pub fn write_io32(file: &mut File, value: u32, offset: u64) -> Result<(), io::Er
 }
 
 /// Reads a hex string from `file` and returns it as `u64`.
-pub fn read_hex(file: &mut File) -> Result<u64, Box<dyn Error>> {
+pub fn read_hex(file: &mut File) -> Result<u64, io::Error> {
     let mut buffer = String::new();
     file.read_to_string(&mut buffer)?;
 
-    Ok(u64::from_str_radix(
-        &buffer.trim().trim_start_matches("0x"),
-        16,
-    )?)
+    u64::from_str_radix(buffer.trim().trim_start_matches("0x"), 16)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
 }
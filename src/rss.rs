@@ -0,0 +1,320 @@
+//! Receive side scaling (RSS) distributes received packets over multiple rx queues.
+//!
+//! The device computes a Toeplitz hash over the addresses and ports of each packet and looks up
+//! the rx queue in a redirection table (RETA) indexed by the lower 7 bits of the hash.
+
+use std::ops::BitOr;
+
+/// Size of the hash key in bytes.
+pub const RSS_KEY_SIZE: usize = 40;
+
+/// Number of entries of the redirection table.
+pub const RETA_SIZE: usize = 128;
+
+/// The key used to verify RSS implementations in Microsoft's RSS specification.
+pub const DEFAULT_RSS_KEY: [u8; RSS_KEY_SIZE] = [
+    0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3, 0x8f, 0xb0,
+    0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3, 0x80, 0x30, 0xf2, 0x0c,
+    0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa,
+];
+
+const ETHER_TYPE_IPV4: u16 = 0x0800;
+const ETHER_TYPE_IPV6: u16 = 0x86dd;
+const IP_PROTO_TCP: u8 = 6;
+const IP_PROTO_UDP: u8 = 17;
+
+/// Set of packet types whose fields are hashed.
+///
+/// Packets matching a TCP or UDP type are hashed over addresses and ports, other packets of an
+/// enabled IP version only over their addresses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RssHashFields(u32);
+
+impl RssHashFields {
+    /// No fields, packets are not hashed.
+    pub const NONE: RssHashFields = RssHashFields(0);
+    /// Source and destination IPv4 addresses.
+    pub const IPV4: RssHashFields = RssHashFields(1 << 0);
+    /// Source and destination IPv4 addresses and TCP ports.
+    pub const IPV4_TCP: RssHashFields = RssHashFields(1 << 1);
+    /// Source and destination IPv4 addresses and UDP ports.
+    pub const IPV4_UDP: RssHashFields = RssHashFields(1 << 2);
+    /// Source and destination IPv6 addresses.
+    pub const IPV6: RssHashFields = RssHashFields(1 << 3);
+    /// Source and destination IPv6 addresses and TCP ports.
+    pub const IPV6_TCP: RssHashFields = RssHashFields(1 << 4);
+    /// Source and destination IPv6 addresses and UDP ports.
+    pub const IPV6_UDP: RssHashFields = RssHashFields(1 << 5);
+    /// All of the above.
+    pub const ALL: RssHashFields = RssHashFields(0x3f);
+
+    /// Returns whether all fields of `other` are contained in `self`.
+    pub fn contains(self, other: RssHashFields) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for RssHashFields {
+    type Output = RssHashFields;
+
+    fn bitor(self, rhs: RssHashFields) -> RssHashFields {
+        RssHashFields(self.0 | rhs.0)
+    }
+}
+
+/// RSS configuration, used with [`DeviceConfig::rss`](crate::DeviceConfig::rss).
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use ixy::*;
+///
+/// let rss = RssConfig::new().hash_fields(RssHashFields::IPV4 | RssHashFields::IPV4_TCP);
+/// let config = DeviceConfig::new().num_rx_queues(4).rss(rss);
+///
+/// let mut dev = ixy_init_with_config("0000:01:00.0", &config).unwrap();
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RssConfig {
+    pub(crate) key: [u8; RSS_KEY_SIZE],
+    pub(crate) hash_fields: RssHashFields,
+    pub(crate) reta: Option<[u8; RETA_SIZE]>,
+}
+
+impl Default for RssConfig {
+    fn default() -> Self {
+        RssConfig {
+            key: DEFAULT_RSS_KEY,
+            hash_fields: RssHashFields::ALL,
+            reta: None,
+        }
+    }
+}
+
+impl RssConfig {
+    /// Returns a configuration hashing all fields with [`DEFAULT_RSS_KEY`] and distributing
+    /// packets evenly over all rx queues.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Sets the hash key.
+    pub fn key(mut self, key: [u8; RSS_KEY_SIZE]) -> Self {
+        self.key = key;
+        self
+    }
+
+    /// Sets the packet types whose fields are hashed.
+    pub fn hash_fields(mut self, fields: RssHashFields) -> Self {
+        self.hash_fields = fields;
+        self
+    }
+
+    /// Sets the redirection table, each entry is the rx queue for the hashes whose lower 7 bits
+    /// equal its index.
+    pub fn reta(mut self, reta: [u8; RETA_SIZE]) -> Self {
+        self.reta = Some(reta);
+        self
+    }
+
+    /// Returns the redirection table, which assigns the entries round robin to `num_queues` rx
+    /// queues unless set explicitly.
+    pub fn redirection_table(&self, num_queues: u16) -> [u8; RETA_SIZE] {
+        self.reta.unwrap_or_else(|| {
+            let mut reta = [0; RETA_SIZE];
+            for (i, entry) in reta.iter_mut().enumerate() {
+                *entry = (i % num_queues.max(1) as usize) as u8;
+            }
+            reta
+        })
+    }
+
+    /// Returns the hash the device computes for the ethernet `frame`, or [`None`] if it is not
+    /// hashed with the configured fields.
+    pub fn hash(&self, frame: &[u8]) -> Option<u32> {
+        self.hash_input(frame)
+            .map(|(_, input)| toeplitz_hash(&self.key, &input))
+    }
+
+    /// Returns the rx queue of a packet with `hash` for a device with `num_queues` rx queues.
+    pub fn queue(&self, hash: u32, num_queues: u16) -> u16 {
+        u16::from(self.redirection_table(num_queues)[hash as usize % RETA_SIZE])
+    }
+
+    /// Returns the type of the packet in `frame` and the input of the hash function, i.e. the
+    /// source and destination addresses followed by the source and destination ports.
+    pub(crate) fn hash_input(&self, frame: &[u8]) -> Option<(RssHashFields, Vec<u8>)> {
+        let ether_type = u16::from_be_bytes([*frame.get(12)?, *frame.get(13)?]);
+        let ip = &frame[14..];
+
+        let (addrs, proto, l4, l3_type, tcp_type, udp_type) = match ether_type {
+            ETHER_TYPE_IPV4 => {
+                let header_len = usize::from(ip.first()? & 0xf) * 4;
+                // only the first fragment contains the ports
+                let fragmented = u16::from_be_bytes([*ip.get(6)?, *ip.get(7)?]) & 0x3fff != 0;
+                let proto = if fragmented { 0 } else { *ip.get(9)? };
+                (
+                    ip.get(12..20)?,
+                    proto,
+                    ip.get(header_len..),
+                    RssHashFields::IPV4,
+                    RssHashFields::IPV4_TCP,
+                    RssHashFields::IPV4_UDP,
+                )
+            }
+            ETHER_TYPE_IPV6 => (
+                ip.get(8..40)?,
+                *ip.get(6)?,
+                ip.get(40..),
+                RssHashFields::IPV6,
+                RssHashFields::IPV6_TCP,
+                RssHashFields::IPV6_UDP,
+            ),
+            _ => return None,
+        };
+
+        let l4_type = match proto {
+            IP_PROTO_TCP => Some(tcp_type),
+            IP_PROTO_UDP => Some(udp_type),
+            _ => None,
+        };
+
+        if let (Some(l4_type), Some(ports)) = (l4_type, l4.and_then(|l4| l4.get(..4))) {
+            if self.hash_fields.contains(l4_type) {
+                return Some((l4_type, [addrs, ports].concat()));
+            }
+        }
+
+        if self.hash_fields.contains(l3_type) {
+            Some((l3_type, addrs.to_vec()))
+        } else {
+            None
+        }
+    }
+}
+
+/// Returns the Toeplitz hash of `input` with `key`, which must be at least 4 bytes longer than
+/// `input`.
+pub fn toeplitz_hash(key: &[u8], input: &[u8]) -> u32 {
+    let mut hash = 0;
+    // the 32 bits of the key starting at the current bit of the input
+    let mut window = u32::from_be_bytes([key[0], key[1], key[2], key[3]]);
+
+    for (i, byte) in input.iter().enumerate() {
+        for bit in 0..8 {
+            if byte & (0x80 >> bit) != 0 {
+                hash ^= window;
+            }
+
+            let next_bit = key.get(i + 4).map_or(0, |k| (k >> (7 - bit)) & 1);
+            window = (window << 1) | u32::from(next_bit);
+        }
+    }
+
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns an ethernet frame with an IPv4 header and the first 4 bytes of a TCP header.
+    fn ipv4_tcp_frame(src: [u8; 4], dst: [u8; 4], sport: u16, dport: u16) -> Vec<u8> {
+        let mut frame = vec![0; 14 + 20 + 4];
+        frame[12..14].copy_from_slice(&ETHER_TYPE_IPV4.to_be_bytes());
+        frame[14] = 0x45;
+        frame[14 + 9] = IP_PROTO_TCP;
+        frame[14 + 12..14 + 16].copy_from_slice(&src);
+        frame[14 + 16..14 + 20].copy_from_slice(&dst);
+        frame[34..36].copy_from_slice(&sport.to_be_bytes());
+        frame[36..38].copy_from_slice(&dport.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn test_toeplitz_hash() {
+        // verification suite of Microsoft's RSS specification
+        let ipv4 = [
+            (
+                [66, 9, 149, 187],
+                [161, 142, 100, 80],
+                2794,
+                1766,
+                0x323e_8fc2,
+                0x51cc_c178,
+            ),
+            (
+                [199, 92, 111, 2],
+                [65, 69, 140, 83],
+                14230,
+                4739,
+                0xd718_262a,
+                0xc626_b0ea,
+            ),
+            (
+                [24, 19, 198, 95],
+                [12, 22, 207, 184],
+                12898,
+                38024,
+                0xd2d0_a5de,
+                0x5c2b_394a,
+            ),
+            (
+                [38, 27, 205, 30],
+                [209, 142, 163, 6],
+                48228,
+                2217,
+                0x8298_9176,
+                0xafc7_327f,
+            ),
+            (
+                [153, 39, 163, 191],
+                [202, 188, 127, 2],
+                44251,
+                1303,
+                0x5d18_09c5,
+                0x10e8_28a2,
+            ),
+        ];
+
+        let l3 = RssConfig::new().hash_fields(RssHashFields::IPV4);
+        let l4 = RssConfig::new();
+
+        for &(src, dst, sport, dport, l3_hash, l4_hash) in &ipv4 {
+            let frame = ipv4_tcp_frame(src, dst, sport, dport);
+            assert_eq!(l3.hash(&frame), Some(l3_hash));
+            assert_eq!(l4.hash(&frame), Some(l4_hash));
+        }
+    }
+
+    #[test]
+    fn test_hash_input() {
+        let rss = RssConfig::new().hash_fields(RssHashFields::IPV4_UDP);
+        let frame = ipv4_tcp_frame([1, 2, 3, 4], [5, 6, 7, 8], 1, 2);
+        assert_eq!(rss.hash(&frame), None);
+
+        let rss = RssConfig::new();
+        let (fields, input) = rss.hash_input(&frame).unwrap();
+        assert_eq!(fields, RssHashFields::IPV4_TCP);
+        assert_eq!(input, [1, 2, 3, 4, 5, 6, 7, 8, 0, 1, 0, 2]);
+
+        // fragments are only hashed over their addresses
+        let mut fragment = frame.clone();
+        fragment[14 + 6] = 0x20;
+        assert_eq!(rss.hash_input(&fragment).unwrap().0, RssHashFields::IPV4);
+
+        let mut arp = frame;
+        arp[12..14].copy_from_slice(&0x0806u16.to_be_bytes());
+        assert_eq!(rss.hash(&arp), None);
+    }
+
+    #[test]
+    fn test_redirection_table() {
+        let rss = RssConfig::new();
+        assert_eq!(rss.redirection_table(3)[..4], [0, 1, 2, 0]);
+        assert_eq!(rss.queue(0x1234_5605, 4), 1);
+
+        let rss = rss.reta([7; RETA_SIZE]);
+        assert_eq!(rss.queue(0x42, 4), 7);
+    }
+}
@@ -0,0 +1,190 @@
+//! Detailed device counters that explain where packets were dropped.
+//!
+//! [`ExtendedStats`] complements [`DeviceStats`](crate::DeviceStats) with the drop, error, size
+//! and flow control counters of the device, see
+//! [`IxyDevice::read_extended_stats`](crate::IxyDevice::read_extended_stats). Devices without
+//! such registers count what they can in software.
+//!
+//! [`RxQueueStats`] and [`TxQueueStats`] hold the counters the driver keeps for each queue while
+//! processing packets, see [`IxyDevice::rx_queue_stats`](crate::IxyDevice::rx_queue_stats) and
+//! [`IxyDevice::tx_queue_stats`](crate::IxyDevice::tx_queue_stats). They show how the queues
+//! are polled, e.g. whether the application runs out of buffers or the tx rings fill up.
+
+/// Upper bounds of the frame size histogram buckets in bytes including the crc, the last bucket
+/// holds all larger frames.
+pub const SIZE_BUCKETS: [usize; 6] = [64, 127, 255, 511, 1023, 1522];
+
+/// Holds detailed counters of a device, counters the device does not provide stay zero.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ExtendedStats {
+    /// Packets dropped because the rx packet buffer was full.
+    pub rx_missed: u64,
+    /// Packets dropped because their rx queue had no free descriptor or buffer.
+    pub rx_no_buffer: u64,
+    /// Packets with a wrong crc.
+    pub rx_crc_errors: u64,
+    /// Bytes with an illegal symbol.
+    pub rx_illegal_bytes: u64,
+    /// Bytes with an error symbol.
+    pub rx_error_bytes: u64,
+    /// Packets whose length field did not match their size.
+    pub rx_length_errors: u64,
+    /// Packets shorter than 64 bytes with a valid crc.
+    pub rx_undersize: u64,
+    /// Packets shorter than 64 bytes with a wrong crc.
+    pub rx_fragments: u64,
+    /// Packets longer than the maximum frame size with a valid crc.
+    pub rx_oversize: u64,
+    /// Packets longer than the maximum frame size with a wrong crc.
+    pub rx_jabbers: u64,
+    /// Good packets the device wrote to host memory.
+    pub rx_dma_pkts: u64,
+    pub rx_broadcast: u64,
+    pub rx_multicast: u64,
+    pub tx_broadcast: u64,
+    pub tx_multicast: u64,
+    /// Packets the driver dropped instead of sending them.
+    pub tx_dropped: u64,
+    /// Received link flow control frames that resume transmission.
+    pub rx_xon: u64,
+    /// Received link flow control (pause) frames that stop transmission.
+    pub rx_xoff: u64,
+    /// Sent link flow control frames that resume transmission.
+    pub tx_xon: u64,
+    /// Sent link flow control (pause) frames that stop transmission.
+    pub tx_xoff: u64,
+    /// Received packets per frame size, see [`SIZE_BUCKETS`].
+    pub rx_sizes: [u64; 6],
+    /// Sent packets per frame size, see [`SIZE_BUCKETS`].
+    pub tx_sizes: [u64; 6],
+    /// Counters of each queue ordered by queue id.
+    pub queues: Vec<QueueCounters>,
+}
+
+/// Holds the counters of an rx and tx queue pair.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QueueCounters {
+    pub rx_pkts: u64,
+    pub rx_bytes: u64,
+    /// Packets dropped because the queue had no free descriptor.
+    pub rx_no_buffer: u64,
+    pub tx_pkts: u64,
+    pub tx_bytes: u64,
+}
+
+/// Holds the counters the driver keeps for an rx queue.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RxQueueStats {
+    /// Packets handed to the application.
+    pub pkts: u64,
+    /// Bytes of the packets handed to the application, without crc.
+    pub bytes: u64,
+    /// Calls of `rx_batch` that received no packet.
+    pub empty_polls: u64,
+    /// Times `rx_batch` stopped early because the queue's mempool had no free buffer left.
+    pub mempool_exhausted: u64,
+}
+
+/// Holds the counters the driver keeps for a tx queue.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TxQueueStats {
+    /// Packets queued for sending.
+    pub pkts: u64,
+    /// Bytes of the packets queued for sending, without crc.
+    pub bytes: u64,
+    /// Times `tx_batch` stopped early because the queue was full.
+    pub ring_full: u64,
+    /// Batches of sent packets whose buffers were freed.
+    pub clean_batches: u64,
+    /// Packets the driver dropped instead of queueing them.
+    pub dropped: u64,
+}
+
+impl ExtendedStats {
+    /// Returns the counters of queue `queue_id`, adding queues as needed.
+    pub(crate) fn queue_mut(&mut self, queue_id: usize) -> &mut QueueCounters {
+        if self.queues.len() <= queue_id {
+            self.queues.resize(queue_id + 1, QueueCounters::default());
+        }
+
+        &mut self.queues[queue_id]
+    }
+
+    /// Counts a received frame of `len` bytes whose first segment is `frame` in software.
+    pub(crate) fn count_rx(&mut self, queue_id: usize, frame: &[u8], len: usize) {
+        match destination(frame) {
+            Destination::Broadcast => self.rx_broadcast += 1,
+            Destination::Multicast => self.rx_multicast += 1,
+            Destination::Unicast => {}
+        }
+        self.rx_sizes[size_bucket(len)] += 1;
+
+        let queue = self.queue_mut(queue_id);
+        queue.rx_pkts += 1;
+        queue.rx_bytes += len as u64;
+    }
+
+    /// Counts a sent frame of `len` bytes whose first segment is `frame` in software.
+    pub(crate) fn count_tx(&mut self, queue_id: usize, frame: &[u8], len: usize) {
+        match destination(frame) {
+            Destination::Broadcast => self.tx_broadcast += 1,
+            Destination::Multicast => self.tx_multicast += 1,
+            Destination::Unicast => {}
+        }
+        self.tx_sizes[size_bucket(len)] += 1;
+
+        let queue = self.queue_mut(queue_id);
+        queue.tx_pkts += 1;
+        queue.tx_bytes += len as u64;
+    }
+}
+
+enum Destination {
+    Unicast,
+    Multicast,
+    Broadcast,
+}
+
+/// Returns the kind of destination address of `frame`.
+fn destination(frame: &[u8]) -> Destination {
+    match frame.get(..6) {
+        Some([0xff, 0xff, 0xff, 0xff, 0xff, 0xff]) => Destination::Broadcast,
+        Some(dst) if dst[0] & 1 != 0 => Destination::Multicast,
+        _ => Destination::Unicast,
+    }
+}
+
+/// Returns the histogram bucket of a frame of `len` bytes without crc.
+fn size_bucket(len: usize) -> usize {
+    SIZE_BUCKETS
+        .iter()
+        .position(|&bound| len + 4 <= bound)
+        .unwrap_or(SIZE_BUCKETS.len() - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_count() {
+        let mut stats = ExtendedStats::default();
+
+        stats.count_rx(1, &[0xff; 60], 60);
+        stats.count_rx(1, &[0x01; 60], 124);
+        stats.count_tx(0, &[0x00; 60], 9000);
+
+        assert_eq!((stats.rx_broadcast, stats.rx_multicast), (1, 1));
+        assert_eq!(stats.rx_sizes, [1, 0, 1, 0, 0, 0]);
+        assert_eq!(stats.tx_sizes, [0, 0, 0, 0, 0, 1]);
+        assert_eq!(stats.queues.len(), 2);
+        assert_eq!(
+            (stats.queues[1].rx_pkts, stats.queues[1].rx_bytes),
+            (2, 184)
+        );
+        assert_eq!(
+            (stats.queues[0].tx_pkts, stats.queues[0].tx_bytes),
+            (1, 9000)
+        );
+    }
+}
@@ -0,0 +1,131 @@
+//! Packet timestamps for latency measurements.
+//!
+//! If enabled with [`DeviceConfig::timestamping`](crate::DeviceConfig::timestamping), received
+//! packets carry the time they were received, see [`Packet::timestamp`]. Devices with an IEEE
+//! 1588 clock timestamp PTP event messages in hardware, all other packets get a software
+//! timestamp read from the CPU's time stamp counter when the driver processes them.
+//!
+//! Sent packets marked with [`Packet::set_tx_timestamp`] are timestamped by the device's clock,
+//! see [`IxyDevice::read_tx_timestamp`](crate::IxyDevice::read_tx_timestamp).
+//!
+//! All timestamps are nanoseconds since the unix epoch, the device's clock is set to the system
+//! time when timestamping is enabled.
+//!
+//! [`Packet::timestamp`]: crate::memory::Packet::timestamp
+//! [`Packet::set_tx_timestamp`]: crate::memory::Packet::set_tx_timestamp
+
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use lazy_static::lazy_static;
+
+/// The time a packet was received or sent in nanoseconds since the unix epoch.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Timestamp {
+    /// Taken by the device's clock, see [`IxyDevice::read_clock`](crate::IxyDevice::read_clock).
+    Hardware(u64),
+    /// Taken by the driver when it processed the packet.
+    Software(u64),
+}
+
+impl Timestamp {
+    /// Returns the nanoseconds since the unix epoch.
+    pub fn nanos(self) -> u64 {
+        match self {
+            Timestamp::Hardware(nanos) | Timestamp::Software(nanos) => nanos,
+        }
+    }
+}
+
+/// Maps time stamp counter values to nanoseconds since the unix epoch.
+struct TscClock {
+    tsc: u64,
+    nanos: u64,
+    nanos_per_tick: f64,
+}
+
+lazy_static! {
+    static ref TSC_CLOCK: TscClock = TscClock::calibrate();
+}
+
+impl TscClock {
+    /// Measures the frequency of the time stamp counter against the system time.
+    fn calibrate() -> TscClock {
+        let nanos = system_time();
+        let start = Instant::now();
+        let tsc = rdtsc();
+
+        thread::sleep(Duration::from_millis(10));
+
+        let ticks = rdtsc().wrapping_sub(tsc);
+        let elapsed = start.elapsed().as_nanos() as f64;
+
+        TscClock {
+            tsc,
+            nanos,
+            nanos_per_tick: if ticks == 0 {
+                0.0
+            } else {
+                elapsed / ticks as f64
+            },
+        }
+    }
+}
+
+/// Calibrates the time stamp counter if it has not been calibrated yet. This takes 10 ms, so
+/// drivers call it at initialization instead of on the first received packet.
+pub(crate) fn calibrate() {
+    lazy_static::initialize(&TSC_CLOCK);
+}
+
+/// Returns the current system time in nanoseconds since the unix epoch.
+pub(crate) fn system_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|time| time.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// Returns the current time in nanoseconds since the unix epoch based on the time stamp counter,
+/// or the system time on platforms without one.
+pub fn now() -> u64 {
+    let clock = &*TSC_CLOCK;
+    if clock.nanos_per_tick == 0.0 {
+        return system_time();
+    }
+
+    let ticks = rdtsc().wrapping_sub(clock.tsc);
+    clock.nanos + (ticks as f64 * clock.nanos_per_tick) as u64
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn rdtsc() -> u64 {
+    #[cfg(target_arch = "x86")]
+    use core::arch::x86;
+    #[cfg(target_arch = "x86_64")]
+    use core::arch::x86_64 as x86;
+
+    unsafe { x86::_rdtsc() }
+}
+
+#[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+fn rdtsc() -> u64 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_now() {
+        let before = system_time();
+        let tsc = now();
+        thread::sleep(Duration::from_millis(1));
+        let after = system_time();
+
+        // allow for the inaccuracy of the calibration
+        assert!(tsc + 5_000_000 >= before && tsc <= after + 5_000_000);
+        assert!(now() >= tsc);
+    }
+}
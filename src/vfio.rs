@@ -1,17 +1,19 @@
 #![allow(dead_code)]
 
-use std::error::Error;
 use std::fs;
-use std::fs::{File, OpenOptions};
+use std::fs::OpenOptions;
 use std::mem;
 use std::os::unix::io::{IntoRawFd, RawFd};
 use std::path::Path;
 use std::ptr;
 
+use crate::error::IxyError;
 use crate::memory::{
     get_vfio_container, set_vfio_container, IOVA_WIDTH, VFIO_GROUP_FILE_DESCRIPTORS,
 };
-use crate::pci::{pci_open_resource_ro, read_hex, BUS_MASTER_ENABLE_BIT, COMMAND_REGISTER_OFFSET};
+use crate::pci::{
+    pci_error, pci_open_resource_ro, read_hex, BUS_MASTER_ENABLE_BIT, COMMAND_REGISTER_OFFSET,
+};
 
 // constants needed for IOMMU. Grabbed from linux/vfio.h
 pub const VFIO_GET_API_VERSION: u64 = 15204;
@@ -112,13 +114,9 @@ pub struct Event {
 }
 
 /// Initializes the IOMMU for a given PCI device. The device must be bound to the VFIO driver.
-pub fn vfio_init(pci_addr: &str) -> Result<RawFd, Box<dyn Error>> {
-    let dfd: RawFd;
-    let group_file: File;
-    let gfd: RawFd;
-
+pub fn vfio_init(pci_addr: &str) -> Result<RawFd, IxyError> {
     if vfio_is_intel_iommu(pci_addr) {
-        let mgaw = vfio_get_intel_iommu_gaw(pci_addr);
+        let mgaw = vfio_get_intel_iommu_gaw(pci_addr)?;
 
         if mgaw < IOVA_WIDTH {
             warn!("IOMMU supports only {} bit wide IOVAs, reduce IOVA_WIDTH in src/memory.rs if DMA mappings fail!", mgaw);
@@ -144,89 +142,94 @@ pub fn vfio_init(pci_addr: &str) -> Result<RawFd, Box<dyn Error>> {
             .read(true)
             .write(true)
             .open("/dev/vfio/vfio")
-            .unwrap();
+            .map_err(|e| IxyError::Vfio {
+                operation: "open /dev/vfio/vfio",
+                errno: e.raw_os_error().unwrap_or(0),
+            })?;
         cfd = container_file.into_raw_fd();
         set_vfio_container(cfd);
 
         // check if the container's API version is the same as the VFIO API's
         if unsafe { libc::ioctl(cfd, VFIO_GET_API_VERSION) } != VFIO_API_VERSION {
-            return Err("unknown VFIO API Version".into());
+            return Err(IxyError::Vfio {
+                operation: "match the VFIO API version",
+                errno: 0,
+            });
         }
 
         // check if type1 is supported
         if unsafe { libc::ioctl(cfd, VFIO_CHECK_EXTENSION, VFIO_TYPE1_IOMMU) } != 1 {
-            return Err("container doesn't support Type1 IOMMU".into());
+            return Err(IxyError::Vfio {
+                operation: "find Type1 IOMMU support in the VFIO container",
+                errno: 0,
+            });
         }
     }
 
     // find vfio group for device
-    let link = fs::read_link(format!("/sys/bus/pci/devices/{}/iommu_group", pci_addr)).unwrap();
+    let link = fs::read_link(format!("/sys/bus/pci/devices/{}/iommu_group", pci_addr))
+        .map_err(pci_error(pci_addr))?;
     let group = link
         .file_name()
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .parse::<i32>()
-        .unwrap();
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.parse::<i32>().ok())
+        .ok_or_else(|| IxyError::Pci {
+            pci_addr: pci_addr.to_string(),
+            source: std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid iommu group {}", link.display()),
+            ),
+        })?;
 
     let mut vfio_gfds = VFIO_GROUP_FILE_DESCRIPTORS.lock().unwrap();
 
-    if !vfio_gfds.contains_key(&group) {
+    let gfd = if let Some(gfd) = vfio_gfds.get(&group) {
+        *gfd
+    } else {
         // open the devices' group
-        group_file = OpenOptions::new()
+        let group_file = OpenOptions::new()
             .read(true)
             .write(true)
             .open(format!("/dev/vfio/{}", group))
-            .unwrap();
-        gfd = group_file.into_raw_fd();
+            .map_err(|e| IxyError::Vfio {
+                operation: "open the VFIO group",
+                errno: e.raw_os_error().unwrap_or(0),
+            })?;
+        let gfd = group_file.into_raw_fd();
 
         // Test the group is viable and available
         if unsafe { libc::ioctl(gfd, VFIO_GROUP_GET_STATUS, &mut group_status) } == -1 {
-            return Err(format!(
-                "failed to VFIO_GROUP_GET_STATUS. Errno: {}",
-                std::io::Error::last_os_error()
-            )
-            .into());
+            return Err(IxyError::last_vfio_error("VFIO_GROUP_GET_STATUS"));
         }
         if (group_status.flags & VFIO_GROUP_FLAGS_VIABLE) != 1 {
-            return Err(
-                "group is not viable (ie, not all devices in this group are bound to vfio)".into(),
-            );
+            return Err(IxyError::Vfio {
+                operation: "use the VFIO group (not all of its devices are bound to vfio)",
+                errno: 0,
+            });
         }
 
         // Add the group to the container
         if unsafe { libc::ioctl(gfd, VFIO_GROUP_SET_CONTAINER, &cfd) } == -1 {
-            return Err(format!(
-                "failed to VFIO_GROUP_SET_CONTAINER. Errno: {}",
-                std::io::Error::last_os_error()
-            )
-            .into());
+            return Err(IxyError::last_vfio_error("VFIO_GROUP_SET_CONTAINER"));
         }
 
         vfio_gfds.insert(group, gfd);
-    } else {
-        gfd = *vfio_gfds.get(&group).unwrap();
-    }
+        gfd
+    };
 
     if first_time_setup {
         // Enable the IOMMU model we want
         if unsafe { libc::ioctl(cfd, VFIO_SET_IOMMU, VFIO_TYPE1_IOMMU) } == -1 {
-            return Err(format!(
-                "failed to VFIO_SET_IOMMU to VFIO_TYPE1_IOMMU. Errno: {}",
-                std::io::Error::last_os_error()
-            )
-            .into());
+            return Err(IxyError::last_vfio_error(
+                "VFIO_SET_IOMMU to VFIO_TYPE1_IOMMU",
+            ));
         }
     }
 
     // Get a file descriptor for the device
-    dfd = unsafe { libc::ioctl(gfd, VFIO_GROUP_GET_DEVICE_FD, pci_addr) };
+    let dfd = unsafe { libc::ioctl(gfd, VFIO_GROUP_GET_DEVICE_FD, pci_addr) };
     if dfd == -1 {
-        return Err(format!(
-            "failed to VFIO_GROUP_GET_DEVICE_FD. Errno: {}",
-            std::io::Error::last_os_error()
-        )
-        .into());
+        return Err(IxyError::last_vfio_error("VFIO_GROUP_GET_DEVICE_FD"));
     }
 
     vfio_enable_dma(dfd)?;
@@ -235,7 +238,7 @@ pub fn vfio_init(pci_addr: &str) -> Result<RawFd, Box<dyn Error>> {
 }
 
 /// Enables DMA Bit for VFIO devices
-pub fn vfio_enable_dma(device_file_descriptor: RawFd) -> Result<(), Box<dyn Error>> {
+pub fn vfio_enable_dma(device_file_descriptor: RawFd) -> Result<(), IxyError> {
     // Get region info for config region
     let mut conf_reg: vfio_region_info = vfio_region_info {
         argsz: mem::size_of::<vfio_region_info>() as u32,
@@ -253,10 +256,9 @@ pub fn vfio_enable_dma(device_file_descriptor: RawFd) -> Result<(), Box<dyn Erro
         )
     } == -1
     {
-        return Err(format!(
-            "failed to VFIO_DEVICE_GET_REGION_INFO for index VFIO_PCI_CONFIG_REGION_INDEX. Errno: {}",
-            std::io::Error::last_os_error()
-        ).into());
+        return Err(IxyError::last_vfio_error(
+            "VFIO_DEVICE_GET_REGION_INFO for index VFIO_PCI_CONFIG_REGION_INDEX",
+        ));
     }
 
     let mut dma: u16 = 0;
@@ -269,11 +271,7 @@ pub fn vfio_enable_dma(device_file_descriptor: RawFd) -> Result<(), Box<dyn Erro
         )
     } == -1
     {
-        return Err(format!(
-            "failed to pread DMA bit. Errno: {}",
-            std::io::Error::last_os_error()
-        )
-        .into());
+        return Err(IxyError::last_vfio_error("pread DMA bit"));
     }
 
     dma |= 1 << BUS_MASTER_ENABLE_BIT;
@@ -287,17 +285,13 @@ pub fn vfio_enable_dma(device_file_descriptor: RawFd) -> Result<(), Box<dyn Erro
         )
     } == -1
     {
-        return Err(format!(
-            "failed to pwrite DMA bit. Errno: {}",
-            std::io::Error::last_os_error()
-        )
-        .into());
+        return Err(IxyError::last_vfio_error("pwrite DMA bit"));
     }
     Ok(())
 }
 
 /// Mmaps a VFIO resource and returns a pointer to the mapped memory.
-pub fn vfio_map_region(fd: RawFd, index: u32) -> Result<(*mut u8, usize), Box<dyn Error>> {
+pub fn vfio_map_region(fd: RawFd, index: u32) -> Result<(*mut u8, usize), IxyError> {
     let mut region_info: vfio_region_info = vfio_region_info {
         argsz: mem::size_of::<vfio_region_info>() as u32,
         flags: 0,
@@ -307,11 +301,7 @@ pub fn vfio_map_region(fd: RawFd, index: u32) -> Result<(*mut u8, usize), Box<dy
         offset: 0,
     };
     if unsafe { libc::ioctl(fd, VFIO_DEVICE_GET_REGION_INFO, &mut region_info) } == -1 {
-        return Err(format!(
-            "failed to VFIO_DEVICE_GET_REGION_INFO. Errno: {}",
-            std::io::Error::last_os_error()
-        )
-        .into());
+        return Err(IxyError::last_vfio_error("VFIO_DEVICE_GET_REGION_INFO"));
     }
 
     let len = region_info.size as usize;
@@ -327,18 +317,14 @@ pub fn vfio_map_region(fd: RawFd, index: u32) -> Result<(*mut u8, usize), Box<dy
         )
     };
     if ptr == libc::MAP_FAILED {
-        return Err(format!(
-            "failed to mmap region. Errno: {}",
-            std::io::Error::last_os_error()
-        )
-        .into());
+        return Err(IxyError::last_vfio_error("mmap region"));
     }
     let addr = ptr as *mut u8;
 
     Ok((addr, len))
 }
 
-pub fn vfio_map_dma(ptr: usize, size: usize) -> Result<usize, Box<dyn Error>> {
+pub fn vfio_map_dma(ptr: usize, size: usize) -> Result<usize, IxyError> {
     let mut iommu_dma_map: vfio_iommu_type1_dma_map = vfio_iommu_type1_dma_map {
         argsz: mem::size_of::<vfio_iommu_type1_dma_map>() as u32,
         vaddr: ptr as *mut u8,
@@ -352,11 +338,9 @@ pub fn vfio_map_dma(ptr: usize, size: usize) -> Result<usize, Box<dyn Error>> {
     if ioctl_result != -1 {
         Ok(iommu_dma_map.iova as usize)
     } else {
-        Err(format!(
-            "failed to map the DMA memory (ulimit set?). Errno: {}",
-            std::io::Error::last_os_error()
-        )
-        .into())
+        Err(IxyError::last_vfio_error(
+            "map the DMA memory (ulimit set?)",
+        ))
     }
 }
 
@@ -370,14 +354,12 @@ pub fn vfio_is_intel_iommu(pci_addr: &str) -> bool {
 }
 
 /// Returns the IOMMU's guest address width.
-pub fn vfio_get_intel_iommu_gaw(pci_addr: &str) -> u8 {
-    let mut iommu_cap_file = pci_open_resource_ro(pci_addr, "iommu/intel-iommu/cap")
-        .expect("failed to read IOMMU capabilities");
+pub fn vfio_get_intel_iommu_gaw(pci_addr: &str) -> Result<u8, IxyError> {
+    let mut iommu_cap_file = pci_open_resource_ro(pci_addr, "iommu/intel-iommu/cap")?;
 
-    let iommu_cap = read_hex(&mut iommu_cap_file)
-        .expect("failed to convert IOMMU capabilities hex string to u64");
+    let iommu_cap = read_hex(&mut iommu_cap_file).map_err(pci_error(pci_addr))?;
 
     let mgaw = ((iommu_cap & VTD_CAP_MGAW_MASK) >> VTD_CAP_MGAW_SHIFT) + 1;
 
-    mgaw as u8
+    Ok(mgaw as u8)
 }
@@ -1,22 +1,51 @@
+use std::cell::{Cell, RefCell};
 use std::collections::VecDeque;
-use std::error::Error;
-use std::fs::File;
-use std::num::Wrapping;
-use std::ops::{Deref, DerefMut, Index, IndexMut};
 use std::os::unix::io::RawFd;
-use std::rc::Rc;
+use std::path::Path;
 use std::sync::atomic::{self, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
-use std::{io, mem, slice, thread};
+use std::{iter, mem, ptr, thread};
 
+use crate::config::DeviceLimits;
+use crate::error::IxyError;
+use crate::interrupts::Interrupts;
 use crate::memory;
-use crate::memory::{Dma, Packet, PACKET_HEADROOM};
-use crate::pci::{self, read_io16, read_io32, read_io8, write_io16, write_io32, write_io8};
+use crate::memory::{get_vfio_container, Dma, Packet, PACKET_HEADROOM};
+use crate::offload::{self, ChecksumStatus, L3Type};
+use crate::pci;
+use crate::stats::{ExtendedStats, RxQueueStats, TxQueueStats};
+use crate::timestamp::{self, Timestamp};
+use crate::vfio::{vfio_init, VFIO_PCI_MSIX_IRQ_INDEX};
 use crate::virtio_constants::*;
-use crate::{DeviceStats, IxyDevice, Mempool};
+use crate::{DeviceConfig, DeviceStats, IxyDevice, Mempool};
+
+use self::packed::PackedVirtqueue;
+use self::split::SplitVirtqueue;
+use self::transport::{LegacyTransport, ModernTransport, QueueAreas, Transport};
+
+mod packed;
+mod split;
+mod transport;
+
+// the device limits the number of queue pairs further and ring sizes are set by the device (2.4)
+const LIMITS: DeviceLimits = DeviceLimits {
+    max_queues: VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX,
+    min_ring_size: 1,
+    max_ring_size: 32768,
+    // devices offering VIRTIO_NET_F_MTU limit this further
+    max_mtu: 65535,
+    max_rss_queues: 0,
+    flow_director: false,
+    rsc: false,
+};
+
+// size of the buffers holding control commands
+const CTRL_BUFFER_SIZE: usize = 2048;
 
-// we're currently only supporting legacy Virtio via PCI so this is fixed (4.1.5.1.3.1)
-const QUEUE_ALIGNMENT: usize = 4096;
+// the mac filter table is sent in one control buffer next to the command header, both address
+// counts and the ack flag (5.1.6.5.2)
+const MAX_MAC_TABLE_ADDRS: usize = (CTRL_BUFFER_SIZE - PACKET_HEADROOM - 2 - 2 * 4 - 1) / 6;
 
 static NET_HEADER: virtio_net_hdr = virtio_net_hdr {
     flags: 0,
@@ -28,28 +57,62 @@ static NET_HEADER: virtio_net_hdr = virtio_net_hdr {
     gso_size: 0,
 };
 
-// NOTE: Currently we only support the legacy interface (device id == 0x1000)
-// NOTE: We currently don't keep track of a "driver ring wrap counter" following upstream ixy
 pub struct VirtioDevice {
     pci_addr: String,
-    bar0: File,
-
-    rx_queue: Virtqueue,
-    tx_queue: Virtqueue,
-    ctrl_queue: Virtqueue,
-
-    rx_mempool: Rc<Mempool>,
-    // tx buffers are managed by user
-    ctrl_mempool: Rc<Mempool>,
-
-    tx_inflight: VecDeque<Packet>,
-    rx_inflight: VecDeque<Packet>,
+    // the legacy or the modern pci interface
+    transport: Box<dyn Transport>,
+    // negotiated features
+    features: u64,
+    // size of the header in front of each packet, it depends on the negotiated features
+    net_hdr_len: usize,
+    vlan_strip: bool,
+    timestamping: bool,
+    // primary mac address, read from the device specific configuration during init
+    mac: Cell<[u8; 6]>,
+    // secondary unicast and subscribed multicast addresses of the mac filter table
+    mac_addrs: Vec<[u8; 6]>,
+    multicast_addrs: Vec<[u8; 6]>,
+
+    // the rx and tx queues of each queue pair
+    rx_queues: Vec<VirtioRxQueue>,
+    tx_queues: Vec<VirtioTxQueue>,
+    // only borrowed while a command is sent, `set_mac_addr` sends one through `&self`
+    ctrl_queue: RefCell<Virtqueue>,
+    // the control queue follows all queue pairs the device supports
+    ctrl_queue_idx: u16,
+
+    ctrl_mempool: Arc<Mempool>,
+
+    // the device is bound to vfio-pci, rx queue `i` interrupts through msi-x vector `i`
+    vfio: bool,
+    interrupts: Interrupts,
 
     // statistics
     rx_pkts: u64,
     tx_pkts: u64,
     rx_bytes: u64,
     tx_bytes: u64,
+    xstats: ExtendedStats,
+}
+
+struct VirtioRxQueue {
+    virtq: Virtqueue,
+    mempool: Arc<Mempool>,
+    // buffers made available to the device by buffer id
+    inflight: Vec<Option<Packet>>,
+    // buffers allocated for the free descriptors by `refill`
+    spare: VecDeque<Packet>,
+    // received buffers of a merged packet whose remaining buffers are yet to come
+    partial: Option<Packet>,
+    remaining_buffers: u16,
+    stats: RxQueueStats,
+}
+
+struct VirtioTxQueue {
+    virtq: Virtqueue,
+    // packets being sent by buffer id, tx buffers are managed by user
+    inflight: Vec<Option<Packet>>,
+    stats: TxQueueStats,
 }
 
 impl IxyDevice for VirtioDevice {
@@ -58,11 +121,15 @@ impl IxyDevice for VirtioDevice {
     }
 
     fn is_card_iommu_capable(&self) -> bool {
-        false
+        self.vfio
     }
 
     fn get_vfio_container(&self) -> Option<RawFd> {
-        None
+        if self.vfio {
+            Some(get_vfio_container())
+        } else {
+            None
+        }
     }
 
     fn get_pci_addr(&self) -> &str {
@@ -70,158 +137,266 @@ impl IxyDevice for VirtioDevice {
     }
 
     fn get_mac_addr(&self) -> [u8; 6] {
-        let mut bar0 = self.bar0.try_clone().unwrap();
-        let mut mac = [0; 6];
-        for (i, byte) in mac.iter_mut().enumerate() {
-            *byte = read_io8(&mut bar0, (20 + i) as u64).unwrap();
-        }
-        mac
+        self.mac.get()
     }
 
     fn set_mac_addr(&self, mac: [u8; 6]) {
-        // since we're using the legacy interface we can update the MAC address without having
-        // negotiated `VIRTIO_NET_F_CTRL_MAC_ADDR` during initialization
-        let mut bar0 = self.bar0.try_clone().unwrap();
-        for (i, byte) in mac.iter().enumerate() {
-            write_io8(&mut bar0, *byte, (20 + i) as u64).unwrap();
+        // 5.1.6.5.2: the control queue sets the mac address if `VIRTIO_NET_F_CTRL_MAC_ADDR` was
+        // negotiated, otherwise only the legacy interface allows writing it to the device
+        // specific configuration, modern devices ignore the write
+        if self.features & (1 << VIRTIO_NET_F_CTRL_MAC_ADDR) != 0 {
+            if let Err(e) = self.send_command(&VirtioNetCtrlMacAddrSet::new(mac).into()) {
+                error!("failed to set mac address: {}", e);
+                return;
+            }
+        } else if self.features & (1 << VIRTIO_F_VERSION_1) != 0 {
+            error!("modern device cannot set its mac address without VIRTIO_NET_F_CTRL_MAC_ADDR");
+            return;
+        } else {
+            for (i, byte) in mac.iter().enumerate() {
+                if let Err(e) = self
+                    .transport
+                    .write_config8(VIRTIO_NET_CONFIG_MAC + i as u64, *byte)
+                {
+                    error!("failed to set mac address: {}", e);
+                    return;
+                }
+            }
         }
+        self.mac.set(mac);
     }
 
     fn rx_batch(
         &mut self,
-        _queue_id: u16,
+        queue_id: u16,
         buffer: &mut VecDeque<Packet>,
         num_packets: usize,
     ) -> usize {
+        let queue = self
+            .rx_queues
+            .get_mut(queue_id as usize)
+            .expect("invalid rx queue id");
+
+        // wait for the device to use a buffer if the queue is idle, the device only interrupts
+        // for buffers used after interrupts were enabled
+        if self.interrupts.interrupts_enabled
+            && self.interrupts.queues[queue_id as usize].interrupt_enabled
+        {
+            if !queue.virtq.enable_interrupts() {
+                if let Err(e) = self.interrupts.queues[queue_id as usize]
+                    .vfio_epoll_wait(i32::from(self.interrupts.timeout_ms))
+                {
+                    error!("waiting for rx interrupt failed: {}", e);
+                }
+            }
+            queue.virtq.disable_interrupts();
+        }
+
         // 2.6.14
+        let buffered = buffer.len();
+        let mrg_rxbuf = self.features & (1 << VIRTIO_NET_F_MRG_RXBUF) != 0;
 
-        mfence();
         // remove received packets from the virtqueue and make them available to the user
-        for _ in 0..num_packets {
-            if self.rx_queue.last_used_idx == self.rx_queue.used.idx {
-                break;
+        while buffer.len() - buffered < num_packets {
+            let mut buf = match queue.pop_packet(self.net_hdr_len, mrg_rxbuf, &mut self.xstats) {
+                Some(buf) => buf,
+                None => break,
+            };
+
+            // the device either validated the checksum or, for packets from the same host, left
+            // the checksum to be completed by us
+            let header = unsafe {
+                ptr::read_unaligned(
+                    buf.headroom_mut(self.net_hdr_len).as_ptr() as *const virtio_net_hdr
+                )
+            };
+            if header.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM != 0 {
+                // the checksum stays unknown if its field is not in the first buffer
+                if offload::complete_checksum(
+                    &mut buf,
+                    usize::from(header.csum_start),
+                    usize::from(header.csum_offset),
+                ) {
+                    buf.meta.l4_checksum = ChecksumStatus::Good;
+                }
+            } else if header.flags & VIRTIO_NET_HDR_F_DATA_VALID != 0 {
+                buf.meta.l4_checksum = ChecksumStatus::Good;
             }
 
-            let used =
-                &self.rx_queue.used[self.rx_queue.last_used_idx.0 % self.rx_queue.size].clone();
-            self.rx_queue.last_used_idx += Wrapping(1);
+            let len = buf.total_len();
+            self.xstats.count_rx(queue_id as usize, &buf, len);
 
-            // mark used descriptor as unused again
-            let desc = &mut self.rx_queue.descriptors_mut()[used.id as usize];
-            assert_eq!(
-                desc.flags, VIRTQ_DESC_F_WRITE,
-                "unsupported flags on rx descriptor: {:x}",
-                desc.flags
-            );
-            desc.addr = 0;
+            // virtio has no vlan offloads, tags are stripped in software
+            if self.vlan_strip {
+                offload::strip_vlan_tag(&mut buf);
+            }
 
-            let mut buf = self.rx_inflight.pop_front().unwrap();
-            // adjust buffer length to actual packet size
-            buf.len = used.len as usize - mem::size_of::<virtio_net_hdr>();
+            if self.timestamping {
+                buf.meta.timestamp = Some(Timestamp::Software(timestamp::now()));
+            }
 
-            self.rx_bytes += buf.len as u64;
+            let len = buf.total_len() as u64;
+            self.rx_bytes += len;
             self.rx_pkts += 1;
+            queue.stats.pkts += 1;
+            queue.stats.bytes += len;
             buffer.push_back(buf);
         }
 
-        // add new descriptors to the available ring so the device can fill those up
-        let mut queued = 0;
-        for idx in 0..self.rx_queue.size {
-            let desc = &mut self.rx_queue.descriptors_mut()[idx as usize];
-            if desc.addr != 0 {
-                continue;
-            }
-
-            let buf = memory::alloc_pkt(
-                &self.rx_mempool,
-                self.rx_mempool.entry_size() - PACKET_HEADROOM,
-            )
-            .expect("rx memory pool exhausted");
-
-            *desc = VirtqDesc {
-                len: buf.len as u32 + mem::size_of::<virtio_net_hdr>() as u32,
-                addr: buf.get_phys_addr() - mem::size_of::<virtio_net_hdr>(),
-                flags: VIRTQ_DESC_F_WRITE,
-                next: 0,
-            };
+        if buffer.len() == buffered {
+            queue.stats.empty_polls += 1;
+        }
 
-            let avail_idx = (self.rx_queue.available.idx + Wrapping(queued)).0 % self.rx_queue.size;
-            self.rx_queue.available[avail_idx] = idx;
+        // add new descriptors to the available ring so the device can fill those up
+        queue.refill(self.net_hdr_len);
 
-            queued += 1;
-            self.rx_inflight.push_back(buf);
+        // notify device unless it suppressed the notification
+        if queue.virtq.publish() {
+            if let Err(e) = self.transport.notify_queue(rx_queue_index(queue_id)) {
+                error!("failed to notify rx queue: {}", e);
+            }
         }
 
-        // notify device
-        mfence();
-        self.rx_queue.available.idx += Wrapping(queued);
-        mfence();
-        self.notify_queue(0).expect("notify queue 0 failed");
+        // switch between interrupts and polling depending on the packet rate
+        if self.interrupts.interrupts_enabled {
+            let received_packets = buffer.len() - buffered;
+            let interrupt = &mut self.interrupts.queues[queue_id as usize];
+            interrupt.rx_pkts += received_packets as u64;
+
+            interrupt.instr_counter += 1;
+            if (interrupt.instr_counter & 0xFFF) == 0 {
+                interrupt.instr_counter = 0;
+                let elapsed = interrupt.last_time_checked.elapsed();
+                let diff = elapsed.as_secs() * 1_000_000_000 + u64::from(elapsed.subsec_nanos());
+                if diff > interrupt.interval {
+                    interrupt.check_interrupt(diff, received_packets, num_packets);
+                }
+            }
+        }
 
         buffer.len()
     }
 
-    fn tx_batch(&mut self, _queue_id: u16, buffer: &mut VecDeque<Packet>) -> usize {
+    fn tx_batch(&mut self, queue_id: u16, buffer: &mut VecDeque<Packet>) -> usize {
+        let queue = self
+            .tx_queues
+            .get_mut(queue_id as usize)
+            .expect("invalid tx queue id");
+
         // 2.6.13
 
-        mfence();
         // free all processed packets
-        while self.tx_queue.last_used_idx != self.tx_queue.used.idx {
-            let used_idx =
-                self.tx_queue.used[self.tx_queue.last_used_idx.0 % self.tx_queue.size].id;
-            self.tx_queue.descriptors_mut()[used_idx as usize] = VirtqDesc::default();
-            self.tx_queue.last_used_idx += Wrapping(1);
-            mem::drop(self.tx_inflight.pop_front());
-            mfence();
+        let mut cleaned = 0;
+        let virtq = &mut queue.virtq;
+        let inflight = &mut queue.inflight;
+        memory::free_pkt_batch(iter::from_fn(|| virtq.pop_used()).filter_map(|(id, _)| {
+            cleaned += 1;
+            inflight[id as usize].take()
+        }));
+        if cleaned > 0 {
+            queue.stats.clean_batches += 1;
         }
 
         // add user-supplied packets to the available ring for sending out
         let mut sent = 0;
-        let mut idx = 0;
         while let Some(mut packet) = buffer.pop_front() {
-            // we cant use `tx_queue.free_descriptor_indices()` here due to borrowck
-            while idx < self.tx_queue.size {
-                let desc = &self.tx_queue.descriptors()[idx as usize];
-                if desc.addr == 0 {
-                    break;
+            if packet.tx_offload().is_some_and(|o| !o.fits(&packet)) {
+                warn!("dropping packet whose offloaded headers are not in its first segment");
+                self.xstats.tx_dropped += 1;
+                queue.stats.dropped += 1;
+                continue;
+            }
+
+            // packets are segmented in software if the device can't do it
+            if let Some(offload) = packet.tx_offload().filter(|o| o.mss.is_some()) {
+                let feature = match offload.l3_type {
+                    L3Type::Ipv4 => VIRTIO_NET_F_HOST_TSO4,
+                    L3Type::Ipv6 => VIRTIO_NET_F_HOST_TSO6,
+                };
+                if self.features & (1 << feature) == 0 {
+                    if !offload.segment_into(packet, buffer) {
+                        break;
+                    }
+                    continue;
                 }
-                idx += 1;
             }
 
+            // every segment of the packet needs its own descriptor
+            let num_segments = packet.num_segments();
+
             // queue is full; put back the packet we've taken out
-            if idx == self.tx_queue.size {
+            if (queue.virtq.num_free() as usize) < num_segments {
                 buffer.push_front(packet);
+                queue.stats.ring_full += 1;
                 break;
             }
 
-            // Virtio expects a header in front of the actual packet data
-            let net_header = unsafe { any_as_u8_slice(&NET_HEADER) };
-            packet
-                .headroom_mut(net_header.len())
-                .copy_from_slice(net_header);
-
-            self.tx_queue.descriptors_mut()[idx as usize] = VirtqDesc {
-                len: (packet.len() + net_header.len()) as u32,
-                addr: packet.get_phys_addr() - net_header.len(),
-                flags: 0,
-                next: 0,
-            };
+            if !offload::insert_vlan_tag(&mut packet) {
+                warn!("dropping packet without room for its vlan tag");
+                self.xstats.tx_dropped += 1;
+                queue.stats.dropped += 1;
+                continue;
+            }
 
-            let avail_idx = (self.tx_queue.available.idx + Wrapping(sent)).0 % self.tx_queue.size;
-            self.tx_queue.available[avail_idx] = idx;
+            // Virtio expects a header in front of the actual packet data, it requests the l4
+            // checksum while the ipv4 header checksum can't be offloaded
+            let mut net_header = virtio_net_hdr { ..NET_HEADER };
+            if let Some(offload) = packet.tx_offload() {
+                offload.prepare(&mut packet);
+                offload.insert_ip_checksum(&mut packet);
+
+                if let Some(l4_type) = offload.l4_checksum {
+                    net_header.flags = VIRTIO_NET_HDR_F_NEEDS_CSUM;
+                    net_header.csum_start = offload.l4_offset() as u16;
+                    net_header.csum_offset = l4_type.checksum_offset() as u16;
+                }
 
-            self.tx_bytes += packet.len() as u64;
+                if let Some(mss) = offload.mss {
+                    net_header.gso_type = match offload.l3_type {
+                        L3Type::Ipv4 => VIRTIO_NET_HDR_GSO_TCPV4,
+                        L3Type::Ipv6 => VIRTIO_NET_HDR_GSO_TCPV6,
+                    };
+                    net_header.gso_size = mss;
+                    net_header.hdr_len = offload.header_len() as u16;
+                }
+            }
+
+            // the remainder of a longer header is only used for received packets
+            let net_header = unsafe { any_as_u8_slice(&net_header) };
+            let headroom = packet.headroom_mut(self.net_hdr_len);
+            headroom[..net_header.len()].copy_from_slice(net_header);
+            headroom[net_header.len()..].fill(0);
+
+            // chain the descriptors of all segments, the header precedes the first one
+            let net_hdr_len = self.net_hdr_len;
+            let id = queue
+                .virtq
+                .add(packet.segments().enumerate().map(|(i, segment)| {
+                    let hdr_len = if i == 0 { net_hdr_len } else { 0 };
+                    Buffer {
+                        addr: segment.get_phys_addr() - hdr_len,
+                        len: (segment.len() + hdr_len) as u32,
+                        writable: false,
+                    }
+                }));
+
+            self.tx_bytes += packet.total_len() as u64;
             self.tx_pkts += 1;
+            queue.stats.pkts += 1;
+            queue.stats.bytes += packet.total_len() as u64;
+            self.xstats
+                .count_tx(queue_id as usize, &packet, packet.total_len());
 
             sent += 1;
-            self.tx_inflight.push_back(packet);
+            queue.inflight[id as usize] = Some(packet);
         }
 
-        // notify device
-        mfence();
-        self.tx_queue.available.idx += Wrapping(sent);
-        mfence();
-        self.notify_queue(1).expect("notify queue 1 failed");
+        // notify device unless it suppressed the notification
+        if queue.virtq.publish() {
+            if let Err(e) = self.transport.notify_queue(tx_queue_index(queue_id)) {
+                error!("failed to notify tx queue: {}", e);
+            }
+        }
 
         sent as usize
     }
@@ -238,269 +413,721 @@ impl IxyDevice for VirtioDevice {
         self.tx_pkts = 0;
         self.rx_bytes = 0;
         self.tx_bytes = 0;
+        self.xstats = ExtendedStats::default();
+    }
+
+    /// Returns the counters the driver kept for rx queue `queue_id`.
+    fn rx_queue_stats(&self, queue_id: u16) -> Option<RxQueueStats> {
+        self.rx_queues
+            .get(queue_id as usize)
+            .map(|queue| queue.stats)
+    }
+
+    /// Returns the counters the driver kept for tx queue `queue_id`.
+    fn tx_queue_stats(&self, queue_id: u16) -> Option<TxQueueStats> {
+        self.tx_queues
+            .get(queue_id as usize)
+            .map(|queue| queue.stats)
+    }
+
+    /// Reads the broadcast, multicast, size and queue counters the driver keeps in software.
+    fn read_extended_stats(&self, stats: &mut ExtendedStats) {
+        *stats = self.xstats.clone();
     }
 
     fn get_link_speed(&self) -> u16 {
         // Virtio doesn't have a "link speed" per se so we just return something reasonable
         1000
     }
+
+    /// Adds vlan id `vlan_id` to the device's vlan filter, the device may still accept all
+    /// vlans in promiscuous mode.
+    fn add_vlan_filter(&mut self, vlan_id: u16) -> Result<(), IxyError> {
+        self.set_vlan_filter(vlan_id, true)
+    }
+
+    /// Removes vlan id `vlan_id` from the device's vlan filter.
+    fn remove_vlan_filter(&mut self, vlan_id: u16) -> Result<(), IxyError> {
+        self.set_vlan_filter(vlan_id, false)
+    }
+
+    fn set_promiscuous(&mut self, enabled: bool) -> Result<(), IxyError> {
+        self.send_command(&VirtioNetCtrlPromisc::new(enabled).into())?;
+        info!("set promiscuous mode to {}", enabled);
+        Ok(())
+    }
+
+    fn set_all_multicast(&mut self, enabled: bool) -> Result<(), IxyError> {
+        self.send_command(&VirtioNetCtrlAllMulti::new(enabled).into())?;
+        info!("set all-multicast mode to {}", enabled);
+        Ok(())
+    }
+
+    fn add_mac_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        crate::check_mac_addr(mac, false)?;
+        if self.mac_addrs.contains(&mac) {
+            return Ok(());
+        }
+        self.check_mac_table_space()?;
+
+        self.mac_addrs.push(mac);
+        self.set_mac_table().inspect_err(|_| {
+            self.mac_addrs.pop();
+        })
+    }
+
+    fn remove_mac_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        let index = self
+            .mac_addrs
+            .iter()
+            .position(|&addr| addr == mac)
+            .ok_or_else(|| {
+                IxyError::InvalidConfig(format!(
+                    "{} has no mac address {}",
+                    self.get_driver_name(),
+                    crate::format_mac_addr(mac)
+                ))
+            })?;
+
+        self.mac_addrs.remove(index);
+        self.set_mac_table().inspect_err(|_| {
+            self.mac_addrs.insert(index, mac);
+        })
+    }
+
+    fn add_multicast_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        crate::check_mac_addr(mac, true)?;
+        if self.multicast_addrs.contains(&mac) {
+            return Ok(());
+        }
+        self.check_mac_table_space()?;
+
+        self.multicast_addrs.push(mac);
+        self.set_mac_table().inspect_err(|_| {
+            self.multicast_addrs.pop();
+        })
+    }
+
+    fn remove_multicast_addr(&mut self, mac: [u8; 6]) -> Result<(), IxyError> {
+        let index = self
+            .multicast_addrs
+            .iter()
+            .position(|&addr| addr == mac)
+            .ok_or_else(|| {
+                IxyError::InvalidConfig(format!(
+                    "{} has no multicast address {}",
+                    self.get_driver_name(),
+                    crate::format_mac_addr(mac)
+                ))
+            })?;
+
+        self.multicast_addrs.remove(index);
+        self.set_mac_table().inspect_err(|_| {
+            self.multicast_addrs.insert(index, mac);
+        })
+    }
 }
 
 impl VirtioDevice {
     /// Returns an initialized `VirtioDevice` on success.
-    pub fn init(pci_addr: &str) -> Result<Self, Box<dyn Error>> {
-        // `getuid()` can't fail according to the man page
+    ///
+    /// Uses the modern pci interface if the device offers it and falls back to the legacy
+    /// interface otherwise. The ring sizes in `config` are ignored as the queues use the maximum
+    /// sizes offered by the device.
+    ///
+    /// Queues come in pairs of an rx and a tx queue, so the device gets as many rx as tx queues,
+    /// the larger of the numbers in `config`. More than one pair requires `VIRTIO_NET_F_MQ`.
+    ///
+    /// Interrupts are delivered through VFIO, they require the device to be in an iommu group
+    /// and to offer `VIRTIO_F_IOMMU_PLATFORM`. Otherwise the device is polled.
+    pub fn init(pci_addr: &str, config: &DeviceConfig) -> Result<Self, IxyError> {
+        config.validate(&LIMITS)?;
+
+        // `getuid()` can't fail according to the man page, the registers are always accessed
+        // through sysfs
         if unsafe { libc::getuid() } != 0 {
             warn!("not running as root, this will probably fail");
         }
 
-        pci::unbind_driver(pci_addr)?;
-        pci::enable_dma(pci_addr)?;
+        // transitional devices offer both interfaces, the modern one is preferred
+        let (mut transport, modern): (Box<dyn Transport>, bool) =
+            match ModernTransport::map(pci_addr)? {
+                Some(transport) => (Box::new(transport), true),
+                None => (Box::new(LegacyTransport::open(pci_addr)?), false),
+            };
+        info!(
+            "using the {} interface",
+            if modern { "modern" } else { "legacy" }
+        );
 
-        // 3.1: device initialization
-        let mut bar0 = pci::pci_open_resource(pci_addr, "resource0")?;
-        debug!("configuring bar0");
+        // VFIO is only used for interrupts, polling devices keep using physical addresses. The
+        // device only translates addresses through the iommu with VIRTIO_F_IOMMU_PLATFORM, which
+        // is checked before the device is bound to vfio-pci.
+        let iommu_group =
+            Path::new(&format!("/sys/bus/pci/devices/{}/iommu_group", pci_addr)).exists();
+        let iommu_platform = transport.device_features()? & (1 << VIRTIO_F_IOMMU_PLATFORM) != 0;
+        let vfio = config.interrupt_timeout != 0 && iommu_group && iommu_platform;
+        let device_fd = if vfio {
+            vfio_init(pci_addr)?
+        } else {
+            if config.interrupt_timeout != 0 {
+                if iommu_group {
+                    warn!("No VIRTIO_F_IOMMU_PLATFORM for VFIO: Disabling Interrupts!");
+                } else {
+                    warn!("Interrupts requested but VFIO not available: Disabling Interrupts!");
+                }
+            }
 
+            pci::unbind_driver(pci_addr)?;
+            pci::enable_dma(pci_addr)?;
+            -1
+        };
+
+        // 3.1: device initialization
         // 1) Reset the device
-        write_io8(&mut bar0, VIRTIO_CONFIG_STATUS_RESET, VIRTIO_PCI_STATUS)?;
-        while read_io8(&mut bar0, VIRTIO_PCI_STATUS)? != VIRTIO_CONFIG_STATUS_RESET {
+        transport.set_status(VIRTIO_CONFIG_STATUS_RESET)?;
+        while transport.status()? != VIRTIO_CONFIG_STATUS_RESET {
             thread::sleep(Duration::from_micros(100));
         }
+        // queue interrupts are signaled through msi-x, the status is only cleared
+        transport.read_isr()?;
 
         // 2) Set ACKNOWLEDGE status bit; OS noticed the device
-        write_io8(&mut bar0, VIRTIO_CONFIG_STATUS_ACK, VIRTIO_PCI_STATUS)?;
+        let mut status = VIRTIO_CONFIG_STATUS_ACK;
+        transport.set_status(status)?;
 
         // 3) Set DRIVER status bit; OS can drive the device
-        write_io8(&mut bar0, VIRTIO_CONFIG_STATUS_DRIVER, VIRTIO_PCI_STATUS)?;
+        status |= VIRTIO_CONFIG_STATUS_DRIVER;
+        transport.set_status(status)?;
 
         // 4) Negotiate features
-        let host_features = read_io32(&mut bar0, VIRTIO_PCI_HOST_FEATURES)?;
+        let host_features = transport.device_features()?;
         debug!("device features: {:b}", host_features);
-        let required_features = (1 << VIRTIO_NET_F_CSUM) // we may offload checksumming to the device
+        let mut required_features = (1 << VIRTIO_NET_F_CSUM) // we may offload checksumming to the device
             | (1 << VIRTIO_NET_F_GUEST_CSUM) // we can handle packets with invalid checksums
             | (1 << VIRTIO_NET_F_CTRL_VQ) // enable the control queue
             | (1 << VIRTIO_NET_F_CTRL_RX) // required to enable promiscuous mode
-            | (1 << VIRTIO_NET_F_MAC) // required to read MAC address
-            | (1 << VIRTIO_F_ANY_LAYOUT); // we don't make assumptions about message framing
+            | (1 << VIRTIO_NET_F_MAC); // required to read MAC address
+        required_features |= if modern {
+            1 << VIRTIO_F_VERSION_1 // we use the modern interface
+        } else {
+            1 << VIRTIO_F_ANY_LAYOUT // we don't make assumptions about message framing
+        };
+        if vfio {
+            required_features |= 1 << VIRTIO_F_IOMMU_PLATFORM; // the device uses our IOVAs
+        }
+        let optional_features = (1 << VIRTIO_NET_F_HOST_TSO4) // we may offload tcp segmentation
+            | (1 << VIRTIO_NET_F_HOST_TSO6)
+            | (1 << VIRTIO_NET_F_CTRL_VLAN) // we may filter vlans
+            | (1 << VIRTIO_NET_F_CTRL_MAC_ADDR) // we may set the mac address through the control queue
+            | (1 << VIRTIO_NET_F_MQ) // we may use multiple queue pairs
+            | (1 << VIRTIO_NET_F_MRG_RXBUF) // we can receive packets spanning multiple buffers
+            | (1 << VIRTIO_NET_F_MTU) // we honor the mtu of the device
+            | (1 << VIRTIO_RING_F_EVENT_IDX) // we may suppress notifications up to a ring index
+            | (1 << VIRTIO_F_RING_PACKED); // we may use packed virtqueues, only modern devices offer them
         if (host_features & required_features) != required_features {
-            debug!("device features:   {:032b}", host_features);
-            debug!("required features: {:032b}", required_features);
-            panic!("device does not support all required features");
+            debug!("device features:   {:064b}", host_features);
+            debug!("required features: {:064b}", required_features);
+            return Err(IxyError::FeatureNegotiation {
+                required: required_features,
+                offered: host_features,
+            });
         }
-        debug!(
-            "guest features before negotiation: {:032b}",
-            read_io32(&mut bar0, VIRTIO_PCI_GUEST_FEATURES)?
-        );
-        write_io32(&mut bar0, required_features, VIRTIO_PCI_GUEST_FEATURES)?;
-        debug!(
-            "guest features after negotiation:  {:032b}",
-            read_io32(&mut bar0, VIRTIO_PCI_GUEST_FEATURES)?
+        let features = required_features | (host_features & optional_features);
+        transport.set_driver_features(features)?;
+        debug!("guest features after negotiation:  {:064b}", features);
+
+        // 5) Set FEATURES_OK, skipped by the legacy interface
+        // 6) Check that the device accepted the features
+        if modern {
+            status |= VIRTIO_CONFIG_STATUS_FEATURES_OK;
+            transport.set_status(status)?;
+            if transport.status()? & VIRTIO_CONFIG_STATUS_FEATURES_OK == 0 {
+                return Err(IxyError::FeatureNegotiation {
+                    required: features,
+                    offered: host_features,
+                });
+            }
+        }
+
+        // 7) Perform network device specific initialization
+        // the header has room for the number of merged buffers if either feature is negotiated
+        let net_hdr_len = if features & (1 << VIRTIO_F_VERSION_1 | 1 << VIRTIO_NET_F_MRG_RXBUF) != 0
+        {
+            mem::size_of::<virtio_net_hdr_mrg_rxbuf>()
+        } else {
+            mem::size_of::<virtio_net_hdr>()
+        };
+        Self::check_mtu(&*transport, features, net_hdr_len, config)?;
+        info!(
+            "using {} virtqueues",
+            if features & (1 << VIRTIO_F_RING_PACKED) != 0 {
+                "packed"
+            } else {
+                "split"
+            }
         );
 
-        // 5) Skipped due to legacy interface
-        // 6) Skipped due to legacy interface
+        // 5.1.5: the device uses pairs of rx and tx queues, the control queue follows the last
+        // pair the device supports
+        let max_pairs = if features & (1 << VIRTIO_NET_F_MQ) != 0 {
+            // the legacy interface uses the guest's endianness which is little endian on x86
+            u16::from_le_bytes([
+                transport.read_config8(VIRTIO_NET_CONFIG_MAX_VQ_PAIRS)?,
+                transport.read_config8(VIRTIO_NET_CONFIG_MAX_VQ_PAIRS + 1)?,
+            ])
+        } else {
+            1
+        };
+        let num_pairs = config.num_rx_queues.max(config.num_tx_queues).max(1);
+        if num_pairs > max_pairs {
+            return Err(IxyError::InvalidConfig(format!(
+                "cannot configure {} rx and {} tx queues: device supports at most {} queue pairs",
+                config.num_rx_queues, config.num_tx_queues, max_pairs
+            )));
+        }
 
-        // 7) Perform network device specific initialization
-        let rx_queue = Self::setup_virtqueue(&mut bar0, VirtqueueType::Receive, 0)?;
-        let tx_queue = Self::setup_virtqueue(&mut bar0, VirtqueueType::Transmit, 1)?;
-        let ctrl_queue = Self::setup_virtqueue(&mut bar0, VirtqueueType::Control, 2)?;
+        // msi-x must be enabled before vectors are assigned to the queues (4.1.5.1.2)
+        let mut interrupts = Interrupts::default();
+        if vfio {
+            interrupts.interrupts_enabled = true;
+            interrupts.timeout_ms = config.interrupt_timeout;
+            interrupts.vfio_setup_interrupt(device_fd)?;
+            if interrupts.interrupt_type != VFIO_PCI_MSIX_IRQ_INDEX {
+                return Err(IxyError::Device(format!(
+                    "interrupt type not supported: {}",
+                    interrupts.interrupt_type
+                )));
+            }
+            interrupts.vfio_enable_msix_queues(device_fd, num_pairs)?;
+        }
 
-        // 2.6.13: allocate buffers to send to the device
-        // we allocate more bufs than what would fit in the rx queue, because we don't want to
-        // stall rx if users hold buffers for longer
-        let rx_mempool = Mempool::allocate(rx_queue.size as usize * 4, 2048)?;
-        let ctrl_mempool = Mempool::allocate(ctrl_queue.size as usize, 2048)?;
+        let mut rx_queues = Vec::with_capacity(num_pairs as usize);
+        let mut tx_queues = Vec::with_capacity(num_pairs as usize);
+        for pair in 0..num_pairs {
+            let virtq = Self::setup_virtqueue(
+                &mut *transport,
+                VirtqueueType::Receive,
+                rx_queue_index(pair),
+                features,
+                if vfio { Some(pair) } else { None },
+            )?;
+
+            // 2.6.13: allocate buffers to send to the device
+            // we allocate more bufs than what would fit in the rx queue, because we don't want to
+            // stall rx if users hold buffers for longer
+            let mempool = Mempool::allocate(
+                config.mempool_size.unwrap_or(virtq.size() as usize * 4),
+                config.buffer_size,
+            )?;
+
+            rx_queues.push(VirtioRxQueue {
+                inflight: (0..virtq.size()).map(|_| None).collect(),
+                virtq,
+                mempool,
+                spare: VecDeque::new(),
+                partial: None,
+                remaining_buffers: 0,
+                stats: RxQueueStats::default(),
+            });
+
+            let virtq = Self::setup_virtqueue(
+                &mut *transport,
+                VirtqueueType::Transmit,
+                tx_queue_index(pair),
+                features,
+                None,
+            )?;
+
+            tx_queues.push(VirtioTxQueue {
+                inflight: (0..virtq.size()).map(|_| None).collect(),
+                virtq,
+                stats: TxQueueStats::default(),
+            });
+        }
+
+        let ctrl_queue_idx = ctrl_queue_index(max_pairs);
+        let ctrl_queue = Self::setup_virtqueue(
+            &mut *transport,
+            VirtqueueType::Control,
+            ctrl_queue_idx,
+            features,
+            None,
+        )?;
+        let ctrl_mempool = Mempool::allocate(ctrl_queue.size() as usize, CTRL_BUFFER_SIZE)?;
+
+        let mut mac = [0; 6];
+        for (i, byte) in mac.iter_mut().enumerate() {
+            *byte = transport.read_config8(VIRTIO_NET_CONFIG_MAC + i as u64)?;
+        }
 
         mfence();
 
         // 8) Signal OK
-        write_io8(&mut bar0, VIRTIO_CONFIG_STATUS_DRIVER_OK, VIRTIO_PCI_STATUS)?;
+        status |= VIRTIO_CONFIG_STATUS_DRIVER_OK;
+        transport.set_status(status)?;
         info!("initialization complete");
 
         let mut device = VirtioDevice {
             pci_addr: pci_addr.to_owned(),
-            bar0,
-            rx_inflight: VecDeque::with_capacity(rx_queue.size as usize),
-            tx_inflight: VecDeque::with_capacity(tx_queue.size as usize),
-            rx_queue,
-            tx_queue,
-            ctrl_queue,
-            rx_mempool,
+            transport,
+            features,
+            net_hdr_len,
+            vlan_strip: config.vlan_strip,
+            timestamping: config.timestamping,
+            mac: Cell::new(mac),
+            mac_addrs: Vec::new(),
+            multicast_addrs: Vec::new(),
+            rx_queues,
+            tx_queues,
+            ctrl_queue: RefCell::new(ctrl_queue),
+            ctrl_queue_idx,
             ctrl_mempool,
+            vfio,
+            interrupts,
             rx_pkts: 0,
             tx_pkts: 0,
             rx_bytes: 0,
             tx_bytes: 0,
+            xstats: ExtendedStats::default(),
         };
 
         // recheck status
         device.check_pci_config_status()?;
-        device.set_promiscuous(true)?;
+
+        // 5.1.6.5.5: the device only uses the first queue pair until told otherwise
+        if device.features & (1 << VIRTIO_NET_F_MQ) != 0 {
+            device.send_command(&VirtioNetCtrlMqPairsSet::new(num_pairs).into())?;
+            info!("using {} queue pairs", num_pairs);
+        }
+        device.set_promiscuous(config.promiscuous)?;
+
+        if config.timestamping {
+            timestamp::calibrate();
+        }
 
         Ok(device)
     }
 
-    fn notify_queue(&mut self, queue_idx: u16) -> Result<(), io::Error> {
-        write_io16(&mut self.bar0, queue_idx, VIRTIO_PCI_QUEUE_NOTIFY)
+    /// Checks that the device can receive frames of the mtu in `config`.
+    fn check_mtu(
+        transport: &dyn Transport,
+        features: u64,
+        net_hdr_len: usize,
+        config: &DeviceConfig,
+    ) -> Result<(), IxyError> {
+        // 5.1.4.1: the device reports its maximum mtu, sending larger packets is not allowed
+        if features & (1 << VIRTIO_NET_F_MTU) != 0 {
+            let mtu = u16::from_le_bytes([
+                transport.read_config8(VIRTIO_NET_CONFIG_MTU)?,
+                transport.read_config8(VIRTIO_NET_CONFIG_MTU + 1)?,
+            ]);
+            debug!("device mtu: {}", mtu);
+            if config.mtu > usize::from(mtu) {
+                return Err(IxyError::InvalidConfig(format!(
+                    "mtu {} exceeds the device's mtu {}",
+                    config.mtu, mtu
+                )));
+            }
+        }
+
+        // without merged buffers each frame must fit into a single buffer
+        let buffer_len = config.buffer_size - PACKET_HEADROOM - net_hdr_len;
+        if features & (1 << VIRTIO_NET_F_MRG_RXBUF) == 0 && config.max_frame_size() > buffer_len {
+            return Err(IxyError::InvalidConfig(format!(
+                "mtu {} does not fit into {} byte buffers without VIRTIO_NET_F_MRG_RXBUF",
+                config.mtu, config.buffer_size
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn notify_queue(&self, queue_idx: u16) -> Result<(), IxyError> {
+        self.transport.notify_queue(queue_idx)
     }
 
-    fn check_pci_config_status(&mut self) -> Result<(), io::Error> {
-        assert_ne!(
-            read_io8(&mut self.bar0, VIRTIO_PCI_STATUS)?,
-            VIRTIO_CONFIG_STATUS_FAILED,
-            "device signaled unrecoverable config error"
-        );
+    fn check_pci_config_status(&mut self) -> Result<(), IxyError> {
+        if self.transport.status()? & VIRTIO_CONFIG_STATUS_FAILED != 0 {
+            return Err(IxyError::Device(
+                "device signaled unrecoverable config error".to_string(),
+            ));
+        }
         Ok(())
     }
 
-    fn set_promiscuous(&mut self, value: bool) -> Result<(), io::Error> {
-        let command = VirtioNetCtrlPromisc::new(value).into();
-        self.send_command(&command)?;
-        info!("set promiscuous mode to {}", value);
+    /// Returns an error if the mac filter table has no room for another address.
+    fn check_mac_table_space(&self) -> Result<(), IxyError> {
+        if self.mac_addrs.len() + self.multicast_addrs.len() >= MAX_MAC_TABLE_ADDRS {
+            return Err(IxyError::InvalidConfig(format!(
+                "{} supports at most {} unicast and multicast addresses",
+                self.get_driver_name(),
+                MAX_MAC_TABLE_ADDRS
+            )));
+        }
+
         Ok(())
     }
 
+    /// Writes the secondary unicast and subscribed multicast addresses to the device's mac
+    /// filter table.
+    fn set_mac_table(&mut self) -> Result<(), IxyError> {
+        let mut data = Vec::new();
+        for addrs in &[&self.mac_addrs, &self.multicast_addrs] {
+            data.extend_from_slice(&(addrs.len() as u32).to_le_bytes());
+            for addr in addrs.iter() {
+                data.extend_from_slice(addr);
+            }
+        }
+
+        self.send_command_data(VIRTIO_NET_CTRL_MAC, VIRTIO_NET_CTRL_MAC_TABLE_SET, &data)
+    }
+
+    /// Adds or removes vlan id `vlan_id` to or from the device's vlan filter.
+    fn set_vlan_filter(&mut self, vlan_id: u16, add: bool) -> Result<(), IxyError> {
+        offload::check_vlan_id(vlan_id)?;
+
+        if self.features & (1 << VIRTIO_NET_F_CTRL_VLAN) == 0 {
+            return Err(IxyError::InvalidConfig(format!(
+                "{} does not support vlan filters",
+                self.get_driver_name()
+            )));
+        }
+
+        if add {
+            self.send_command(&VirtioNetCtrlVlanAdd::new(vlan_id).into())
+        } else {
+            self.send_command(&VirtioNetCtrlVlanDel::new(vlan_id).into())
+        }
+    }
+
     fn send_command<C: VirtioNetCtrlCommand>(
-        &mut self,
+        &self,
         command: &VirtioNetCtrl<C>,
-    ) -> Result<(), io::Error> {
-        let cmd_len = mem::size_of::<VirtioNetCtrl<C>>();
-        mfence();
-        let idx = self
-            .ctrl_queue
-            .free_descriptor_indices()
-            .next()
-            .expect("command queue full");
-        debug!(
-            "found free descriptor at index {} (out of {})",
-            idx, self.ctrl_queue.size
-        );
+    ) -> Result<(), IxyError> {
+        let data = unsafe { any_as_u8_slice(&command.command_data) };
+        self.send_command_data(command.class, command.command, data)
+    }
 
-        let mut buf = memory::alloc_pkt(&self.ctrl_mempool, cmd_len).unwrap();
-        buf.copy_from_slice(unsafe { any_as_u8_slice(command) });
-
-        // one descriptor for everything; should work as we negotiated VIRTIO_F_ANY_LAYOUT during
-        // init but doesn't in practice
-        // let desc = &mut ctrl_queue.descriptors_mut()[idx as usize];
-        // desc.len = cmd_len as u32;
-        // desc.addr = buf.get_phys_addr();
-        // desc.flags = VIRTQ_DESC_F_WRITE;
-        // desc.next = 0;
-
-        // device-readable payload: cmd header
-        let desc = &mut self.ctrl_queue.descriptors_mut()[idx as usize];
-        desc.len = 2;
-        desc.addr = buf.get_phys_addr();
-        desc.flags = VIRTQ_DESC_F_NEXT;
-        desc.next = idx + 1;
-        // device-readable payload: cmd data
-        let desc = &mut self.ctrl_queue.descriptors_mut()[(idx + 1) as usize];
-        desc.len = mem::size_of::<C>() as u32;
-        desc.addr = buf.get_phys_addr() + 2;
-        desc.flags = VIRTQ_DESC_F_NEXT;
-        desc.next = idx + 2;
-        // device-writable tail: ack flag
-        let desc = &mut self.ctrl_queue.descriptors_mut()[(idx + 2) as usize];
-        desc.len = 1;
-        desc.addr = buf.get_phys_addr() + 2 + mem::size_of::<C>();
-        desc.flags = VIRTQ_DESC_F_WRITE;
-        desc.next = 0;
-
-        let avail_idx = self.ctrl_queue.available.idx.0 % self.ctrl_queue.size;
-        self.ctrl_queue.available[avail_idx] = idx;
+    /// Sends the command `command` of class `class` with the variable length `data` through the
+    /// control queue and waits for its acknowledgement.
+    fn send_command_data(&self, class: u8, command: u8, data: &[u8]) -> Result<(), IxyError> {
+        let mut ctrl_queue = self.ctrl_queue.borrow_mut();
+        let cmd_len = 2 + data.len() + 1;
+        if ctrl_queue.num_free() < 3 {
+            return Err(IxyError::Device("command queue full".to_string()));
+        }
 
-        mfence();
-        self.ctrl_queue.available.idx += Wrapping(1);
-        mfence();
-        self.notify_queue(2)?;
+        let mut buf =
+            memory::alloc_pkt(&self.ctrl_mempool, cmd_len).ok_or(IxyError::MempoolExhausted)?;
+        buf[0] = class;
+        buf[1] = command;
+        buf[2..2 + data.len()].copy_from_slice(data);
+        buf[2 + data.len()] = 0;
+
+        // one descriptor for everything should work as we negotiated VIRTIO_F_ANY_LAYOUT during
+        // init but doesn't in practice, so the command header, the command data and the
+        // device-writable ack flag get a descriptor each
+        let phys = buf.get_phys_addr();
+        let buffers = [
+            Buffer {
+                addr: phys,
+                len: 2,
+                writable: false,
+            },
+            Buffer {
+                addr: phys + 2,
+                len: data.len() as u32,
+                writable: false,
+            },
+            Buffer {
+                addr: phys + 2 + data.len(),
+                len: 1,
+                writable: true,
+            },
+        ];
+        let id = ctrl_queue.add(buffers.iter().copied());
+        if ctrl_queue.publish() {
+            self.notify_queue(self.ctrl_queue_idx)?;
+        }
 
-        #[allow(clippy::while_immutable_condition)]
-        while self.ctrl_queue.last_used_idx == self.ctrl_queue.used.idx {
-            mfence();
+        let (used_id, used_len) = loop {
+            if let Some(used) = ctrl_queue.pop_used() {
+                break used;
+            }
             debug!("waiting...");
             thread::sleep(Duration::from_millis(100));
-        }
-        assert_eq!(
-            (self.ctrl_queue.last_used_idx + Wrapping(1)).0 % self.ctrl_queue.size,
-            self.ctrl_queue.used.idx.0
-        );
-        self.ctrl_queue.last_used_idx = self.ctrl_queue.used.idx;
-
-        let used = &self.ctrl_queue.used[self.ctrl_queue.used.idx.0];
+        };
 
-        debug!(
-            "used ctrl buffer @ {:p} id {} len {}",
-            &used, used.id, used.len
-        );
-        assert_eq!(
-            used.id, idx,
-            "used buffer has different index than the one sent"
-        );
+        debug!("used ctrl buffer id {} len {}", used_id, used_len);
+        if used_id != id {
+            return Err(IxyError::Device(format!(
+                "device used command buffer {} instead of {}",
+                used_id, id
+            )));
+        }
 
         // ensure that the command was correctly acknowledged
-        let ack = unsafe { (*(buf.get_virt_addr() as *const VirtioNetCtrl<C>)).ack };
-        assert_eq!(
-            ack, VIRTIO_NET_OK,
-            "sent command was not acknowledged correctly"
-        );
+        if buf[2 + data.len()] != VIRTIO_NET_OK {
+            return Err(IxyError::Device(
+                "sent command was not acknowledged correctly".to_string(),
+            ));
+        }
 
         Ok(())
     }
 
+    /// Sets up queue `index` in the layout given by the negotiated `features`, the device
+    /// interrupts through msi-x vector `vector` if one is given.
     fn setup_virtqueue(
-        bar0: &mut File,
+        transport: &mut dyn Transport,
         virtq_type: VirtqueueType,
         index: u16,
-    ) -> Result<Virtqueue, Box<dyn Error>> {
-        assert!(
-            virtq_type.is_valid_index(index),
-            "invalid queue index {} for {:?}",
-            index,
-            virtq_type
-        );
+        features: u64,
+        vector: Option<u16>,
+    ) -> Result<Virtqueue, IxyError> {
+        let packed = features & (1 << VIRTIO_F_RING_PACKED) != 0;
+        let event_idx = features & (1 << VIRTIO_RING_F_EVENT_IDX) != 0;
 
         // 4.1.5.1.3: create virtqueue itself
-        write_io16(bar0, index, VIRTIO_PCI_QUEUE_SEL)?;
-        let max_queue_size = read_io16(bar0, VIRTIO_PCI_QUEUE_NUM)?;
+        let max_queue_size = transport.max_queue_size(index)?;
         debug!(
             "max queue size of queue #{} ({:?}): {}",
             index, virtq_type, max_queue_size
         );
-        assert!(max_queue_size > 0, "queue #{} doesn't exist", index);
-        let virtqueue_mem_size = Virtqueue::size(max_queue_size);
+        if max_queue_size == 0 {
+            return Err(IxyError::Device(format!("queue #{} doesn't exist", index)));
+        }
+        let virtqueue_mem_size = if packed {
+            PackedVirtqueue::memory_size(max_queue_size)
+        } else {
+            SplitVirtqueue::memory_size(max_queue_size)
+        };
         let mem: Dma<u8> = Dma::allocate(virtqueue_mem_size, true)?;
         debug!(
             "allocated {:#x} bytes for virtqueue at {:p}",
             virtqueue_mem_size, mem.virt
         );
-        write_io32(
-            bar0,
-            (mem.phys >> VIRTIO_PCI_QUEUE_ADDR_SHIFT) as u32,
-            VIRTIO_PCI_QUEUE_PFN,
-        )?;
+        let virtq = unsafe {
+            if packed {
+                Virtqueue::Packed(PackedVirtqueue::new(max_queue_size, mem.virt, event_idx))
+            } else {
+                Virtqueue::Split(SplitVirtqueue::new(max_queue_size, mem.virt, event_idx))
+            }
+        };
 
-        // DMA memory already follows stricter alignment than `VirtqDesc`
-        #[allow(clippy::cast_ptr_alignment)]
-        let mut virtq = unsafe { Virtqueue::new(max_queue_size, mem.virt as *mut VirtqDesc) };
-        debug!("virtq desc:  {:p}", virtq.desc);
-        debug!("virtq avail: {:p}", virtq.available.ptr);
-        debug!("virtq used:  {:p}", virtq.used.ptr);
-        for i in 0..virtq.size {
-            virtq.descriptors_mut()[i as usize] = VirtqDesc::default();
-            virtq.available[i] = 0;
-            virtq.used[i] = VirtqUsedElem::default();
+        let areas = virtq.areas(mem.phys);
+        debug!(
+            "virtq areas: desc {:#x} driver {:#x} device {:#x}",
+            areas.desc, areas.driver, areas.device
+        );
+        if let Some(vector) = vector {
+            transport.set_queue_vector(index, vector)?;
         }
-        virtq.available.idx = Wrapping(0);
-        virtq.used.idx = Wrapping(0);
-
-        // optimization hint to not get interrupted when the device consumes a buffer
-        virtq.available.flags = VIRTQ_AVAIL_F_NO_INTERRUPT;
-        virtq.used.flags = 0;
+        transport.enable_queue(index, max_queue_size, areas)?;
 
         Ok(virtq)
     }
 }
 
+impl VirtioRxQueue {
+    /// Returns the next packet the device received, [`None`] if it has not completed one yet.
+    /// With `VIRTIO_NET_F_MRG_RXBUF` a packet spans `num_buffers` buffers, which are chained as
+    /// its segments. The virtio header is in front of the first segment.
+    ///
+    /// Buffers too short to hold the header are counted as length errors in `xstats` and
+    /// dropped.
+    fn pop_packet(
+        &mut self,
+        net_hdr_len: usize,
+        mrg_rxbuf: bool,
+        xstats: &mut ExtendedStats,
+    ) -> Option<Packet> {
+        loop {
+            let (id, len) = self.virtq.pop_used()?;
+
+            let mut buf = match self.inflight.get_mut(id as usize).and_then(Option::take) {
+                Some(buf) => buf,
+                None => {
+                    error!("device used unknown rx buffer {}", id);
+                    continue;
+                }
+            };
+            let buf = match self.partial.take() {
+                Some(mut head) => {
+                    // 5.1.6.4: only the first buffer of a merged packet holds a header, the data
+                    // of the others starts where the header would be
+                    unsafe {
+                        buf.addr_virt = buf.addr_virt.sub(net_hdr_len);
+                    }
+                    buf.addr_phys -= net_hdr_len;
+                    buf.len = len as usize;
+                    head.push_segment(buf);
+                    head
+                }
+                None if (len as usize) < net_hdr_len => {
+                    warn!("device used rx buffer {} without a complete header", id);
+                    xstats.rx_length_errors += 1;
+                    continue;
+                }
+                None => {
+                    // adjust buffer length to actual packet size
+                    buf.len = len as usize - net_hdr_len;
+                    self.remaining_buffers = if mrg_rxbuf {
+                        let header = unsafe {
+                            ptr::read_unaligned(buf.headroom_mut(net_hdr_len).as_ptr()
+                                as *const virtio_net_hdr_mrg_rxbuf)
+                        };
+                        header.num_buffers.max(1)
+                    } else {
+                        1
+                    };
+                    buf
+                }
+            };
+
+            // wait for the remaining buffers of a merged packet
+            self.remaining_buffers -= 1;
+            if self.remaining_buffers > 0 {
+                self.partial = Some(buf);
+                continue;
+            }
+
+            return Some(buf);
+        }
+    }
+
+    /// Adds a new buffer for each free descriptor, the buffers only become visible to the device
+    /// once they are published.
+    fn refill(&mut self, net_hdr_len: usize) {
+        let num_free = self.virtq.num_free() as usize;
+        if num_free == 0 {
+            return;
+        }
+
+        // leave descriptors empty for now if all buffers are held by the user, the header is
+        // written to the packets' headroom
+        let allocated = memory::alloc_pkt_batch(
+            &self.mempool,
+            &mut self.spare,
+            num_free,
+            self.mempool.entry_size() - PACKET_HEADROOM,
+        );
+        if allocated < num_free {
+            self.stats.mempool_exhausted += 1;
+        }
+
+        while let Some(buf) = self.spare.pop_front() {
+            let id = self.virtq.add(iter::once(Buffer {
+                addr: buf.get_phys_addr() - net_hdr_len,
+                len: (buf.len + net_hdr_len) as u32,
+                writable: true,
+            }));
+            self.inflight[id as usize] = Some(buf);
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 enum VirtqueueType {
     Receive,
@@ -508,120 +1135,121 @@ enum VirtqueueType {
     Control,
 }
 
-impl VirtqueueType {
-    fn is_valid_index(self, index: u16) -> bool {
-        // we don't support VIRTIO_NET_F_MQ atm so there are only 3 queues
-        let valid = match self {
-            VirtqueueType::Receive => 0,
-            VirtqueueType::Transmit => 1,
-            VirtqueueType::Control => 2,
-        };
-        index == valid
-    }
+/// A buffer of a descriptor chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Buffer {
+    addr: usize,
+    len: u32,
+    // the device writes to the buffer instead of reading from it
+    writable: bool,
 }
 
-pub struct Virtqueue {
-    size: u16,
-    desc: *mut VirtqDesc,
-    available: RingWrapper<VirtqAvail>,
-    used: RingWrapper<VirtqUsed>,
-    last_used_idx: Wrapping<u16>,
+/// A virtqueue in the split or, with `VIRTIO_F_RING_PACKED`, the packed layout.
+///
+/// Chains of buffers are added and published to the device in batches, used chains are returned
+/// by their id.
+enum Virtqueue {
+    Split(SplitVirtqueue),
+    Packed(PackedVirtqueue),
 }
 
 impl Virtqueue {
-    unsafe fn new(size: u16, ptr: *mut VirtqDesc) -> Virtqueue {
-        let size_usize = size as usize;
-        let avail = ptr.wrapping_add(size_usize) as *mut VirtqAvail;
-        let used =
-            align((*avail).ring.as_mut_ptr().wrapping_add(size_usize) as _) as *mut VirtqUsed;
+    fn areas(&self, phys: usize) -> QueueAreas {
+        match self {
+            Virtqueue::Split(virtq) => virtq.areas(phys),
+            Virtqueue::Packed(virtq) => virtq.areas(phys),
+        }
+    }
 
-        Virtqueue {
-            size,
-            desc: ptr,
-            available: RingWrapper { ptr: avail, size },
-            used: RingWrapper { ptr: used, size },
-            last_used_idx: Wrapping(0),
+    /// Returns the number of descriptors, buffer ids range from 0 to this number.
+    fn size(&self) -> u16 {
+        match self {
+            Virtqueue::Split(virtq) => virtq.size(),
+            Virtqueue::Packed(virtq) => virtq.size(),
         }
     }
 
-    pub fn descriptors(&self) -> &[VirtqDesc] {
-        unsafe { slice::from_raw_parts(self.desc, self.size as usize) }
+    /// Returns the number of free descriptors.
+    fn num_free(&self) -> u16 {
+        match self {
+            Virtqueue::Split(virtq) => virtq.num_free(),
+            Virtqueue::Packed(virtq) => virtq.num_free(),
+        }
     }
 
-    pub fn descriptors_mut(&mut self) -> &mut [VirtqDesc] {
-        unsafe { slice::from_raw_parts_mut(self.desc, self.size as usize) }
+    /// Adds a chain of `buffers` and returns its id, the chain is not visible to the device
+    /// until the next call of [`publish`](Virtqueue::publish).
+    ///
+    /// # Panics
+    ///
+    /// Panics if there are less free descriptors than buffers.
+    fn add(&mut self, buffers: impl Iterator<Item = Buffer>) -> u16 {
+        match self {
+            Virtqueue::Split(virtq) => virtq.add(buffers),
+            Virtqueue::Packed(virtq) => virtq.add(buffers),
+        }
     }
 
-    pub fn free_descriptor_indices(&self) -> impl Iterator<Item = u16> + '_ {
-        self.descriptors()
-            .iter()
-            .enumerate()
-            .filter_map(|(idx, desc)| {
-                if desc.addr == 0 {
-                    Some(idx as u16)
-                } else {
-                    None
-                }
-            })
+    /// Makes all added chains available to the device and returns whether the device needs to
+    /// be notified.
+    fn publish(&mut self) -> bool {
+        match self {
+            Virtqueue::Split(virtq) => virtq.publish(),
+            Virtqueue::Packed(virtq) => virtq.publish(),
+        }
     }
 
-    fn size(queue_size: u16) -> usize {
-        // from 2.6.2
-        let queue_size = queue_size as usize;
-        align(mem::size_of::<VirtqDesc>() * queue_size + mem::size_of::<u16>() * (3 + queue_size))
-            + align(mem::size_of::<u16>() * 3 + mem::size_of::<VirtqUsedElem>() * queue_size)
+    /// Asks the device to interrupt once it uses the next chain and returns whether a used chain
+    /// is already pending.
+    fn enable_interrupts(&mut self) -> bool {
+        match self {
+            Virtqueue::Split(virtq) => virtq.enable_interrupts(),
+            Virtqueue::Packed(virtq) => virtq.enable_interrupts(),
+        }
     }
-}
 
-struct RingWrapper<T: Ring> {
-    ptr: *mut T,
-    size: u16,
-}
+    /// Asks the device not to interrupt when it uses chains.
+    fn disable_interrupts(&mut self) {
+        match self {
+            Virtqueue::Split(virtq) => virtq.disable_interrupts(),
+            Virtqueue::Packed(virtq) => virtq.disable_interrupts(),
+        }
+    }
 
-impl<T: Ring> Index<u16> for RingWrapper<T> {
-    type Output = <T as Ring>::Element;
-    fn index(&self, idx: u16) -> &Self::Output {
-        assert!(
-            idx < self.size,
-            "index {} is greater than queue size {}",
-            idx,
-            self.size
-        );
-        unsafe { &*self.ring().add(idx as usize) }
+    /// Returns the id and the number of written bytes of the next chain the device used and
+    /// frees its descriptors.
+    fn pop_used(&mut self) -> Option<(u16, u32)> {
+        match self {
+            Virtqueue::Split(virtq) => virtq.pop_used(),
+            Virtqueue::Packed(virtq) => virtq.pop_used(),
+        }
     }
 }
 
-impl<T: Ring> IndexMut<u16> for RingWrapper<T> {
-    fn index_mut(&mut self, idx: u16) -> &mut Self::Output {
-        assert!(
-            idx < self.size,
-            "index {} is greater than queue size {}",
-            idx,
-            self.size
-        );
-        unsafe { &mut *self.ring_mut().add(idx as usize) }
-    }
+/// Returns the index of the rx queue of queue pair `pair` (5.1.2).
+fn rx_queue_index(pair: u16) -> u16 {
+    2 * pair
 }
 
-impl<T: Ring> Deref for RingWrapper<T> {
-    type Target = T;
-    fn deref(&self) -> &T {
-        unsafe { &*self.ptr }
-    }
+/// Returns the index of the tx queue of queue pair `pair` (5.1.2).
+fn tx_queue_index(pair: u16) -> u16 {
+    2 * pair + 1
 }
 
-impl<T: Ring> DerefMut for RingWrapper<T> {
-    fn deref_mut(&mut self) -> &mut T {
-        unsafe { &mut *self.ptr }
-    }
+/// Returns the index of the control queue, which follows the `max_pairs` queue pairs the device
+/// supports even if the driver uses fewer (5.1.2).
+fn ctrl_queue_index(max_pairs: u16) -> u16 {
+    2 * max_pairs
 }
 
-fn mfence() {
-    atomic::fence(Ordering::SeqCst);
+/// Returns whether the ring index `event_idx` is among the entries from `old_idx` up to
+/// `new_idx` that were just made available or used (2.6.7.2, 2.6.10.2).
+fn need_event(event_idx: u16, new_idx: u16, old_idx: u16) -> bool {
+    new_idx.wrapping_sub(event_idx).wrapping_sub(1) < new_idx.wrapping_sub(old_idx)
 }
 
-fn align(ptr: usize) -> usize {
-    ptr + (ptr as *const u8).align_offset(QUEUE_ALIGNMENT)
+fn mfence() {
+    atomic::fence(Ordering::SeqCst);
 }
 
 // from https://stackoverflow.com/a/42186553
@@ -635,20 +1263,133 @@ unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
 mod tests {
     use super::*;
 
+    use crate::memory::Dma;
+
     #[test]
-    fn test_align() {
-        // we use a function based on libstd; just checking against the macro from the spec to make
-        // sure we have the same behavior
-        fn align_spec(x: usize) -> usize {
-            (x + (QUEUE_ALIGNMENT - 1)) & !(QUEUE_ALIGNMENT - 1)
-        }
+    fn test_queue_index() {
+        // receiveq1, transmitq1, ..., receiveqN, transmitqN
+        let indices: Vec<_> = (0..3)
+            .flat_map(|pair| vec![rx_queue_index(pair), tx_queue_index(pair)])
+            .collect();
+        assert_eq!(indices, [0, 1, 2, 3, 4, 5]);
+
+        // queue id `i` uses the queues of pair `i`
+        assert_eq!((rx_queue_index(2), tx_queue_index(2)), (4, 5));
+
+        assert_eq!(ctrl_queue_index(1), 2);
+        assert_eq!(ctrl_queue_index(3), 6);
+    }
 
-        for i in 0..1_000_000 {
-            let aligned = align(i);
-            assert!(aligned >= i);
-            assert!(aligned - i < QUEUE_ALIGNMENT);
-            assert_eq!(aligned % QUEUE_ALIGNMENT, 0);
-            assert_eq!(aligned, align_spec(i));
-        }
+    #[test]
+    fn test_ctrl_command_encoding() {
+        // 5.1.6.5.5: class 4, command 0 and the number of pairs as le16
+        let command: VirtioNetCtrl<_> = VirtioNetCtrlMqPairsSet::new(0x0102).into();
+        assert_eq!((command.class, command.command), (4, 0));
+        let data = unsafe { any_as_u8_slice(&command.command_data) };
+        assert_eq!(data, &[0x02, 0x01][..]);
+
+        // 5.1.6.5.2: class 1, command 1 and the six bytes of the address
+        let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+        let command: VirtioNetCtrl<_> = VirtioNetCtrlMacAddrSet::new(mac).into();
+        assert_eq!((command.class, command.command), (1, 1));
+        let data = unsafe { any_as_u8_slice(&command.command_data) };
+        assert_eq!(data, &mac[..]);
+    }
+
+    #[test]
+    fn test_merged_rx_buffers() {
+        let net_hdr_len = mem::size_of::<virtio_net_hdr_mrg_rxbuf>();
+        let size = 4;
+        let mem: Dma<u8> = Dma::allocate_anonymous(SplitVirtqueue::memory_size(size)).unwrap();
+        let virtq = unsafe { SplitVirtqueue::new(size, mem.virt, false) };
+        let mut device = split::tests::Device::new(&virtq);
+        let mut queue = VirtioRxQueue {
+            virtq: Virtqueue::Split(virtq),
+            mempool: Mempool::allocate_anonymous(2 * size as usize, 0).unwrap(),
+            inflight: (0..size).map(|_| None).collect(),
+            spare: VecDeque::new(),
+            partial: None,
+            remaining_buffers: 0,
+            stats: RxQueueStats::default(),
+        };
+        queue.refill(net_hdr_len);
+        queue.virtq.publish();
+
+        // the device writes the header and the frame to consecutive buffers, the anonymous
+        // memory's physical addresses are its virtual ones
+        let mut write = |frame: &[u8], num_buffers: u16| {
+            let mut chunks = Vec::new();
+            let mut offset = 0;
+            for i in 0..num_buffers {
+                let (id, buffers) = device.pop_avail().unwrap();
+                let addr = buffers[0].addr as *mut u8;
+                let header_len = if i == 0 { net_hdr_len } else { 0 };
+                let len = (buffers[0].len as usize - header_len).min(frame.len() - offset);
+                unsafe {
+                    if i == 0 {
+                        ptr::write_bytes(addr, 0, net_hdr_len);
+                        ptr::write_unaligned(addr.add(net_hdr_len - 2) as *mut u16, num_buffers);
+                    }
+                    ptr::copy(frame[offset..].as_ptr(), addr.add(header_len), len);
+                }
+                offset += len;
+                chunks.push((id, (header_len + len) as u32));
+            }
+            assert_eq!(offset, frame.len());
+            chunks
+        };
+
+        let frame: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        let chunks = write(&frame, 2);
+        let small = write(&[0xab; 60], 1);
+
+        // the packet is only complete once its last buffer is used
+        let mut xstats = ExtendedStats::default();
+        device.push_used(chunks[0].0, chunks[0].1);
+        assert!(queue.pop_packet(net_hdr_len, true, &mut xstats).is_none());
+        assert!(queue.partial.is_some());
+        assert_eq!(queue.remaining_buffers, 1);
+
+        device.push_used(chunks[1].0, chunks[1].1);
+        device.push_used(small[0].0, small[0].1);
+        let mut packet = queue.pop_packet(net_hdr_len, true, &mut xstats).unwrap();
+        assert_eq!(packet.num_segments(), 2);
+        assert_eq!(packet.total_len(), frame.len());
+        let data: Vec<u8> = packet.segments().flat_map(|s| s.iter().copied()).collect();
+        assert_eq!(data, frame);
+        let header = unsafe {
+            ptr::read_unaligned(
+                packet.headroom_mut(net_hdr_len).as_ptr() as *const virtio_net_hdr_mrg_rxbuf
+            )
+        };
+        assert_eq!(header.num_buffers, 2);
+
+        let packet = queue.pop_packet(net_hdr_len, true, &mut xstats).unwrap();
+        assert_eq!(packet.num_segments(), 1);
+        assert_eq!(&packet[..], &[0xab; 60][..]);
+        assert!(queue.partial.is_none());
+        assert!(queue.pop_packet(net_hdr_len, true, &mut xstats).is_none());
+
+        // buffers without a complete header are dropped
+        queue.refill(net_hdr_len);
+        queue.virtq.publish();
+        let (id, _) = device.pop_avail().unwrap();
+        device.push_used(id, net_hdr_len as u32 - 1);
+        assert!(queue.pop_packet(net_hdr_len, true, &mut xstats).is_none());
+        assert_eq!(xstats.rx_length_errors, 1);
+    }
+
+    #[test]
+    fn test_need_event() {
+        assert!(need_event(0, 1, 0));
+        assert!(need_event(3, 5, 2));
+        assert!(!need_event(5, 5, 2));
+        assert!(!need_event(1, 5, 2));
+        assert!(!need_event(0, 0, 0));
+
+        // the indices wrap around
+        assert!(need_event(u16::MAX, 1, u16::MAX - 1));
+        assert!(need_event(0, 1, u16::MAX - 1));
+        assert!(!need_event(1, 1, u16::MAX - 1));
     }
 }
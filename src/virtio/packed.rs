@@ -0,0 +1,433 @@
+//! The packed virtqueue layout: a single descriptor ring shared by the driver and the device
+//! (2.7).
+//!
+//! The driver makes descriptors available and the device marks them used in ring order. Both
+//! keep a wrap counter that flips whenever they pass the end of the ring, a descriptor's avail
+//! and used flags are compared against these counters to tell who owns it. The device writes one
+//! used descriptor per chain and skips the chain's other descriptors.
+//!
+//! Notifications are suppressed by the event structures of the driver and the device (2.7.10),
+//! with `VIRTIO_RING_F_EVENT_IDX` they may ask for a notification at a specific descriptor.
+
+use std::{mem, ptr};
+
+use super::transport::QueueAreas;
+use super::{mfence, need_event, Buffer};
+use crate::virtio_constants::*;
+
+pub(super) struct PackedVirtqueue {
+    size: u16,
+    desc: *mut VirtqPackedDesc,
+    driver_event: *mut VirtqPackedEvent,
+    device_event: *mut VirtqPackedEvent,
+    // next descriptor to make available and the driver ring wrap counter (2.7.1)
+    next_avail: u16,
+    avail_wrap_counter: bool,
+    // next descriptor the device marks used and the device ring wrap counter
+    next_used: u16,
+    used_wrap_counter: bool,
+    num_free: u16,
+    // unused buffer ids and the number of descriptors of the chain of each id in use
+    free_ids: Vec<u16>,
+    chain_len: Vec<u16>,
+    // first descriptor added since the last call of `publish` and its flags, they are written
+    // last to make all chains of a batch available at once (2.7.13)
+    batch_head: Option<(u16, u16)>,
+    // descriptors added since the last call of `publish`
+    num_added: u16,
+    // `VIRTIO_RING_F_EVENT_IDX` was negotiated
+    event_idx: bool,
+}
+
+impl PackedVirtqueue {
+    /// Returns the number of bytes of a queue with `size` descriptors.
+    pub(super) fn memory_size(size: u16) -> usize {
+        mem::size_of::<VirtqPackedDesc>() * size as usize + 2 * mem::size_of::<VirtqPackedEvent>()
+    }
+
+    /// Returns an empty queue with `size` descriptors in the memory at `ptr`, which must be
+    /// aligned to 16 bytes and hold `memory_size(size)` bytes.
+    pub(super) unsafe fn new(size: u16, ptr: *mut u8, event_idx: bool) -> PackedVirtqueue {
+        // DMA memory already follows stricter alignment than `VirtqPackedDesc`
+        #[allow(clippy::cast_ptr_alignment)]
+        let desc = ptr as *mut VirtqPackedDesc;
+        let driver_event = desc.add(size as usize) as *mut VirtqPackedEvent;
+        ptr::write_bytes(ptr, 0, PackedVirtqueue::memory_size(size));
+
+        // optimization hint to not get interrupted when the device consumes a buffer
+        (*driver_event).flags = VIRTQ_EVENT_F_DISABLE;
+
+        PackedVirtqueue {
+            size,
+            desc,
+            driver_event,
+            device_event: driver_event.add(1),
+            next_avail: 0,
+            avail_wrap_counter: true,
+            next_used: 0,
+            used_wrap_counter: true,
+            num_free: size,
+            free_ids: (0..size).rev().collect(),
+            chain_len: vec![0; size as usize],
+            batch_head: None,
+            num_added: 0,
+            event_idx,
+        }
+    }
+
+    /// Returns the physical addresses of the queue's areas if it starts at `phys`.
+    pub(super) fn areas(&self, phys: usize) -> QueueAreas {
+        QueueAreas {
+            desc: phys,
+            driver: phys + (self.driver_event as usize - self.desc as usize),
+            device: phys + (self.device_event as usize - self.desc as usize),
+        }
+    }
+
+    pub(super) fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub(super) fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    /// Adds a chain of `buffers` to the ring and returns its buffer id.
+    pub(super) fn add(&mut self, buffers: impl Iterator<Item = Buffer>) -> u16 {
+        let id = self.free_ids.pop().expect("no free buffer id left");
+        let mut len = 0;
+        let mut buffers = buffers.peekable();
+        while let Some(buffer) = buffers.next() {
+            assert!(self.num_free > 0, "no free descriptor left");
+            self.num_free -= 1;
+            len += 1;
+
+            let mut flags = if self.avail_wrap_counter {
+                VIRTQ_DESC_F_AVAIL
+            } else {
+                VIRTQ_DESC_F_USED
+            };
+            if buffer.writable {
+                flags |= VIRTQ_DESC_F_WRITE;
+            }
+            if buffers.peek().is_some() {
+                flags |= VIRTQ_DESC_F_NEXT;
+            }
+
+            let desc = unsafe { &mut *self.desc.add(self.next_avail as usize) };
+            desc.addr = buffer.addr;
+            desc.len = buffer.len;
+            desc.id = id;
+            if self.batch_head.is_none() {
+                self.batch_head = Some((self.next_avail, flags));
+            } else {
+                desc.flags = flags;
+            }
+
+            self.next_avail += 1;
+            if self.next_avail == self.size {
+                self.next_avail = 0;
+                self.avail_wrap_counter = !self.avail_wrap_counter;
+            }
+        }
+        self.chain_len[id as usize] = len;
+        self.num_added += len;
+
+        id
+    }
+
+    /// Makes all chains added since the last call available to the device and returns whether
+    /// the device needs to be notified about them.
+    pub(super) fn publish(&mut self) -> bool {
+        let (idx, flags) = match self.batch_head.take() {
+            Some(head) => head,
+            None => return false,
+        };
+        mfence();
+        unsafe {
+            ptr::write_volatile(&mut (*self.desc.add(idx as usize)).flags, flags);
+        }
+        mfence();
+
+        // descriptors of the previous lap have negative offsets relative to the current one
+        let new_idx = self.next_avail;
+        let old_idx = new_idx.wrapping_sub(self.num_added);
+        self.num_added = 0;
+
+        // 2.7.10: the device either asks for a notification once a specific descriptor is
+        // available, for every descriptor or for none at all
+        let event = unsafe { ptr::read_volatile(self.device_event) };
+        match event.flags {
+            VIRTQ_EVENT_F_DISABLE => false,
+            VIRTQ_EVENT_F_DESC if self.event_idx => {
+                let mut event_idx = event.desc & !(1 << 15);
+                if (event.desc & (1 << 15) != 0) != self.avail_wrap_counter {
+                    event_idx = event_idx.wrapping_sub(self.size);
+                }
+                need_event(event_idx, new_idx, old_idx)
+            }
+            _ => true,
+        }
+    }
+
+    /// Asks the device to interrupt once it uses the next chain and returns whether a used chain
+    /// is already pending, the device may not interrupt for that one.
+    pub(super) fn enable_interrupts(&mut self) -> bool {
+        let flags = if self.event_idx {
+            let desc = self.next_used | u16::from(self.used_wrap_counter) << 15;
+            unsafe { ptr::write_volatile(&mut (*self.driver_event).desc, desc) };
+            mfence();
+            VIRTQ_EVENT_F_DESC
+        } else {
+            VIRTQ_EVENT_F_ENABLE
+        };
+        unsafe { ptr::write_volatile(&mut (*self.driver_event).flags, flags) };
+        mfence();
+
+        self.used_pending()
+    }
+
+    /// Asks the device not to interrupt when it uses chains.
+    pub(super) fn disable_interrupts(&mut self) {
+        unsafe { ptr::write_volatile(&mut (*self.driver_event).flags, VIRTQ_EVENT_F_DISABLE) };
+    }
+
+    /// Returns the id and the written length of the next used chain and frees its descriptors.
+    pub(super) fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.used_pending() {
+            return None;
+        }
+        mfence();
+
+        let desc = unsafe { self.desc.add(self.next_used as usize) };
+        let (id, len) = unsafe { ((*desc).id, (*desc).len) };
+        let chain_len = self.chain_len[id as usize];
+        self.num_free += chain_len;
+        self.free_ids.push(id);
+
+        self.next_used += chain_len;
+        if self.next_used >= self.size {
+            self.next_used -= self.size;
+            self.used_wrap_counter = !self.used_wrap_counter;
+        }
+
+        Some((id, len))
+    }
+
+    /// Returns whether the device marked the next chain used.
+    fn used_pending(&self) -> bool {
+        let flags = unsafe { ptr::read_volatile(&(*self.desc.add(self.next_used as usize)).flags) };
+        let avail = flags & VIRTQ_DESC_F_AVAIL != 0;
+        let used = flags & VIRTQ_DESC_F_USED != 0;
+        avail == used && used == self.used_wrap_counter
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::memory::Dma;
+
+    /// Consumes the available chains of a packed virtqueue like a device.
+    struct Device {
+        desc: *mut VirtqPackedDesc,
+        size: u16,
+        next_avail: u16,
+        avail_wrap_counter: bool,
+        next_used: u16,
+        used_wrap_counter: bool,
+    }
+
+    impl Device {
+        fn new(virtq: &PackedVirtqueue) -> Device {
+            Device {
+                desc: virtq.desc,
+                size: virtq.size,
+                next_avail: 0,
+                avail_wrap_counter: true,
+                next_used: 0,
+                used_wrap_counter: true,
+            }
+        }
+
+        /// Returns the buffer id and the buffers of the next available chain.
+        fn pop_avail(&mut self) -> Option<(u16, Vec<Buffer>)> {
+            let mut buffers = Vec::new();
+            loop {
+                let desc = unsafe { &*self.desc.add(self.next_avail as usize) };
+                let avail = desc.flags & VIRTQ_DESC_F_AVAIL != 0;
+                let used = desc.flags & VIRTQ_DESC_F_USED != 0;
+                if avail == used || avail != self.avail_wrap_counter {
+                    assert!(buffers.is_empty(), "chain is partially available");
+                    return None;
+                }
+
+                buffers.push(Buffer {
+                    addr: desc.addr,
+                    len: desc.len,
+                    writable: desc.flags & VIRTQ_DESC_F_WRITE != 0,
+                });
+
+                self.next_avail += 1;
+                if self.next_avail == self.size {
+                    self.next_avail = 0;
+                    self.avail_wrap_counter = !self.avail_wrap_counter;
+                }
+
+                if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                    return Some((desc.id, buffers));
+                }
+            }
+        }
+
+        fn push_used(&mut self, id: u16, len: u32, chain_len: u16) {
+            let desc = unsafe { &mut *self.desc.add(self.next_used as usize) };
+            desc.id = id;
+            desc.len = len;
+            desc.flags = if self.used_wrap_counter {
+                VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED
+            } else {
+                0
+            };
+
+            self.next_used += chain_len;
+            if self.next_used >= self.size {
+                self.next_used -= self.size;
+                self.used_wrap_counter = !self.used_wrap_counter;
+            }
+        }
+    }
+
+    fn buffer(addr: usize, writable: bool) -> Buffer {
+        Buffer {
+            addr,
+            len: 100,
+            writable,
+        }
+    }
+
+    #[test]
+    fn test_packed_publish() {
+        let size = 8;
+        let mem: Dma<u8> = Dma::allocate_anonymous(PackedVirtqueue::memory_size(size)).unwrap();
+        let mut virtq = unsafe { PackedVirtqueue::new(size, mem.virt, false) };
+        let mut device = Device::new(&virtq);
+
+        // chains only become visible together with the whole batch
+        let first = virtq.add((0..2).map(|i| buffer(i, i == 1)));
+        let second = virtq.add(std::iter::once(buffer(2, true)));
+        assert_ne!(first, second);
+        assert_eq!(virtq.num_free(), 5);
+        assert!(device.pop_avail().is_none());
+
+        assert!(virtq.publish());
+        assert_eq!(
+            device.pop_avail(),
+            Some((first, vec![buffer(0, false), buffer(1, true)]))
+        );
+        assert_eq!(device.pop_avail(), Some((second, vec![buffer(2, true)])));
+        assert!(device.pop_avail().is_none());
+        assert!(virtq.pop_used().is_none());
+
+        device.push_used(first, 10, 2);
+        assert_eq!(virtq.pop_used(), Some((first, 10)));
+        assert!(virtq.pop_used().is_none());
+        device.push_used(second, 20, 1);
+        assert_eq!(virtq.pop_used(), Some((second, 20)));
+        assert_eq!(virtq.num_free(), size);
+
+        unsafe {
+            assert_eq!((*virtq.driver_event).flags, VIRTQ_EVENT_F_DISABLE);
+        }
+    }
+
+    #[test]
+    fn test_packed_wrap() {
+        // the ring wraps at different positions in each round
+        let size = 5;
+        let mem: Dma<u8> = Dma::allocate_anonymous(PackedVirtqueue::memory_size(size)).unwrap();
+        let mut virtq = unsafe { PackedVirtqueue::new(size, mem.virt, false) };
+        let mut device = Device::new(&virtq);
+
+        for round in 0..20 {
+            let mut chains = Vec::new();
+            let mut chain_len = (round % 3 + 1) as u16;
+            while virtq.num_free() >= chain_len {
+                let addr = round * 100 + chains.len() * 10;
+                let id = virtq.add((0..chain_len).map(|i| buffer(addr + i as usize, false)));
+                chains.push((id, chain_len));
+                chain_len = chain_len % 3 + 1;
+            }
+            virtq.publish();
+
+            for (i, &(id, chain_len)) in chains.iter().enumerate() {
+                let (avail_id, buffers) = device.pop_avail().unwrap();
+                assert_eq!(avail_id, id);
+                assert_eq!(buffers.len(), chain_len as usize);
+                assert_eq!(buffers[0].addr, round * 100 + i * 10);
+            }
+            assert!(device.pop_avail().is_none());
+
+            // the device completes the chains in reverse order
+            for &(id, chain_len) in chains.iter().rev() {
+                device.push_used(id, u32::from(id), chain_len);
+            }
+            for &(id, _) in chains.iter().rev() {
+                assert_eq!(virtq.pop_used(), Some((id, u32::from(id))));
+            }
+            assert!(virtq.pop_used().is_none());
+            assert_eq!(virtq.num_free(), size);
+        }
+    }
+
+    #[test]
+    fn test_packed_notifications() {
+        let size = 4;
+        let mem: Dma<u8> = Dma::allocate_anonymous(PackedVirtqueue::memory_size(size)).unwrap();
+        let mut virtq = unsafe { PackedVirtqueue::new(size, mem.virt, true) };
+        let mut device = Device::new(&virtq);
+        let device_event = virtq.device_event;
+        let set_device_event = |desc, flags| unsafe {
+            *device_event = VirtqPackedEvent { desc, flags };
+        };
+
+        assert!(!virtq.publish());
+        let id = virtq.add(std::iter::once(buffer(0, true)));
+        assert!(virtq.publish());
+        device.pop_avail().unwrap();
+        device.push_used(id, 0, 1);
+        assert_eq!(virtq.pop_used(), Some((id, 0)));
+
+        // the device wants a notification once the first descriptor of the second lap is
+        // available, the batch making it available wraps around the end of the ring
+        set_device_event(0, VIRTQ_EVENT_F_DESC);
+        virtq.add((0..2).map(|i| buffer(i, true)));
+        assert!(!virtq.publish());
+        virtq.add((0..2).map(|i| buffer(i, true)));
+        assert!(virtq.publish());
+
+        set_device_event(0, VIRTQ_EVENT_F_DISABLE);
+        for _ in 0..2 {
+            let (id, buffers) = device.pop_avail().unwrap();
+            device.push_used(id, 0, buffers.len() as u16);
+            assert_eq!(virtq.pop_used(), Some((id, 0)));
+        }
+        virtq.add(std::iter::once(buffer(0, true)));
+        assert!(!virtq.publish());
+
+        // interrupts are requested for the next used chain unless one is already pending
+        assert!(!virtq.enable_interrupts());
+        unsafe {
+            assert_eq!((*virtq.driver_event).flags, VIRTQ_EVENT_F_DESC);
+            assert_eq!((*virtq.driver_event).desc, 1);
+        }
+        let (id, _) = device.pop_avail().unwrap();
+        device.push_used(id, 0, 1);
+        assert!(virtq.enable_interrupts());
+        virtq.disable_interrupts();
+        unsafe {
+            assert_eq!((*virtq.driver_event).flags, VIRTQ_EVENT_F_DISABLE);
+        }
+    }
+}
@@ -0,0 +1,439 @@
+//! The split virtqueue layout: a descriptor table with separate available and used rings (2.6).
+//!
+//! Free descriptors are chained through their `next` field, so the descriptors of a chain keep
+//! the links of the free list they were taken from.
+//!
+//! With `VIRTIO_RING_F_EVENT_IDX` the driver and the device suppress notifications with the
+//! `used_event` and `avail_event` fields behind the rings instead of flags (2.6.7, 2.6.10).
+
+use std::num::Wrapping;
+use std::ops::{Deref, DerefMut, Index, IndexMut};
+use std::{mem, ptr};
+
+use super::transport::QueueAreas;
+use super::{mfence, need_event, Buffer};
+use crate::virtio_constants::*;
+
+// the legacy interface fixes the alignment of the used ring (4.1.5.1.3.1), the modern interface
+// takes the address of each area and accepts the legacy layout
+const QUEUE_ALIGNMENT: usize = 4096;
+
+pub(super) struct SplitVirtqueue {
+    size: u16,
+    desc: *mut VirtqDesc,
+    available: RingWrapper<VirtqAvail>,
+    used: RingWrapper<VirtqUsed>,
+    // first descriptor of the free list
+    free_head: u16,
+    num_free: u16,
+    // chains added to the available ring since the last call of `publish`
+    num_added: u16,
+    last_used_idx: Wrapping<u16>,
+    // `VIRTIO_RING_F_EVENT_IDX` was negotiated
+    event_idx: bool,
+}
+
+impl SplitVirtqueue {
+    /// Returns the number of bytes of a queue with `size` descriptors (2.6.2).
+    pub(super) fn memory_size(size: u16) -> usize {
+        let size = size as usize;
+        align(mem::size_of::<VirtqDesc>() * size + mem::size_of::<u16>() * (3 + size))
+            + align(mem::size_of::<u16>() * 3 + mem::size_of::<VirtqUsedElem>() * size)
+    }
+
+    /// Returns an empty queue with `size` descriptors in the memory at `ptr`, which must be
+    /// aligned to `QUEUE_ALIGNMENT` and hold `memory_size(size)` bytes.
+    pub(super) unsafe fn new(size: u16, ptr: *mut u8, event_idx: bool) -> SplitVirtqueue {
+        let size_usize = size as usize;
+        // DMA memory already follows stricter alignment than `VirtqDesc`
+        #[allow(clippy::cast_ptr_alignment)]
+        let desc = ptr as *mut VirtqDesc;
+        let avail = desc.add(size_usize) as *mut VirtqAvail;
+        let used = align((*avail).ring.as_mut_ptr().add(size_usize) as usize) as *mut VirtqUsed;
+        ptr::write_bytes(ptr, 0, SplitVirtqueue::memory_size(size));
+
+        let mut virtq = SplitVirtqueue {
+            size,
+            desc,
+            available: RingWrapper { ptr: avail, size },
+            used: RingWrapper { ptr: used, size },
+            free_head: 0,
+            num_free: size,
+            num_added: 0,
+            last_used_idx: Wrapping(0),
+            event_idx,
+        };
+
+        for i in 0..size {
+            (*virtq.desc.add(i as usize)).next = i + 1;
+        }
+
+        // optimization hint to not get interrupted when the device consumes a buffer
+        virtq.available.flags = VIRTQ_AVAIL_F_NO_INTERRUPT;
+
+        virtq
+    }
+
+    /// Returns the physical addresses of the queue's areas if it starts at `phys`.
+    pub(super) fn areas(&self, phys: usize) -> QueueAreas {
+        QueueAreas {
+            desc: phys,
+            driver: phys + (self.available.ptr as usize - self.desc as usize),
+            device: phys + (self.used.ptr as usize - self.desc as usize),
+        }
+    }
+
+    pub(super) fn size(&self) -> u16 {
+        self.size
+    }
+
+    pub(super) fn num_free(&self) -> u16 {
+        self.num_free
+    }
+
+    /// Adds a chain of `buffers` to the available ring and returns the id of its head.
+    pub(super) fn add(&mut self, buffers: impl Iterator<Item = Buffer>) -> u16 {
+        let head = self.free_head;
+        let mut idx = head;
+        let mut buffers = buffers.peekable();
+        while let Some(buffer) = buffers.next() {
+            assert!(self.num_free > 0, "no free descriptor left");
+            self.num_free -= 1;
+
+            let desc = unsafe { &mut *self.desc.add(idx as usize) };
+            desc.addr = buffer.addr;
+            desc.len = buffer.len;
+            desc.flags = if buffer.writable {
+                VIRTQ_DESC_F_WRITE
+            } else {
+                0
+            };
+
+            // the next free descriptor continues the chain
+            if buffers.peek().is_some() {
+                desc.flags |= VIRTQ_DESC_F_NEXT;
+            }
+            self.free_head = desc.next;
+            idx = desc.next;
+        }
+
+        let avail_idx = (self.available.idx + Wrapping(self.num_added)).0 % self.size;
+        self.available[avail_idx] = head;
+        self.num_added += 1;
+
+        head
+    }
+
+    /// Makes all chains added since the last call available to the device and returns whether
+    /// the device needs to be notified about them.
+    pub(super) fn publish(&mut self) -> bool {
+        if self.num_added == 0 {
+            return false;
+        }
+
+        let old_idx = self.available.idx;
+        mfence();
+        self.available.idx += Wrapping(self.num_added);
+        self.num_added = 0;
+        mfence();
+
+        // 2.6.10.2: the device either asks for a notification once a specific chain is available
+        // or suppresses all of them
+        if self.event_idx {
+            let avail_event = unsafe { ptr::read_volatile(self.avail_event()) };
+            need_event(avail_event, self.available.idx.0, old_idx.0)
+        } else {
+            let flags = unsafe { ptr::read_volatile(&(*self.used.ptr).flags) };
+            flags & VIRTQ_USED_F_NO_NOTIFY == 0
+        }
+    }
+
+    /// Asks the device to interrupt once it uses the next chain and returns whether a used chain
+    /// is already pending, the device may not interrupt for that one.
+    pub(super) fn enable_interrupts(&mut self) -> bool {
+        // 2.6.7.2: the device ignores the flag if the used event is negotiated
+        unsafe {
+            if self.event_idx {
+                ptr::write_volatile(self.used_event(), self.last_used_idx.0);
+            } else {
+                ptr::write_volatile(&mut (*self.available.ptr).flags, 0);
+            }
+        }
+        mfence();
+
+        unsafe { ptr::read_volatile(&(*self.used.ptr).idx) != self.last_used_idx }
+    }
+
+    /// Asks the device not to interrupt when it uses chains.
+    pub(super) fn disable_interrupts(&mut self) {
+        // the used event is left behind, the device interrupts at most once more after it wrapped
+        unsafe {
+            ptr::write_volatile(&mut (*self.available.ptr).flags, VIRTQ_AVAIL_F_NO_INTERRUPT);
+        }
+    }
+
+    /// Returns the id and the written length of the next used chain and frees its descriptors.
+    pub(super) fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used_idx = unsafe { ptr::read_volatile(&(*self.used.ptr).idx) };
+        if self.last_used_idx == used_idx {
+            return None;
+        }
+        mfence();
+
+        let elem = self.used[self.last_used_idx.0 % self.size].clone();
+        self.last_used_idx += Wrapping(1);
+
+        // return the chain to the free list
+        let mut idx = elem.id;
+        loop {
+            self.num_free += 1;
+            let desc = unsafe { &mut *self.desc.add(idx as usize) };
+            if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                desc.next = self.free_head;
+                break;
+            }
+            idx = desc.next;
+        }
+        self.free_head = elem.id;
+
+        Some((elem.id, elem.len))
+    }
+
+    /// Returns the used event behind the available ring.
+    fn used_event(&self) -> *mut u16 {
+        unsafe {
+            (*self.available.ptr)
+                .ring
+                .as_mut_ptr()
+                .add(self.size as usize)
+        }
+    }
+
+    /// Returns the available event behind the used ring.
+    fn avail_event(&self) -> *mut u16 {
+        unsafe { (*self.used.ptr).ring.as_mut_ptr().add(self.size as usize) as *mut u16 }
+    }
+}
+
+struct RingWrapper<T: Ring> {
+    ptr: *mut T,
+    size: u16,
+}
+
+impl<T: Ring> Index<u16> for RingWrapper<T> {
+    type Output = <T as Ring>::Element;
+    fn index(&self, idx: u16) -> &Self::Output {
+        assert!(
+            idx < self.size,
+            "index {} is greater than queue size {}",
+            idx,
+            self.size
+        );
+        unsafe { &*self.ring().add(idx as usize) }
+    }
+}
+
+impl<T: Ring> IndexMut<u16> for RingWrapper<T> {
+    fn index_mut(&mut self, idx: u16) -> &mut Self::Output {
+        assert!(
+            idx < self.size,
+            "index {} is greater than queue size {}",
+            idx,
+            self.size
+        );
+        unsafe { &mut *self.ring_mut().add(idx as usize) }
+    }
+}
+
+impl<T: Ring> Deref for RingWrapper<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr }
+    }
+}
+
+impl<T: Ring> DerefMut for RingWrapper<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.ptr }
+    }
+}
+
+fn align(ptr: usize) -> usize {
+    ptr + (ptr as *const u8).align_offset(QUEUE_ALIGNMENT)
+}
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    use std::iter;
+
+    use crate::memory::Dma;
+
+    #[test]
+    fn test_align() {
+        // we use a function based on libstd; just checking against the macro from the spec to make
+        // sure we have the same behavior
+        fn align_spec(x: usize) -> usize {
+            (x + (QUEUE_ALIGNMENT - 1)) & !(QUEUE_ALIGNMENT - 1)
+        }
+
+        for i in 0..1_000_000 {
+            let aligned = align(i);
+            assert!(aligned >= i);
+            assert!(aligned - i < QUEUE_ALIGNMENT);
+            assert_eq!(aligned % QUEUE_ALIGNMENT, 0);
+            assert_eq!(aligned, align_spec(i));
+        }
+    }
+
+    /// Consumes the available chains of a split virtqueue like a device.
+    pub(in crate::virtio) struct Device {
+        desc: *const VirtqDesc,
+        avail: *const VirtqAvail,
+        used: *mut VirtqUsed,
+        size: u16,
+        last_avail_idx: Wrapping<u16>,
+    }
+
+    impl Device {
+        pub(in crate::virtio) fn new(virtq: &SplitVirtqueue) -> Device {
+            Device {
+                desc: virtq.desc,
+                avail: virtq.available.ptr,
+                used: virtq.used.ptr,
+                size: virtq.size,
+                last_avail_idx: Wrapping(0),
+            }
+        }
+
+        /// Returns the head and the buffers of the next available chain.
+        pub(in crate::virtio) fn pop_avail(&mut self) -> Option<(u16, Vec<Buffer>)> {
+            unsafe {
+                if (*self.avail).idx == self.last_avail_idx {
+                    return None;
+                }
+
+                let head = *(*self.avail)
+                    .ring
+                    .as_ptr()
+                    .add((self.last_avail_idx.0 % self.size) as usize);
+                self.last_avail_idx += Wrapping(1);
+
+                let mut buffers = Vec::new();
+                let mut idx = head;
+                loop {
+                    let desc = &*self.desc.add(idx as usize);
+                    buffers.push(Buffer {
+                        addr: desc.addr,
+                        len: desc.len,
+                        writable: desc.flags & VIRTQ_DESC_F_WRITE != 0,
+                    });
+                    if desc.flags & VIRTQ_DESC_F_NEXT == 0 {
+                        break;
+                    }
+                    idx = desc.next;
+                }
+
+                Some((head, buffers))
+            }
+        }
+
+        pub(in crate::virtio) fn push_used(&mut self, id: u16, len: u32) {
+            unsafe {
+                let used = &mut *self.used;
+                let elem = &mut *used
+                    .ring
+                    .as_mut_ptr()
+                    .add((used.idx.0 % self.size) as usize);
+                elem.id = id;
+                elem.len = len;
+                used.idx += Wrapping(1);
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_virtqueue() {
+        let size = 4;
+        let mem: Dma<u8> = Dma::allocate_anonymous(SplitVirtqueue::memory_size(size)).unwrap();
+        let mut virtq = unsafe { SplitVirtqueue::new(size, mem.virt, false) };
+        let mut device = Device::new(&virtq);
+
+        let buffer = |addr| Buffer {
+            addr,
+            len: 100,
+            writable: false,
+        };
+
+        for round in 0..10 {
+            // a chain of three descriptors and a single one fill the queue
+            let chain = virtq.add((0..3).map(|i| buffer(round * 10 + i)));
+            let single = virtq.add(std::iter::once(buffer(round * 10 + 5)));
+            assert_eq!(virtq.num_free(), 0);
+            assert!(device.pop_avail().is_none());
+
+            assert!(virtq.publish());
+            let (head, buffers) = device.pop_avail().unwrap();
+            assert_eq!(head, chain);
+            assert_eq!(
+                buffers.iter().map(|b| b.addr).collect::<Vec<_>>(),
+                [round * 10, round * 10 + 1, round * 10 + 2]
+            );
+            assert_eq!(device.pop_avail().unwrap().0, single);
+            assert!(device.pop_avail().is_none());
+
+            // the device completes the chains out of order
+            device.push_used(single, 1);
+            device.push_used(chain, 2);
+            assert_eq!(virtq.pop_used(), Some((single, 1)));
+            assert_eq!(virtq.pop_used(), Some((chain, 2)));
+            assert_eq!(virtq.pop_used(), None);
+            assert_eq!(virtq.num_free(), size);
+        }
+    }
+
+    #[test]
+    fn test_split_notifications() {
+        let size = 8;
+        let mem: Dma<u8> = Dma::allocate_anonymous(SplitVirtqueue::memory_size(size)).unwrap();
+        let buffer = Buffer {
+            addr: 0,
+            len: 100,
+            writable: true,
+        };
+
+        // without the event index the device suppresses notifications with a flag
+        let mut virtq = unsafe { SplitVirtqueue::new(size, mem.virt, false) };
+        assert!(!virtq.publish());
+        virtq.add(iter::once(buffer));
+        assert!(virtq.publish());
+        unsafe { (*virtq.used.ptr).flags = VIRTQ_USED_F_NO_NOTIFY };
+        virtq.add(iter::once(buffer));
+        assert!(!virtq.publish());
+
+        let mut virtq = unsafe { SplitVirtqueue::new(size, mem.virt, true) };
+        let mut device = Device::new(&virtq);
+
+        // the device wants a notification once the third chain is available
+        unsafe { *virtq.avail_event() = 2 };
+        virtq.add(iter::once(buffer));
+        virtq.add(iter::once(buffer));
+        assert!(!virtq.publish());
+        virtq.add(iter::once(buffer));
+        virtq.add(iter::once(buffer));
+        assert!(virtq.publish());
+        virtq.add(iter::once(buffer));
+        assert!(!virtq.publish());
+
+        // interrupts are requested for the next used chain unless one is already pending
+        assert!(!virtq.enable_interrupts());
+        assert_eq!(unsafe { *virtq.used_event() }, 0);
+        let (head, _) = device.pop_avail().unwrap();
+        device.push_used(head, 1);
+        assert!(virtq.enable_interrupts());
+        assert_eq!(virtq.pop_used(), Some((head, 1)));
+        assert!(!virtq.enable_interrupts());
+        assert_eq!(unsafe { *virtq.used_event() }, 1);
+    }
+}
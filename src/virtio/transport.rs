@@ -0,0 +1,483 @@
+//! Register access of virtio devices through the legacy or the modern pci interface.
+//!
+//! Legacy devices expose a fixed header in the i/o bar 0 (4.1.4.8). Modern devices locate their
+//! common configuration, notification, ISR and device configuration structures with vendor
+//! specific pci capabilities (4.1.4), the structures are mapped from memory bars.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::fs::FileExt;
+use std::ptr;
+
+use crate::error::IxyError;
+use crate::pci::{self, read_io16, read_io32, read_io8, write_io16, write_io32, write_io8};
+use crate::virtio_constants::*;
+
+// offsets in the pci configuration space
+const PCI_STATUS: usize = 0x06;
+const PCI_STATUS_CAP_LIST: u8 = 0x10;
+const PCI_CAPABILITY_LIST: usize = 0x34;
+const PCI_CAP_ID_VNDR: u8 = 0x09;
+
+// 4.1.4 - the capabilities of virtio devices are at least 16 bytes long
+const VIRTIO_PCI_CAP_LEN: u8 = 16;
+
+/// Physical addresses of the three areas of a virtqueue.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct QueueAreas {
+    pub(super) desc: usize,
+    pub(super) driver: usize,
+    pub(super) device: usize,
+}
+
+/// Access to the registers of a virtio device.
+pub(super) trait Transport {
+    /// Returns the device status.
+    fn status(&mut self) -> Result<u8, IxyError>;
+
+    /// Sets the device status to `status`.
+    fn set_status(&mut self, status: u8) -> Result<(), IxyError>;
+
+    /// Returns the features offered by the device.
+    fn device_features(&mut self) -> Result<u64, IxyError>;
+
+    /// Accepts the features `features`.
+    fn set_driver_features(&mut self, features: u64) -> Result<(), IxyError>;
+
+    /// Returns the maximum number of entries of queue `index`, 0 if there is no such queue.
+    fn max_queue_size(&mut self, index: u16) -> Result<u16, IxyError>;
+
+    /// Hands queue `index` with `size` entries located at `areas` to the device.
+    fn enable_queue(&mut self, index: u16, size: u16, areas: QueueAreas) -> Result<(), IxyError>;
+
+    /// Assigns msi-x vector `vector` to queue `index`, msi-x must already be enabled.
+    fn set_queue_vector(&mut self, index: u16, vector: u16) -> Result<(), IxyError>;
+
+    /// Notifies the device about new buffers in queue `index`.
+    fn notify_queue(&self, index: u16) -> Result<(), IxyError>;
+
+    /// Reads and clears the interrupt status.
+    fn read_isr(&mut self) -> Result<u8, IxyError>;
+
+    /// Reads the byte at `offset` in the device specific configuration.
+    fn read_config8(&self, offset: u64) -> Result<u8, IxyError>;
+
+    /// Writes the byte at `offset` in the device specific configuration.
+    fn write_config8(&self, offset: u64, value: u8) -> Result<(), IxyError>;
+}
+
+/// The legacy interface through the i/o bar 0.
+pub(super) struct LegacyTransport {
+    bar0: File,
+    // the device specific configuration moves behind the msi-x vectors once msi-x is enabled
+    msix: bool,
+}
+
+impl LegacyTransport {
+    /// Opens the legacy interface of the device at `pci_addr`.
+    pub(super) fn open(pci_addr: &str) -> Result<LegacyTransport, IxyError> {
+        Ok(LegacyTransport {
+            bar0: pci::pci_open_resource(pci_addr, "resource0")?,
+            msix: false,
+        })
+    }
+
+    fn config_offset(&self) -> u64 {
+        if self.msix {
+            VIRTIO_PCI_CONFIG_MSIX
+        } else {
+            VIRTIO_PCI_CONFIG
+        }
+    }
+}
+
+impl Transport for LegacyTransport {
+    fn status(&mut self) -> Result<u8, IxyError> {
+        Ok(read_io8(&mut self.bar0, VIRTIO_PCI_STATUS)?)
+    }
+
+    fn set_status(&mut self, status: u8) -> Result<(), IxyError> {
+        Ok(write_io8(&mut self.bar0, status, VIRTIO_PCI_STATUS)?)
+    }
+
+    fn device_features(&mut self) -> Result<u64, IxyError> {
+        Ok(u64::from(read_io32(
+            &mut self.bar0,
+            VIRTIO_PCI_HOST_FEATURES,
+        )?))
+    }
+
+    fn set_driver_features(&mut self, features: u64) -> Result<(), IxyError> {
+        // the legacy interface only knows the lower 32 feature bits
+        Ok(write_io32(
+            &mut self.bar0,
+            features as u32,
+            VIRTIO_PCI_GUEST_FEATURES,
+        )?)
+    }
+
+    fn max_queue_size(&mut self, index: u16) -> Result<u16, IxyError> {
+        write_io16(&mut self.bar0, index, VIRTIO_PCI_QUEUE_SEL)?;
+        Ok(read_io16(&mut self.bar0, VIRTIO_PCI_QUEUE_NUM)?)
+    }
+
+    fn enable_queue(&mut self, index: u16, _size: u16, areas: QueueAreas) -> Result<(), IxyError> {
+        // 4.1.5.1.3.1 - the legacy layout is fixed by the queue size and the page aligned start
+        write_io16(&mut self.bar0, index, VIRTIO_PCI_QUEUE_SEL)?;
+        write_io32(
+            &mut self.bar0,
+            (areas.desc >> VIRTIO_PCI_QUEUE_ADDR_SHIFT) as u32,
+            VIRTIO_PCI_QUEUE_PFN,
+        )?;
+        Ok(())
+    }
+
+    fn set_queue_vector(&mut self, index: u16, vector: u16) -> Result<(), IxyError> {
+        self.msix = true;
+        write_io16(&mut self.bar0, index, VIRTIO_PCI_QUEUE_SEL)?;
+        write_io16(&mut self.bar0, vector, VIRTIO_MSI_QUEUE_VECTOR)?;
+        check_vector(index, read_io16(&mut self.bar0, VIRTIO_MSI_QUEUE_VECTOR)?)
+    }
+
+    fn notify_queue(&self, index: u16) -> Result<(), IxyError> {
+        // a positional write leaves the file offset alone, so it works without `&mut`
+        Ok(self
+            .bar0
+            .write_all_at(&index.to_ne_bytes(), VIRTIO_PCI_QUEUE_NOTIFY)?)
+    }
+
+    fn read_isr(&mut self) -> Result<u8, IxyError> {
+        Ok(read_io8(&mut self.bar0, VIRTIO_PCI_ISR)?)
+    }
+
+    fn read_config8(&self, offset: u64) -> Result<u8, IxyError> {
+        let mut bar0 = self.bar0.try_clone()?;
+        Ok(read_io8(&mut bar0, self.config_offset() + offset)?)
+    }
+
+    fn write_config8(&self, offset: u64, value: u8) -> Result<(), IxyError> {
+        let mut bar0 = self.bar0.try_clone()?;
+        Ok(write_io8(&mut bar0, value, self.config_offset() + offset)?)
+    }
+}
+
+/// The modern interface through the structures located by the device's pci capabilities.
+pub(super) struct ModernTransport {
+    common: *mut u8,
+    notify: *mut u8,
+    notify_off_multiplier: u32,
+    isr: *mut u8,
+    device: *mut u8,
+    device_len: usize,
+    // notification addresses of the enabled queues relative to `notify`
+    notify_offsets: Vec<usize>,
+}
+
+impl ModernTransport {
+    /// Maps the structures of the modern interface of the device at `pci_addr`, [`None`] if the
+    /// device only has the legacy interface.
+    pub(super) fn map(pci_addr: &str) -> Result<Option<ModernTransport>, IxyError> {
+        let mut config = Vec::new();
+        pci::pci_open_resource_ro(pci_addr, "config")?
+            .read_to_end(&mut config)
+            .map_err(pci::pci_error(pci_addr))?;
+
+        // 4.1.4 - the driver uses the first capability of each type
+        let caps = parse_capabilities(&config);
+        let find = |cfg_type| caps.iter().find(|cap| cap.cfg_type == cfg_type);
+        let (common, notify, isr, device) = match (
+            find(VIRTIO_PCI_CAP_COMMON_CFG),
+            find(VIRTIO_PCI_CAP_NOTIFY_CFG),
+            find(VIRTIO_PCI_CAP_ISR_CFG),
+            find(VIRTIO_PCI_CAP_DEVICE_CFG),
+        ) {
+            (Some(common), Some(notify), Some(isr), Some(device)) => (common, notify, isr, device),
+            _ => return Ok(None),
+        };
+
+        if (common.length as usize) < VIRTIO_PCI_COMMON_CFG_SIZE {
+            return Err(IxyError::Device(format!(
+                "common configuration of {} bytes is too small",
+                common.length
+            )));
+        }
+
+        // structures may share a bar, each bar is mapped once
+        let mut bars = BTreeMap::new();
+        let mut map = |cap: &VirtioPciCap| -> Result<*mut u8, IxyError> {
+            let (addr, len) = match bars.get(&cap.bar) {
+                Some(&bar) => bar,
+                None => {
+                    let bar = pci::pci_map_bar(pci_addr, cap.bar)?;
+                    bars.insert(cap.bar, bar);
+                    bar
+                }
+            };
+
+            if cap.offset as usize + cap.length as usize > len {
+                return Err(IxyError::Device(format!(
+                    "capability of type {} exceeds bar {}",
+                    cap.cfg_type, cap.bar
+                )));
+            }
+
+            Ok(unsafe { addr.add(cap.offset as usize) })
+        };
+
+        Ok(Some(ModernTransport {
+            common: map(common)?,
+            notify: map(notify)?,
+            notify_off_multiplier: notify.notify_off_multiplier,
+            isr: map(isr)?,
+            device: map(device)?,
+            device_len: device.length as usize,
+            notify_offsets: Vec::new(),
+        }))
+    }
+
+    fn read8(&self, offset: usize) -> u8 {
+        unsafe { ptr::read_volatile(self.common.add(offset)) }
+    }
+
+    fn write8(&self, offset: usize, value: u8) {
+        unsafe { ptr::write_volatile(self.common.add(offset), value) }
+    }
+
+    fn read16(&self, offset: usize) -> u16 {
+        unsafe { ptr::read_volatile(self.common.add(offset) as *const u16) }
+    }
+
+    fn write16(&self, offset: usize, value: u16) {
+        unsafe { ptr::write_volatile(self.common.add(offset) as *mut u16, value) }
+    }
+
+    fn read32(&self, offset: usize) -> u32 {
+        unsafe { ptr::read_volatile(self.common.add(offset) as *const u32) }
+    }
+
+    fn write32(&self, offset: usize, value: u32) {
+        unsafe { ptr::write_volatile(self.common.add(offset) as *mut u32, value) }
+    }
+
+    /// Writes the 64 bit `value` as two 32 bit halves, devices need not support 64 bit accesses.
+    fn write64(&self, offset: usize, value: u64) {
+        self.write32(offset, value as u32);
+        self.write32(offset + 4, (value >> 32) as u32);
+    }
+
+    fn check_config_offset(&self, offset: u64) -> Result<usize, IxyError> {
+        if offset as usize >= self.device_len {
+            return Err(IxyError::Device(format!(
+                "offset {} exceeds the device configuration of {} bytes",
+                offset, self.device_len
+            )));
+        }
+        Ok(offset as usize)
+    }
+}
+
+impl Transport for ModernTransport {
+    fn status(&mut self) -> Result<u8, IxyError> {
+        Ok(self.read8(VIRTIO_PCI_COMMON_STATUS))
+    }
+
+    fn set_status(&mut self, status: u8) -> Result<(), IxyError> {
+        self.write8(VIRTIO_PCI_COMMON_STATUS, status);
+        Ok(())
+    }
+
+    fn device_features(&mut self) -> Result<u64, IxyError> {
+        self.write32(VIRTIO_PCI_COMMON_DFSELECT, 0);
+        let low = self.read32(VIRTIO_PCI_COMMON_DF);
+        self.write32(VIRTIO_PCI_COMMON_DFSELECT, 1);
+        let high = self.read32(VIRTIO_PCI_COMMON_DF);
+
+        Ok(u64::from(high) << 32 | u64::from(low))
+    }
+
+    fn set_driver_features(&mut self, features: u64) -> Result<(), IxyError> {
+        self.write32(VIRTIO_PCI_COMMON_GFSELECT, 0);
+        self.write32(VIRTIO_PCI_COMMON_GF, features as u32);
+        self.write32(VIRTIO_PCI_COMMON_GFSELECT, 1);
+        self.write32(VIRTIO_PCI_COMMON_GF, (features >> 32) as u32);
+        Ok(())
+    }
+
+    fn max_queue_size(&mut self, index: u16) -> Result<u16, IxyError> {
+        if index >= self.read16(VIRTIO_PCI_COMMON_NUMQ) {
+            return Ok(0);
+        }
+
+        self.write16(VIRTIO_PCI_COMMON_Q_SELECT, index);
+        Ok(self.read16(VIRTIO_PCI_COMMON_Q_SIZE))
+    }
+
+    fn enable_queue(&mut self, index: u16, size: u16, areas: QueueAreas) -> Result<(), IxyError> {
+        // 4.1.5.1.3
+        self.write16(VIRTIO_PCI_COMMON_Q_SELECT, index);
+        self.write16(VIRTIO_PCI_COMMON_Q_SIZE, size);
+        self.write64(VIRTIO_PCI_COMMON_Q_DESCLO, areas.desc as u64);
+        self.write64(VIRTIO_PCI_COMMON_Q_AVAILLO, areas.driver as u64);
+        self.write64(VIRTIO_PCI_COMMON_Q_USEDLO, areas.device as u64);
+
+        let notify_off = self.read16(VIRTIO_PCI_COMMON_Q_NOFF);
+        if self.notify_offsets.len() <= index as usize {
+            self.notify_offsets.resize(index as usize + 1, 0);
+        }
+        self.notify_offsets[index as usize] =
+            usize::from(notify_off) * self.notify_off_multiplier as usize;
+
+        self.write16(VIRTIO_PCI_COMMON_Q_ENABLE, 1);
+        Ok(())
+    }
+
+    fn set_queue_vector(&mut self, index: u16, vector: u16) -> Result<(), IxyError> {
+        self.write16(VIRTIO_PCI_COMMON_Q_SELECT, index);
+        self.write16(VIRTIO_PCI_COMMON_Q_MSIX, vector);
+        check_vector(index, self.read16(VIRTIO_PCI_COMMON_Q_MSIX))
+    }
+
+    fn notify_queue(&self, index: u16) -> Result<(), IxyError> {
+        let offset = self.notify_offsets[index as usize];
+        unsafe { ptr::write_volatile(self.notify.add(offset) as *mut u16, index) };
+        Ok(())
+    }
+
+    fn read_isr(&mut self) -> Result<u8, IxyError> {
+        Ok(unsafe { ptr::read_volatile(self.isr) })
+    }
+
+    fn read_config8(&self, offset: u64) -> Result<u8, IxyError> {
+        let offset = self.check_config_offset(offset)?;
+        Ok(unsafe { ptr::read_volatile(self.device.add(offset)) })
+    }
+
+    fn write_config8(&self, offset: u64, value: u8) -> Result<(), IxyError> {
+        let offset = self.check_config_offset(offset)?;
+        unsafe { ptr::write_volatile(self.device.add(offset), value) };
+        Ok(())
+    }
+}
+
+/// Checks the vector read back after assigning one to queue `index`, devices that fail to
+/// allocate resources for the vector report `VIRTIO_MSI_NO_VECTOR` (4.1.4.3.3).
+fn check_vector(index: u16, vector: u16) -> Result<(), IxyError> {
+    if vector == VIRTIO_MSI_NO_VECTOR {
+        return Err(IxyError::Device(format!(
+            "device cannot assign a msi-x vector to queue #{}",
+            index
+        )));
+    }
+    Ok(())
+}
+
+/// A vendor specific pci capability of a virtio device (4.1.4).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct VirtioPciCap {
+    cfg_type: u8,
+    bar: u8,
+    offset: u32,
+    length: u32,
+    // only set for notification capabilities
+    notify_off_multiplier: u32,
+}
+
+/// Returns the virtio capabilities in the pci configuration space `config` in list order.
+fn parse_capabilities(config: &[u8]) -> Vec<VirtioPciCap> {
+    let byte = |offset: usize| config.get(offset).copied().unwrap_or(0);
+    let dword = |offset: usize| {
+        config
+            .get(offset..offset + 4)
+            .map_or(0, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    };
+
+    let mut caps = Vec::new();
+    if byte(PCI_STATUS) & PCI_STATUS_CAP_LIST == 0 {
+        return caps;
+    }
+
+    // a broken list could loop, the 256 bytes of the configuration space fit at most 48 entries
+    let mut next = byte(PCI_CAPABILITY_LIST) & 0xfc;
+    for _ in 0..48 {
+        if next == 0 {
+            break;
+        }
+
+        let pos = usize::from(next);
+        let cfg_type = byte(pos + 3);
+        if byte(pos) == PCI_CAP_ID_VNDR
+            && byte(pos + 2) >= VIRTIO_PCI_CAP_LEN
+            && pos + usize::from(VIRTIO_PCI_CAP_LEN) <= config.len()
+        {
+            caps.push(VirtioPciCap {
+                cfg_type,
+                bar: byte(pos + 4),
+                offset: dword(pos + 8),
+                length: dword(pos + 12),
+                notify_off_multiplier: if cfg_type == VIRTIO_PCI_CAP_NOTIFY_CFG {
+                    dword(pos + 16)
+                } else {
+                    0
+                },
+            });
+        }
+
+        next = byte(pos + 1) & 0xfc;
+    }
+
+    caps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_capabilities() {
+        let mut config = vec![0; 256];
+        config[PCI_STATUS] = PCI_STATUS_CAP_LIST;
+        config[PCI_CAPABILITY_LIST] = 0x40;
+
+        // msi-x capability followed by the common configuration and notification capabilities
+        config[0x40..0x42].copy_from_slice(&[0x11, 0x50]);
+        config[0x50..0x54].copy_from_slice(&[PCI_CAP_ID_VNDR, 0x70, 16, 1]);
+        config[0x54] = 4;
+        config[0x58..0x5c].copy_from_slice(&0x1000u32.to_le_bytes());
+        config[0x5c..0x60].copy_from_slice(&56u32.to_le_bytes());
+        config[0x70..0x74].copy_from_slice(&[PCI_CAP_ID_VNDR, 0x00, 20, 2]);
+        config[0x74] = 4;
+        config[0x78..0x7c].copy_from_slice(&0x3000u32.to_le_bytes());
+        config[0x7c..0x80].copy_from_slice(&0x1000u32.to_le_bytes());
+        config[0x80..0x84].copy_from_slice(&4u32.to_le_bytes());
+
+        assert_eq!(
+            parse_capabilities(&config),
+            vec![
+                VirtioPciCap {
+                    cfg_type: VIRTIO_PCI_CAP_COMMON_CFG,
+                    bar: 4,
+                    offset: 0x1000,
+                    length: 56,
+                    notify_off_multiplier: 0,
+                },
+                VirtioPciCap {
+                    cfg_type: VIRTIO_PCI_CAP_NOTIFY_CFG,
+                    bar: 4,
+                    offset: 0x3000,
+                    length: 0x1000,
+                    notify_off_multiplier: 4,
+                },
+            ]
+        );
+
+        // the capability list of a legacy device is empty or has no virtio capabilities
+        config[PCI_STATUS] = 0;
+        assert!(parse_capabilities(&config).is_empty());
+
+        // loops end eventually, the first of the 48 visited entries is the msi-x capability
+        config[PCI_STATUS] = PCI_STATUS_CAP_LIST;
+        config[0x71] = 0x50;
+        assert_eq!(parse_capabilities(&config).len(), 47);
+    }
+}
@@ -50,6 +50,45 @@ pub const VIRTIO_PCI_ISR: u64                  = 19; /* interrupt status registe
 /* Only if MSIX is enabled: */
 pub const VIRTIO_MSI_CONFIG_VECTOR: u64        = 20; /* configuration change vector (16, RW) */
 pub const VIRTIO_MSI_QUEUE_VECTOR: u64         = 22; /* vector for selected VQ notifications (16, RW) */
+/* The device specific configuration follows the header, 4 bytes later if MSIX is enabled. */
+pub const VIRTIO_PCI_CONFIG: u64               = 20;
+pub const VIRTIO_PCI_CONFIG_MSIX: u64          = 24;
+/* Vector value used to disable MSI for queue or configuration changes. */
+pub const VIRTIO_MSI_NO_VECTOR: u16            = 0xffff;
+
+/* Fields of the network device configuration (5.1.4). */
+pub const VIRTIO_NET_CONFIG_MAC: u64           = 0;  /* mac address (6 bytes, RO) */
+pub const VIRTIO_NET_CONFIG_STATUS: u64        = 6;  /* link status (16, RO) */
+pub const VIRTIO_NET_CONFIG_MAX_VQ_PAIRS: u64  = 8;  /* max. number of queue pairs (16, RO) */
+pub const VIRTIO_NET_CONFIG_MTU: u64           = 10; /* max. mtu (16, RO) */
+
+/*
+ * Vendor specific PCI capabilities of modern devices locating their structures (4.1.4).
+ */
+pub const VIRTIO_PCI_CAP_COMMON_CFG: u8        = 1; /* common configuration */
+pub const VIRTIO_PCI_CAP_NOTIFY_CFG: u8        = 2; /* notifications */
+pub const VIRTIO_PCI_CAP_ISR_CFG: u8           = 3; /* ISR status */
+pub const VIRTIO_PCI_CAP_DEVICE_CFG: u8        = 4; /* device specific configuration */
+pub const VIRTIO_PCI_CAP_PCI_CFG: u8           = 5; /* PCI configuration access */
+
+/* Fields of the common configuration structure (4.1.4.3). */
+pub const VIRTIO_PCI_COMMON_DFSELECT: usize    = 0;  /* device feature word selection (32, RW) */
+pub const VIRTIO_PCI_COMMON_DF: usize          = 4;  /* selected device feature word (32, RO) */
+pub const VIRTIO_PCI_COMMON_GFSELECT: usize    = 8;  /* driver feature word selection (32, RW) */
+pub const VIRTIO_PCI_COMMON_GF: usize          = 12; /* selected driver feature word (32, RW) */
+pub const VIRTIO_PCI_COMMON_MSIX: usize        = 16; /* configuration change vector (16, RW) */
+pub const VIRTIO_PCI_COMMON_NUMQ: usize        = 18; /* number of queues (16, RO) */
+pub const VIRTIO_PCI_COMMON_STATUS: usize      = 20; /* device status (8, RW) */
+pub const VIRTIO_PCI_COMMON_CFGGENERATION: usize = 21; /* configuration generation (8, RO) */
+pub const VIRTIO_PCI_COMMON_Q_SELECT: usize    = 22; /* queue selection (16, RW) */
+pub const VIRTIO_PCI_COMMON_Q_SIZE: usize      = 24; /* size of the selected queue (16, RW) */
+pub const VIRTIO_PCI_COMMON_Q_MSIX: usize      = 26; /* vector of the selected queue (16, RW) */
+pub const VIRTIO_PCI_COMMON_Q_ENABLE: usize    = 28; /* selected queue is enabled (16, RW) */
+pub const VIRTIO_PCI_COMMON_Q_NOFF: usize      = 30; /* notify offset of the selected queue (16, RO) */
+pub const VIRTIO_PCI_COMMON_Q_DESCLO: usize    = 32; /* descriptor table address (64, RW) */
+pub const VIRTIO_PCI_COMMON_Q_AVAILLO: usize   = 40; /* available ring address (64, RW) */
+pub const VIRTIO_PCI_COMMON_Q_USEDLO: usize    = 48; /* used ring address (64, RW) */
+pub const VIRTIO_PCI_COMMON_CFG_SIZE: usize    = 56;
 
 /* Status byte for guest to report progress. */
 pub const VIRTIO_CONFIG_STATUS_RESET: u8       = 0x00;
@@ -71,6 +110,14 @@ pub const VIRTQ_DESC_F_NEXT: u16               = 1;
 pub const VIRTQ_DESC_F_WRITE: u16              = 2;
 /* This means the buffer contains a list of buffer descriptors. */
 pub const VIRTQ_DESC_F_INDIRECT: u16           = 4;
+/* Mark a descriptor of a packed virtqueue as available and used, together with the wrap counters. */
+pub const VIRTQ_DESC_F_AVAIL: u16              = 1 << 7;
+pub const VIRTQ_DESC_F_USED: u16               = 1 << 15;
+
+/* Event suppression flags of packed virtqueues. */
+pub const VIRTQ_EVENT_F_ENABLE: u16            = 0;
+pub const VIRTQ_EVENT_F_DISABLE: u16           = 1;
+pub const VIRTQ_EVENT_F_DESC: u16              = 2;
 
 /* The feature bitmap for virtio net */
 pub const VIRTIO_NET_F_CSUM: usize             = 0;  /* Host handles pkts w/ partial csum */
@@ -104,9 +151,15 @@ pub const VIRTIO_F_ANY_LAYOUT: usize           = 27;
 /* We support indirect buffer descriptors */
 pub const VIRTIO_RING_F_INDIRECT_DESC: usize   = 28;
 
+/* The used_event and avail_event fields suppress notifications up to a given ring index */
+pub const VIRTIO_RING_F_EVENT_IDX: usize       = 29;
+
 pub const VIRTIO_F_VERSION_1: usize            = 32;
 pub const VIRTIO_F_IOMMU_PLATFORM: usize       = 33;
 
+/* The device supports the packed virtqueue layout */
+pub const VIRTIO_F_RING_PACKED: usize          = 34;
+
 
 /**
  * Control the RX mode, ie. promiscuous, allmulti, etc...
@@ -123,6 +176,49 @@ pub const VIRTIO_NET_CTRL_RX_NOMULTI: u8       = 3;
 pub const VIRTIO_NET_CTRL_RX_NOUNI: u8         = 4;
 pub const VIRTIO_NET_CTRL_RX_NOBCAST: u8       = 5;
 
+/**
+ * Control the MAC filter table.
+ *
+ * The MAC filter table is managed by the hypervisor, the guest should
+ * assume the size is infinite.  Filtering should be considered
+ * non-perfect, ie. based on hypervisor resources, the guest may
+ * received packets from sources not specified in the filter list.
+ *
+ * In addition to the class/cmd header, the TABLE_SET command requires
+ * two out scatterlists.  Each contains a 4 byte count of entries followed
+ * by a concatenated byte stream of the ETH_ALEN MAC addresses.  The
+ * first sg list contains unicast addresses, the second is for multicast.
+ * This functionality is present if the VIRTIO_NET_F_CTRL_RX feature
+ * is available.
+ */
+pub const VIRTIO_NET_CTRL_MAC: u8              = 1;
+pub const VIRTIO_NET_CTRL_MAC_TABLE_SET: u8    = 0;
+pub const VIRTIO_NET_CTRL_MAC_ADDR_SET: u8     = 1;
+
+/**
+ * Control VLAN filtering
+ *
+ * The VLAN filter table is controlled via a simple ADD/DEL interface.
+ * VLAN IDs not added may be filtered by the hypervisor.  Del is the
+ * opposite of add.  Both commands expect an out entry containing a 2
+ * byte VLAN ID.  VLAN filtering is available with the
+ * VIRTIO_NET_F_CTRL_VLAN feature bit.
+ */
+pub const VIRTIO_NET_CTRL_VLAN: u8             = 2;
+pub const VIRTIO_NET_CTRL_VLAN_ADD: u8         = 0;
+pub const VIRTIO_NET_CTRL_VLAN_DEL: u8         = 1;
+
+/**
+ * Control the number of rx and tx queue pairs used by the device.
+ *
+ * The command expects an out entry containing the 2 byte number of
+ * queue pairs. It is available with the VIRTIO_NET_F_MQ feature.
+ */
+pub const VIRTIO_NET_CTRL_MQ: u8               = 4;
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET: u8  = 0;
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN: u16 = 1;
+pub const VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX: u16 = 0x8000;
+
 pub const VIRTIO_NET_OK: u8                    = 0;
 pub const VIRTIO_NET_ERR: u8                   = 1;
 
@@ -142,6 +238,15 @@ pub struct virtio_net_hdr {
     pub csum_offset: u16, // Offset after that to place checksum
 }
 
+/**
+ * The header used with VIRTIO_NET_F_MRG_RXBUF or VIRTIO_F_VERSION_1.
+ */
+#[repr(C)]
+pub struct virtio_net_hdr_mrg_rxbuf {
+    pub hdr: virtio_net_hdr,
+    pub num_buffers: u16, // Number of merged rx buffers
+}
+
 pub const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8      = 1;    /**< Use csum_start,csum_offset*/
 pub const VIRTIO_NET_HDR_F_DATA_VALID: u8      = 2;    /**< Checksum is valid */
 
@@ -175,6 +280,25 @@ pub struct VirtqDesc {
     pub next: u16,   /* We chain unused descriptors via this. */
 }
 
+/* Packed virtqueue descriptors: 16 bytes.
+ * A chain occupies consecutive descriptors. */
+#[repr(C)]
+#[derive(Default)]
+pub struct VirtqPackedDesc {
+    pub addr: usize, /* Buffer address (guest-physical). */
+    pub len: u32,    /* Buffer length. */
+    pub id: u16,     /* Buffer ID. */
+    pub flags: u16,  /* The flags as indicated above. */
+}
+
+/* Event suppression structure of the driver and the device area of a packed virtqueue. */
+#[repr(C)]
+#[derive(Default)]
+pub struct VirtqPackedEvent {
+    pub desc: u16,  /* Descriptor ring change event offset and wrap counter. */
+    pub flags: u16, /* Descriptor ring change event flags. */
+}
+
 #[repr(C)]
 pub struct VirtqAvail {
     pub flags: u16,
@@ -265,6 +389,76 @@ impl VirtioNetCtrlPromisc {
     }
 }
 
+#[derive(Debug)]
+pub struct VirtioNetCtrlAllMulti(u8);
+
+impl VirtioNetCtrlCommand for VirtioNetCtrlAllMulti {
+    const CLASS: u8   = VIRTIO_NET_CTRL_RX;
+    const COMMAND: u8 = VIRTIO_NET_CTRL_RX_ALLMULTI;
+}
+
+impl VirtioNetCtrlAllMulti {
+    pub fn new(on: bool) -> VirtioNetCtrlAllMulti {
+        VirtioNetCtrlAllMulti(on as u8)
+    }
+}
+
+#[derive(Debug)]
+pub struct VirtioNetCtrlMacAddrSet([u8; 6]);
+
+impl VirtioNetCtrlCommand for VirtioNetCtrlMacAddrSet {
+    const CLASS: u8   = VIRTIO_NET_CTRL_MAC;
+    const COMMAND: u8 = VIRTIO_NET_CTRL_MAC_ADDR_SET;
+}
+
+impl VirtioNetCtrlMacAddrSet {
+    pub fn new(mac: [u8; 6]) -> VirtioNetCtrlMacAddrSet {
+        VirtioNetCtrlMacAddrSet(mac)
+    }
+}
+
+#[derive(Debug)]
+pub struct VirtioNetCtrlVlanAdd(u16);
+
+impl VirtioNetCtrlCommand for VirtioNetCtrlVlanAdd {
+    const CLASS: u8   = VIRTIO_NET_CTRL_VLAN;
+    const COMMAND: u8 = VIRTIO_NET_CTRL_VLAN_ADD;
+}
+
+impl VirtioNetCtrlVlanAdd {
+    pub fn new(vlan_id: u16) -> VirtioNetCtrlVlanAdd {
+        VirtioNetCtrlVlanAdd(vlan_id)
+    }
+}
+
+#[derive(Debug)]
+pub struct VirtioNetCtrlVlanDel(u16);
+
+impl VirtioNetCtrlCommand for VirtioNetCtrlVlanDel {
+    const CLASS: u8   = VIRTIO_NET_CTRL_VLAN;
+    const COMMAND: u8 = VIRTIO_NET_CTRL_VLAN_DEL;
+}
+
+impl VirtioNetCtrlVlanDel {
+    pub fn new(vlan_id: u16) -> VirtioNetCtrlVlanDel {
+        VirtioNetCtrlVlanDel(vlan_id)
+    }
+}
+
+#[derive(Debug)]
+pub struct VirtioNetCtrlMqPairsSet(u16);
+
+impl VirtioNetCtrlCommand for VirtioNetCtrlMqPairsSet {
+    const CLASS: u8   = VIRTIO_NET_CTRL_MQ;
+    const COMMAND: u8 = VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET;
+}
+
+impl VirtioNetCtrlMqPairsSet {
+    pub fn new(pairs: u16) -> VirtioNetCtrlMqPairsSet {
+        VirtioNetCtrlMqPairsSet(pairs)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -273,5 +467,6 @@ mod tests {
     #[test]
     fn static_type_sizes() {
         assert_eq!(mem::size_of::<VirtioNetCtrl<VirtioNetCtrlPromisc>>(), 4);
+        assert_eq!(mem::size_of::<VirtioNetCtrl<VirtioNetCtrlVlanAdd>>(), 6);
     }
 }